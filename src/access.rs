@@ -0,0 +1,128 @@
+use mongodb::bson::{doc, Bson, Document};
+
+/// Who is reading, from least to most trusted.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Public,
+    Admin,
+}
+
+/// Fields of `posts` that only some roles may read, with the least trusted
+/// role that may. Every other field is readable by everyone.
+const POST_FIELDS: [(&str, Role); 1] = [("author_email", Role::Admin)];
+
+/// Which fields a role may not read, applied to the query itself so that
+/// hidden fields never leave the server: as an exclusion in a find's
+/// projection, or as an `$unset` at the start of a pipeline.
+///
+/// [`PostRepository::with_role`](crate::repository::PostRepository::with_role)
+/// applies [`ReadPolicy::posts`] to every read it makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadPolicy {
+    fields: &'static [(&'static str, Role)],
+}
+
+impl ReadPolicy {
+    pub fn posts() -> Self {
+        ReadPolicy { fields: &POST_FIELDS }
+    }
+
+    /// Fields `role` may not read.
+    pub fn hidden(&self, role: Role) -> Vec<&'static str> {
+        self.fields.iter().filter(|(_, least)| role < *least).map(|(field, _)| *field).collect()
+    }
+
+    /// Narrows `projection` so that it leaves out the fields `role` may not
+    /// read: they are dropped from an inclusion projection and added to an
+    /// exclusion one, which is also what no projection becomes.
+    pub fn restrict_projection(&self, role: Role, projection: &mut Option<Document>) {
+        let hidden = self.hidden(role);
+        if hidden.is_empty() {
+            return;
+        }
+        let projection = projection.get_or_insert_with(Document::new);
+        if is_inclusion(projection) {
+            let covered: Vec<String> = projection.keys()
+                .filter(|field| hidden.iter().any(|hidden| covers(hidden, field)))
+                .cloned()
+                .collect();
+            for field in covered {
+                projection.remove(&field);
+            }
+        } else {
+            for field in hidden {
+                projection.insert(field, 0);
+            }
+        }
+    }
+
+    /// `pipeline` with the fields `role` may not read removed from its input,
+    /// right after the stages that have to come first. Fields brought in by
+    /// later stages, e.g. a `$lookup` of other posts, aren't covered.
+    pub fn restrict_pipeline(&self, role: Role, mut pipeline: Vec<Document>) -> Vec<Document> {
+        let hidden = self.hidden(role);
+        if hidden.is_empty() {
+            return pipeline;
+        }
+        let at = pipeline.iter()
+            .take_while(|stage| ["$match", "$geoNear", "$search"].iter().any(|first| stage.contains_key(*first)))
+            .count();
+        pipeline.insert(at, doc! { "$unset": hidden });
+        pipeline
+    }
+}
+
+/// Whether `projection` lists the fields to keep rather than those to drop.
+fn is_inclusion(projection: &Document) -> bool {
+    projection.iter().any(|(field, value)| {
+        field != "_id" && !matches!(value, Bson::Int32(0) | Bson::Int64(0) | Bson::Boolean(false))
+            && !matches!(value, Bson::Double(value) if *value == 0.0)
+    })
+}
+
+/// Whether hiding `hidden` has to hide `field` too: the same field or one
+/// inside it.
+fn covers(hidden: &str, field: &str) -> bool {
+    field == hidden || field.strip_prefix(hidden).is_some_and(|rest| rest.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projections_leave_out_hidden_fields() {
+        let policy = ReadPolicy::posts();
+        let mut none = None;
+        policy.restrict_projection(Role::Public, &mut none);
+        assert_eq!(none, Some(doc! { "author_email": 0 }));
+
+        let mut inclusion = Some(doc! { "title": 1, "author_email": 1, "author_email.domain": 1 });
+        policy.restrict_projection(Role::Public, &mut inclusion);
+        assert_eq!(inclusion, Some(doc! { "title": 1 }));
+
+        let mut exclusion = Some(doc! { "_id": 1, "message": 0 });
+        policy.restrict_projection(Role::Public, &mut exclusion);
+        assert_eq!(exclusion, Some(doc! { "_id": 1, "message": 0, "author_email": 0 }));
+
+        let mut admin = None;
+        policy.restrict_projection(Role::Admin, &mut admin);
+        assert_eq!(admin, None);
+    }
+
+    #[test]
+    fn pipelines_unset_hidden_fields_after_leading_matches() {
+        let policy = ReadPolicy::posts();
+        let pipeline = vec![
+            doc! { "$match": { "$text": { "$search": "mongo" } } },
+            doc! { "$sort": { "created_at": -1 } },
+        ];
+        assert_eq!(policy.restrict_pipeline(Role::Public, pipeline.clone()), [
+            pipeline[0].clone(),
+            doc! { "$unset": ["author_email"] },
+            pipeline[1].clone(),
+        ]);
+        assert_eq!(policy.restrict_pipeline(Role::Admin, pipeline.clone()), pipeline);
+    }
+}
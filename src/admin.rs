@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::results::CollectionType;
+
+use crate::digest::Fnv1a;
+use crate::error::Result;
+use crate::ids::number;
+use crate::latency::{LatencyHistograms, OpLatency};
+use crate::namespace::Namespace;
+
+/// Digest of a collection's contents, see [`checksum`].
+#[derive(serde::Serialize, Debug)]
+pub struct Checksum {
+    pub collection: String,
+    pub documents: u64,
+    pub digest: String,
+}
+
+/// Streams `collection` in `_id` order and hashes every document's canonical
+/// Extended JSON, so two copies (another cluster, a restored backup) can be
+/// compared by digest instead of document by document.
+///
+/// Canonical Extended JSON keeps types apart (an `int` 1 and a `long` 1
+/// differ) but field order counts too: the same fields in another order are
+/// a different document here, as they are to the server.
+pub async fn checksum(ns: &Namespace, collection: &str) -> Result<Checksum> {
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let mut cursor = ns.collection::<Document>(collection).find(None, options).await?;
+    let mut hasher = Fnv1a::new();
+    let mut documents = 0;
+    while let Some(document) = cursor.try_next().await? {
+        hasher.update(Bson::Document(document).into_canonical_extjson().to_string().as_bytes());
+        // Separator, so document boundaries are part of the digest
+        hasher.update(b"\n");
+        documents += 1;
+    }
+    Ok(Checksum { collection: ns.name(collection), documents, digest: hasher.hex() })
+}
+
+/// Sizes and counts of every collection in a namespace, with the latencies
+/// of the operations run against them, as served at `/admin/overview`.
+#[derive(serde::Serialize, Debug)]
+pub struct Overview {
+    pub collections: Vec<CollectionOverview>,
+    /// Percentiles of every op since the process started, as at
+    /// `/metrics/latency`.
+    pub latencies: Vec<OpLatency>,
+}
+
+/// One collection's `collStats`. Counts and sizes come from the storage
+/// engine's metadata, so they are estimates, like
+/// `estimated_document_count`, but cost nothing to read.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct CollectionOverview {
+    /// Without the namespace's prefix.
+    pub name: String,
+    pub documents: i64,
+    /// Uncompressed size of the documents.
+    pub size_bytes: i64,
+    pub avg_document_bytes: i64,
+    /// What the documents take on disk, compressed, including free space.
+    pub storage_bytes: i64,
+    pub total_index_bytes: i64,
+    /// Size of each index, by name.
+    pub index_bytes: BTreeMap<String, i64>,
+}
+
+impl CollectionOverview {
+    fn from_stats(name: &str, stats: &Document) -> Self {
+        let index_bytes = stats.get_document("indexSizes").ok()
+            .map(|sizes| sizes.iter().map(|(index, size)| (index.clone(), number(Some(size)))).collect())
+            .unwrap_or_default();
+        CollectionOverview {
+            name: name.to_string(),
+            documents: number(stats.get("count")),
+            size_bytes: number(stats.get("size")),
+            avg_document_bytes: number(stats.get("avgObjSize")),
+            storage_bytes: number(stats.get("storageSize")),
+            total_index_bytes: number(stats.get("totalIndexSize")),
+            index_bytes,
+        }
+    }
+}
+
+/// `collStats` of every collection in `ns`, by name, skipping views and
+/// `system.` collections, together with `latency`'s summary.
+pub async fn overview(ns: &Namespace, latency: &LatencyHistograms) -> Result<Overview> {
+    let prefix = ns.name("");
+    let mut specs: Vec<_> = ns.db().list_collections(None, None).await?.try_collect().await?;
+    specs.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut collections = Vec::new();
+    for spec in specs {
+        if spec.name.starts_with("system.") || spec.collection_type == CollectionType::View {
+            continue;
+        }
+        let Some(name) = spec.name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let stats = ns.db().run_command(doc! { "collStats": &spec.name }, None).await?;
+        collections.push(CollectionOverview::from_stats(name, &stats));
+    }
+    Ok(Overview { collections, latencies: latency.summary() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_stats_take_whichever_number_type() {
+        let stats = doc! {
+            "count": 3,
+            "size": 300_i64,
+            "avgObjSize": 100.0,
+            "storageSize": 4096,
+            "totalIndexSize": 8192_i64,
+            "indexSizes": { "_id_": 4096, "tags_1": 4096_i64 },
+        };
+        let overview = CollectionOverview::from_stats("posts", &stats);
+        assert_eq!(overview.documents, 3);
+        assert_eq!(overview.avg_document_bytes, 100);
+        assert_eq!(overview.total_index_bytes, 8192);
+        let index_bytes = BTreeMap::from([("_id_".to_string(), 4096), ("tags_1".to_string(), 4096)]);
+        assert_eq!(overview.index_bytes, index_bytes);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use futures::TryStreamExt;
+use mongodb::IndexModel;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+
+use crate::error::Result;
+use crate::ids::number;
+use crate::namespace::Namespace;
+
+/// Profiler entries read per run, the most recent first.
+const PROFILE_SAMPLE: i64 = 10_000;
+
+/// An index that would have served queries the profiler saw scan a whole
+/// collection.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    /// Without the namespace's prefix.
+    pub collection: String,
+    /// Equality fields first, then the sort, then range fields, so one index
+    /// both narrows the scan and returns documents in order.
+    pub keys: Document,
+    /// Collection scans it would have replaced.
+    pub scans: u64,
+    /// Time they took, together.
+    pub millis: i64,
+    /// The filter of one of them, values and all.
+    pub example: Document,
+}
+
+/// How a query uses each field, from its filter and sort.
+#[derive(Debug, Default, PartialEq)]
+struct Shape {
+    equality: Vec<String>,
+    sort: Vec<(String, i32)>,
+    range: Vec<String>,
+}
+
+impl Shape {
+    /// Shape of `filter` and `sort`. Operators an index can't serve on its
+    /// own, like `$or`, `$expr` or `$text`, are left out: an index for the
+    /// rest still narrows the scan.
+    fn of(filter: &Document, sort: Option<&Document>) -> Shape {
+        let mut shape = Shape::default();
+        shape.add_filter(filter);
+        for (field, direction) in sort.into_iter().flatten() {
+            let direction = if number(Some(direction)) < 0 { -1 } else { 1 };
+            shape.sort.push((field.clone(), direction));
+        }
+        shape.equality.sort();
+        shape.equality.dedup();
+        shape.range.sort();
+        shape.range.dedup();
+        shape.range.retain(|field| !shape.equality.contains(field));
+        shape
+    }
+
+    fn add_filter(&mut self, filter: &Document) {
+        for (field, condition) in filter {
+            if field == "$and" {
+                for clause in condition.as_array().into_iter().flatten() {
+                    if let Some(clause) = clause.as_document() {
+                        self.add_filter(clause);
+                    }
+                }
+                continue;
+            }
+            if field.starts_with('$') {
+                continue;
+            }
+            match condition {
+                Bson::Document(operators) if operators.keys().next().is_some_and(|op| op.starts_with('$')) => {
+                    if operators.contains_key("$eq") || operators.contains_key("$in")
+                        || operators.contains_key("$elemMatch")
+                    {
+                        self.equality.push(field.clone());
+                    } else {
+                        self.range.push(field.clone());
+                    }
+                }
+                _ => self.equality.push(field.clone()),
+            }
+        }
+    }
+
+    /// The keys of the index, ESR-ordered; `None` when the query constrains
+    /// nothing an index could.
+    fn index_keys(&self) -> Option<Document> {
+        let mut keys = Document::new();
+        for field in &self.equality {
+            keys.insert(field.as_str(), 1);
+        }
+        for (field, direction) in &self.sort {
+            if !keys.contains_key(field) {
+                keys.insert(field.as_str(), *direction);
+            }
+        }
+        for field in &self.range {
+            if !keys.contains_key(field) {
+                keys.insert(field.as_str(), 1);
+            }
+        }
+        (!keys.is_empty() && keys.keys().all(|field| field != "_id")).then_some(keys)
+    }
+}
+
+/// The filter and sort of a profiler entry, for the operations
+/// that take a filter: finds, aggregations through their leading `$match`
+/// and `$sort`, counts, distincts, updates, deletes and findAndModify.
+fn query_of(entry: &Document) -> Option<(Document, Option<Document>)> {
+    let command = entry.get_document("command").ok()?;
+    let get = |field: &str| command.get_document(field).ok().cloned();
+    match entry.get_str("op").ok()? {
+        "query" => Some((get("filter").unwrap_or_default(), get("sort"))),
+        "update" | "remove" => Some((get("q").unwrap_or_default(), None)),
+        "command" if command.contains_key("aggregate") => {
+            let stages: Vec<&Document> = command.get_array("pipeline").ok()?.iter()
+                .filter_map(Bson::as_document)
+                .collect();
+            let filter = stages.first().and_then(|stage| stage.get_document("$match").ok()).cloned();
+            let sort_at = usize::from(filter.is_some());
+            let sort = stages.get(sort_at).and_then(|stage| stage.get_document("$sort").ok()).cloned();
+            Some((filter.unwrap_or_default(), sort))
+        }
+        "command" if command.contains_key("count") || command.contains_key("distinct") => {
+            Some((get("query").unwrap_or_default(), None))
+        }
+        "command" if command.contains_key("findAndModify") => {
+            Some((get("query").unwrap_or_default(), get("sort")))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `index` serves a query on `keys`: it starts with the same fields,
+/// in the same directions.
+fn covers(index: &Document, keys: &Document) -> bool {
+    index.len() >= keys.len() && index.iter().zip(keys).all(|((field, direction), (key, wanted))| {
+        field == key && number(Some(direction)) == number(Some(wanted))
+    })
+}
+
+/// Turns the profiler on for `ns`'s database, recording only collection
+/// scans, however fast, so that [`advise`] has something to read while the
+/// profiler itself costs next to nothing. Needs MongoDB 4.4.2 or later.
+pub async fn enable_profiler(ns: &Namespace) -> Result<()> {
+    ns.db().run_command(doc! { "profile": 1, "filter": { "planSummary": "COLLSCAN" } }, None).await?;
+    Ok(())
+}
+
+/// Groups the collection scans recorded in `system.profile` by the index
+/// that would have served them, and suggests those wanted by at least
+/// `min_scans` of them, most wanted first. Collections outside `ns`, and
+/// indexes that exist already (created since the scans ran), are left out.
+pub async fn advise(ns: &Namespace, min_scans: u64) -> Result<Vec<Suggestion>> {
+    let prefix = format!("{}.{}", ns.db().name(), ns.name(""));
+    let options = FindOptions::builder().sort(doc! { "ts": -1 }).limit(PROFILE_SAMPLE).build();
+    let mut entries = ns.db().collection::<Document>("system.profile")
+        .find(doc! { "planSummary": "COLLSCAN" }, options).await?;
+    let mut wanted: BTreeMap<(String, String), Suggestion> = BTreeMap::new();
+    while let Some(entry) = entries.try_next().await? {
+        let Some(collection) = entry.get_str("ns").ok().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+        let Some((filter, sort)) = query_of(&entry) else {
+            continue;
+        };
+        let Some(keys) = Shape::of(&filter, sort.as_ref()).index_keys() else {
+            continue;
+        };
+        let suggestion = wanted.entry((collection.to_string(), keys.to_string())).or_insert_with(|| Suggestion {
+            collection: collection.to_string(),
+            keys,
+            scans: 0,
+            millis: 0,
+            example: filter,
+        });
+        suggestion.scans += 1;
+        suggestion.millis += number(entry.get("millis"));
+    }
+    let mut suggestions = Vec::new();
+    for suggestion in wanted.into_values().filter(|suggestion| suggestion.scans >= min_scans) {
+        let indexes: Vec<IndexModel> = ns.collection::<Document>(&suggestion.collection).list_indexes(None).await?
+            .try_collect().await?;
+        if !indexes.iter().any(|index| covers(&index.keys, &suggestion.keys)) {
+            suggestions.push(suggestion);
+        }
+    }
+    suggestions.sort_by(|a, b| b.scans.cmp(&a.scans).then(b.millis.cmp(&a.millis)));
+    Ok(suggestions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_go_equality_then_sort_then_range() {
+        let filter = doc! {
+            "created_at": { "$gte": 0 },
+            "$and": [{ "tags": "rust" }, { "status": { "$in": ["published"] } }],
+            "$or": [{ "title": "a" }],
+        };
+        let shape = Shape::of(&filter, Some(&doc! { "created_at": -1, "title": 1 }));
+        assert_eq!(shape.index_keys(), Some(doc! { "status": 1, "tags": 1, "created_at": -1, "title": 1 }));
+        assert_eq!(Shape::of(&doc! { "lang": { "$exists": true } }, None).index_keys(), Some(doc! { "lang": 1 }));
+        assert_eq!(Shape::of(&doc! { "$expr": { "$gt": ["$a", "$b"] } }, None).index_keys(), None);
+        assert_eq!(Shape::of(&doc! { "_id": 1 }, None).index_keys(), None);
+    }
+
+    #[test]
+    fn queries_are_read_from_each_kind_of_profiler_entry() {
+        let find = doc! { "op": "query", "command": {
+            "find": "posts", "filter": { "tags": "a" }, "sort": { "n": 1 },
+        }};
+        assert_eq!(query_of(&find), Some((doc! { "tags": "a" }, Some(doc! { "n": 1 }))));
+        let aggregate = doc! { "op": "command", "command": { "aggregate": "posts", "pipeline": [
+            { "$match": { "tags": "a" } }, { "$sort": { "n": -1 } }, { "$limit": 5 },
+        ]}};
+        assert_eq!(query_of(&aggregate), Some((doc! { "tags": "a" }, Some(doc! { "n": -1 }))));
+        let update = doc! { "op": "update", "command": { "q": { "title": "t" }, "u": { "$set": { "n": 1 } } } };
+        assert_eq!(query_of(&update), Some((doc! { "title": "t" }, None)));
+        assert_eq!(query_of(&doc! { "op": "insert", "command": { "insert": "posts" } }), None);
+    }
+
+    #[test]
+    fn an_index_covers_keys_it_starts_with() {
+        let index = doc! { "tags": 1, "created_at": -1 };
+        assert!(covers(&index, &doc! { "tags": 1 }));
+        assert!(covers(&index, &doc! { "tags": 1_i64, "created_at": -1 }));
+        assert!(!covers(&index, &doc! { "tags": 1, "created_at": 1 }));
+        assert!(!covers(&index, &doc! { "created_at": -1 }));
+    }
+}
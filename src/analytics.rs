@@ -0,0 +1,32 @@
+use mongodb::Client;
+use mongodb::options::{ClientOptions, ReadConcern, ReadPreference, ReadPreferenceOptions, SelectionCriteria};
+
+use crate::namespace::Namespace;
+use crate::repository::{PostRepository, APP_NAME};
+
+/// A second client for analytics aggregations, isolated from the one serving
+/// application traffic.
+///
+/// It has its own small connection pool, so a burst of heavy reports can't
+/// starve regular requests of connections, and reads from secondaries when
+/// there are any (`secondaryPreferred`) with read concern `available`: the
+/// cheapest reads there are, fine for reports that tolerate slightly stale or
+/// (on sharded clusters, mid-migration) duplicated documents. Its operations
+/// also show up under their own `appName` in `$currentOp` and the logs.
+pub fn connect(options: &ClientOptions) -> mongodb::error::Result<Client> {
+    let mut options = options.clone();
+    options.app_name = Some(format!("{}-analytics", APP_NAME));
+    options.max_pool_size = Some(2);
+    options.selection_criteria = Some(SelectionCriteria::ReadPreference(ReadPreference::SecondaryPreferred {
+        options: ReadPreferenceOptions::default(),
+    }));
+    options.read_concern = Some(ReadConcern::available());
+    Client::with_options(options)
+}
+
+/// The posts repository on the analytics client, in the same database and
+/// with the same collection prefix as `ns`.
+pub fn repository(client: &Client, ns: &Namespace) -> PostRepository {
+    let ns = ns.with_database(client.database(ns.db().name()));
+    PostRepository::new(&ns).with_context("analytics")
+}
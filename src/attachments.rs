@@ -0,0 +1,354 @@
+use futures::TryStreamExt;
+use futures::io::Cursor;
+use image::ImageFormat;
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson};
+use mongodb::bson::oid::ObjectId;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::options::{FindOneAndUpdateOptions, GridFsBucketOptions, GridFsUploadOptions, ReturnDocument};
+use sha2::{Digest, Sha256};
+
+use crate::{Post, PostId};
+use crate::error::{is_duplicate_key, Error, Result};
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// GridFS bucket of attachments, i.e. `attachments.files` and `attachments.chunks`.
+pub const ATTACHMENTS: &str = "attachments";
+/// One [`Blob`] per distinct content stored in [`ATTACHMENTS`], keyed by
+/// its SHA-256.
+pub const ATTACHMENT_BLOBS: &str = "attachment_blobs";
+/// Longest side of a thumbnail, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// Which rendition of an attachment to read, as in `?size=thumb`.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Size {
+    /// The bytes as uploaded.
+    #[default]
+    Original,
+    /// A PNG of at most [`THUMBNAIL_SIZE`] a side, for images only.
+    Thumb,
+}
+
+/// Content stored once in GridFS, however many posts attach it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Blob {
+    /// SHA-256 of the content, in hex.
+    #[serde(rename = "_id")]
+    pub sha256: String,
+    /// The GridFS file holding the content, which is also the attachment id
+    /// posts carry.
+    pub file_id: ObjectId,
+    #[serde(with = "serde_helpers::int")]
+    pub length: i64,
+    /// Posts attaching it; the file is purged when the last one lets go.
+    #[serde(with = "serde_helpers::int")]
+    pub refs: i64,
+}
+
+/// Binary attachments of posts, kept in GridFS rather than in the post
+/// itself: a document is capped at 16MB, GridFS splits a file into 255KB
+/// chunks, and a post only carries the ids of its files in `attachments`.
+///
+/// Files are content-addressed: uploading bytes that are stored already
+/// attaches the stored file instead of another copy, and [`ATTACHMENT_BLOBS`]
+/// counts the posts attaching each file so that it is only deleted once
+/// none do.
+///
+/// An image gets a thumbnail when it is first stored: a file of its own in
+/// the bucket, naming the original in `metadata.thumbnail_of`, and purged
+/// along with it.
+#[derive(Clone)]
+pub struct Attachments {
+    bucket: GridFsBucket,
+    blobs: Collection<Blob>,
+    posts: Collection<Post>,
+}
+
+impl Attachments {
+    pub fn new(ns: &Namespace) -> Self {
+        let options = GridFsBucketOptions::builder().bucket_name(ns.name(ATTACHMENTS)).build();
+        Attachments {
+            bucket: ns.db().gridfs_bucket(options),
+            blobs: ns.collection(ATTACHMENT_BLOBS),
+            posts: ns.collection("posts"),
+        }
+    }
+
+    /// Attaches `bytes` to the post, storing them as `filename` unless the
+    /// same bytes are stored already, and returns the file's id. A post
+    /// attaching the same bytes twice has them once.
+    ///
+    /// The reference is taken before the post names the file, so a post
+    /// never names a file that could be purged; if the post turns out not
+    /// to exist, or to have the file already, the reference is let go again.
+    pub async fn upload_attachment(&self, post_id: PostId, filename: &str, bytes: &[u8]) -> Result<ObjectId> {
+        let file_id = self.acquire(filename, bytes).await?;
+        let result = self.posts.update_one(
+            doc! { "_id": post_id, "attachments": { "$ne": file_id } },
+            doc! { "$push": { "attachments": file_id }, "$inc": { "version": 1 } },
+            None,
+        ).await;
+        match result {
+            Ok(update) if update.matched_count == 1 => Ok(file_id),
+            Ok(_) => {
+                self.release(file_id).await?;
+                match self.posts.find_one(doc! { "_id": post_id }, None).await? {
+                    Some(_) => Ok(file_id),
+                    None => Err(Error::NotFound),
+                }
+            }
+            Err(e) => {
+                self.release(file_id).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// The contents of an attachment, or [`Error::NotFound`].
+    pub async fn download_attachment(&self, file_id: ObjectId) -> Result<Vec<u8>> {
+        // The driver's own missing-file error can't be matched on, so look first
+        let mut files = self.bucket.find(doc! { "_id": file_id }, None).await?;
+        if files.try_next().await?.is_none() {
+            return Err(Error::NotFound);
+        }
+        let mut bytes = Vec::new();
+        self.bucket.download_to_futures_0_3_writer(Bson::ObjectId(file_id), &mut bytes).await?;
+        Ok(bytes)
+    }
+
+    /// The contents of an attachment in the given [`Size`], or
+    /// [`Error::NotFound`], which is also what a thumbnail of something that
+    /// isn't an image is.
+    pub async fn download_sized(&self, file_id: ObjectId, size: Size) -> Result<Vec<u8>> {
+        match size {
+            Size::Original => self.download_attachment(file_id).await,
+            Size::Thumb => match self.thumbnail_of(file_id).await? {
+                Some(thumbnail_id) => self.download_attachment(thumbnail_id).await,
+                None => Err(Error::NotFound),
+            },
+        }
+    }
+
+    /// The id of the thumbnail of `file_id`, if it has one.
+    pub async fn thumbnail_of(&self, file_id: ObjectId) -> Result<Option<ObjectId>> {
+        let mut thumbnails = self.bucket.find(doc! { "metadata.thumbnail_of": file_id }, None).await?;
+        Ok(thumbnails.try_next().await?.and_then(|file| file.id.as_object_id()))
+    }
+
+    /// Removes an attachment from the post, and from GridFS if no other
+    /// post attaches it.
+    pub async fn delete_attachment(&self, post_id: PostId, file_id: ObjectId) -> Result<()> {
+        let update = self.posts.update_one(
+            doc! { "_id": post_id, "attachments": file_id },
+            doc! { "$pull": { "attachments": file_id }, "$inc": { "version": 1 } },
+            None,
+        ).await?;
+        if update.matched_count == 1 {
+            self.release(file_id).await?;
+        }
+        Ok(())
+    }
+
+    /// A reference to the file holding `bytes`, uploading them if no file
+    /// does yet.
+    async fn acquire(&self, filename: &str, bytes: &[u8]) -> Result<ObjectId> {
+        let sha256 = sha256(bytes);
+        loop {
+            let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+            let existing = self.blobs.find_one_and_update(
+                doc! { "_id": &sha256, "refs": { "$gt": 0 } },
+                doc! { "$inc": { "refs": 1 } },
+                options,
+            ).await?;
+            if let Some(blob) = existing {
+                return Ok(blob.file_id);
+            }
+            let options = GridFsUploadOptions::builder().metadata(doc! { "sha256": &sha256 }).build();
+            let file_id = self.bucket.upload_from_futures_0_3_reader(filename, Cursor::new(bytes), options).await?;
+            let blob = Blob { sha256: sha256.clone(), file_id, length: bytes.len() as i64, refs: 1 };
+            // Before the blob, so that whoever finds the blob finds the thumbnail too
+            let stored = match self.upload_thumbnail(file_id, filename, bytes).await {
+                Ok(()) => self.blobs.insert_one(&blob, None).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match stored {
+                Ok(_) => return Ok(file_id),
+                Err(e) => {
+                    self.purge(file_id).await?;
+                    if !is_duplicate_key(&e) {
+                        return Err(e.into());
+                    }
+                    // Someone stored the same bytes meanwhile, or is purging them, or
+                    // crashed doing so: finish any purge, and look again
+                    if let Some(purged) = self.blobs.find_one(doc! { "_id": &sha256, "refs": 0 }, None).await? {
+                        self.purge(purged.file_id).await?;
+                        self.blobs.delete_one(doc! { "_id": &sha256, "refs": 0 }, None).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lets go of one reference to `file_id`, purging the file after the
+    /// last. A file from before attachments were counted has no blob: it
+    /// was only ever attached once, so it goes right away.
+    async fn release(&self, file_id: ObjectId) -> Result<()> {
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        let blob = self.blobs.find_one_and_update(
+            doc! { "file_id": file_id, "refs": { "$gt": 0 } },
+            doc! { "$inc": { "refs": -1 } },
+            options,
+        ).await?;
+        match blob {
+            Some(blob) if blob.refs > 0 => return Ok(()),
+            // At 0 it is no longer handed out, so it can only be purged
+            Some(blob) => {
+                self.purge(file_id).await?;
+                self.blobs.delete_one(doc! { "_id": blob.sha256, "refs": 0 }, None).await?;
+            }
+            None if self.blobs.find_one(doc! { "file_id": file_id }, None).await?.is_none() => {
+                self.purge(file_id).await?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Stores a thumbnail of `bytes` for `file_id` if they are an image.
+    /// Decoding and scaling are CPU-bound, so they run off the runtime's
+    /// threads; an image that fails to decode just gets no thumbnail.
+    async fn upload_thumbnail(&self, file_id: ObjectId, name: &str, bytes: &[u8]) -> mongodb::error::Result<()> {
+        let owned = bytes.to_vec();
+        let Some(png) = tokio::task::spawn_blocking(move || thumbnail(&owned)).await.ok().flatten() else {
+            return Ok(());
+        };
+        let options = GridFsUploadOptions::builder().metadata(doc! { "thumbnail_of": file_id }).build();
+        let name = format!("{}.thumb.png", name);
+        self.bucket.upload_from_futures_0_3_reader(name, Cursor::new(png), options).await?;
+        Ok(())
+    }
+
+    /// Deletes the file, its thumbnail and their chunks, if someone else
+    /// hasn't already.
+    async fn purge(&self, file_id: ObjectId) -> Result<()> {
+        let thumbnails: Vec<ObjectId> = self.bucket.find(doc! { "metadata.thumbnail_of": file_id }, None).await?
+            .try_filter_map(|file| async move { Ok(file.id.as_object_id()) })
+            .try_collect().await?;
+        for thumbnail_id in thumbnails {
+            self.purge_file(thumbnail_id).await?;
+        }
+        self.purge_file(file_id).await
+    }
+
+    async fn purge_file(&self, file_id: ObjectId) -> Result<()> {
+        // The chunks go either way; as in `download_attachment`, the driver's
+        // missing-file error can't be matched on, so tell it by looking
+        match self.bucket.delete(Bson::ObjectId(file_id)).await {
+            Err(e) if self.bucket.find(doc! { "_id": file_id }, None).await?.try_next().await?.is_some() => {
+                Err(e.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `bytes` scaled down to fit [`THUMBNAIL_SIZE`] and encoded as a PNG, or
+/// `None` if they aren't an image. One that fits already isn't scaled up.
+pub fn thumbnail(bytes: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(bytes).ok()?;
+    let image = if image.width() <= THUMBNAIL_SIZE && image.height() <= THUMBNAIL_SIZE {
+        image
+    } else {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    };
+    let mut png = Vec::new();
+    image.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png).ok()?;
+    Some(png)
+}
+
+/// The MIME type of an attachment, told from its first bytes.
+pub fn content_type(bytes: &[u8]) -> &'static str {
+    image::guess_format(bytes).map_or("application/octet-stream", |format| format.to_mime_type())
+}
+
+/// The hex SHA-256 blobs are keyed by.
+pub fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::new(width, height));
+        image.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        png
+    }
+
+    #[test]
+    fn thumbnails_fit_without_growing() {
+        let large = image::load_from_memory(&thumbnail(&png(1024, 512)).unwrap()).unwrap();
+        assert_eq!((large.width(), large.height()), (256, 128));
+        let small = image::load_from_memory(&thumbnail(&png(40, 30)).unwrap()).unwrap();
+        assert_eq!((small.width(), small.height()), (40, 30));
+        assert_eq!(thumbnail(b"not an image"), None);
+        assert_eq!(content_type(&png(1, 1)), "image/png");
+        assert_eq!(content_type(b"not an image"), "application/octet-stream");
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn the_same_bytes_are_stored_once_until_nobody_attaches_them() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let (first, second) = (Post::new("First", "a", &[]), Post::new("Second", "b", &[]));
+        ns.collection::<Post>("posts").insert_many([&first, &second], None).await.unwrap();
+        let attachments = Attachments::new(&ns);
+
+        let id = attachments.upload_attachment(first.id, "a.txt", b"shared").await.unwrap();
+        assert_eq!(attachments.upload_attachment(second.id, "b.txt", b"shared").await.unwrap(), id);
+        assert_eq!(attachments.upload_attachment(second.id, "b.txt", b"shared").await.unwrap(), id);
+        let blob = attachments.blobs.find_one(doc! { "_id": sha256(b"shared") }, None).await.unwrap().unwrap();
+        assert_eq!((blob.file_id, blob.refs), (id, 2));
+        let missing = attachments.upload_attachment(ObjectId::new(), "c.txt", b"shared").await;
+        assert!(matches!(missing, Err(Error::NotFound)));
+
+        attachments.delete_attachment(first.id, id).await.unwrap();
+        assert_eq!(attachments.download_attachment(id).await.unwrap(), b"shared");
+        attachments.delete_attachment(second.id, id).await.unwrap();
+        assert!(matches!(attachments.download_attachment(id).await, Err(Error::NotFound)));
+        assert_eq!(attachments.blobs.count_documents(None, None).await.unwrap(), 0);
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn images_get_a_thumbnail_that_goes_with_them() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let post = Post::new("Pictures", "a", &[]);
+        ns.collection::<Post>("posts").insert_one(&post, None).await.unwrap();
+        let attachments = Attachments::new(&ns);
+
+        let image_id = attachments.upload_attachment(post.id, "a.png", &png(512, 512)).await.unwrap();
+        let thumb = attachments.download_sized(image_id, Size::Thumb).await.unwrap();
+        assert_eq!(image::load_from_memory(&thumb).unwrap().width(), THUMBNAIL_SIZE);
+        let text_id = attachments.upload_attachment(post.id, "a.txt", b"text").await.unwrap();
+        assert!(matches!(attachments.download_sized(text_id, Size::Thumb).await, Err(Error::NotFound)));
+
+        attachments.delete_attachment(post.id, image_id).await.unwrap();
+        assert_eq!(attachments.thumbnail_of(image_id).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::Instant;
+
+use futures::TryStreamExt;
+use mongodb::IndexModel;
+use mongodb::bson::{self, doc, Bson, Document};
+
+use crate::bench::LatencyReport;
+use crate::error::Result;
+use crate::ids::number;
+use crate::namespace::Namespace;
+
+/// One metadata field of a post, in [`Post::metadata`](crate::Post::metadata).
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub k: String,
+    pub v: Bson,
+}
+
+impl Attribute {
+    pub fn new(k: &str, v: impl Into<Bson>) -> Self {
+        Attribute { k: k.to_string(), v: v.into() }
+    }
+}
+
+/// The index behind the attribute pattern: one entry per attribute, so a
+/// single index serves an equality or range query on any key, however many
+/// different keys posts carry.
+pub fn index() -> IndexModel {
+    IndexModel::builder().keys(doc! { "metadata.k": 1, "metadata.v": 1 }).build()
+}
+
+/// Filter for posts whose attribute `k` is `v`. `$elemMatch`, so that `k`
+/// and `v` have to be the same attribute rather than any two of the post's.
+pub fn with(k: &str, v: impl Into<Bson>) -> Document {
+    doc! { "metadata": { "$elemMatch": { "k": k, "v": v.into() } } }
+}
+
+/// Filter for posts whose attribute `k` is in `[from, to)`.
+pub fn within(k: &str, from: impl Into<Bson>, to: impl Into<Bson>) -> Document {
+    doc! { "metadata": { "$elemMatch": { "k": k, "v": { "$gte": from.into(), "$lt": to.into() } } } }
+}
+
+/// Filter for posts with every one of `attributes`.
+pub fn with_all(attributes: &[Attribute]) -> Document {
+    let matches: Vec<Document> = attributes.iter()
+        .map(|attribute| doc! { "$elemMatch": { "k": &attribute.k, "v": attribute.v.clone() } })
+        .collect();
+    doc! { "metadata": { "$all": matches } }
+}
+
+/// Latency of equality queries on one attribute out of many keys, stored
+/// as `{k, v}` pairs behind [`index`] and as plain fields of an `attrs`
+/// subdocument behind a wildcard index, from [`bench`]; `*_index_bytes` is
+/// the size of the index each one used.
+#[derive(Debug)]
+pub struct AttributeReport {
+    pub pairs: LatencyReport,
+    pub wildcard: LatencyReport,
+    pub pairs_index_bytes: i64,
+    pub wildcard_index_bytes: i64,
+}
+
+/// Attributes per document in [`bench`].
+const ATTRIBUTES: usize = 5;
+
+/// The attributes of the `n`th document in [`bench`]: [`ATTRIBUTES`] of the
+/// `keys` keys, each with one of 100 values.
+fn bench_attributes(n: usize, keys: usize) -> Vec<Attribute> {
+    (0..ATTRIBUTES)
+        .map(|i| Attribute::new(&format!("k{}", (n + i * 7) % keys), (n % 100) as i64))
+        .collect()
+}
+
+/// Writes `documents` documents with attributes out of `keys` keys both ways
+/// into scratch collections, times `samples` queries for one key's value on
+/// each, and drops the collections again. Both answer from one index; the
+/// pairs' only needs declaring once, while the wildcard one also indexes
+/// fields nobody queries, and can serve only one of them per query plan.
+pub async fn bench(ns: &Namespace, documents: usize, keys: usize, samples: usize) -> Result<AttributeReport> {
+    const BATCH: usize = 1000;
+    let (pairs_name, wildcard_name) = ("attribute_bench_pairs", "attribute_bench_wildcard");
+    let pairs = ns.collection::<Document>(pairs_name);
+    let wildcard = ns.collection::<Document>(wildcard_name);
+    pairs.drop(None).await?;
+    wildcard.drop(None).await?;
+    pairs.create_index(index(), None).await?;
+    wildcard.create_index(IndexModel::builder().keys(doc! { "attrs.$**": 1 }).build(), None).await?;
+    for batch_start in (0..documents).step_by(BATCH) {
+        let batch_end = documents.min(batch_start + BATCH);
+        let attributes: Vec<Vec<Attribute>> = (batch_start..batch_end)
+            .map(|n| bench_attributes(n, keys))
+            .collect();
+        let mut batch = Vec::new();
+        for attributes in &attributes {
+            batch.push(doc! { "metadata": bson::to_bson(attributes)? });
+        }
+        pairs.insert_many(batch, None).await?;
+        let batch: Vec<Document> = attributes.iter()
+            .map(|attributes| {
+                let attrs: Document = attributes.iter().map(|a| (a.k.clone(), a.v.clone())).collect();
+                doc! { "attrs": attrs }
+            })
+            .collect();
+        wildcard.insert_many(batch, None).await?;
+    }
+
+    let (mut pairs_latencies, mut wildcard_latencies) = (Vec::new(), Vec::new());
+    for n in 0..samples {
+        let (k, v) = (format!("k{}", n % keys), (n % 100) as i64);
+        let started = Instant::now();
+        let _: Vec<Document> = pairs.find(with(&k, v), None).await?.try_collect().await?;
+        pairs_latencies.push(started.elapsed());
+        let started = Instant::now();
+        let _: Vec<Document> = wildcard.find(doc! { format!("attrs.{}", k): v }, None).await?.try_collect().await?;
+        wildcard_latencies.push(started.elapsed());
+    }
+    let mut sizes = Vec::new();
+    for (name, index) in [(pairs_name, "metadata.k_1_metadata.v_1"), (wildcard_name, "attrs.$**_1")] {
+        let stats = ns.db().run_command(doc! { "collStats": ns.name(name) }, None).await?;
+        sizes.push(stats.get_document("indexSizes").ok().map_or(0, |sizes| number(sizes.get(index))));
+    }
+    pairs.drop(None).await?;
+    wildcard.drop(None).await?;
+    Ok(AttributeReport {
+        pairs: LatencyReport::from_samples(pairs_latencies),
+        wildcard: LatencyReport::from_samples(wildcard_latencies),
+        pairs_index_bytes: sizes[0],
+        wildcard_index_bytes: sizes[1],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_attribute_is_matched_on_its_own_element() {
+        let filter = with_all(&[Attribute::new("color", "red"), Attribute::new("pages", 12)]);
+        let matches = filter.get_document("metadata").unwrap().get_array("$all").unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[1], Bson::Document(doc! { "$elemMatch": { "k": "pages", "v": 12 } }));
+        assert_eq!(with("color", "red"), doc! { "metadata": { "$elemMatch": { "k": "color", "v": "red" } } });
+    }
+}
@@ -0,0 +1,263 @@
+use std::sync::OnceLock;
+
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::FindOptions;
+use regex::Regex;
+use whatlang::Lang;
+
+use crate::{Post, PostId};
+use crate::batch_jobs::{BatchJob, BatchJobs, JobKind};
+use crate::error::{is_validation_error, Result};
+use crate::namespace::Namespace;
+
+/// How far a backfill got; saved as its [`BatchJob`] after every chunk, so a
+/// stopped run picks up after `last_id`.
+#[derive(serde::Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    pub job: String,
+    /// The last post of the last chunk done; `None` before the first.
+    pub last_id: Option<PostId>,
+    pub scanned: u64,
+    /// Posts matching the filter when the job started.
+    pub total: Option<u64>,
+    pub updated: u64,
+    /// Posts that changed between being read and being updated; a later
+    /// write got there first, so they are left as that write left them.
+    pub conflicts: u64,
+    /// Posts the validator wouldn't take the update for.
+    pub rejected: u64,
+    pub finished: bool,
+}
+
+impl Progress {
+    fn of(job: &BatchJob) -> Self {
+        Progress {
+            job: job.name.clone(),
+            last_id: job.checkpoint.as_ref().and_then(Bson::as_object_id),
+            scanned: job.done,
+            total: job.total,
+            updated: job.count("updated"),
+            conflicts: job.count("conflicts"),
+            rejected: job.count("rejected"),
+            finished: job.finished(),
+        }
+    }
+
+    fn record(&self, job: &mut BatchJob) {
+        job.done = self.scanned;
+        job.checkpoint = self.last_id.map(Bson::ObjectId);
+        job.counts = doc! {
+            "updated": self.updated as i64,
+            "conflicts": self.conflicts as i64,
+            "rejected": self.rejected as i64,
+        };
+        if self.finished && job.finished_at.is_none() {
+            job.finished_at = Some(DateTime::now());
+        }
+    }
+}
+
+/// A change to every post matching a filter, made in `_id` order one chunk
+/// at a time so it can be stopped and resumed, and so it never holds a
+/// cursor open for long.
+///
+/// Each post is updated only if it is still at the version it was read at,
+/// so the update `process` computed from it is never applied on top of a
+/// change it didn't see.
+pub struct Backfill {
+    job: String,
+    posts: Collection<Post>,
+    jobs: BatchJobs,
+    chunk_size: usize,
+}
+
+impl Backfill {
+    pub fn new(ns: &Namespace, job: &str, chunk_size: usize) -> Self {
+        Backfill {
+            job: job.to_string(),
+            posts: ns.collection("posts"),
+            jobs: BatchJobs::new(ns),
+            chunk_size: chunk_size.max(1),
+        }
+    }
+
+    /// The saved progress, or none at all for a job that hasn't run.
+    pub async fn progress(&self) -> Result<Progress> {
+        Ok(match self.jobs.get(&self.job).await? {
+            Some(job) => Progress::of(&job),
+            None => Progress { job: self.job.clone(), ..Progress::default() },
+        })
+    }
+
+    /// Forgets the saved progress, so the next run starts over.
+    pub async fn reset(&self) -> Result<()> {
+        self.jobs.remove(&self.job).await
+    }
+
+    /// Runs `process` on every post matching `filter` after the saved
+    /// progress, applying the update it returns, if any, and calling
+    /// `report` after every chunk. The job's version bump is added to the
+    /// update. Returns straight away once a job has finished; see
+    /// [`Backfill::reset`].
+    pub async fn run(
+        &self,
+        filter: Document,
+        mut process: impl FnMut(&Post) -> Option<Document>,
+        mut report: impl FnMut(&Progress),
+    ) -> Result<Progress> {
+        let mut job = match self.jobs.get(&self.job).await? {
+            Some(job) => job,
+            None => {
+                let total = self.posts.count_documents(filter.clone(), None).await?;
+                BatchJob::new(&self.job, JobKind::Backfill, Some(total))
+            }
+        };
+        let mut progress = Progress::of(&job);
+        while !progress.finished {
+            let filter = match progress.last_id {
+                Some(last_id) => doc! { "$and": [filter.clone(), { "_id": { "$gt": last_id } }] },
+                None => filter.clone(),
+            };
+            let options = FindOptions::builder().sort(doc! { "_id": 1 }).limit(self.chunk_size as i64).build();
+            let chunk: Vec<Post> = self.posts.find(filter, options).await?.try_collect().await?;
+            for post in &chunk {
+                progress.scanned += 1;
+                let Some(mut update) = process(post) else {
+                    continue;
+                };
+                update.insert("$inc", doc! { "version": 1 });
+                let version: Bson = if post.version == 0 {
+                    // Posts written before `version` existed match as version 0
+                    doc! { "$in": [0, Bson::Null] }.into()
+                } else {
+                    post.version.into()
+                };
+                match self.posts.update_one(doc! { "_id": post.id, "version": version }, update, None).await {
+                    Ok(result) if result.matched_count == 1 => progress.updated += 1,
+                    Ok(_) => progress.conflicts += 1,
+                    Err(e) if is_validation_error(&e) => {
+                        tracing::warn!(post = %post.id, "backfill {} rejected: {}", self.job, e);
+                        progress.rejected += 1;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            progress.last_id = chunk.last().map(|post| post.id).or(progress.last_id);
+            progress.finished = chunk.len() < self.chunk_size;
+            progress.record(&mut job);
+            self.jobs.save(&mut job).await?;
+            report(&progress);
+        }
+        Ok(progress)
+    }
+}
+
+/// Most tags a post may have, like the validator says.
+const MAX_TAGS: usize = 5;
+
+/// The `#hashtags` in `message` that would make valid tags: lowercased, 3
+/// to 10 letters, digits, `-` or `_`, in order of first appearance and each
+/// once. A `#` inside a word, like in `C#` or a URL fragment, doesn't start one.
+pub fn hashtags(message: &str) -> Vec<String> {
+    static HASHTAG: OnceLock<Regex> = OnceLock::new();
+    let hashtag = HASHTAG.get_or_init(|| Regex::new(r"(?:^|[^\w#&/])#([\w-]+)").expect("pattern is valid"));
+    let mut found: Vec<String> = Vec::new();
+    for captures in hashtag.captures_iter(message) {
+        let tag = captures[1].to_lowercase();
+        if (3..=10).contains(&tag.chars().count()) && !found.contains(&tag) {
+            found.push(tag);
+        }
+    }
+    found
+}
+
+/// The update adding `post`'s hashtags it isn't tagged with yet, as many as
+/// fit under the validator's limit; `None` when there are none to add.
+pub fn hashtag_update(post: &Post) -> Option<Document> {
+    let room = MAX_TAGS.saturating_sub(post.tags.len());
+    let new: Vec<String> = hashtags(&post.message).into_iter()
+        .filter(|tag| !post.tags.contains(tag))
+        .take(room)
+        .collect();
+    if new.is_empty() {
+        return None;
+    }
+    Some(doc! { "$addToSet": { "tags": { "$each": new } } })
+}
+
+/// Name of the hashtag job's checkpoint.
+pub const HASHTAGS: &str = "hashtags";
+
+/// Tags every post with the hashtags in its message, see [`hashtag_update`].
+pub async fn backfill_hashtags(
+    ns: &Namespace,
+    chunk_size: usize,
+    report: impl FnMut(&Progress),
+) -> Result<Progress> {
+    // Only messages with a `#` can have hashtags; the rest are skipped on the server
+    let filter = doc! { "message": { "$regex": "#" } };
+    Backfill::new(ns, HASHTAGS, chunk_size).run(filter, hashtag_update, report).await
+}
+
+/// Languages the text index can stem, as whatlang names them and as `lang`
+/// names them (ISO 639-1, like MongoDB's `language_override` takes).
+const TEXT_LANGUAGES: [(Lang, &str); 15] = [
+    (Lang::Dan, "da"), (Lang::Deu, "de"), (Lang::Eng, "en"), (Lang::Fin, "fi"), (Lang::Fra, "fr"),
+    (Lang::Hun, "hu"), (Lang::Ita, "it"), (Lang::Nld, "nl"), (Lang::Nob, "nb"), (Lang::Por, "pt"),
+    (Lang::Ron, "ro"), (Lang::Rus, "ru"), (Lang::Spa, "es"), (Lang::Swe, "sv"), (Lang::Tur, "tr"),
+];
+
+/// The language `message` is written in, if whatlang is confident of it and
+/// the text index supports it. A `lang` the index doesn't know would make
+/// the write fail, so anything else is left undetected.
+pub fn detect_lang(message: &str) -> Option<&'static str> {
+    let info = whatlang::detect(message).filter(|info| info.is_reliable())?;
+    TEXT_LANGUAGES.iter().find(|(lang, _)| *lang == info.lang()).map(|(_, code)| *code)
+}
+
+/// Name of the language job's checkpoint.
+pub const LANGUAGES: &str = "languages";
+
+/// Sets `lang` on every post without one whose message's language
+/// [`detect_lang`] can tell, so the text index stems it in that language
+/// instead of the default. Posts it can't tell are scanned and left alone.
+pub async fn backfill_languages(
+    ns: &Namespace,
+    chunk_size: usize,
+    report: impl FnMut(&Progress),
+) -> Result<Progress> {
+    let filter = doc! { "lang": { "$exists": false } };
+    let update = |post: &Post| detect_lang(&post.message).map(|lang| doc! { "$set": { "lang": lang } });
+    Backfill::new(ns, LANGUAGES, chunk_size).run(filter, update, report).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashtags_become_valid_tags() {
+        let message = "#Rust and #mongodb, not C# or a.com/#frag; #no #waytoolongtag #rust #async-io";
+        assert_eq!(hashtags(message), ["rust", "mongodb", "async-io"]);
+    }
+
+    #[test]
+    fn only_new_tags_that_fit_are_added() {
+        let post = Post::new("Tagged", "#rust #mongo #async #driver", &["rust", "db", "web"]);
+        assert_eq!(hashtag_update(&post), Some(doc! { "$addToSet": { "tags": { "$each": ["mongo", "async"] } } }));
+        let post = Post::new("Plain", "No hashtags #rust", &["rust"]);
+        assert_eq!(hashtag_update(&post), None);
+    }
+
+    #[test]
+    fn languages_are_detected_when_the_index_supports_them() {
+        let german = "Die Datenbank speichert jeden Beitrag als Dokument, und die Suche findet ihn schnell wieder.";
+        assert_eq!(detect_lang(german), Some("de"));
+        let english = "The database stores every post as a document, and the search finds it again quickly.";
+        assert_eq!(detect_lang(english), Some("en"));
+        assert_eq!(detect_lang("数据库把每篇文章存成一个文档，搜索很快就能再找到它。"), None);
+        assert_eq!(detect_lang("ok"), None);
+    }
+}
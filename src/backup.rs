@@ -0,0 +1,294 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use futures::TryStreamExt;
+use md5::{Digest, Md5};
+use mongodb::Collection;
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+use mongodb::options::{FindOptions, ReplaceOptions};
+
+use crate::attachments::{ATTACHMENTS, ATTACHMENT_BLOBS};
+use crate::ejson::{self, Mode};
+use crate::namespace::Namespace;
+use crate::repository::{ImportReport, PostRepository};
+use crate::transfer::{self, Conflicts, Format, Result};
+
+/// The posts' file in a backup directory, as [`transfer::export`] writes it.
+pub const POSTS_FILE: &str = "posts.jsonl";
+/// Each bucket's directory in a backup holds this manifest, a line per
+/// file, next to one blob per file.
+pub const MANIFEST_FILE: &str = "files.jsonl";
+
+/// One file of a bucket export: its `files` document exactly as stored, in
+/// canonical Extended JSON, and what its contents have to hash to.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct ManifestEntry {
+    file: serde_json::Value,
+    /// Name of the blob holding the contents, next to the manifest.
+    blob: String,
+    md5: String,
+}
+
+/// What a [`restore`] wrote.
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub posts: ImportReport,
+    pub files: BucketReport,
+}
+
+/// What [`import_bucket`] did with the files in a bucket export.
+#[derive(serde::Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct BucketReport {
+    pub restored: u64,
+    /// Files whose `_id` is stored already; GridFS files never change, so
+    /// those are left as they are.
+    pub skipped: u64,
+}
+
+/// The reference counts of the attachments in a backup directory.
+pub const BLOBS_FILE: &str = "attachment_blobs.jsonl";
+
+/// Writes the posts and the attachments bucket to `dir`: `posts.jsonl`,
+/// `attachments/` as [`export_bucket`] lays it out, and the attachments'
+/// reference counts. Returns how many posts and files were written.
+pub async fn backup(ns: &Namespace, dir: &Path) -> Result<(u64, u64)> {
+    fs::create_dir_all(dir)?;
+    let posts = transfer::export(ns, &dir.join(POSTS_FILE), Format::JsonLines, 0).await?;
+    let files = export_bucket(ns, ATTACHMENTS, &dir.join(ATTACHMENTS)).await?;
+    let mut blobs_file = BufWriter::new(File::create(dir.join(BLOBS_FILE))?);
+    let mut blobs = ns.collection::<Document>(ATTACHMENT_BLOBS).find(None, None).await?;
+    while let Some(blob) = blobs.try_next().await? {
+        ejson::write_line(&mut blobs_file, &blob, Mode::Canonical)?;
+    }
+    blobs_file.flush()?;
+    Ok((posts, files))
+}
+
+/// Reads back what [`backup`] wrote. Posts already stored are replaced;
+/// files already stored are skipped. The posts go in as the resumable
+/// import `job`, see [`transfer::import`].
+pub async fn restore(ns: &Namespace, repo: &PostRepository, dir: &Path, job: &str) -> Result<RestoreReport> {
+    // Files first, so no restored post names an attachment that isn't there yet
+    let files = import_bucket(ns, ATTACHMENTS, &dir.join(ATTACHMENTS)).await?;
+    restore_blobs(ns, &dir.join(BLOBS_FILE)).await?;
+    let posts_file = dir.join(POSTS_FILE);
+    let posts = transfer::import(ns, repo, &posts_file, Format::JsonLines, 1000, Conflicts::Upsert, job).await?;
+    Ok(RestoreReport { posts, files })
+}
+
+/// Puts back the reference counts of the attachments, replacing those that
+/// are there: the restored posts are the ones they count. A backup from
+/// before attachments were counted has none.
+async fn restore_blobs(ns: &Namespace, path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let blobs = ns.collection::<Document>(ATTACHMENT_BLOBS);
+    for blob in ejson::read_lines::<Document>(BufReader::new(File::open(path)?)) {
+        let blob = blob.map_err(|e| format!("{} {}", BLOBS_FILE, e))?;
+        let id = blob.get("_id").cloned().ok_or("a blob without an _id")?;
+        let options = ReplaceOptions::builder().upsert(true).build();
+        blobs.replace_one(doc! { "_id": id }, blob, options).await?;
+    }
+    Ok(())
+}
+
+/// Writes every file of the GridFS bucket `bucket` to `dir`: the blobs,
+/// `0.bin`, `1.bin` and so on, and the manifest listing them with their
+/// `files` documents, ids, metadata and upload dates included, and their
+/// MD5. A file whose chunks don't add up to its `length`, or that disagree
+/// with an `md5` its document kept from older drivers, fails the export.
+pub async fn export_bucket(ns: &Namespace, bucket: &str, dir: &Path) -> Result<u64> {
+    fs::create_dir_all(dir)?;
+    let (files, chunks) = bucket_collections(ns, bucket);
+    let mut manifest = BufWriter::new(File::create(dir.join(MANIFEST_FILE))?);
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let mut cursor = files.find(None, options).await?;
+    let mut exported = 0;
+    while let Some(file) = cursor.try_next().await? {
+        let id = file.get("_id").cloned().ok_or("a files document without an _id")?;
+        let blob = format!("{}.bin", exported);
+        let mut out = BufWriter::new(File::create(dir.join(&blob))?);
+        let (mut hasher, mut length) = (Md5::new(), 0);
+        let options = FindOptions::builder().sort(doc! { "n": 1 }).build();
+        let mut data = chunks.find(doc! { "files_id": &id }, options).await?;
+        while let Some(chunk) = data.try_next().await? {
+            let bytes = &chunk.get_binary_generic("data")?[..];
+            hasher.update(bytes);
+            out.write_all(bytes)?;
+            length += bytes.len() as i64;
+        }
+        out.flush()?;
+        let md5 = hex(&hasher.finalize());
+        check_file(&file, length, &md5)?;
+        let entry = ManifestEntry { file: ejson::to_value(&file, Mode::Canonical)?, blob, md5 };
+        serde_json::to_writer(&mut manifest, &entry)?;
+        manifest.write_all(b"\n")?;
+        exported += 1;
+    }
+    manifest.flush()?;
+    Ok(exported)
+}
+
+/// Restores a bucket export from `dir` into the GridFS bucket `bucket`,
+/// keeping every `files` document as it was. Each blob is checked against
+/// the `length` and MD5 in the manifest before anything of it is written;
+/// the chunks go in first and the `files` document last, so a file that
+/// fails halfway is never visible, and its chunks are removed again.
+pub async fn import_bucket(ns: &Namespace, bucket: &str, dir: &Path) -> Result<BucketReport> {
+    let (files, chunks) = bucket_collections(ns, bucket);
+    let mut report = BucketReport::default();
+    for (index, line) in BufReader::new(File::open(dir.join(MANIFEST_FILE))?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ManifestEntry = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", MANIFEST_FILE, index + 1, e))?;
+        let file: Document = ejson::from_value(entry.file)
+            .map_err(|e| format!("{} line {}: `file`: {}", MANIFEST_FILE, index + 1, e))?;
+        let id = file.get("_id").cloned().ok_or("a files document without an _id")?;
+        if files.find_one(doc! { "_id": &id }, None).await?.is_some() {
+            report.skipped += 1;
+            continue;
+        }
+        let blob = fs::read(dir.join(&entry.blob))?;
+        let md5 = hex(&Md5::digest(&blob));
+        check_file(&file, blob.len() as i64, &md5)
+            .and_then(|()| check_md5(&entry.md5, &md5))
+            .map_err(|e| format!("{}: {}", entry.blob, e))?;
+        let chunk_size = number(file.get("chunkSize")).filter(|size| *size > 0).ok_or("no chunkSize")? as usize;
+        let written = match write_chunks(&chunks, &id, &blob, chunk_size).await {
+            Ok(()) => files.insert_one(&file, None).await.map(drop).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            chunks.delete_many(doc! { "files_id": &id }, None).await?;
+            return Err(e);
+        }
+        report.restored += 1;
+    }
+    Ok(report)
+}
+
+async fn write_chunks(
+    chunks: &Collection<Document>,
+    id: &Bson,
+    blob: &[u8],
+    chunk_size: usize,
+) -> Result<()> {
+    // Empty files have no chunks at all
+    for (n, data) in blob.chunks(chunk_size).enumerate() {
+        let data = Binary { subtype: BinarySubtype::Generic, bytes: data.to_vec() };
+        chunks.insert_one(doc! { "files_id": id, "n": n as i32, "data": data }, None).await?;
+    }
+    Ok(())
+}
+
+/// The `files` and `chunks` collections behind `bucket`.
+fn bucket_collections(ns: &Namespace, bucket: &str) -> (Collection<Document>, Collection<Document>) {
+    let files = ns.db().collection(&format!("{}.files", ns.name(bucket)));
+    let chunks = ns.db().collection(&format!("{}.chunks", ns.name(bucket)));
+    (files, chunks)
+}
+
+/// Whether the contents read, `length` bytes hashing to `md5`, are those
+/// that `file` describes.
+#[allow(clippy::result_large_err)] // boxed like the rest of this module
+fn check_file(file: &Document, length: i64, md5: &str) -> Result<()> {
+    let id = file.get("_id").map_or_else(|| "?".to_string(), |id| id.to_string());
+    if number(file.get("length")) != Some(length) {
+        return Err(format!("file {} has {} bytes, its length says {:?}", id, length, file.get("length")).into());
+    }
+    match file.get_str("md5") {
+        Ok(stored) => check_md5(stored, md5).map_err(|e| format!("file {}: {}", id, e).into()),
+        Err(_) => Ok(()),
+    }
+}
+
+#[allow(clippy::result_large_err)] // boxed like the rest of this module
+fn check_md5(expected: &str, actual: &str) -> Result<()> {
+    if !expected.eq_ignore_ascii_case(actual) {
+        return Err(format!("MD5 is {}, expected {}", actual, expected).into());
+    }
+    Ok(())
+}
+
+fn number(value: Option<&Bson>) -> Option<i64> {
+    match value? {
+        Bson::Int32(n) => Some(*n as i64),
+        Bson::Int64(n) => Some(*n),
+        Bson::Double(n) if n.fract() == 0.0 => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+    use crate::attachments::Attachments;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+    use crate::Post;
+
+    #[test]
+    fn files_have_to_match_their_length_and_md5() {
+        let empty_md5 = hex(&Md5::digest(b""));
+        assert_eq!(empty_md5, "d41d8cd98f00b204e9800998ecf8427e");
+        let file = doc! { "_id": 1, "length": 0_i64 };
+        assert!(check_file(&file, 0, &empty_md5).is_ok());
+        assert!(check_file(&file, 1, &empty_md5).is_err());
+        let legacy = doc! { "_id": 1, "length": 0, "md5": "D41D8CD98F00B204E9800998ECF8427E" };
+        assert!(check_file(&legacy, 0, &empty_md5).is_ok());
+        assert!(check_file(&legacy, 0, "00").is_err());
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn buckets_round_trip_with_ids_and_metadata() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let (from, to) = (Namespace::new(sandbox.database(), "from_"), Namespace::new(sandbox.database(), "to_"));
+        let post = Post::new("Attached", "See files", &[]);
+        from.collection::<Post>("posts").insert_one(&post, None).await.unwrap();
+        let attachments = Attachments::new(&from);
+        // Spans three chunks of 255KB
+        let big: Vec<u8> = (0..600_000).map(|i| (i % 251) as u8).collect();
+        let mut ids = Vec::new();
+        for (name, bytes) in [("empty.txt", &b""[..]), ("hello.txt", b"hello"), ("big.bin", &big)] {
+            ids.push(attachments.upload_attachment(post.id, name, bytes).await.unwrap());
+        }
+
+        let dir = std::env::temp_dir().join(format!("bucket-{}", ObjectId::new()));
+        assert_eq!(export_bucket(&from, ATTACHMENTS, &dir).await.unwrap(), 3);
+        let report = import_bucket(&to, ATTACHMENTS, &dir).await.unwrap();
+        assert_eq!(report, BucketReport { restored: 3, skipped: 0 });
+        assert_eq!(import_bucket(&to, ATTACHMENTS, &dir).await.unwrap().skipped, 3);
+
+        let (original, _) = bucket_collections(&from, ATTACHMENTS);
+        let (restored, _) = bucket_collections(&to, ATTACHMENTS);
+        let original: Vec<Document> = original.find(None, None).await.unwrap().try_collect().await.unwrap();
+        let restored: Vec<Document> = restored.find(None, None).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(original, restored);
+        let copy = Attachments::new(&to);
+        assert_eq!(copy.download_attachment(ids[2]).await.unwrap(), big);
+
+        // Same length, different contents
+        fs::write(dir.join("1.bin"), b"jello").unwrap();
+        let again = Namespace::new(sandbox.database(), "again_");
+        assert!(import_bucket(&again, ATTACHMENTS, &dir).await.is_err());
+        let (files, chunks) = bucket_collections(&again, ATTACHMENTS);
+        assert!(files.find_one(doc! { "_id": ids[1] }, None).await.unwrap().is_none());
+        assert_eq!(chunks.count_documents(doc! { "files_id": ids[1] }, None).await.unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::{FindOptions, ReplaceOptions};
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// One [`BatchJob`] per long-running operation, keyed by its name.
+pub const BATCH_JOBS: &str = "batch_jobs";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// A [`Backfill`](crate::backfill::Backfill) over posts.
+    Backfill,
+    /// A [`transfer::import`](crate::transfer::import) of a file.
+    Import,
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobKind::Backfill => "backfill",
+            JobKind::Import => "import",
+        })
+    }
+}
+
+/// How far a long-running operation got, saved after every step so that a
+/// restarted run picks up from `checkpoint` instead of starting over.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct BatchJob {
+    #[serde(rename = "_id")]
+    pub name: String,
+    pub kind: JobKind,
+    /// What there is to get through, counted when the job started; `None`
+    /// when that couldn't be told up front.
+    #[serde(default, with = "serde_helpers::optional_int")]
+    pub total: Option<u64>,
+    /// What has been got through, over every run.
+    #[serde(with = "serde_helpers::int")]
+    pub done: u64,
+    /// Where the next run starts, in the job's own terms: the last `_id`
+    /// of a backfill, nothing for an import, which starts after `done`.
+    pub checkpoint: Option<Bson>,
+    /// The job's own tallies, e.g. `{ updated, conflicts }`, over every run.
+    #[serde(default)]
+    pub counts: Document,
+    #[serde(with = "serde_helpers::datetime")]
+    pub started_at: DateTime,
+    #[serde(with = "serde_helpers::datetime")]
+    pub updated_at: DateTime,
+    #[serde(default, with = "serde_helpers::optional_datetime")]
+    pub finished_at: Option<DateTime>,
+}
+
+impl BatchJob {
+    pub fn new(name: &str, kind: JobKind, total: Option<u64>) -> Self {
+        let now = DateTime::now();
+        BatchJob {
+            name: name.to_string(),
+            kind,
+            total,
+            done: 0,
+            checkpoint: None,
+            counts: Document::new(),
+            started_at: now,
+            updated_at: now,
+            finished_at: None,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+
+    /// `done` out of `total`, as a percentage: 100 once finished, whatever
+    /// the count said, and never more than 100 before, as what there is to
+    /// do can change while the job runs.
+    pub fn percent(&self) -> Option<f64> {
+        if self.finished() {
+            return Some(100.0);
+        }
+        match self.total? {
+            0 => Some(0.0),
+            total => Some((self.done as f64 / total as f64 * 100.0).min(100.0)),
+        }
+    }
+
+    /// The tally `name` in `counts`, 0 if never set.
+    pub fn count(&self, name: &str) -> u64 {
+        self.counts.get_i64(name).map_or(0, |count| count as u64)
+    }
+}
+
+/// The saved [`BatchJob`]s.
+#[derive(Clone)]
+pub struct BatchJobs {
+    col: Collection<BatchJob>,
+}
+
+impl BatchJobs {
+    pub fn new(ns: &Namespace) -> Self {
+        BatchJobs { col: ns.collection(BATCH_JOBS) }
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Option<BatchJob>> {
+        Ok(self.col.find_one(doc! { "_id": name }, None).await?)
+    }
+
+    /// Every job, the most recently updated first.
+    pub async fn list(&self) -> Result<Vec<BatchJob>> {
+        let options = FindOptions::builder().sort(doc! { "updated_at": -1 }).build();
+        Ok(self.col.find(None, options).await?.try_collect().await?)
+    }
+
+    /// Saves `job` as it is now, stamping `updated_at`.
+    pub async fn save(&self, job: &mut BatchJob) -> Result<()> {
+        job.updated_at = DateTime::now();
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.col.replace_one(doc! { "_id": &job.name }, &*job, options).await?;
+        Ok(())
+    }
+
+    /// Forgets the job `name`, so its next run starts over.
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        self.col.delete_one(doc! { "_id": name }, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentages_stay_within_bounds() {
+        let mut job = BatchJob::new("hashtags", JobKind::Backfill, Some(200));
+        job.done = 50;
+        assert_eq!(job.percent(), Some(25.0));
+        job.done = 250;
+        assert_eq!(job.percent(), Some(100.0));
+        job.total = None;
+        assert_eq!(job.percent(), None);
+        job.finished_at = Some(DateTime::now());
+        assert_eq!(job.percent(), Some(100.0));
+    }
+}
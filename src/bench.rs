@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::repository::{nearest_reads, PostRepository, SearchFilters};
+
+/// Latency percentiles over a run of identical calls.
+#[derive(serde::Serialize, Debug)]
+pub struct LatencyReport {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyReport {
+    pub(crate) fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let at = |quantile: f64| {
+            let rank = (quantile * samples.len() as f64).ceil() as usize;
+            samples.get(rank.saturating_sub(1)).copied().unwrap_or_default()
+        };
+        LatencyReport { samples: samples.len(), p50: at(0.5), p99: at(0.99), max: at(1.0) }
+    }
+}
+
+/// Runs the `/posts/search` query `samples` times against `nearest` without
+/// and then with hedging, and reports both latency distributions. Only a
+/// sharded cluster will show a difference; see [`nearest_reads`].
+pub async fn compare_hedging(ns: &Namespace, samples: usize) -> Result<(LatencyReport, LatencyReport)> {
+    let filters = SearchFilters { text: Some("post".to_string()), per_page: 20, ..Default::default() };
+    let mut reports = Vec::new();
+    for hedge in [false, true] {
+        let repo = PostRepository::with_options(ns, nearest_reads(hedge)).with_context("bench");
+        let mut latencies = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let started = Instant::now();
+            repo.search(&filters).await?;
+            latencies.push(started.elapsed());
+        }
+        reports.push(LatencyReport::from_samples(latencies));
+    }
+    let hedged = reports.pop().expect("two runs");
+    let unhedged = reports.pop().expect("two runs");
+    Ok((unhedged, hedged))
+}
@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+use crate::error::{Error, Result};
+
+/// Caps how many database calls run at once.
+///
+/// A load spike otherwise turns into every request holding a pooled
+/// connection or waiting on one, until the driver's own wait queue times out
+/// for all of them at once. With a bulkhead at or below the pool size the
+/// excess waits here instead, and gives up with [`Error::Overloaded`] after
+/// `queue_timeout`, which keeps latency bounded and the pool usable.
+#[derive(Clone)]
+pub struct Bulkhead {
+    permits: Arc<Semaphore>,
+    max_permits: usize,
+    queue_timeout: Duration,
+    queued: Arc<AtomicUsize>,
+    rejected: Arc<AtomicU64>,
+}
+
+/// Point-in-time view of a [`Bulkhead`].
+#[derive(serde::Serialize, Debug)]
+pub struct BulkheadStats {
+    pub max_concurrent: usize,
+    pub in_flight: usize,
+    /// Calls waiting for a permit right now.
+    pub queued: usize,
+    /// Calls that gave up waiting since startup.
+    pub rejected: u64,
+}
+
+impl Bulkhead {
+    pub fn new(max_concurrent: usize, queue_timeout: Duration) -> Self {
+        Bulkhead {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_permits: max_concurrent,
+            queue_timeout,
+            queued: Arc::new(AtomicUsize::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Runs `operation` once a permit is free, or fails after waiting `queue_timeout`.
+    pub async fn call<T>(&self, operation: impl Future<Output = Result<T>>) -> Result<T> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let permit = tokio::time::timeout(self.queue_timeout, self.permits.acquire()).await;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        let _permit = match permit {
+            Ok(permit) => permit.expect("bulkhead semaphore is never closed"),
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(Error::Overloaded { waited: self.queue_timeout });
+            }
+        };
+        operation.await
+    }
+
+    pub fn stats(&self) -> BulkheadStats {
+        BulkheadStats {
+            max_concurrent: self.max_permits,
+            in_flight: self.max_permits - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn calls_over_the_limit_wait_then_give_up() {
+        let bulkhead = Bulkhead::new(1, Duration::from_millis(20));
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let holder = tokio::spawn({
+            let bulkhead = bulkhead.clone();
+            async move { bulkhead.call(async { released.await.ok(); Ok(()) }).await }
+        });
+        while bulkhead.stats().in_flight == 0 {
+            tokio::task::yield_now().await;
+        }
+        let rejected: Result<()> = bulkhead.call(async { Ok(()) }).await;
+        assert!(matches!(rejected, Err(Error::Overloaded { waited }) if waited == Duration::from_millis(20)));
+        let stats = bulkhead.stats();
+        assert_eq!((stats.in_flight, stats.queued, stats.rejected), (1, 0, 1));
+
+        release.send(()).unwrap();
+        holder.await.unwrap().unwrap();
+        assert_eq!(bulkhead.call(async { Ok(7) }).await.unwrap(), 7);
+        assert_eq!(bulkhead.stats().in_flight, 0);
+    }
+}
@@ -0,0 +1,77 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use tokio_util::sync::CancellationToken;
+
+/// A token that cancels the operations of a handle, with the tag they carry
+/// in their `comment` as `cancel`, so that the server side can be found in
+/// `$currentOp` and stopped too.
+///
+/// Handed to [`crate::repository::PostRepository::with_cancellation`]: once
+/// the token is cancelled, an aggregation of that handle still running stops
+/// being waited for, is killed on the server with `killOp`, and fails with
+/// [`Error::Cancelled`](crate::error::Error::Cancelled).
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    token: CancellationToken,
+    tag: ObjectId,
+}
+
+impl Cancellation {
+    pub fn new(token: CancellationToken) -> Self {
+        Cancellation { token, tag: ObjectId::new() }
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn tag(&self) -> ObjectId {
+        self.tag
+    }
+}
+
+/// `$currentOp` filter for the operations tagged `tag`: the command itself,
+/// or a `getMore` on the cursor it opened.
+fn tagged(tag: ObjectId) -> Document {
+    doc! { "$or": [
+        { "command.comment.cancel": tag },
+        { "cursor.originatingCommand.comment.cancel": tag },
+    ]}
+}
+
+/// Kills every operation tagged `tag` running on `client`'s deployment, and
+/// returns how many there were. Needs the `inprog` and `killop` privileges
+/// for operations of other users; an operation that just finished is gone
+/// from `$currentOp` already, and one that just started may not be there yet.
+pub async fn kill(client: &Client, tag: ObjectId) -> mongodb::error::Result<usize> {
+    let admin = client.database("admin");
+    let pipeline = vec![
+        doc! { "$currentOp": { "allUsers": true } },
+        doc! { "$match": tagged(tag) },
+        doc! { "$project": { "opid": 1 } },
+    ];
+    let ops: Vec<Document> = admin.aggregate(pipeline, None).await?.try_collect().await?;
+    for opid in ops.iter().filter_map(|op| op.get("opid")) {
+        // A number, or "<shard>:<number>" through mongos; `killOp` takes either
+        admin.run_command(doc! { "killOp": 1, "op": opid.clone() }, None).await?;
+    }
+    Ok(ops.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_matched_on_the_command_and_its_cursor() {
+        let cancellation = Cancellation::new(CancellationToken::new());
+        let filter = tagged(cancellation.tag());
+        let paths: Vec<&String> = filter.get_array("$or").unwrap().iter()
+            .flat_map(|clause| clause.as_document().unwrap().keys())
+            .collect();
+        assert_eq!(paths, ["command.comment.cancel", "cursor.originatingCommand.comment.cancel"]);
+        assert_ne!(Cancellation::new(CancellationToken::new()).tag(), cancellation.tag());
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::time::Duration;
+
+use mongodb::Client;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+
+/// Server features the demo relies on that older or standalone servers lack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Multi-document transactions, used by the transaction and unit of work demos.
+    Transactions,
+    /// Change streams, followed by the read model projector.
+    ChangeStreams,
+    /// `$merge` into another collection, used to rebuild the read model.
+    Merge,
+    /// `$dateTrunc`, used to bucket posts per day.
+    DateTrunc,
+    TimeSeries,
+    /// `$unionWith`, used to read across monthly partitions and the archive.
+    UnionWith,
+    /// Reads from a snapshot outside a transaction, for consistent exports.
+    SnapshotReads,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 7] = [
+        Feature::Transactions,
+        Feature::ChangeStreams,
+        Feature::Merge,
+        Feature::DateTrunc,
+        Feature::TimeSeries,
+        Feature::UnionWith,
+        Feature::SnapshotReads,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Transactions => "transactions",
+            Feature::ChangeStreams => "change streams",
+            Feature::Merge => "$merge",
+            Feature::DateTrunc => "$dateTrunc",
+            Feature::TimeSeries => "time series collections",
+            Feature::UnionWith => "$unionWith",
+            Feature::SnapshotReads => "snapshot reads",
+        }
+    }
+
+    /// First server `(major, minor)` version that has the feature.
+    pub fn min_version(&self) -> (i64, i64) {
+        match self {
+            Feature::Transactions => (4, 0),
+            Feature::ChangeStreams => (3, 6),
+            Feature::Merge => (4, 2),
+            Feature::DateTrunc => (5, 0),
+            Feature::TimeSeries => (5, 0),
+            Feature::UnionWith => (4, 4),
+            Feature::SnapshotReads => (5, 0),
+        }
+    }
+
+    /// Whether the feature only works against a replica set or sharded cluster.
+    pub fn needs_replica_set(&self) -> bool {
+        matches!(self, Feature::Transactions | Feature::ChangeStreams | Feature::SnapshotReads)
+    }
+}
+
+/// Why a demo was skipped.
+#[derive(Debug)]
+pub enum Unsupported {
+    TooOld { feature: Feature, server_version: String },
+    Standalone { feature: Feature },
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsupported::TooOld { feature, server_version } => {
+                let (major, minor) = feature.min_version();
+                write!(f, "{} needs MongoDB {}.{}, server is {}", feature.name(), major, minor, server_version)
+            }
+            Unsupported::Standalone { feature } => write!(
+                f,
+                "{} require a replica set but the server is standalone; restart mongod with \
+                 `--replSet rs0` and rerun with `--force-single-node` to turn it into a single-node replica set",
+                feature.name(),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// How the server we are connected to is deployed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topology {
+    Standalone,
+    ReplicaSet(String),
+    Sharded,
+}
+
+/// What the connected server can do, probed once via `buildInfo` and `hello`.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub version: String,
+    version_parts: [i64; 3],
+    pub topology: Topology,
+}
+
+impl Capabilities {
+    pub async fn probe(client: &Client) -> mongodb::error::Result<Self> {
+        let admin = client.database("admin");
+        let build_info = admin.run_command(doc! { "buildInfo": 1 }, None).await?;
+        let hello = hello(client).await?;
+        Ok(Capabilities::from_replies(&build_info, &hello))
+    }
+
+    fn from_replies(build_info: &Document, hello: &Document) -> Self {
+        let mut version_parts = [0; 3];
+        if let Ok(parts) = build_info.get_array("versionArray") {
+            for (slot, part) in version_parts.iter_mut().zip(parts) {
+                *slot = match part {
+                    Bson::Int32(n) => *n as i64,
+                    Bson::Int64(n) => *n,
+                    _ => 0,
+                };
+            }
+        }
+        let topology = if hello.get_str("msg") == Ok("isdbgrid") {
+            Topology::Sharded
+        } else if let Ok(set_name) = hello.get_str("setName") {
+            Topology::ReplicaSet(set_name.to_string())
+        } else {
+            Topology::Standalone
+        };
+        Capabilities {
+            version: build_info.get_str("version").unwrap_or("unknown").to_string(),
+            version_parts,
+            topology,
+        }
+    }
+
+    /// Whether the server version is `major.minor` or newer.
+    pub fn at_least(&self, major: i64, minor: i64) -> bool {
+        (self.version_parts[0], self.version_parts[1]) >= (major, minor)
+    }
+
+    /// `Ok` when the server has `feature`, otherwise the reason to skip it.
+    pub fn check(&self, feature: Feature) -> Result<(), Unsupported> {
+        if feature.needs_replica_set() && self.topology == Topology::Standalone {
+            return Err(Unsupported::Standalone { feature });
+        }
+        // Transactions reached sharded clusters one release after replica sets
+        let (major, minor) = match (feature, &self.topology) {
+            (Feature::Transactions, Topology::Sharded) => (4, 2),
+            _ => feature.min_version(),
+        };
+        if self.at_least(major, minor) {
+            Ok(())
+        } else {
+            Err(Unsupported::TooOld { feature, server_version: self.version.clone() })
+        }
+    }
+}
+
+async fn hello(client: &Client) -> mongodb::error::Result<Document> {
+    let admin = client.database("admin");
+    // `hello` only exists from 4.4.2 on; older servers answer `isMaster`
+    match admin.run_command(doc! { "hello": 1 }, None).await {
+        Ok(reply) => Ok(reply),
+        Err(_) => admin.run_command(doc! { "isMaster": 1 }, None).await,
+    }
+}
+
+/// Turns a `mongod --replSet <name>` that was never initiated into a
+/// single-node replica set reachable at `host`, then waits until it can take
+/// writes. Does nothing when the set is already initiated. The client has to
+/// use a direct connection: an uninitiated member is not selectable otherwise.
+pub async fn force_single_node(client: &Client, host: &str) -> mongodb::error::Result<()> {
+    let admin = client.database("admin");
+    let options = admin.run_command(doc! { "getCmdLineOpts": 1 }, None).await?;
+    let set_name = options.get_document("parsed")
+        .and_then(|parsed| parsed.get_document("replication"))
+        .and_then(|replication| replication.get_str("replSetName"))
+        .map_err(|_| not_ready("mongod was not started with `--replSet`; restart it with `--replSet rs0`"))?
+        .to_string();
+    let config = doc! { "_id": set_name, "members": [{ "_id": 0, "host": host }] };
+    match admin.run_command(doc! { "replSetInitiate": config }, None).await {
+        // AlreadyInitialized
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 23) => {}
+        result => {
+            result?;
+        }
+    }
+    for _ in 0..60 {
+        let reply = hello(client).await?;
+        if reply.get_bool("isWritablePrimary").or_else(|_| reply.get_bool("ismaster")) == Ok(true) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    Err(not_ready("the replica set member did not become primary within 30 seconds"))
+}
+
+fn not_ready(message: &str) -> mongodb::error::Error {
+    std::io::Error::other(message.to_string()).into()
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::watcher::{ChangeHandler, PostChange};
+
+/// What a [`ChangeStreamBridge`] does once its channel is full because its
+/// consumers read slower than posts change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Keep the change stream going and overwrite the oldest unread
+    /// changes, which a lagging receiver is told it missed. Any number of
+    /// receivers, each at its own pace: for live views such as server-sent
+    /// events, where a client that fell behind reloads rather than replays.
+    DropOldest,
+    /// Stop reading the change stream until the receiver catches up. Nothing
+    /// is lost, while the watcher, and its resume token, wait for the
+    /// slowest change: for consumers that have to see every change, such as
+    /// webhook senders.
+    Pause,
+    /// Fail the watcher with [`BridgeError::Lagged`]. The change that didn't
+    /// fit keeps its resume token unsaved, so a restarted watcher delivers
+    /// it again: for consumers whose falling behind means something is wrong.
+    Error,
+}
+
+/// Why a [`ChangeStreamBridge`] stopped its watcher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeError {
+    /// The channel held `capacity` unread changes, under [`LagPolicy::Error`].
+    Lagged { capacity: usize },
+    /// The receiver was dropped, so no one is left to forward changes to.
+    Closed,
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::Lagged { capacity } => write!(f, "change receiver fell {} changes behind", capacity),
+            BridgeError::Closed => write!(f, "change receiver was dropped"),
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+/// What a [`ChangeReceiver`] hands out.
+#[derive(Debug, Clone)]
+pub enum Received {
+    Change(PostChange),
+    /// `count` changes were dropped since the last one received, under
+    /// [`LagPolicy::DropOldest`].
+    Missed(u64),
+}
+
+#[derive(Clone)]
+enum Sender {
+    Broadcast(broadcast::Sender<PostChange>),
+    Bounded(mpsc::Sender<PostChange>, LagPolicy),
+}
+
+/// Forwards the changes a [`Watcher`](crate::watcher::Watcher) hands it
+/// into a channel of `capacity` changes, for consumers that aren't change
+/// handlers themselves, such as HTTP streams, and handling a full channel
+/// as its [`LagPolicy`] says; an unbounded one would grow for as long as a
+/// consumer is stuck. Cheap to clone: clones feed the same channel.
+#[derive(Clone)]
+pub struct ChangeStreamBridge {
+    sender: Sender,
+    capacity: usize,
+}
+
+impl ChangeStreamBridge {
+    /// A bridge and its first receiver. `capacity` has to be at least one.
+    pub fn new(capacity: usize, policy: LagPolicy) -> (Self, ChangeReceiver) {
+        let (sender, receiver) = match policy {
+            LagPolicy::DropOldest => {
+                let (sender, receiver) = broadcast::channel(capacity);
+                (Sender::Broadcast(sender), ChangeReceiver::Broadcast(receiver))
+            }
+            LagPolicy::Pause | LagPolicy::Error => {
+                let (sender, receiver) = mpsc::channel(capacity);
+                (Sender::Bounded(sender, policy), ChangeReceiver::Bounded(receiver))
+            }
+        };
+        (ChangeStreamBridge { sender, capacity }, receiver)
+    }
+
+    /// Another receiver, of the changes from now on, under
+    /// [`LagPolicy::DropOldest`]; the other policies have one receiver only.
+    pub fn subscribe(&self) -> Option<ChangeReceiver> {
+        match &self.sender {
+            Sender::Broadcast(sender) => Some(ChangeReceiver::Broadcast(sender.subscribe())),
+            Sender::Bounded(..) => None,
+        }
+    }
+
+    async fn forward(&self, change: &PostChange) -> Result<(), BridgeError> {
+        match &self.sender {
+            // No receivers is fine: changes nobody listens to are dropped
+            Sender::Broadcast(sender) => {
+                let _ = sender.send(change.clone());
+                Ok(())
+            }
+            Sender::Bounded(sender, LagPolicy::Error) => sender.try_send(change.clone()).map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => BridgeError::Lagged { capacity: self.capacity },
+                mpsc::error::TrySendError::Closed(_) => BridgeError::Closed,
+            }),
+            Sender::Bounded(sender, _) => sender.send(change.clone()).await.map_err(|_| BridgeError::Closed),
+        }
+    }
+}
+
+impl ChangeHandler for ChangeStreamBridge {
+    fn handle<'a>(&'a self, change: &'a PostChange) -> BoxFuture<'a, mongodb::error::Result<()>> {
+        async move { self.forward(change).await.map_err(mongodb::error::Error::custom) }.boxed()
+    }
+}
+
+/// The receiving end of a [`ChangeStreamBridge`].
+pub enum ChangeReceiver {
+    Broadcast(broadcast::Receiver<PostChange>),
+    Bounded(mpsc::Receiver<PostChange>),
+}
+
+impl ChangeReceiver {
+    /// The next change, or how many were missed before it; `None` once the
+    /// bridge and all its clones are gone.
+    pub async fn recv(&mut self) -> Option<Received> {
+        match self {
+            ChangeReceiver::Broadcast(receiver) => match receiver.recv().await {
+                Ok(change) => Some(Received::Change(change)),
+                Err(broadcast::error::RecvError::Lagged(count)) => Some(Received::Missed(count)),
+                Err(broadcast::error::RecvError::Closed) => None,
+            },
+            ChangeReceiver::Bounded(receiver) => receiver.recv().await.map(Received::Change),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+
+    fn deleted() -> PostChange {
+        PostChange::Deleted(ObjectId::new())
+    }
+
+    #[tokio::test]
+    async fn full_channels_follow_their_lag_policy() {
+        let (bridge, mut receiver) = ChangeStreamBridge::new(2, LagPolicy::DropOldest);
+        for _ in 0..3 {
+            bridge.handle(&deleted()).await.unwrap();
+        }
+        assert!(matches!(receiver.recv().await, Some(Received::Missed(1))));
+        assert!(matches!(receiver.recv().await, Some(Received::Change(PostChange::Deleted(_)))));
+
+        let (bridge, mut receiver) = ChangeStreamBridge::new(1, LagPolicy::Error);
+        bridge.handle(&deleted()).await.unwrap();
+        assert!(bridge.handle(&deleted()).await.is_err());
+        assert!(receiver.recv().await.is_some());
+        assert!(bridge.subscribe().is_none());
+
+        let (bridge, mut receiver) = ChangeStreamBridge::new(1, LagPolicy::Pause);
+        bridge.handle(&deleted()).await.unwrap();
+        let paused = tokio::spawn({
+            let bridge = bridge.clone();
+            async move { bridge.handle(&deleted()).await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!paused.is_finished());
+        assert!(receiver.recv().await.is_some());
+        assert!(paused.await.unwrap());
+        drop(receiver);
+        assert_eq!(bridge.forward(&deleted()).await, Err(BridgeError::Closed));
+    }
+}
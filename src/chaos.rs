@@ -0,0 +1,307 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::UpdateOptions;
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::Post;
+use crate::error::{is_validation_error, Error, Result};
+use crate::ids::number;
+use crate::namespace::Namespace;
+use crate::schema::{self, posts_schema};
+use crate::seed::{seeded, TITLE_PREFIX};
+
+/// The limits of the `posts` validator that mutations stay within, or just
+/// outside of. `None` where the validator sets none, in which case there is
+/// no mutation just outside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub title_max: Option<usize>,
+    pub message_max: Option<usize>,
+    pub tags_max: Option<usize>,
+    pub tag_min: Option<usize>,
+    pub tag_max: Option<usize>,
+    pub version_min: Option<i64>,
+}
+
+impl Limits {
+    /// Read from the `properties` of a `{ "$jsonSchema": ... }` validator.
+    pub fn from_validator(validator: &Document) -> Self {
+        let properties = validator.get_document("$jsonSchema")
+            .and_then(|schema| schema.get_document("properties"))
+            .ok();
+        let property = |path: &[&str], limit: &str| {
+            let mut doc = properties?;
+            for field in path {
+                doc = doc.get_document(field).ok()?;
+            }
+            doc.get(limit).map(|limit| number(Some(limit)))
+        };
+        let length = |path: &[&str], limit: &str| property(path, limit).map(|n| n.max(0) as usize);
+        Limits {
+            title_max: length(&["title"], "maxLength"),
+            message_max: length(&["message"], "maxLength"),
+            tags_max: length(&["tags"], "maxItems"),
+            tag_min: length(&["tags", "items"], "minLength"),
+            tag_max: length(&["tags", "items"], "maxLength"),
+            version_min: property(&["version"], "minimum"),
+        }
+    }
+}
+
+/// One change to a post: within the validator's limits, or just past one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    Retitle,
+    RewriteMessage,
+    Retag,
+    BumpVersion,
+    /// One character past the title's maximum.
+    LongTitle,
+    LongMessage,
+    /// One tag past the maximum.
+    TooManyTags,
+    ShortTag,
+    LongTag,
+    NegativeVersion,
+    /// `message` is required.
+    MissingMessage,
+    /// `tags` a string instead of an array.
+    TagsNotArray,
+}
+
+impl Mutation {
+    pub const VALID: [Mutation; 4] =
+        [Mutation::Retitle, Mutation::RewriteMessage, Mutation::Retag, Mutation::BumpVersion];
+    pub const INVALID: [Mutation; 8] = [
+        Mutation::LongTitle,
+        Mutation::LongMessage,
+        Mutation::TooManyTags,
+        Mutation::ShortTag,
+        Mutation::LongTag,
+        Mutation::NegativeVersion,
+        Mutation::MissingMessage,
+        Mutation::TagsNotArray,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mutation::Retitle => "retitle",
+            Mutation::RewriteMessage => "rewrite message",
+            Mutation::Retag => "retag",
+            Mutation::BumpVersion => "bump version",
+            Mutation::LongTitle => "long title",
+            Mutation::LongMessage => "long message",
+            Mutation::TooManyTags => "too many tags",
+            Mutation::ShortTag => "short tag",
+            Mutation::LongTag => "long tag",
+            Mutation::NegativeVersion => "negative version",
+            Mutation::MissingMessage => "missing message",
+            Mutation::TagsNotArray => "tags not an array",
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        Mutation::VALID.contains(self)
+    }
+
+    /// Whether `limits` has the limit this goes past; valid ones always apply.
+    fn applies(&self, limits: &Limits) -> bool {
+        match self {
+            Mutation::LongTitle => limits.title_max.is_some(),
+            Mutation::LongMessage => limits.message_max.is_some(),
+            Mutation::TooManyTags => limits.tags_max.is_some(),
+            Mutation::ShortTag => limits.tag_min.is_some_and(|min| min > 0),
+            Mutation::LongTag => limits.tag_max.is_some(),
+            Mutation::NegativeVersion => limits.version_min.is_some(),
+            _ => true,
+        }
+    }
+
+    /// The update making the change. Titles keep [`TITLE_PREFIX`], so the
+    /// post stays one [`crate::seed::clear`] deletes.
+    pub fn update(&self, rng: &mut impl Rng, limits: &Limits) -> Document {
+        let title_max = limits.title_max.unwrap_or(300);
+        let tag_min = limits.tag_min.unwrap_or(3).max(1);
+        let tag_max = limits.tag_max.unwrap_or(10).max(tag_min);
+        let tags_max = limits.tags_max.unwrap_or(5).max(1);
+        match self {
+            Mutation::Retitle => {
+                let title = format!("{}chaos {:016x}", TITLE_PREFIX, rng.gen::<u64>());
+                let title: String = title.chars().take(title_max).collect();
+                doc! {
+                    "$set": { "title_prefixes": Post::title_prefixes(&title), "title": title },
+                    "$inc": { "version": 1 },
+                }
+            }
+            Mutation::RewriteMessage => {
+                let len = rng.gen_range(0..=limits.message_max.unwrap_or(4000));
+                doc! { "$set": { "message": text(rng, len) }, "$inc": { "version": 1 } }
+            }
+            Mutation::Retag => {
+                let tags: Vec<String> = (0..rng.gen_range(1..=tags_max))
+                    .map(|_| {
+                        let len = rng.gen_range(tag_min..=tag_max);
+                        tag(rng, len)
+                    })
+                    .collect();
+                doc! { "$set": { "tags": tags }, "$inc": { "version": 1 } }
+            }
+            Mutation::BumpVersion => doc! { "$inc": { "version": 1 } },
+            Mutation::LongTitle => {
+                let title = format!("{}{}", TITLE_PREFIX, text(rng, title_max + 1));
+                let title: String = title.chars().take(title_max + 1).collect();
+                doc! { "$set": { "title": title } }
+            }
+            Mutation::LongMessage => {
+                doc! { "$set": { "message": text(rng, limits.message_max.unwrap_or(4000) + 1) } }
+            }
+            Mutation::TooManyTags => {
+                let tags: Vec<String> = (0..=tags_max).map(|_| tag(rng, tag_min)).collect();
+                doc! { "$set": { "tags": tags } }
+            }
+            Mutation::ShortTag => doc! { "$set": { "tags": [tag(rng, tag_min - 1)] } },
+            Mutation::LongTag => doc! { "$set": { "tags": [tag(rng, tag_max + 1)] } },
+            Mutation::NegativeVersion => doc! { "$set": { "version": limits.version_min.unwrap_or(0) - 1 } },
+            Mutation::MissingMessage => doc! { "$unset": { "message": "" } },
+            Mutation::TagsNotArray => doc! { "$set": { "tags": tag(rng, tag_min) } },
+        }
+    }
+}
+
+/// Exactly `len` characters of lowercase letters and spaces.
+fn text(rng: &mut impl Rng, len: usize) -> String {
+    (0..len).map(|_| *b"abcdefghijklmnopqrstuvwxyz ".choose(rng).expect("not empty") as char).collect()
+}
+
+fn tag(rng: &mut impl Rng, len: usize) -> String {
+    (0..len).map(|_| rng.gen_range(b'a'..=b'z') as char).collect()
+}
+
+/// What [`run`] does.
+#[derive(Debug, Clone, Copy)]
+pub struct ChaosOptions {
+    pub mutations: usize,
+    /// Share of the mutations going just past a limit, from 0 to 1.
+    pub invalid: f64,
+    /// Write those anyway, bypassing the validator, so there are invalid
+    /// posts for [`invalid`] to find.
+    pub bypass_validation: bool,
+}
+
+/// What [`run`] did. `unexpected` is what the validator got wrong: valid
+/// mutations it rejected and invalid ones it let through on its own.
+#[derive(serde::Serialize, Debug, Default)]
+pub struct ChaosReport {
+    pub applied: usize,
+    /// Invalid mutations the validator rejected, as it should.
+    pub rejected: usize,
+    /// Invalid mutations written with the validator bypassed.
+    pub planted: usize,
+    /// Mutations that failed otherwise, e.g. a retitle to a taken title.
+    pub failed: usize,
+    pub unexpected: Vec<(ObjectId, &'static str, String)>,
+}
+
+/// The `posts` validator in `ns`, or [`posts_schema`]'s when it has none.
+pub async fn validator(ns: &Namespace) -> Result<Document> {
+    Ok(schema::validator(ns, "posts").await?.unwrap_or_else(|| {
+        posts_schema().options.get_document("validator").cloned().expect("posts_schema has a validator")
+    }))
+}
+
+/// Applies [`ChaosOptions::mutations`] random mutations to random seeded
+/// posts, as many distinct ones as there are. Only seeded posts are touched,
+/// so real data never is.
+pub async fn run(ns: &Namespace, options: &ChaosOptions, rng: &mut StdRng) -> Result<ChaosReport> {
+    let limits = Limits::from_validator(&validator(ns).await?);
+    let col = ns.collection::<Document>("posts");
+    let pipeline = vec![
+        doc! { "$match": seeded() },
+        doc! { "$sample": { "size": options.mutations as i64 } },
+        doc! { "$project": { "_id": 1 } },
+    ];
+    let sampled: Vec<Document> = col.aggregate(pipeline, None).await?.try_collect().await?;
+    let invalid: Vec<Mutation> = Mutation::INVALID.into_iter()
+        .filter(|mutation| mutation.applies(&limits))
+        .collect();
+    let mut report = ChaosReport::default();
+    for id in sampled.iter().filter_map(|sample| sample.get("_id").and_then(Bson::as_object_id)) {
+        let mutation = match invalid.choose(rng) {
+            Some(mutation) if rng.gen_bool(options.invalid.clamp(0.0, 1.0)) => *mutation,
+            _ => *Mutation::VALID.choose(rng).expect("not empty"),
+        };
+        let update = mutation.update(rng, &limits);
+        let bypass = options.bypass_validation && !mutation.is_valid();
+        let update_options = UpdateOptions::builder().bypass_document_validation(bypass).build();
+        match (col.update_one(doc! { "_id": id }, update, update_options).await, mutation.is_valid()) {
+            (Ok(_), true) => report.applied += 1,
+            (Ok(_), false) if bypass => report.planted += 1,
+            (Ok(_), false) => report.unexpected.push((id, mutation.name(), "accepted".to_string())),
+            (Err(e), false) if is_validation_error(&e) => report.rejected += 1,
+            (Err(e), true) if is_validation_error(&e) => {
+                report.unexpected.push((id, mutation.name(), Error::from(e).to_string()));
+            }
+            (Err(_), _) => report.failed += 1,
+        }
+    }
+    Ok(report)
+}
+
+/// The ids of the posts the `posts` validator rejects, as written with it
+/// bypassed or before it was tightened.
+pub async fn invalid(ns: &Namespace) -> Result<Vec<ObjectId>> {
+    let filter = doc! { "$nor": [validator(ns).await?] };
+    let options = mongodb::options::FindOptions::builder().projection(doc! { "_id": 1 }).build();
+    let found: Vec<Document> = ns.collection::<Document>("posts").find(filter, options).await?
+        .try_collect().await?;
+    Ok(found.iter().filter_map(|post| post.get("_id").and_then(Bson::as_object_id)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits::from_validator(posts_schema().options.get_document("validator").unwrap())
+    }
+
+    #[test]
+    fn limits_are_read_from_the_validator() {
+        assert_eq!(limits(), Limits {
+            title_max: Some(300),
+            message_max: Some(4000),
+            tags_max: Some(5),
+            tag_min: Some(3),
+            tag_max: Some(10),
+            version_min: Some(0),
+        });
+        assert_eq!(Limits::from_validator(&doc! {}).title_max, None);
+    }
+
+    #[test]
+    fn invalid_mutations_go_just_past_the_limit() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let limits = limits();
+        let set = |mutation: Mutation, rng: &mut StdRng| {
+            mutation.update(rng, &limits).get_document("$set").unwrap().clone()
+        };
+        for _ in 0..50 {
+            let title = set(Mutation::Retitle, &mut rng).get_str("title").unwrap().to_string();
+            assert!(title.starts_with(TITLE_PREFIX) && title.len() <= 300);
+            let tags = set(Mutation::Retag, &mut rng).get_array("tags").unwrap().clone();
+            assert!((1..=5).contains(&tags.len()));
+            assert!(tags.iter().all(|tag| (3..=10).contains(&tag.as_str().unwrap().len())));
+        }
+        assert_eq!(set(Mutation::LongTitle, &mut rng).get_str("title").unwrap().len(), 301);
+        assert_eq!(set(Mutation::LongMessage, &mut rng).get_str("message").unwrap().len(), 4001);
+        assert_eq!(set(Mutation::TooManyTags, &mut rng).get_array("tags").unwrap().len(), 6);
+        assert_eq!(set(Mutation::ShortTag, &mut rng).get_array("tags").unwrap()[0].as_str().unwrap().len(), 2);
+        assert_eq!(set(Mutation::LongTag, &mut rng).get_array("tags").unwrap()[0].as_str().unwrap().len(), 11);
+        assert_eq!(set(Mutation::NegativeVersion, &mut rng).get_i64("version"), Ok(-1));
+    }
+}
@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+use crate::error::{Error, Result};
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A probe is in flight; everyone else keeps failing fast until it lands,
+    /// or until `until`, when the probe is presumed lost (e.g. its caller went
+    /// away) and another one may go out.
+    HalfOpen { until: Instant },
+}
+
+/// Stops calling a database that is down.
+///
+/// After `threshold` consecutive connection or timeout errors the breaker
+/// opens and every call fails immediately with [`Error::CircuitOpen`]
+/// instead of waiting for server selection to time out. Once `cooldown` has
+/// passed, the next call goes through as a probe: success closes the breaker,
+/// failure opens it for another `cooldown`. Other errors, like
+/// [`Error::NotFound`], say nothing about the database's health and don't count.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    state: Arc<Mutex<State>>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker { state: Arc::new(Mutex::new(State::Closed { failures: 0 })), threshold, cooldown }
+    }
+
+    /// Runs `operation` unless the breaker is open.
+    pub async fn call<T>(&self, operation: impl Future<Output = Result<T>>) -> Result<T> {
+        self.before()?;
+        let result = operation.await;
+        self.after(result.as_ref().err().is_some_and(is_unavailable));
+        result
+    }
+
+    #[allow(clippy::result_large_err)] // same `Result` as the operations it guards
+    fn before(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } | State::HalfOpen { until } if Instant::now() >= until => {
+                *state = State::HalfOpen { until: Instant::now() + self.cooldown };
+                Ok(())
+            }
+            State::Open { until } | State::HalfOpen { until } => {
+                Err(Error::CircuitOpen { retry_in: until - Instant::now() })
+            }
+        }
+    }
+
+    fn after(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (&*state, failed) {
+            // A call from before the breaker opened, that took its time
+            (State::Open { until }, false) => State::Open { until: *until },
+            (_, false) => State::Closed { failures: 0 },
+            (State::Closed { failures }, true) if failures + 1 < self.threshold => {
+                State::Closed { failures: failures + 1 }
+            }
+            (_, true) => {
+                tracing::warn!(cooldown_ms = self.cooldown.as_millis() as u64, "circuit breaker opened");
+                State::Open { until: Instant::now() + self.cooldown }
+            }
+        };
+    }
+}
+
+/// Whether `err` means the database couldn't be reached or didn't answer in time.
+fn is_unavailable(err: &Error) -> bool {
+    matches!(err, Error::DeadlineExceeded | Error::Connection(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.after(true);
+        breaker.after(false);
+        breaker.after(true);
+        assert!(breaker.before().is_ok());
+        breaker.after(true);
+        assert!(matches!(breaker.before(), Err(Error::CircuitOpen { .. })));
+    }
+
+    #[test]
+    fn a_probe_after_the_cooldown_closes_or_reopens_it() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.after(true);
+        // The probe
+        assert!(breaker.before().is_ok());
+        breaker.after(true);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+        assert!(breaker.before().is_ok());
+        breaker.after(false);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Closed { failures: 0 }));
+    }
+
+    #[test]
+    fn only_one_probe_goes_out_at_a_time() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        *breaker.state.lock().unwrap() = State::Open { until: Instant::now() };
+        assert!(breaker.before().is_ok());
+        assert!(matches!(breaker.before(), Err(Error::CircuitOpen { .. })));
+    }
+
+    #[tokio::test]
+    async fn errors_that_dont_mean_the_database_is_down_dont_count() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        let result: Result<()> = breaker.call(async { Err(Error::NotFound) }).await;
+        assert!(matches!(result, Err(Error::NotFound)));
+        let result: Result<()> = breaker.call(async { Err(Error::DeadlineExceeded) }).await;
+        assert!(matches!(result, Err(Error::DeadlineExceeded)));
+        let result = breaker.call(async { Ok(()) }).await;
+        assert!(matches!(result, Err(Error::CircuitOpen { .. })));
+    }
+
+    #[test]
+    fn a_late_success_leaves_an_open_breaker_open() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.before().unwrap();
+        breaker.after(true);
+        breaker.after(false);
+        assert!(matches!(breaker.before(), Err(Error::CircuitOpen { .. })));
+    }
+}
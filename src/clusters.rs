@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use mongodb::Client;
+use mongodb::event::command::CommandEventHandler;
+
+use crate::config::AppConfig;
+use crate::namespace::Namespace;
+use crate::repository::APP_NAME;
+
+/// The cluster of the top-level config, which everything not routed
+/// elsewhere runs on.
+pub const PRIMARY: &str = "primary";
+/// Where reporting aggregations go, e.g. a replica set's analytics nodes;
+/// see [`CLUSTER_ROUTES`](crate::repository::CLUSTER_ROUTES).
+pub const ANALYTICS: &str = "analytics";
+
+/// The namespace of each configured cluster, all with the primary's
+/// collection prefix. A cluster that isn't configured is the primary one, so
+/// methods routed to it run where they would without routing.
+#[derive(Clone)]
+pub struct Clusters {
+    primary: Namespace,
+    others: BTreeMap<String, Namespace>,
+}
+
+impl Clusters {
+    /// Just the primary cluster.
+    pub fn new(primary: &Namespace) -> Self {
+        Clusters { primary: primary.clone(), others: BTreeMap::new() }
+    }
+
+    /// A client per cluster of `config.clusters`, each with its own pool and
+    /// its own `appName` (`<app>-<cluster>`), reporting its commands to
+    /// `handler` like the primary's.
+    pub async fn connect(
+        config: &AppConfig,
+        primary: &Namespace,
+        handler: Option<Arc<dyn CommandEventHandler>>,
+    ) -> mongodb::error::Result<Self> {
+        let mut clusters = Clusters::new(primary);
+        for (name, cluster) in &config.clusters {
+            let mut options = config.cluster_options(cluster).await?;
+            options.app_name = Some(format!("{}-{}", APP_NAME, name));
+            options.command_event_handler = handler.clone();
+            let client = Client::with_options(options)?;
+            let database = cluster.database.as_deref().unwrap_or(primary.db().name());
+            clusters.others.insert(name.clone(), primary.with_database(client.database(database)));
+        }
+        Ok(clusters)
+    }
+
+    pub fn primary(&self) -> &Namespace {
+        &self.primary
+    }
+
+    /// The namespace of `cluster`, `None` unless configured besides the
+    /// primary one.
+    pub fn get(&self, cluster: &str) -> Option<&Namespace> {
+        self.others.get(cluster)
+    }
+}
@@ -0,0 +1,541 @@
+use std::borrow::Cow;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime as ChronoDateTime, SecondsFormat, Utc};
+use mongodb::bson::{self, Bson, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::{ejson, Post, PostStatus};
+use crate::ejson::Mode;
+use crate::transfer::Result;
+
+/// How posts are written to and read from one file format, so a format is
+/// one implementation of this and the exports and imports stream through
+/// it unchanged; [`Format::codec`](crate::transfer::Format::codec) picks
+/// the codec of each format.
+pub trait RecordCodec: Sync {
+    /// What records its encoder is best given.
+    fn records(&self) -> Records;
+
+    /// An encoder writing records to `out`, one after another.
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>>;
+
+    /// The posts in `input`, read lazily in file order. One that can't be
+    /// read comes out as an error saying where it is.
+    fn decoder(&self, input: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>>;
+
+    /// How many posts [`RecordCodec::decoder`] would read from `input`. By
+    /// default it reads them; codecs that can tell records apart without
+    /// decoding them do that instead.
+    fn count(&self, input: Box<dyn BufRead>) -> Result<u64> {
+        self.decoder(input)?.try_fold(0, |count, post| post.map(|_| count + 1))
+    }
+}
+
+/// What a [`RecordEncoder`] writes out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Records {
+    /// Documents as stored, so fields [`Post`] doesn't know survive too.
+    Stored,
+    /// Posts decoded from them, for formats with fixed columns.
+    Decoded,
+}
+
+/// A post for a [`RecordEncoder`], either way it was read.
+pub enum Record<'a> {
+    Stored(&'a Document),
+    Decoded(&'a Post),
+}
+
+impl Record<'_> {
+    /// The post as a document, serialized if it was decoded.
+    pub fn document(&self) -> Result<Cow<'_, Document>> {
+        Ok(match self {
+            Record::Stored(document) => Cow::Borrowed(*document),
+            Record::Decoded(post) => Cow::Owned(bson::to_document(*post)?),
+        })
+    }
+
+    /// The post as a [`Post`], decoded if it was stored.
+    pub fn post(&self) -> Result<Cow<'_, Post>> {
+        Ok(match self {
+            Record::Stored(document) => Cow::Owned(bson::from_document((*document).clone())?),
+            Record::Decoded(post) => Cow::Borrowed(*post),
+        })
+    }
+}
+
+/// Writes the records of one file, from [`RecordCodec::encoder`].
+pub trait RecordEncoder {
+    fn encode(&mut self, record: Record<'_>) -> Result<()>;
+
+    /// Writes whatever is left, such as a footer, and flushes the output.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// One post per line as relaxed Extended JSON.
+pub struct JsonLinesCodec;
+
+struct JsonLinesEncoder<'a> {
+    out: Box<dyn Write + 'a>,
+}
+
+impl RecordCodec for JsonLinesCodec {
+    fn records(&self) -> Records {
+        Records::Stored
+    }
+
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>> {
+        Ok(Box::new(JsonLinesEncoder { out }))
+    }
+
+    fn decoder(&self, input: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+        Ok(Box::new(ejson::read_lines(input)))
+    }
+
+    fn count(&self, input: Box<dyn BufRead>) -> Result<u64> {
+        let mut count = 0;
+        for line in input.lines() {
+            if !line?.trim().is_empty() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl RecordEncoder for JsonLinesEncoder<'_> {
+    fn encode(&mut self, record: Record<'_>) -> Result<()> {
+        ejson::write_line(&mut self.out, &*record.document()?, Mode::Relaxed)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// The posts as BSON documents back to back, as `mongodump` writes a
+/// collection, so every type survives as stored: no dates as strings, no
+/// 64-bit integers as doubles.
+pub struct BsonCodec;
+
+struct BsonEncoder<'a> {
+    out: Box<dyn Write + 'a>,
+}
+
+impl RecordCodec for BsonCodec {
+    fn records(&self) -> Records {
+        Records::Stored
+    }
+
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>> {
+        Ok(Box::new(BsonEncoder { out }))
+    }
+
+    fn decoder(&self, input: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+        Ok(Box::new(documents(input, |input| Ok(bson::from_document(Document::from_reader(input)?)?))))
+    }
+
+    /// Skips over each document by the length it starts with.
+    fn count(&self, input: Box<dyn BufRead>) -> Result<u64> {
+        documents(input, |input| {
+            let mut length = [0; 4];
+            input.read_exact(&mut length)?;
+            let rest = (i32::from_le_bytes(length) as u64).saturating_sub(4);
+            if std::io::copy(&mut input.take(rest), &mut std::io::sink())? < rest {
+                return Err("the file ends within the document".into());
+            }
+            Ok(())
+        }).try_fold(0, |count, document| document.map(|_| count + 1))
+    }
+}
+
+impl RecordEncoder for BsonEncoder<'_> {
+    fn encode(&mut self, record: Record<'_>) -> Result<()> {
+        Ok(record.document()?.to_writer(&mut self.out)?)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// The posts as MessagePack maps back to back. Ids, dates and other BSON
+/// types are maps of their Extended JSON keys, such as `{ "$oid": ... }`,
+/// which read back as those types; integers read back as the narrowest
+/// BSON integer holding them, as MessagePack keeps no width.
+pub struct MessagePackCodec;
+
+struct MessagePackEncoder<'a> {
+    out: Box<dyn Write + 'a>,
+}
+
+impl RecordCodec for MessagePackCodec {
+    fn records(&self) -> Records {
+        Records::Stored
+    }
+
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>> {
+        Ok(Box::new(MessagePackEncoder { out }))
+    }
+
+    fn decoder(&self, input: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+        // Read as a document first, which knows the Extended JSON keys
+        Ok(Box::new(documents(input, |input| {
+            let document: Document = rmp_serde::from_read(input)?;
+            Ok(bson::from_document(document)?)
+        })))
+    }
+
+    fn count(&self, input: Box<dyn BufRead>) -> Result<u64> {
+        documents(input, |input| Ok(rmp_serde::from_read::<_, serde::de::IgnoredAny>(input)?))
+            .try_fold(0, |count, document| document.map(|_| count + 1))
+    }
+}
+
+impl RecordEncoder for MessagePackEncoder<'_> {
+    fn encode(&mut self, record: Record<'_>) -> Result<()> {
+        // Named, so structs such as ids are maps rather than arrays
+        Ok(rmp_serde::encode::write_named(&mut self.out, &*record.document()?)?)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// The records of a file of back to back documents, each read by `read`
+/// until the file ends. The first that can't be read ends it too, as an
+/// error naming its position.
+fn documents<T>(
+    mut input: Box<dyn BufRead>,
+    read: fn(&mut dyn BufRead) -> Result<T>,
+) -> impl Iterator<Item = Result<T>> {
+    let mut index = 0;
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let record = match input.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => read(&mut *input),
+            Err(e) => Err(e.into()),
+        };
+        index += 1;
+        failed = record.is_err();
+        Some(record.map_err(|e| format!("document {}: {}", index, e).into()))
+    })
+}
+
+/// Header of a CSV export. Tags are joined with `;`, dates are RFC 3339.
+pub const CSV_COLUMNS: [&str; 9] =
+    ["_id", "title", "message", "tags", "created_at", "version", "status", "publish_at", "lang"];
+
+/// One post per row with [`CSV_COLUMNS`].
+pub struct CsvCodec;
+
+struct CsvEncoder<'a> {
+    out: csv::Writer<Box<dyn Write + 'a>>,
+}
+
+impl RecordCodec for CsvCodec {
+    fn records(&self) -> Records {
+        Records::Decoded
+    }
+
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>> {
+        let mut out = csv::Writer::from_writer(out);
+        out.write_record(CSV_COLUMNS)?;
+        Ok(Box::new(CsvEncoder { out }))
+    }
+
+    fn decoder(&self, input: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+        Ok(Box::new(csv::Reader::from_reader(input).into_records().enumerate()
+            .map(|(index, row)| {
+                let post = row.map_err(Into::into).and_then(|row| parse_csv_row(&row));
+                // The header is line 1
+                post.map_err(|e| format!("line {}: {}", index + 2, e).into())
+            })))
+    }
+
+    fn count(&self, input: Box<dyn BufRead>) -> Result<u64> {
+        let mut count = 0;
+        for row in csv::Reader::from_reader(input).into_records() {
+            row?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl RecordEncoder for CsvEncoder<'_> {
+    fn encode(&mut self, record: Record<'_>) -> Result<()> {
+        Ok(self.out.write_record(csv_row(&*record.post()?))?)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+fn csv_row(post: &Post) -> [String; 9] {
+    let date = |date: DateTime| date.to_chrono().to_rfc3339_opts(SecondsFormat::Millis, true);
+    [
+        post.id.to_hex(),
+        post.title.clone(),
+        post.message.clone(),
+        post.tags.join(";"),
+        date(post.created_at),
+        post.version.to_string(),
+        post.status.as_str().to_string(),
+        post.publish_at.map(date).unwrap_or_default(),
+        post.lang.clone().unwrap_or_default(),
+    ]
+}
+
+#[allow(clippy::result_large_err)] // boxed like the rest of this module
+fn parse_csv_row(row: &csv::StringRecord) -> Result<Post> {
+    let field = |column: usize| row.get(column).unwrap_or_default();
+    let date = |column: usize| -> Result<Option<DateTime>> {
+        match field(column) {
+            "" => Ok(None),
+            value => {
+                let date = ChronoDateTime::parse_from_rfc3339(value)?.with_timezone(&Utc);
+                Ok(Some(DateTime::from_chrono(date)))
+            }
+        }
+    };
+    let title = field(1);
+    let tags: Vec<&str> = field(3).split(';').filter(|tag| !tag.is_empty()).collect();
+    let status: PostStatus = bson::from_bson(Bson::String(field(6).to_string()))?;
+    Ok(Post {
+        id: ObjectId::parse_str(field(0))?,
+        message: field(2).to_string(),
+        created_at: date(4)?.ok_or("created_at is empty")?,
+        version: field(5).parse()?,
+        status,
+        publish_at: date(7)?,
+        lang: Some(field(8).to_string()).filter(|lang| !lang.is_empty()),
+        ..Post::new(title, "", &tags)
+    })
+}
+
+/// Posts per row group of a Parquet export.
+const PARQUET_BATCH: usize = 1024;
+
+/// The columns of [`CsvCodec`], typed, compressed with Snappy, in row
+/// groups of [`PARQUET_BATCH`] posts. Export only: it can't be read back.
+pub struct ParquetCodec;
+
+const PARQUET_IMPORT: &str = "Parquet exports can't be imported, export to jsonl, bson, msgpack or csv instead";
+
+/// Parquet's writer has to be `Send`, which a locked stdout isn't, so it
+/// writes to a buffer, handed on to `out` after every row group.
+struct ParquetEncoder<'a> {
+    out: Box<dyn Write + 'a>,
+    writer: ArrowWriter<Vec<u8>>,
+    schema: Arc<Schema>,
+    batch: Vec<Post>,
+}
+
+impl RecordCodec for ParquetCodec {
+    fn records(&self) -> Records {
+        Records::Decoded
+    }
+
+    fn encoder<'a>(&self, out: Box<dyn Write + 'a>) -> Result<Box<dyn RecordEncoder + 'a>> {
+        let schema = posts_schema();
+        let writer = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(parquet_properties()))?;
+        Ok(Box::new(ParquetEncoder { out, writer, schema, batch: Vec::with_capacity(PARQUET_BATCH) }))
+    }
+
+    fn decoder(&self, _: Box<dyn BufRead>) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+        Err(PARQUET_IMPORT.into())
+    }
+}
+
+impl ParquetEncoder<'_> {
+    /// Writes the posts so far as a row group.
+    fn write_batch(&mut self) -> Result<()> {
+        self.writer.write(&posts_batch(&self.schema, &self.batch)?)?;
+        self.batch.clear();
+        self.writer.flush()?;
+        // Out of the writer's own buffer first, so nothing is taken out of order
+        self.writer.sync()?;
+        self.out.write_all(&std::mem::take(self.writer.inner_mut()))?;
+        Ok(())
+    }
+}
+
+impl RecordEncoder for ParquetEncoder<'_> {
+    fn encode(&mut self, record: Record<'_>) -> Result<()> {
+        self.batch.push(record.post()?.into_owned());
+        if self.batch.len() == PARQUET_BATCH {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        if !self.batch.is_empty() {
+            self.write_batch()?;
+        }
+        let ParquetEncoder { mut out, writer, .. } = *self;
+        out.write_all(&writer.into_inner()?)?;
+        Ok(out.flush()?)
+    }
+}
+
+pub(crate) fn parquet_properties() -> WriterProperties {
+    WriterProperties::builder().set_compression(Compression::SNAPPY).build()
+}
+
+pub(crate) fn timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+}
+
+/// [`CSV_COLUMNS`] with their types: tags are a list of strings and dates
+/// timestamps.
+fn posts_schema() -> Arc<Schema> {
+    let string = |name: &str, nullable: bool| Field::new(name, DataType::Utf8, nullable);
+    Arc::new(Schema::new(vec![
+        string("_id", false),
+        string("title", false),
+        string("message", false),
+        Field::new("tags", DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))), false),
+        Field::new("created_at", timestamp(), false),
+        Field::new("version", DataType::Int64, false),
+        string("status", false),
+        Field::new("publish_at", timestamp(), true),
+        string("lang", true),
+    ]))
+}
+
+#[allow(clippy::result_large_err)] // boxed like the rest of this module
+fn posts_batch(schema: &Arc<Schema>, posts: &[Post]) -> Result<RecordBatch> {
+    let strings = |value: fn(&Post) -> Option<&str>| -> ArrayRef {
+        Arc::new(posts.iter().map(value).collect::<StringArray>())
+    };
+    let dates = |value: fn(&Post) -> Option<DateTime>| -> ArrayRef {
+        let millis: TimestampMillisecondArray = posts.iter()
+            .map(|post| value(post).map(|date| date.timestamp_millis()))
+            .collect();
+        Arc::new(millis.with_timezone("UTC"))
+    };
+    let mut tags = ListBuilder::new(StringBuilder::new());
+    for post in posts {
+        tags.append_value(post.tags.iter().map(Some));
+    }
+    let ids: StringArray = posts.iter().map(|post| Some(post.id.to_hex())).collect();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(ids),
+        strings(|post| Some(&post.title)),
+        strings(|post| Some(&post.message)),
+        Arc::new(tags.finish()),
+        dates(|post| Some(post.created_at)),
+        Arc::new(posts.iter().map(|post| post.version).collect::<Int64Array>()),
+        strings(|post| Some(post.status.as_str())),
+        dates(|post| post.publish_at),
+        strings(|post| post.lang.as_deref()),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use mongodb::bson::doc;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::*;
+
+    fn encode<'r>(codec: &dyn RecordCodec, records: impl IntoIterator<Item = Record<'r>>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = codec.encoder(Box::new(&mut bytes)).unwrap();
+        for record in records {
+            encoder.encode(record).unwrap();
+        }
+        encoder.finish().unwrap();
+        bytes
+    }
+
+    #[test]
+    fn every_codec_reads_back_what_it_wrote() {
+        let post = &Post {
+            lang: Some("en".to_string()),
+            version: 3,
+            publish_at: Some(DateTime::from_millis(1_700_000_000_000)),
+            ..Post::new("Hello, \"world\"", "Line one\nline two", &["tag1", "tag2"])
+        };
+        let stored = &bson::to_document(post).unwrap();
+        for codec in [&JsonLinesCodec as &dyn RecordCodec, &BsonCodec, &MessagePackCodec, &CsvCodec] {
+            let bytes = encode(codec, [Record::Stored(stored), Record::Decoded(post)]);
+            assert_eq!(codec.count(Box::new(Cursor::new(bytes.clone()))).unwrap(), 2);
+            let read: Vec<Post> = codec.decoder(Box::new(Cursor::new(bytes))).unwrap()
+                .collect::<Result<_>>().unwrap();
+            for read in read {
+                assert_eq!((read.id, &read.title, &read.message), (post.id, &post.title, &post.message));
+                assert_eq!((&read.tags, read.created_at), (&post.tags, post.created_at));
+                assert_eq!((read.publish_at, read.version, &read.lang), (post.publish_at, 3, &post.lang));
+                assert_eq!(read.title_prefixes, post.title_prefixes);
+            }
+        }
+    }
+
+    #[test]
+    fn binary_codecs_keep_types_and_fields_posts_dont_have() {
+        let stored = &doc! {
+            "_id": ObjectId::new(),
+            "count": 3_i64,
+            "at": DateTime::from_millis(1_700_000_000_000),
+            "extra": { "scores": [1, 2.5] },
+        };
+        let bytes = encode(&BsonCodec, [Record::Stored(stored)]);
+        let read: Vec<Document> = documents(Box::new(Cursor::new(bytes)), |input| {
+            Ok(Document::from_reader(input)?)
+        }).collect::<Result<_>>().unwrap();
+        assert_eq!(read, std::slice::from_ref(stored));
+        let bytes = encode(&MessagePackCodec, [Record::Stored(stored)]);
+        let read: Vec<Document> = documents(Box::new(Cursor::new(bytes)), |input| Ok(rmp_serde::from_read(input)?))
+            .collect::<Result<_>>().unwrap();
+        let mut narrowed = stored.clone();
+        narrowed.insert("count", 3);
+        assert_eq!(read, [narrowed]);
+    }
+
+    #[test]
+    fn a_truncated_file_fails_at_its_broken_document() {
+        let post = &Post::new("Cut", "Short", &[]);
+        let mut bytes = encode(&BsonCodec, [Record::Decoded(post), Record::Decoded(post)]);
+        bytes.truncate(bytes.len() - 1);
+        let read: Vec<Result<Post>> = BsonCodec.decoder(Box::new(Cursor::new(bytes.clone()))).unwrap().collect();
+        assert!(read[0].is_ok());
+        assert!(read[1].as_ref().unwrap_err().to_string().starts_with("document 2: "), "{:?}", read[1]);
+        assert_eq!(read.len(), 2);
+        assert!(BsonCodec.count(Box::new(Cursor::new(bytes))).is_err());
+    }
+
+    #[test]
+    fn parquet_is_written_a_row_group_at_a_time() {
+        let posts: Vec<Post> = (0..PARQUET_BATCH + 1)
+            .map(|n| Post::new(&format!("Post {}", n), "", &[]))
+            .collect();
+        let bytes = encode(&ParquetCodec, posts.iter().map(Record::Decoded));
+        let path = std::env::temp_dir().join(format!("codec-{}.parquet", ObjectId::new()));
+        std::fs::write(&path, bytes).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!((reader.num_row_groups(), metadata.num_rows()), (2, PARQUET_BATCH as i64 + 1));
+        std::fs::remove_file(&path).unwrap();
+        assert!(ParquetCodec.decoder(Box::new(std::io::empty())).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arrow_array::{Array, ArrayRef, Float64Array, ListArray, RecordBatch, StringArray};
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_schema::{DataType, Field, Schema};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+
+use crate::ids::number;
+use crate::namespace::Namespace;
+use crate::transfer::cell;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Percentiles of the value field [`compare`] computes on both sides.
+pub const PERCENTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Counts and mean value for documents sharing a value of the group-by field.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Group {
+    /// As a CSV export would write it.
+    pub key: String,
+    pub count: u64,
+    /// Of the numeric values only; `None` when there are none.
+    pub mean: Option<f64>,
+}
+
+/// What one side computed, and how long it took.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// Most documents first.
+    pub groups: Vec<Group>,
+    /// One per [`PERCENTILES`].
+    pub percentiles: Vec<Option<f64>>,
+    /// Reading the documents into record batches; zero on the server side.
+    pub load: Duration,
+    /// Computing from the batches, or the server's aggregations round trip.
+    pub compute: Duration,
+}
+
+/// The same analysis computed client-side over Arrow record batches and
+/// server-side by aggregation.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub documents: u64,
+    pub batches: usize,
+    pub client: Analysis,
+    pub server: Analysis,
+}
+
+/// Two columns: `group`, the values of the group-by field as a list (arrays
+/// as they are, anything else a list of one, nothing when missing), and
+/// `value`, the value field when it is a number.
+fn schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new("group", DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))), false),
+        Field::new("value", DataType::Float64, true),
+    ]))
+}
+
+/// The value at the dotted `path` of `doc`.
+fn get<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (head, rest) = path.split_once('.').map_or((path, None), |(head, rest)| (head, Some(rest)));
+    match (doc.get(head)?, rest) {
+        (value, None) => Some(value),
+        (Bson::Document(doc), Some(rest)) => get(doc, rest),
+        _ => None,
+    }
+}
+
+fn batch(documents: &[Document], group_by: &str, value: &str) -> Result<RecordBatch> {
+    let mut groups = ListBuilder::new(StringBuilder::new());
+    for document in documents {
+        match get(document, group_by) {
+            Some(Bson::Array(keys)) => groups.append_value(keys.iter().map(|key| Some(cell(key)))),
+            Some(Bson::Null) | None => groups.append_value(std::iter::empty::<Option<String>>()),
+            Some(key) => groups.append_value([Some(cell(key))]),
+        }
+    }
+    let values: Float64Array = documents.iter()
+        .map(|document| match get(document, value) {
+            Some(Bson::Int32(n)) => Some(*n as f64),
+            Some(Bson::Int64(n)) => Some(*n as f64),
+            Some(Bson::Double(n)) => Some(*n),
+            _ => None,
+        })
+        .collect();
+    let columns: Vec<ArrayRef> = vec![Arc::new(groups.finish()), Arc::new(values)];
+    Ok(RecordBatch::try_new(schema(), columns)?)
+}
+
+/// Streams `collection`, only the two fields, into record batches of
+/// `batch_size` documents.
+pub async fn load(
+    ns: &Namespace,
+    collection: &str,
+    group_by: &str,
+    value: &str,
+    batch_size: usize,
+) -> Result<Vec<RecordBatch>> {
+    let options = FindOptions::builder()
+        .projection(doc! { "_id": 0, group_by: 1, value: 1 })
+        .batch_size(batch_size as u32)
+        .build();
+    let mut cursor = ns.collection::<Document>(collection).find(None, options).await?;
+    let mut batches = Vec::new();
+    let mut documents = Vec::with_capacity(batch_size);
+    while let Some(document) = cursor.try_next().await? {
+        documents.push(document);
+        if documents.len() == batch_size {
+            batches.push(batch(&documents, group_by, value)?);
+            documents.clear();
+        }
+    }
+    if !documents.is_empty() {
+        batches.push(batch(&documents, group_by, value)?);
+    }
+    Ok(batches)
+}
+
+fn columns(batch: &RecordBatch) -> (&ListArray, &Float64Array) {
+    let groups = batch.column(0).as_any().downcast_ref::<ListArray>().expect("`group` is a list");
+    let values = batch.column(1).as_any().downcast_ref::<Float64Array>().expect("`value` is a float");
+    (groups, values)
+}
+
+/// Count and mean value per group, a document counting once for every
+/// value of an array, and not at all without one, like `$unwind` does.
+pub fn group_by(batches: &[RecordBatch]) -> Vec<Group> {
+    let mut totals: HashMap<String, (u64, u64, f64)> = HashMap::new();
+    for batch in batches {
+        let (groups, values) = columns(batch);
+        for row in 0..batch.num_rows() {
+            let keys = groups.value(row);
+            let keys = keys.as_any().downcast_ref::<StringArray>().expect("`group` holds strings");
+            let value = values.is_valid(row).then(|| values.value(row));
+            for key in keys.iter().flatten() {
+                let (count, numbers, sum) = totals.entry(key.to_string()).or_default();
+                *count += 1;
+                if let Some(value) = value {
+                    *numbers += 1;
+                    *sum += value;
+                }
+            }
+        }
+    }
+    let groups = totals.into_iter()
+        .map(|(key, (count, numbers, sum))| Group { key, count, mean: (numbers > 0).then(|| sum / numbers as f64) })
+        .collect();
+    sorted(groups)
+}
+
+fn sorted(mut groups: Vec<Group>) -> Vec<Group> {
+    groups.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    groups
+}
+
+/// The `percentiles` of the numeric values, by nearest rank; each document
+/// counts once.
+pub fn percentiles(batches: &[RecordBatch], percentiles: &[f64]) -> Vec<Option<f64>> {
+    let mut values: Vec<f64> = batches.iter().flat_map(|batch| columns(batch).1.iter().flatten()).collect();
+    values.sort_by(f64::total_cmp);
+    percentiles.iter()
+        .map(|p| {
+            let rank = (p * values.len() as f64).ceil() as usize;
+            values.get(rank.saturating_sub(1)).copied()
+        })
+        .collect()
+}
+
+/// The same counts, means and [`PERCENTILES`] by aggregation, the latter
+/// with `$percentile`, so it needs MongoDB 7.0+. The server's percentiles
+/// are approximate, so they may differ slightly from the client's.
+async fn server_side(ns: &Namespace, collection: &str, group_by: &str, value: &str) -> Result<Analysis> {
+    let collection = ns.collection::<Document>(collection);
+    let started = Instant::now();
+    let field = format!("${}", group_by);
+    let pipeline = vec![
+        doc! { "$unwind": &field },
+        doc! { "$group": { "_id": &field, "count": { "$sum": 1 }, "mean": { "$avg": format!("${}", value) } } },
+    ];
+    let groups: Vec<Document> = collection.aggregate(pipeline, None).await?.try_collect().await?;
+    let pipeline = vec![doc! { "$group": { "_id": Bson::Null, "percentiles": { "$percentile": {
+        "input": format!("${}", value),
+        "p": PERCENTILES.to_vec(),
+        "method": "approximate",
+    }}}}];
+    let summary: Option<Document> = collection.aggregate(pipeline, None).await?.try_next().await?;
+    let compute = started.elapsed();
+    let groups = groups.iter()
+        .map(|group| Group {
+            key: group.get("_id").map(cell).unwrap_or_default(),
+            count: number(group.get("count")) as u64,
+            mean: group.get("mean").and_then(Bson::as_f64),
+        })
+        .collect();
+    let found = summary.as_ref().and_then(|summary| summary.get_array("percentiles").ok());
+    let percentiles = (0..PERCENTILES.len())
+        .map(|at| found.and_then(|found| found.get(at)).and_then(Bson::as_f64))
+        .collect();
+    Ok(Analysis { groups: sorted(groups), percentiles, load: Duration::ZERO, compute })
+}
+
+/// Groups `collection` by `group_by` with the mean of `value`, and takes
+/// percentiles of `value`, once client-side on record batches of
+/// `batch_size` documents and once server-side, timing both.
+pub async fn compare(
+    ns: &Namespace,
+    collection: &str,
+    group_by: &str,
+    value: &str,
+    batch_size: usize,
+) -> Result<Comparison> {
+    let started = Instant::now();
+    let batches = load(ns, collection, group_by, value, batch_size).await?;
+    let load = started.elapsed();
+    let started = Instant::now();
+    let client = Analysis {
+        groups: self::group_by(&batches),
+        percentiles: percentiles(&batches, &PERCENTILES),
+        load,
+        compute: started.elapsed(),
+    };
+    Ok(Comparison {
+        documents: batches.iter().map(|batch| batch.num_rows() as u64).sum(),
+        batches: batches.len(),
+        client,
+        server: server_side(ns, collection, group_by, value).await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_unwind_arrays_and_percentiles_count_documents_once() {
+        let documents = [
+            doc! { "tags": ["rust", "mongo"], "meta": { "version": 1 } },
+            doc! { "tags": ["rust"], "meta": { "version": 4.0 } },
+            doc! { "tags": "go", "meta": { "version": "n/a" } },
+            doc! { "meta": { "version": 7_i64 } },
+        ];
+        let batches = [
+            batch(&documents[..3], "tags", "meta.version").unwrap(),
+            batch(&documents[3..], "tags", "meta.version").unwrap(),
+        ];
+        assert_eq!(group_by(&batches), [
+            Group { key: "rust".to_string(), count: 2, mean: Some(2.5) },
+            Group { key: "go".to_string(), count: 1, mean: None },
+            Group { key: "mongo".to_string(), count: 1, mean: Some(1.0) },
+        ]);
+        assert_eq!(percentiles(&batches, &[0.0, 0.5, 1.0]), [Some(1.0), Some(4.0), Some(7.0)]);
+        assert_eq!(percentiles(&[], &[0.5]), [None]);
+    }
+}
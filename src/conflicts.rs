@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use mongodb::bson::{self, Document};
+
+use crate::Post;
+
+/// What a [`ConflictResolver`] decided a conflicting post should be.
+#[derive(Debug, Clone)]
+pub enum Resolution {
+    /// The post both the database and the offline store end up with, or
+    /// `None` for deleting it from both. The sync gives it a version past
+    /// both sides' before writing it.
+    Resolved(Option<Box<Post>>),
+    /// Leave it to be resolved by hand; it stays pending.
+    Pending,
+}
+
+/// Decides what to do with a post changed or deleted both offline and in the
+/// database since its last sync, in
+/// [`OfflineStore::sync`](crate::offline::OfflineStore::sync).
+///
+/// `synced` is the post as it was at its last sync, `None` if it never was;
+/// `offline` and `database` are what each side has now, `None` when deleted.
+pub trait ConflictResolver: Sync {
+    fn resolve(&self, synced: Option<&Post>, offline: Option<&Post>, database: Option<&Post>) -> Resolution;
+}
+
+/// Resolves nothing: every conflict is reported, for resolving by hand.
+pub struct Manual;
+
+impl ConflictResolver for Manual {
+    fn resolve(&self, _: Option<&Post>, _: Option<&Post>, _: Option<&Post>) -> Resolution {
+        Resolution::Pending
+    }
+}
+
+/// The offline side wins, deletes included. Posts carry no write time, so
+/// "last" is the sync itself: the offline change is the one being written
+/// now, after whatever the database got in the meantime.
+pub struct LastWriteWins;
+
+impl ConflictResolver for LastWriteWins {
+    fn resolve(&self, _: Option<&Post>, offline: Option<&Post>, _: Option<&Post>) -> Resolution {
+        Resolution::Resolved(offline.cloned().map(Box::new))
+    }
+}
+
+/// The database side wins, and the offline change is dropped.
+pub struct ServerWins;
+
+impl ConflictResolver for ServerWins {
+    fn resolve(&self, _: Option<&Post>, _: Option<&Post>, database: Option<&Post>) -> Resolution {
+        Resolution::Resolved(database.cloned().map(Box::new))
+    }
+}
+
+/// Fields are merged three ways against the synced post: each field takes the
+/// side that changed it. Fields both sides changed differently, deletes on
+/// either side, and posts never synced stay pending.
+pub struct FieldMerge;
+
+/// Not merged: `_id` is the same on every side, and the others are derived
+/// from the merged fields or set by the sync.
+const UNMERGED: [&str; 5] = ["_id", "version", "title_prefixes", "rendered_html", "message_hash"];
+
+impl ConflictResolver for FieldMerge {
+    fn resolve(&self, synced: Option<&Post>, offline: Option<&Post>, database: Option<&Post>) -> Resolution {
+        let (Some(synced), Some(offline), Some(database)) = (synced, offline, database) else {
+            return Resolution::Pending;
+        };
+        merge(synced, offline, database).map_or(Resolution::Pending, |post| Resolution::Resolved(Some(Box::new(post))))
+    }
+}
+
+/// `database` with the fields `offline` changed since `synced`, `None` if
+/// `database` changed one of them differently.
+fn merge(synced: &Post, offline: &Post, database: &Post) -> Option<Post> {
+    let synced = bson::to_document(synced).ok()?;
+    let offline = bson::to_document(offline).ok()?;
+    let mut merged = bson::to_document(database).ok()?;
+    let fields: BTreeSet<&String> = synced.keys().chain(offline.keys()).collect();
+    for field in fields.into_iter().filter(|field| !UNMERGED.contains(&field.as_str())) {
+        let before = synced.get(field);
+        let ours = offline.get(field);
+        if ours == before {
+            continue;
+        }
+        let theirs = merged.get(field);
+        if theirs != before && theirs != ours {
+            return None;
+        }
+        set(&mut merged, field, ours);
+    }
+    let mut post: Post = bson::from_document(merged).ok()?;
+    post.title_prefixes = Post::title_prefixes(&post.title);
+    Some(post.rendered().into_owned())
+}
+
+fn set(doc: &mut Document, field: &str, value: Option<&bson::Bson>) {
+    match value {
+        Some(value) => doc.insert(field, value.clone()),
+        None => doc.remove(field),
+    };
+}
+
+/// The resolvers by name, for `sync-up --resolve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    #[default]
+    Manual,
+    LastWriteWins,
+    ServerWins,
+    FieldMerge,
+}
+
+impl Strategy {
+    pub fn resolver(self) -> &'static dyn ConflictResolver {
+        match self {
+            Strategy::Manual => &Manual,
+            Strategy::LastWriteWins => &LastWriteWins,
+            Strategy::ServerWins => &ServerWins,
+            Strategy::FieldMerge => &FieldMerge,
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(Strategy::Manual),
+            "last-write-wins" => Ok(Strategy::LastWriteWins),
+            "server-wins" => Ok(Strategy::ServerWins),
+            "field-merge" => Ok(Strategy::FieldMerge),
+            _ => Err(format!("unknown strategy {:?}, try manual, last-write-wins, server-wins or field-merge", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sides() -> (Post, Post, Post) {
+        let synced = Post::new("Hello", "World", &["rust"]);
+        let mut offline = Post { version: 1, ..synced.clone() };
+        offline.title = "Hello offline".to_string();
+        let mut database = Post { version: 1, ..synced.clone() };
+        database.tags = vec!["rust".to_string(), "mongo".to_string()];
+        (synced, offline, database)
+    }
+
+    fn resolved(resolution: Resolution) -> Option<Post> {
+        match resolution {
+            Resolution::Resolved(post) => post.map(|post| *post),
+            Resolution::Pending => panic!("left pending"),
+        }
+    }
+
+    #[test]
+    fn manual_leaves_everything_pending() {
+        let (synced, offline, database) = sides();
+        assert!(matches!(Manual.resolve(Some(&synced), Some(&offline), Some(&database)), Resolution::Pending));
+        assert_eq!("manual".parse(), Ok(Strategy::default()));
+    }
+
+    #[test]
+    fn last_write_wins_takes_the_offline_side() {
+        let (synced, offline, database) = sides();
+        let post = resolved(LastWriteWins.resolve(Some(&synced), Some(&offline), Some(&database))).unwrap();
+        assert_eq!((post.title.as_str(), post.tags.len()), ("Hello offline", 1));
+        assert!(resolved(LastWriteWins.resolve(Some(&synced), None, Some(&database))).is_none());
+    }
+
+    #[test]
+    fn server_wins_takes_the_database_side() {
+        let (synced, offline, database) = sides();
+        let post = resolved(ServerWins.resolve(Some(&synced), Some(&offline), Some(&database))).unwrap();
+        assert_eq!((post.title.as_str(), post.tags.len()), ("Hello", 2));
+        assert!(resolved(ServerWins.resolve(Some(&synced), Some(&offline), None)).is_none());
+    }
+
+    #[test]
+    fn field_merge_takes_each_change_and_leaves_clashes_pending() {
+        let (synced, offline, mut database) = sides();
+        let post = resolved(FieldMerge.resolve(Some(&synced), Some(&offline), Some(&database))).unwrap();
+        assert_eq!((post.title.as_str(), post.tags.len()), ("Hello offline", 2));
+        assert!(post.title_prefixes.contains(&"offline".to_string()));
+
+        database.title = "Hello database".to_string();
+        assert!(matches!(FieldMerge.resolve(Some(&synced), Some(&offline), Some(&database)), Resolution::Pending));
+        assert!(matches!(FieldMerge.resolve(None, Some(&offline), Some(&offline)), Resolution::Pending));
+        assert!(matches!(FieldMerge.resolve(Some(&synced), None, Some(&database)), Resolution::Pending));
+    }
+}
@@ -0,0 +1,150 @@
+use mongodb::Collection;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{
+    Acknowledgment, CollectionOptions, ReadConcern, ReadPreference, ReadPreferenceOptions, SelectionCriteria,
+    SessionOptions, WriteConcern,
+};
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+
+/// Scratch collection the probes write to; dropped afterwards.
+pub const CONSISTENCY_PROBE: &str = "consistency_probe";
+
+/// One way of writing a document and reading it straight back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Combination {
+    /// `w: majority` rather than `w: 1`.
+    pub majority_write: bool,
+    /// `secondaryPreferred` rather than `primary`.
+    pub secondary_read: bool,
+    /// Read concern `majority` rather than `local`.
+    pub majority_read: bool,
+    /// Write and read in one causally consistent session.
+    pub causal: bool,
+}
+
+impl Combination {
+    /// From the cheapest to the one that always reads its own writes, even
+    /// from a lagging secondary.
+    pub const ALL: [Combination; 6] = [
+        Combination { majority_write: false, secondary_read: false, majority_read: false, causal: false },
+        Combination { majority_write: false, secondary_read: true, majority_read: false, causal: false },
+        Combination { majority_write: true, secondary_read: true, majority_read: false, causal: false },
+        Combination { majority_write: true, secondary_read: true, majority_read: true, causal: false },
+        Combination { majority_write: false, secondary_read: true, majority_read: false, causal: true },
+        Combination { majority_write: true, secondary_read: true, majority_read: true, causal: true },
+    ];
+
+    /// Whether MongoDB promises this reads what it just wrote: a primary has
+    /// applied a write before acknowledging it, and a causally consistent
+    /// session makes a secondary wait until it has caught up, provided both
+    /// sides use `majority`.
+    pub fn guaranteed(&self) -> bool {
+        !self.secondary_read || (self.causal && self.majority_write && self.majority_read)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "w:{} {} rc:{}{}",
+            if self.majority_write { "majority" } else { "1" },
+            if self.secondary_read { "secondaryPreferred" } else { "primary" },
+            if self.majority_read { "majority" } else { "local" },
+            if self.causal { " causal" } else { "" },
+        )
+    }
+
+    fn collection(&self, ns: &Namespace) -> Collection<Document> {
+        let w = if self.majority_write { Acknowledgment::Majority } else { Acknowledgment::Nodes(1) };
+        let read_preference = if self.secondary_read {
+            ReadPreference::SecondaryPreferred { options: ReadPreferenceOptions::default() }
+        } else {
+            ReadPreference::Primary
+        };
+        let options = CollectionOptions::builder()
+            .write_concern(WriteConcern::builder().w(w).build())
+            .selection_criteria(SelectionCriteria::ReadPreference(read_preference))
+            .read_concern(if self.majority_read { ReadConcern::majority() } else { ReadConcern::local() })
+            .build();
+        ns.collection_with_options(CONSISTENCY_PROBE, options)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ConsistencyReport {
+    pub combination: String,
+    pub guaranteed: bool,
+    pub rounds: usize,
+    /// Reads that didn't find the document just written.
+    pub stale: usize,
+}
+
+/// Writes `rounds` documents per combination and reads each one back right
+/// away, counting the reads that missed it. Without secondaries every read
+/// goes to the primary and nothing is stale; on a replica set the cheap
+/// combinations show how often a lagging secondary answers.
+pub async fn read_after_write(ns: &Namespace, rounds: usize) -> Result<Vec<ConsistencyReport>> {
+    let mut reports = Vec::new();
+    for combination in Combination::ALL {
+        let col = combination.collection(ns);
+        let mut session = match combination.causal {
+            true => {
+                let options = SessionOptions::builder().causal_consistency(true).build();
+                Some(col.client().start_session(options).await?)
+            }
+            false => None,
+        };
+        let mut stale = 0;
+        for round in 0..rounds {
+            let id = ObjectId::new();
+            let probe = doc! { "_id": id, "round": round as i64 };
+            let found = match &mut session {
+                Some(session) => {
+                    col.insert_one_with_session(probe, None, session).await?;
+                    col.find_one_with_session(doc! { "_id": id }, None, session).await?
+                }
+                None => {
+                    col.insert_one(probe, None).await?;
+                    col.find_one(doc! { "_id": id }, None).await?
+                }
+            };
+            if found.is_none() {
+                stale += 1;
+            }
+        }
+        reports.push(ConsistencyReport {
+            combination: combination.describe(),
+            guaranteed: combination.guaranteed(),
+            rounds,
+            stale,
+        });
+    }
+    ns.collection::<Document>(CONSISTENCY_PROBE).drop(None).await?;
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    /// Point it at a replica set with secondaries to see the other combinations go stale.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn guaranteed_combinations_read_their_writes() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        for report in read_after_write(&ns, 50).await.unwrap() {
+            println!("{:<45} stale {}/{}", report.combination, report.stale, report.rounds);
+            if report.guaranteed {
+                assert_eq!(report.stale, 0, "{}", report.combination);
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::str::FromStr;
+
+use futures::future::{BoxFuture, FutureExt};
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+
+use crate::Post;
+use crate::config::AppConfig;
+use crate::error::{Error, Result};
+use crate::ids::number;
+use crate::namespace::Namespace;
+use crate::repository::{PostStore, UpdateSummary};
+
+/// The data source when `data_api_source` isn't set, Atlas's default name
+/// for a project's first cluster.
+const DEFAULT_DATA_SOURCE: &str = "mongodb-atlas";
+
+/// What [`PostStore`] calls go through.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The driver, over a connection to the cluster.
+    Driver,
+    /// HTTPS calls to a Data API endpoint, for environments that can't
+    /// keep connections open, like serverless functions.
+    DataApi,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "driver" => Ok(Backend::Driver),
+            "data-api" => Ok(Backend::DataApi),
+            _ => Err(format!("unknown backend {:?}, try driver or data-api", s)),
+        }
+    }
+}
+
+/// `posts` over the MongoDB Atlas Data API: each operation is one HTTPS
+/// `POST` to `<url>/action/<action>` with an `api-key` header and the
+/// request and response bodies in canonical Extended JSON.
+///
+/// Atlas no longer hosts the Data API itself (it was retired in 2025), but
+/// self-hosted replacements serve the same protocol. There are no sessions,
+/// retries, deadlines or comments here; an answer other than a success
+/// comes back as [`Error::DataApi`].
+pub struct DataApiRepository {
+    http: reqwest::Client,
+    url: String,
+    api_key: String,
+    data_source: String,
+    database: String,
+    collection: String,
+}
+
+impl DataApiRepository {
+    /// `None` unless `config` has a Data API URL and key. Posts are those of
+    /// `ns`'s database and prefix.
+    pub fn new(config: &AppConfig, ns: &Namespace) -> Option<Self> {
+        Some(DataApiRepository {
+            http: reqwest::Client::new(),
+            url: config.data_api_url.clone()?.trim_end_matches('/').to_string(),
+            api_key: config.data_api_key.clone()?,
+            data_source: config.data_api_source.clone().unwrap_or_else(|| DEFAULT_DATA_SOURCE.to_string()),
+            database: ns.db().name().to_string(),
+            collection: ns.name("posts"),
+        })
+    }
+
+    /// Runs `action` with `body` on the collection and returns the answer.
+    async fn call(&self, action: &str, mut body: Document) -> Result<Document> {
+        body.insert("dataSource", &self.data_source);
+        body.insert("database", &self.database);
+        body.insert("collection", &self.collection);
+        let response = self.http.post(format!("{}/action/{}", self.url, action))
+            .header("api-key", &self.api_key)
+            .header("content-type", "application/ejson")
+            .header("accept", "application/ejson")
+            .body(Bson::Document(body).into_canonical_extjson().to_string())
+            .send().await
+            .map_err(|e| Error::DataApi(format!("{} failed: {}", action, e)))?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| Error::DataApi(format!("{} failed: {}", action, e)))?;
+        if !status.is_success() {
+            return Err(Error::DataApi(error_message(action, status.as_u16(), &text)));
+        }
+        let value: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| Error::DataApi(format!("{} answered with invalid JSON: {}", action, e)))?;
+        match Bson::try_from(value) {
+            Ok(Bson::Document(answer)) => Ok(answer),
+            _ => Err(Error::DataApi(format!("{} answered with something other than an object", action))),
+        }
+    }
+
+    pub async fn insert(&self, post: &Post) -> Result<ObjectId> {
+        let post = post.rendered();
+        let answer = self.call("insertOne", doc! { "document": bson::to_document(&*post)? }).await;
+        match answer {
+            Ok(answer) => Ok(answer.get_object_id("insertedId").unwrap_or(post.id)),
+            // The unique title index rejected it
+            Err(Error::DataApi(message)) if message.contains("E11000") => {
+                Err(Error::DuplicateTitle(post.title.clone()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn find_by_id(&self, id: ObjectId) -> Result<Post> {
+        let answer = self.call("findOne", doc! { "filter": { "_id": id } }).await?;
+        match answer.get("document") {
+            Some(Bson::Document(post)) => decode(post.clone()),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    pub async fn find_by_tag(&self, tag: &str) -> Result<Vec<Post>> {
+        let answer = self.call("find", doc! { "filter": { "tags": tag } }).await?;
+        let posts = answer.get_array("documents")
+            .map_err(|_| Error::DataApi("find answered without documents".into()))?;
+        let mut decoded = Vec::with_capacity(posts.len());
+        for post in posts {
+            let Bson::Document(post) = post else {
+                return Err(Error::DataApi("find answered with a document that isn't an object".into()));
+            };
+            decoded.push(decode(post.clone())?);
+        }
+        Ok(decoded)
+    }
+
+    pub async fn update_title_by_tag(&self, tag: &str, title: &str) -> Result<UpdateSummary> {
+        let update = doc! {
+            "$set": { "title": title, "title_prefixes": Post::title_prefixes(title) },
+            "$inc": { "version": 1 },
+        };
+        let answer = self.call("updateMany", doc! { "filter": { "tags": tag }, "update": update }).await?;
+        Ok(UpdateSummary {
+            matched: count(&answer, "matchedCount"),
+            modified: count(&answer, "modifiedCount"),
+            upserted_id: answer.get("upsertedId").cloned(),
+        })
+    }
+
+    pub async fn delete_by_tag(&self, tag: &str) -> Result<u64> {
+        let answer = self.call("deleteMany", doc! { "filter": { "tags": tag } }).await?;
+        Ok(count(&answer, "deletedCount"))
+    }
+
+    pub async fn delete_by_id(&self, id: ObjectId) -> Result<()> {
+        let answer = self.call("deleteOne", doc! { "filter": { "_id": id } }).await?;
+        match count(&answer, "deletedCount") {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PostStore for DataApiRepository {
+    fn insert<'a>(&'a self, post: &'a Post) -> BoxFuture<'a, Result<ObjectId>> {
+        DataApiRepository::insert(self, post).boxed()
+    }
+
+    fn find_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<Post>> {
+        DataApiRepository::find_by_id(self, id).boxed()
+    }
+
+    fn find_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<Vec<Post>>> {
+        DataApiRepository::find_by_tag(self, tag).boxed()
+    }
+
+    fn update_title_by_tag<'a>(&'a self, tag: &'a str, title: &'a str) -> BoxFuture<'a, Result<UpdateSummary>> {
+        DataApiRepository::update_title_by_tag(self, tag, title).boxed()
+    }
+
+    fn delete_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<u64>> {
+        DataApiRepository::delete_by_tag(self, tag).boxed()
+    }
+
+    fn delete_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<()>> {
+        DataApiRepository::delete_by_id(self, id).boxed()
+    }
+}
+
+#[allow(clippy::result_large_err)] // same `Result` as every other method here
+fn decode(post: Document) -> Result<Post> {
+    let id = post.get("_id").cloned();
+    let deserializer = bson::Deserializer::new(Bson::Document(post));
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| Error::Decode { id, path: e.path().to_string(), message: e.inner().to_string() })
+}
+
+fn count(answer: &Document, field: &str) -> u64 {
+    number(answer.get(field)).max(0) as u64
+}
+
+/// The `error` of a failed call's body, `{ "error": ..., "error_code": ... }`,
+/// or the body itself when it isn't one.
+fn error_message(action: &str, status: u16, body: &str) -> String {
+    let error = serde_json::from_str::<serde_json::Value>(body).ok()
+        .and_then(|body| body.get("error").and_then(|error| error.as_str()).map(str::to_string));
+    format!("{} answered {}: {}", action, status, error.as_deref().unwrap_or(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_read_from_the_body() {
+        let body = r#"{"error":"E11000 duplicate key error","error_code":"DuplicateKey"}"#;
+        assert_eq!(error_message("insertOne", 400, body), "insertOne answered 400: E11000 duplicate key error");
+        assert_eq!(error_message("find", 502, "Bad Gateway"), "find answered 502: Bad Gateway");
+        assert_eq!("data-api".parse(), Ok(Backend::DataApi));
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+/// A point in time by which a whole sequence of operations has to finish.
+///
+/// Handed to [`crate::repository::PostRepository::with_deadline`], every call
+/// made through that handle gets whatever is left of the budget as its
+/// `maxTimeMS`, so a request doing several calls in a row can't take longer
+/// than its budget in total.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Self {
+        Deadline { at: Instant::now() + budget }
+    }
+
+    /// Time left, or `None` once the deadline has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_counts_down_to_none() {
+        let left = Deadline::after(Duration::from_secs(60)).remaining().unwrap();
+        assert!(left <= Duration::from_secs(60) && left > Duration::from_secs(59));
+        assert_eq!(Deadline::after(Duration::ZERO).remaining(), None);
+        assert_eq!(Deadline { at: Instant::now() - Duration::from_secs(1) }.remaining(), None);
+    }
+}
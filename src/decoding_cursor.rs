@@ -0,0 +1,104 @@
+use std::marker::PhantomData;
+
+use futures::{Stream, TryStreamExt};
+use mongodb::bson::{self, Bson, Document};
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+
+/// A cursor of `T`s that survives documents which don't decode into one.
+/// The driver's typed cursor fails the whole stream at the first of them,
+/// and its error names neither the document nor the field; this one reads
+/// documents untyped and decodes them itself, so a bad one is logged by
+/// `_id` and field and skipped, and the stream goes on.
+///
+/// Up to `budget` documents are skipped that way. The one after that fails
+/// the stream with its [`Error::Decode`]: a few legacy posts are to be
+/// expected, but a whole collection not decoding is a bug to hear about,
+/// not to log past. A budget of zero makes the cursor strict again, while
+/// still saying which document broke it.
+pub struct DecodingCursor<T, S = mongodb::Cursor<Document>> {
+    documents: S,
+    budget: usize,
+    skipped: Vec<Bson>,
+    decoded: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned, S: Stream<Item = mongodb::error::Result<Document>> + Unpin> DecodingCursor<T, S> {
+    /// `documents` is usually a cursor of the collection as [`Document`]s,
+    /// e.g. from `collection.clone_with_type::<Document>().find(...)`.
+    pub fn new(documents: S, budget: usize) -> Self {
+        DecodingCursor { documents, budget, skipped: Vec::new(), decoded: PhantomData }
+    }
+
+    /// The next document that decodes, `None` at the end.
+    pub async fn next(&mut self) -> Result<Option<T>> {
+        while let Some(document) = self.documents.try_next().await? {
+            match decode(document) {
+                Ok(item) => return Ok(Some(item)),
+                Err(Error::Decode { id, path, message }) if self.skipped.len() < self.budget => {
+                    let id = id.unwrap_or(Bson::Null);
+                    tracing::warn!(document = %id, path, "skipping a document that doesn't decode: {}", message);
+                    self.skipped.push(id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// The `_id`s of the documents skipped so far, `null` for any without.
+    pub fn skipped(&self) -> &[Bson] {
+        &self.skipped
+    }
+}
+
+/// `document` as a `T`, or [`Error::Decode`] naming it and the field.
+#[allow(clippy::result_large_err)] // same `Result` as the method calling it
+fn decode<T: DeserializeOwned>(document: Document) -> Result<T> {
+    let id = document.get("_id").cloned();
+    let deserializer = bson::Deserializer::new(Bson::Document(document));
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| Error::Decode { id, path: e.path().to_string(), message: e.inner().to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Counted {
+        count: i32,
+    }
+
+    fn documents(documents: Vec<Document>) -> impl Stream<Item = mongodb::error::Result<Document>> + Unpin {
+        futures::stream::iter(documents.into_iter().map(Ok))
+    }
+
+    #[tokio::test]
+    async fn bad_documents_are_skipped_until_the_budget_runs_out() {
+        let stored = vec![
+            doc! { "_id": 1, "count": 1 },
+            doc! { "_id": 2, "count": "two" },
+            doc! { "_id": 3, "count": 3 },
+            doc! { "_id": 4 },
+        ];
+        let mut cursor = DecodingCursor::<Counted, _>::new(documents(stored.clone()), 2);
+        let mut decoded = Vec::new();
+        while let Some(item) = cursor.next().await.unwrap() {
+            decoded.push(item.count);
+        }
+        assert_eq!(decoded, [1, 3]);
+        assert_eq!(cursor.skipped(), [Bson::Int32(2), Bson::Int32(4)]);
+
+        let mut cursor = DecodingCursor::<Counted, _>::new(documents(stored), 1);
+        assert_eq!(cursor.next().await.unwrap(), Some(Counted { count: 1 }));
+        assert_eq!(cursor.next().await.unwrap(), Some(Counted { count: 3 }));
+        match cursor.next().await {
+            Err(Error::Decode { id, path, .. }) => assert_eq!((id, path.as_str()), (Some(Bson::Int32(4)), ".")),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+}
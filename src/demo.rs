@@ -0,0 +1,1984 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::TimeZone;
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{Client, Collection};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{ClientOptions, ReplaceOptions, UpdateOptions};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::capabilities::{Capabilities, Feature, Topology};
+use crate::error::Error;
+use crate::namespace::Namespace;
+use crate::repository::{self, PostRepository};
+use crate::{analytics, attachments, deadline, events, feeds, ids, migrations, partition, projection, query_cache};
+use crate::{related, saga, scheduler, sharding, transactions, unit_of_work, views, watcher};
+use crate::{LocalizedContent, Post, PostStatus};
+
+/// Everything the steps share: connections, the prepared `posts` collection
+/// and what the server turned out to support.
+pub struct Demo {
+    client: Client,
+    ns: Namespace,
+    caps: Capabilities,
+    col: Collection<Post>,
+    repo: PostRepository,
+    /// Reports run on their own client, away from application traffic.
+    analytics: PostRepository,
+    /// Of `client`, see [`app_name`].
+    app_name: Option<String>,
+    cleanups: Cleanups,
+}
+
+impl Demo {
+    /// Probes the server and migrates `posts` to its current validator and indexes.
+    pub async fn new(client: &Client, client_options: &ClientOptions, ns: &Namespace) -> Result<Self, Error> {
+        let caps = Capabilities::probe(client).await?;
+        println!("connected to MongoDB {} ({:?})", caps.version, caps.topology);
+        migrations::posts().run(ns).await?;
+        let analytics_client = analytics::connect(client_options)?;
+        Ok(Demo {
+            client: client.clone(),
+            ns: ns.clone(),
+            caps,
+            col: ns.collection("posts"),
+            // Every repository operation's comment carries `ctx: "demo"`
+            repo: PostRepository::new(ns).with_context("demo"),
+            analytics: analytics::repository(&analytics_client, ns),
+            app_name: client_options.app_name.clone(),
+            cleanups: Cleanups::default(),
+        })
+    }
+
+    /// Where steps, and whoever runs the demo, register what has to be
+    /// undone however the demo ends.
+    pub fn cleanups(&self) -> &Cleanups {
+        &self.cleanups
+    }
+
+    /// Registers killing the sessions `client` leaves open, when it has an
+    /// app name of its own to find them by.
+    fn abort_sessions_on_cleanup(&self) {
+        let (Some(app_name), client) = (self.app_name.clone(), self.client.clone()) else {
+            return;
+        };
+        self.cleanups.register("abort sessions", async move {
+            match kill_sessions(&client, &app_name).await {
+                Ok(0) => {}
+                Ok(killed) => println!("aborted {} session(s) left open", killed),
+                Err(e) => eprintln!("unable to abort sessions left open: {}", e),
+            }
+        });
+    }
+
+    async fn delete_tagged(&self, tags: &[&str]) {
+        self.col.delete_many(doc! { "tags": { "$in": tags } }, None).await.expect("Unable to clean up posts");
+    }
+
+    async fn find_tagged(&self, tag: &str) -> Vec<Post> {
+        self.col.find(doc! { "tags": tag }, None).await
+            .expect("Unable to get Cursor")
+            .try_collect().await
+            .expect("Unable to collect items from Cursor")
+    }
+
+    async fn find_titled(&self, title: &str) -> Post {
+        self.repo.find_by_title(title).await
+            .expect("Unable to find post")
+            .unwrap_or_else(|| panic!("{:?} is missing", title))
+    }
+}
+
+/// An app name of its own for a demo run's client, so that sessions it
+/// leaves open can be told apart from everyone else's and aborted.
+pub fn app_name() -> String {
+    format!("{}-demo-{}", repository::APP_NAME, ObjectId::new().to_hex())
+}
+
+/// Kills the sessions of the client named `app_name`, and with them any
+/// transaction they still hold open, which would otherwise keep its locks
+/// until `transactionLifetimeLimitSeconds` and fail the next run's writes
+/// with write conflicts. Returns how many there were.
+async fn kill_sessions(client: &Client, app_name: &str) -> mongodb::error::Result<usize> {
+    let admin = client.database("admin");
+    let pipeline = vec![
+        doc! { "$currentOp": { "idleSessions": true } },
+        doc! { "$match": { "appName": app_name, "lsid.id": { "$exists": true } } },
+        doc! { "$project": { "lsid.id": 1 } },
+    ];
+    let sessions: Vec<Document> = admin.aggregate(pipeline, None).await?.try_collect().await?;
+    let lsids: Vec<Document> = sessions.iter().filter_map(|op| op.get_document("lsid").ok().cloned()).collect();
+    if !lsids.is_empty() {
+        admin.run_command(doc! { "killSessions": lsids.clone() }, None).await?;
+    }
+    Ok(lsids.len())
+}
+
+/// Actions that undo what a demo run set up beyond its steps' own
+/// teardowns, such as stopping workers a step spawned, run newest first
+/// whether the run passed, failed or was interrupted with Ctrl-C. Those a
+/// step registers run as soon as it ends, before its teardown removes what
+/// they might still write to; the others once the run is over. Cheap to
+/// clone: clones share the actions.
+#[derive(Clone, Default)]
+pub struct Cleanups {
+    actions: Arc<Mutex<Vec<Cleanup>>>,
+}
+
+/// A registered action and the name it is reported by.
+type Cleanup = (String, BoxFuture<'static, ()>);
+
+impl Cleanups {
+    pub fn register(&self, name: impl Into<String>, action: impl Future<Output = ()> + Send + 'static) {
+        self.actions.lock().unwrap().push((name.into(), action.boxed()));
+    }
+
+    /// Registers aborting `worker`: a task spawned by a step outlives the
+    /// step's future, so a step that panics or is interrupted before it
+    /// stops its workers itself would leave them running.
+    pub fn abort<T>(&self, name: &str, worker: &JoinHandle<T>) {
+        let worker = worker.abort_handle();
+        self.register(format!("stop {}", name), async move { worker.abort() });
+    }
+
+    fn depth(&self) -> usize {
+        self.actions.lock().unwrap().len()
+    }
+
+    /// Runs, newest first, every action registered since there were `depth`.
+    /// One that panics is reported and doesn't stop the others.
+    async fn unwind(&self, depth: usize) {
+        loop {
+            let action = {
+                let mut actions = self.actions.lock().unwrap();
+                if actions.len() <= depth {
+                    break;
+                }
+                actions.pop()
+            };
+            if let Some((name, action)) = action {
+                if AssertUnwindSafe(action).catch_unwind().await.is_err() {
+                    eprintln!("cleanup {:?} failed", name);
+                }
+            }
+        }
+    }
+}
+
+type Action = for<'a> fn(&'a Demo) -> BoxFuture<'a, ()>;
+
+/// A named part of the demo. `setup` seeds what `run` needs and `teardown`
+/// removes it again, so every step can run on its own or in any order.
+pub struct Step {
+    pub name: &'static str,
+    pub description: &'static str,
+    setup: Action,
+    run: Action,
+    teardown: Action,
+}
+
+/// All steps, in the order `demo run` runs them without `--steps`.
+pub const STEPS: &[Step] = &[
+    Step {
+        name: "insert",
+        description: "insert posts in bulk",
+        setup: nothing,
+        run: insert,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "find",
+        description: "find posts by tag",
+        setup: seed_samples,
+        run: find,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "update",
+        description: "update the title of posts with a tag",
+        setup: seed_samples,
+        run: update,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "delete",
+        description: "delete posts with a tag",
+        setup: seed_samples,
+        run: delete,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "aggregate",
+        description: "group post ids by tag",
+        setup: seed_samples,
+        run: aggregate,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "transaction",
+        description: "insert a post and its tag counts in one transaction",
+        setup: nothing,
+        run: transaction,
+        teardown: remove_transactional,
+    },
+    Step {
+        name: "unit-of-work",
+        description: "replace a post and keep tag counts in sync",
+        setup: seed_transactional,
+        run: unit_of_work,
+        teardown: remove_transactional,
+    },
+    Step {
+        name: "projection",
+        description: "rebuild the CQRS read model, then follow the change stream",
+        setup: seed_samples,
+        run: projection,
+        teardown: remove_projection,
+    },
+    Step {
+        name: "watcher",
+        description: "keep posts_by_tag live from the change stream, resuming after a restart",
+        setup: nothing,
+        run: watcher,
+        teardown: remove_watched,
+    },
+    Step {
+        name: "events",
+        description: "rebuild an event-sourced post by replaying its events",
+        setup: nothing,
+        run: event_sourcing,
+        teardown: nothing,
+    },
+    Step {
+        name: "saga",
+        description: "publish posts through a saga, compensating on failure",
+        setup: nothing,
+        run: saga,
+        teardown: remove_saga,
+    },
+    Step {
+        name: "scheduler",
+        description: "publish reviewed posts once they are due",
+        setup: seed_scheduled,
+        run: scheduler,
+        teardown: remove_scheduled,
+    },
+    Step {
+        name: "lifecycle",
+        description: "move a post through its states",
+        setup: seed_lifecycle,
+        run: lifecycle,
+        teardown: remove_lifecycle,
+    },
+    Step {
+        name: "i18n",
+        description: "read and search posts in another language",
+        setup: seed_i18n,
+        run: i18n,
+        teardown: remove_i18n,
+    },
+    Step {
+        name: "duplicate-titles",
+        description: "reject titles that only differ in case",
+        setup: seed_samples,
+        run: duplicate_titles,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "timezone",
+        description: "bucket posts by the reader's local day",
+        setup: seed_late_night,
+        run: timezone,
+        teardown: remove_late_night,
+    },
+    Step {
+        name: "date-range",
+        description: "find and count posts in a date range",
+        setup: seed_late_night,
+        run: date_range,
+        teardown: remove_late_night,
+    },
+    Step {
+        name: "partitions",
+        description: "write posts into monthly collections and read across them",
+        setup: nothing,
+        run: partitions,
+        teardown: remove_partitions,
+    },
+    Step {
+        name: "search",
+        description: "faceted search, as served by `GET /posts/search`",
+        setup: seed_samples,
+        run: search,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "suggest",
+        description: "title suggestions, as served by `GET /posts/suggest`",
+        setup: seed_samples,
+        run: suggest,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "relevance",
+        description: "rank text matches by the index weights",
+        setup: seed_relevance,
+        run: relevance,
+        teardown: remove_relevance,
+    },
+    Step {
+        name: "legacy",
+        description: "keep unknown fields through a read-modify-write",
+        setup: seed_legacy,
+        run: legacy,
+        teardown: remove_legacy,
+    },
+    Step {
+        name: "decode-error",
+        description: "report which document and field failed to decode",
+        setup: seed_broken,
+        run: decode_error,
+        teardown: remove_broken,
+    },
+    Step {
+        name: "patch",
+        description: "write only the provided fields",
+        setup: seed_patch,
+        run: patch,
+        teardown: remove_patch,
+    },
+    Step {
+        name: "batch-get",
+        description: "fetch several posts by id, in the order asked for",
+        setup: seed_samples,
+        run: batch_get,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "summaries",
+        description: "lean list views with id, title and tags",
+        setup: seed_samples,
+        run: summaries,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "pagination",
+        description: "page through tag1 two posts at a time with an _id cursor",
+        setup: seed_samples,
+        run: pagination,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "ulid-pages",
+        description: "page by time over ULID _ids, as strings and as binary",
+        setup: nothing,
+        run: ulid_pages,
+        teardown: remove_ulid_posts,
+    },
+    Step {
+        name: "title-sort",
+        description: "list titles with locale and numeric collations",
+        setup: seed_titles,
+        run: title_sort,
+        teardown: remove_titles,
+    },
+    Step {
+        name: "replace",
+        description: "replace a whole post, guarded by its version",
+        setup: seed_replace,
+        run: replace,
+        teardown: remove_replace,
+    },
+    Step {
+        name: "archive",
+        description: "move a post into `posts_archive`",
+        setup: seed_archive,
+        run: archive,
+        teardown: remove_archive,
+    },
+    Step {
+        name: "with-archive",
+        description: "query `posts` and `posts_archive` together",
+        setup: seed_with_archive,
+        run: with_archive,
+        teardown: remove_with_archive,
+    },
+    Step {
+        name: "import",
+        description: "ordered and unordered imports with bad documents",
+        setup: seed_samples,
+        run: import,
+        teardown: remove_import,
+    },
+    Step {
+        name: "sync",
+        description: "make the collection match a list of posts in two commands",
+        setup: seed_samples,
+        run: sync,
+        teardown: remove_sync,
+    },
+    Step {
+        name: "deadlines",
+        description: "bound a series of operations by one time budget",
+        setup: seed_samples,
+        run: deadlines,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "query-cache",
+        description: "answer a repeated search from `query_cache`",
+        setup: seed_samples,
+        run: query_cache,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "related-tags",
+        description: "\"users also tagged\", precomputed with `$merge`",
+        setup: seed_samples,
+        run: related_tags,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "similar",
+        description: "rank posts by how many tags they share, and draw some by tag frequency",
+        setup: seed_samples,
+        run: similar,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "view-buckets",
+        description: "views bucketed per post and hour, counted from the buckets and unrolled for reports",
+        setup: seed_samples,
+        run: view_buckets,
+        teardown: remove_views,
+    },
+    Step {
+        name: "activity-feed",
+        description: "keep each user's last events in one document with $push and $slice",
+        setup: seed_samples,
+        run: activity_feed,
+        teardown: remove_feeds,
+    },
+    Step {
+        name: "lost-updates",
+        description: "race tasks incrementing one counter, read-modify-write against $inc",
+        setup: nothing,
+        run: lost_updates,
+        teardown: remove_counter,
+    },
+    Step {
+        name: "txn-contention",
+        description: "race transactions over one counter and count the retries",
+        setup: nothing,
+        run: txn_contention,
+        teardown: remove_counter,
+    },
+    Step {
+        name: "preallocated-counters",
+        description: "count views per hour in daily documents made ahead of time, against upserting them",
+        setup: seed_samples,
+        run: preallocated_counters,
+        teardown: remove_daily_counters,
+    },
+    Step {
+        name: "attachments",
+        description: "store a file in GridFS, list it on its post and read it back",
+        setup: seed_samples,
+        run: attachments,
+        teardown: remove_samples,
+    },
+    Step {
+        name: "shard-key",
+        description: "query by author through the shard key and spot scatter-gather plans",
+        setup: nothing,
+        run: shard_key,
+        teardown: remove_authored_posts,
+    },
+    Step {
+        name: "tag-merge",
+        description: "merge two tags into one and split it again, keeping tag_counts right",
+        setup: nothing,
+        run: tag_merge,
+        teardown: remove_merged_tags,
+    },
+    Step {
+        name: "comments",
+        description: "comment on posts stored apart from them and join them back with $lookup",
+        setup: seed_samples,
+        run: comments,
+        teardown: remove_comments,
+    },
+    Step {
+        name: "viral-post",
+        description: "embed a post's first comment ids and spill the rest to an overflow collection",
+        setup: seed_samples,
+        run: viral_post,
+        teardown: remove_comments,
+    },
+    Step {
+        name: "quota",
+        description: "cap the posts per author with a guarded upsert in a transaction",
+        setup: nothing,
+        run: quota,
+        teardown: remove_quota_posts,
+    },
+];
+
+/// How long each phase of a step took.
+#[derive(Debug)]
+pub struct StepReport {
+    pub name: &'static str,
+    pub setup: Duration,
+    pub run: Duration,
+    pub teardown: Duration,
+    pub passed: bool,
+    /// Ctrl-C stopped the step before it was done; it was torn down still.
+    pub interrupted: bool,
+}
+
+/// Looks up the steps named by `--steps`; all steps when there are none.
+pub fn select(names: &[String]) -> Result<Vec<&'static Step>, String> {
+    if names.is_empty() {
+        return Ok(STEPS.iter().collect());
+    }
+    names.iter()
+        .map(|name| {
+            STEPS.iter().find(|step| step.name == name).ok_or_else(|| {
+                let known: Vec<&str> = STEPS.iter().map(|step| step.name).collect();
+                format!("unknown demo step {:?}, expected one of: {}", name, known.join(", "))
+            })
+        })
+        .collect()
+}
+
+/// Runs the steps one after another, then the demo's [`Cleanups`]. A step
+/// that panics is reported as failed, still torn down, and doesn't stop the
+/// steps after it.
+///
+/// Ctrl-C stops the step running, which is torn down and cleaned up after
+/// as if it had failed, and skips the steps after it; a second Ctrl-C,
+/// while that cleanup runs, quits right away.
+pub async fn run(demo: &Demo, steps: &[&Step]) -> Vec<StepReport> {
+    let interrupt = CancellationToken::new();
+    let listener = tokio::spawn(interrupt_on_ctrl_c(interrupt.clone()));
+    // Newer than what was registered before the run, so it runs first:
+    // dropping a sandbox database would wait on open transactions' locks
+    demo.abort_sessions_on_cleanup();
+    let mut reports = Vec::with_capacity(steps.len());
+    for step in steps {
+        if interrupt.is_cancelled() {
+            break;
+        }
+        println!("== {}: {}", step.name, step.description);
+        let depth = demo.cleanups.depth();
+        let (setup, mut outcome) = timed((step.setup)(demo), &interrupt).await;
+        let run = if outcome == Outcome::Passed {
+            let (run, ran) = timed((step.run)(demo), &interrupt).await;
+            outcome = ran;
+            run
+        } else {
+            Duration::ZERO
+        };
+        demo.cleanups.unwind(depth).await;
+        // Teardowns always finish, so there is nothing left for the next run
+        let (teardown, torn_down) = timed((step.teardown)(demo), &CancellationToken::new()).await;
+        reports.push(StepReport {
+            name: step.name,
+            setup,
+            run,
+            teardown,
+            passed: outcome == Outcome::Passed && torn_down == Outcome::Passed,
+            interrupted: outcome == Outcome::Interrupted,
+        });
+    }
+    demo.cleanups.unwind(0).await;
+    listener.abort();
+    reports
+}
+
+/// Cancels `interrupt` at the first Ctrl-C, and exits at the second.
+async fn interrupt_on_ctrl_c(interrupt: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    println!("interrupted, cleaning up; Ctrl-C again to quit right away");
+    interrupt.cancel();
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(130);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Passed,
+    /// Panicked.
+    Failed,
+    Interrupted,
+}
+
+async fn timed(action: BoxFuture<'_, ()>, interrupt: &CancellationToken) -> (Duration, Outcome) {
+    let started = Instant::now();
+    let outcome = tokio::select! {
+        result = AssertUnwindSafe(action).catch_unwind() => {
+            if result.is_ok() { Outcome::Passed } else { Outcome::Failed }
+        }
+        _ = interrupt.cancelled() => Outcome::Interrupted,
+    };
+    (started.elapsed(), outcome)
+}
+
+pub fn print_report(reports: &[StepReport]) {
+    println!("{:<18} {:>10} {:>10} {:>10}  result", "step", "setup", "run", "teardown");
+    for report in reports {
+        println!(
+            "{:<18} {:>8}ms {:>8}ms {:>8}ms  {}",
+            report.name,
+            report.setup.as_millis(),
+            report.run.as_millis(),
+            report.teardown.as_millis(),
+            match (report.passed, report.interrupted) {
+                (true, _) => "ok",
+                (false, true) => "interrupted",
+                (false, false) => "FAILED",
+            },
+        );
+    }
+    let total: Duration = reports.iter().map(|report| report.setup + report.run + report.teardown).sum();
+    println!("{} steps in {}ms", reports.len(), total.as_millis());
+}
+
+fn nothing(_: &Demo) -> BoxFuture<'_, ()> {
+    async {}.boxed()
+}
+
+fn sample_posts() -> Vec<Post> {
+    vec![
+        Post::new("Post 1", "This is post 1", &["tag1"]),
+        Post::new("Post 2", "This is post 2", &["tag1", "tag2"]),
+        Post::new("Hello", "World", &["tag1", "tag3"]),
+    ]
+}
+
+fn seed_samples(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_many(sample_posts(), None).await.expect("Unable to insert posts");
+    }.boxed()
+}
+
+fn remove_samples(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["tag1", "tag2", "tag3"]).boxed()
+}
+
+fn insert(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let posts = sample_posts();
+        let inserted = demo.repo.insert_many(&posts).await.expect("Unable to insert posts");
+        assert_eq!(inserted.len(), posts.len());
+    }.boxed()
+}
+
+fn find(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        println!("posts: {:?}", demo.repo.find_by_tag("tag1").await.expect("Unable to find posts"));
+    }.boxed()
+}
+
+fn update(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let updated = demo.repo.update_title_by_tag("tag2", "Updated title").await.expect("Unable to update posts");
+        assert_eq!(updated, repository::UpdateSummary { matched: 1, modified: 1, upserted_id: None });
+        println!("posts: {:?}", demo.find_tagged("tag2").await);
+    }.boxed()
+}
+
+fn delete(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let deleted = demo.repo.delete_by_tag("tag2").await.expect("Unable to delete posts");
+        assert_eq!(deleted, 1);
+        println!("posts: {:?}", demo.find_tagged("tag2").await);
+    }.boxed()
+}
+
+fn aggregate(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let post_by_tags = demo.repo.group_by_tag().await.expect("Unable to aggregate posts");
+        println!("posts_by_tag: {:?}", post_by_tags);
+    }.boxed()
+}
+
+/// Requires a replica set or sharded cluster.
+fn transaction(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::Transactions) {
+            println!("skipping transaction: {}", skip);
+            return;
+        }
+        let tag_counts = demo.ns.collection::<Document>(transactions::TAG_COUNTS);
+        let txn_count = || async {
+            let count = tag_counts.find_one(doc! { "_id": "txn" }, None).await
+                .expect("Unable to read tag counts");
+            count.and_then(|count| count.get_i32("count").ok()).unwrap_or(0)
+        };
+        let post = Post::new("Transactional", "Inserted inside a transaction", &["txn"]);
+        transactions::insert_counting_tags(&demo.client, &demo.ns, &post).await
+            .expect("Unable to insert post with its tag counts");
+        println!("transaction committed, {} post(s) tagged txn", txn_count().await);
+        assert_eq!(txn_count().await, 1);
+        // Same title again: the insert fails, the transaction aborts and takes the count's `$inc` with it
+        let duplicate = Post::new("Transactional", "Rejected by the unique title index", &["txn"]);
+        match transactions::insert_counting_tags(&demo.client, &demo.ns, &duplicate).await {
+            Ok(()) => panic!("duplicate title was inserted"),
+            Err(e) => println!("transaction aborted: {}", e),
+        }
+        println!("still {} post(s) tagged txn", txn_count().await);
+        assert_eq!(txn_count().await, 1);
+    }.boxed()
+}
+
+fn seed_transactional(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let post = Post::new("Transactional", "Inserted inside a transaction", &["txn"]);
+        demo.col.insert_one(post, None).await.expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_transactional(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["txn", "uow"]).await;
+        demo.ns.collection::<Document>(transactions::TAG_COUNTS)
+            .delete_many(doc! { "_id": { "$in": ["txn", "uow"] } }, None).await
+            .expect("Unable to clean up tag counts");
+    }.boxed()
+}
+
+/// Replaces the transactional post and keeps tag counts in sync.
+fn unit_of_work(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::Transactions) {
+            println!("skipping unit of work: {}", skip);
+            return;
+        }
+        let mut uow = unit_of_work::UnitOfWork::new(&demo.client, &demo.ns);
+        let replacement = Post::new("Unit of work", "Committed together with its tag counts", &["uow"]);
+        uow.delete_many("posts", doc! { "title": "Transactional" });
+        uow.insert_post(&replacement).expect("Unable to serialize post");
+        for tag in &replacement.tags {
+            uow.count_tag(tag, 1);
+        }
+        let pending = uow.len();
+        match uow.commit().await {
+            Ok(()) => println!("unit of work committed {} mutations", pending),
+            Err(e) => println!("unit of work aborted: {}", e),
+        }
+    }.boxed()
+}
+
+fn projection(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let projector = projection::Projector::new(&demo.ns);
+        match demo.caps.check(Feature::Merge) {
+            Ok(()) => projector.rebuild().await.expect("Unable to rebuild read model"),
+            Err(skip) => println!("skipping read model rebuild: {}", skip),
+        }
+        match demo.caps.check(Feature::ChangeStreams) {
+            Ok(()) => {
+                let live = tokio::spawn({
+                    let projector = projector.clone();
+                    async move { projector.run().await }
+                });
+                demo.cleanups.abort("projector", &live);
+                demo.col.insert_one(Post::new("Projected", "Picked up by the projector", &["cqrs"]), None).await
+                    .expect("Unable to insert post");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                if live.is_finished() {
+                    println!("projector stopped: {:?}", live.await);
+                } else {
+                    live.abort();
+                }
+            }
+            Err(skip) => println!("skipping live projection: {}", skip),
+        }
+        let read_model: Vec<projection::PostReadModel> = demo.ns.collection(projection::READ_MODEL)
+            .find(None, None).await
+            .expect("Unable to get Cursor")
+            .try_collect().await
+            .expect("Unable to collect items from Cursor");
+        println!("post_read_model: {:?}", read_model);
+    }.boxed()
+}
+
+fn remove_projection(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["tag1", "tag2", "tag3", "cqrs"]).await;
+        demo.ns.collection::<Document>(projection::READ_MODEL).drop(None).await
+            .expect("Unable to drop read model");
+    }.boxed()
+}
+
+/// Runs the `posts_by_tag` watcher for a second, stops it, changes a post
+/// while it's down and starts it again: the change is picked up from the
+/// saved resume token.
+fn watcher(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::ChangeStreams) {
+            println!("skipping watcher: {}", skip);
+            return;
+        }
+        let start = || {
+            let ns = demo.ns.clone();
+            let live = tokio::spawn(async move {
+                watcher::Watcher::new(&ns, "demo").run(&watcher::PostsByTag::new(&ns)).await
+            });
+            demo.cleanups.abort("watcher", &live);
+            live
+        };
+        // Gives the stream time to open before anything changes
+        let settle = || tokio::time::sleep(Duration::from_millis(500));
+        let stop = |live: tokio::task::JoinHandle<_>| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if live.is_finished() {
+                panic!("watcher stopped: {:?}", live.await);
+            }
+            live.abort();
+        };
+        let post_ids = |tag: &'static str| async move {
+            let doc = demo.ns.collection::<Document>(watcher::POSTS_BY_TAG)
+                .find_one(doc! { "_id": tag }, None).await
+                .expect("Unable to read posts_by_tag");
+            doc.and_then(|doc| doc.get_array("post_ids").ok().map(|ids| ids.len())).unwrap_or(0)
+        };
+
+        let live = start();
+        settle().await;
+        let id = demo.repo.insert(&Post::new("Watched", "Indexed by the watcher", &["watched"])).await
+            .expect("Unable to insert post");
+        stop(live).await;
+        println!("posts_by_tag watched: {} post(s)", post_ids("watched").await);
+        assert_eq!(post_ids("watched").await, 1);
+
+        demo.col.update_one(doc! { "_id": id }, doc! { "$set": { "tags": ["resumed"] } }, None).await
+            .expect("Unable to retag post");
+        stop(start()).await;
+        let (watched, resumed) = (post_ids("watched").await, post_ids("resumed").await);
+        println!("after resuming: watched {}, resumed {}", watched, resumed);
+        assert_eq!((watched, resumed), (0, 1));
+    }.boxed()
+}
+
+fn remove_watched(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["watched", "resumed"]).await;
+        demo.ns.collection::<Document>(watcher::POSTS_BY_TAG).drop(None).await
+            .expect("Unable to drop posts_by_tag");
+        demo.ns.collection::<Document>(watcher::RESUME_TOKENS).delete_one(doc! { "_id": "demo" }, None).await
+            .expect("Unable to forget resume token");
+    }.boxed()
+}
+
+/// Appends events, then rebuilds the post by replaying them. Every run uses
+/// a fresh aggregate id, so there is nothing to clean up.
+fn event_sourcing(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let store = events::EventStore::new(&demo.ns).await
+            .expect("Unable to create event store")
+            .snapshot_every(2);
+        let post_id = ObjectId::new();
+        let mut version = 0;
+        for event in [
+            events::PostEvent::Created {
+                title: "Sourced".to_string(),
+                message: "Rebuilt from events".to_string(),
+                tags: vec!["events".to_string()],
+            },
+            events::PostEvent::TitleChanged { title: "Sourced post".to_string() },
+            events::PostEvent::TagAdded { tag: "replay".to_string() },
+        ] {
+            version = store.append(post_id, version, event).await.expect("Unable to append event");
+        }
+        let stale = store.append(post_id, 1, events::PostEvent::Deleted).await;
+        if let Err(e) = stale {
+            println!("stale append rejected: {}", events::is_conflict(&e));
+        }
+        let aggregate = store.load(post_id).await.expect("Unable to load aggregate");
+        println!("event-sourced post: {:?}", aggregate);
+    }.boxed()
+}
+
+/// The second run finds its outbox slot taken and compensates.
+fn saga(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let saga = saga::PublishPostSaga::new(&demo.ns);
+        let post = Post::new("Saga", "Created by a saga", &["saga"]);
+        let record = saga.run(post).await.expect("Unable to persist saga");
+        println!("saga: {:?}", record.status);
+        let post = Post::new("Saga rollback", "Removed again by compensation", &["saga"]);
+        demo.ns.collection::<Document>(saga::OUTBOX)
+            .insert_one(doc! { "_id": post.id, "event": "already_queued" }, None).await
+            .expect("Unable to seed outbox");
+        let record = saga.run(post).await.expect("Unable to persist saga");
+        println!("saga: {:?} {:?}", record.status, record.steps);
+    }.boxed()
+}
+
+fn remove_saga(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["saga"]).boxed()
+}
+
+fn seed_scheduled(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let now = DateTime::now().timestamp_millis();
+        let drafts = vec![
+            Post {
+                status: PostStatus::Review,
+                publish_at: Some(DateTime::from_millis(now - 1000)),
+                ..Post::new("Due post", "Published by the scheduler", &["scheduled"])
+            },
+            Post {
+                status: PostStatus::Review,
+                publish_at: Some(DateTime::from_millis(now + 3_600_000)),
+                ..Post::new("Future post", "Still waiting", &["scheduled"])
+            },
+        ];
+        demo.col.insert_many(drafts, None).await.expect("Unable to insert drafts");
+    }.boxed()
+}
+
+fn remove_scheduled(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["scheduled"]).boxed()
+}
+
+/// Only the reviewed post that is already due gets published.
+fn scheduler(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let scheduler = scheduler::Scheduler::new(&demo.ns).await.expect("Unable to create scheduler");
+        let worker = tokio::spawn(async move {
+            scheduler.run(Duration::from_millis(200)).await
+        });
+        demo.cleanups.abort("scheduler", &worker);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        worker.abort();
+        println!("scheduled posts: {:?}", demo.find_tagged("scheduled").await);
+    }.boxed()
+}
+
+fn seed_lifecycle(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let draft = Post {
+            status: PostStatus::Draft,
+            ..Post::new("Lifecycle", "Moving through states", &["lifecycle"])
+        };
+        demo.col.insert_one(&draft, None).await.expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_lifecycle(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["lifecycle"]).boxed()
+}
+
+/// Transitions are checked by the update filter itself.
+fn lifecycle(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let draft = demo.find_titled("Lifecycle").await;
+        for status in [PostStatus::Review, PostStatus::Archived, PostStatus::Published, PostStatus::Archived] {
+            match demo.repo.transition(draft.id, status).await {
+                Ok(post) => println!("post is now {}", post.status.as_str()),
+                Err(e) => println!("transition rejected: {}", e),
+            }
+        }
+    }.boxed()
+}
+
+fn seed_i18n(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let translated = Post {
+            lang: Some("en".to_string()),
+            content: vec![LocalizedContent { lang: "de".to_string(), message: "Hallo Welt".to_string() }],
+            ..Post::new("Greeting", "Hello world", &["i18n"])
+        };
+        let untranslated = Post::new("Farewell", "Goodbye world", &["i18n"]);
+        demo.col.insert_many([translated, untranslated], None).await.expect("Unable to insert posts");
+    }.boxed()
+}
+
+fn remove_i18n(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["i18n"]).boxed()
+}
+
+/// Reads in German, falling back to the default message.
+fn i18n(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let localized = demo.repo.find_by_tag_localized("i18n", "de").await.expect("Unable to find posts");
+        println!("localized posts: {:?}", localized.iter().map(|post| &post.message).collect::<Vec<_>>());
+        let found = demo.repo.search_in_language("Welt", "de").await.expect("Unable to search posts");
+        println!("german search: {:?}", found.iter().map(|post| &post.title).collect::<Vec<_>>());
+    }.boxed()
+}
+
+/// Titles are unique regardless of case.
+fn duplicate_titles(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(e) = demo.repo.insert(&Post::new("hello", "Lowercase duplicate", &["dupe"])).await {
+            println!("insert rejected: {}", e);
+        }
+        let hello = demo.repo.find_by_title("HELLO").await.expect("Unable to find post");
+        println!("found by title: {:?}", hello.map(|post| post.title));
+    }.boxed()
+}
+
+/// Stored in UTC, but already June 2nd in Berlin.
+fn seed_late_night(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let late_night = DateTime::parse_rfc3339_str("2024-06-01T22:30:00Z").expect("Invalid date");
+        demo.col.insert_one(Post {
+            created_at: late_night,
+            ..Post::new("Late night", "Already June 2nd in Berlin", &["timezone"])
+        }, None).await.expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_late_night(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["timezone"]).boxed()
+}
+
+/// Dates are stored in UTC but bucketed by the reader's local day.
+fn timezone(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        for tz in ["UTC", "Europe/Berlin"] {
+            let counts = demo.analytics.daily_counts(tz).await.expect("Unable to count posts");
+            let on_june_2nd = demo.repo.find_created_on("2024-06-02", tz).await.expect("Unable to find posts");
+            println!("{}: {:?}, created on 2024-06-02: {}", tz, counts.first(), on_june_2nd.len());
+        }
+    }.boxed()
+}
+
+/// Date ranges are `[from, to)` in UTC.
+fn date_range(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let from = chrono::Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let to = from + chrono::Duration::days(7);
+        let in_range = demo.repo.find_between(from, to).await.expect("Unable to find posts");
+        println!("posts in first week of June: {:?}", in_range.iter().map(|post| &post.title).collect::<Vec<_>>());
+        match demo.caps.check(Feature::DateTrunc) {
+            Ok(()) => {
+                let buckets = demo.analytics.count_per_day(from, to).await.expect("Unable to count posts");
+                println!("posts per day: {:?}", buckets);
+            }
+            Err(skip) => println!("skipping posts per day: {}", skip),
+        }
+    }.boxed()
+}
+
+/// Three posts around June 2024, each written into its month's collection.
+fn partitions(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let partitions = partition::MonthlyPartitions::new(&demo.ns, "posts");
+        for at in ["2024-05-30T12:00:00Z", "2024-06-15T12:00:00Z", "2024-07-02T12:00:00Z"] {
+            let post = Post {
+                created_at: DateTime::parse_rfc3339_str(at).expect("Invalid date"),
+                ..Post::new(&format!("Partitioned {}", &at[..10]), "Stored by month", &["partition"])
+            };
+            partitions.insert(&post).await.expect("Unable to insert post");
+        }
+        let from = chrono::Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let to = chrono::Utc.with_ymd_and_hms(2024, 7, 15, 0, 0, 0).unwrap();
+        println!("partitions for June 1st to July 15th: {:?}", partitions.partitions_between(from, to));
+        match demo.caps.check(Feature::UnionWith) {
+            Ok(()) => {
+                let posts = partitions.find_between(from, to).await.expect("Unable to find posts");
+                println!("across partitions: {:?}", posts.iter().map(|post| &post.title).collect::<Vec<_>>());
+            }
+            Err(skip) => println!("skipping cross-partition read: {}", skip),
+        }
+    }.boxed()
+}
+
+fn remove_partitions(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        for name in ["posts_2024_05", "posts_2024_06", "posts_2024_07"] {
+            demo.ns.collection::<Document>(name).drop(None).await.expect("Unable to drop partition");
+        }
+    }.boxed()
+}
+
+fn search(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let results = demo.repo.search(&repository::SearchFilters {
+            tags: vec!["tag1".to_string()],
+            text: Some("post".to_string()),
+            per_page: 2,
+            ..Default::default()
+        }).await.expect("Unable to search posts");
+        println!("search: {} total, tags {:?}, first page {:?}",
+            results.total, results.tag_counts, results.items.iter().map(|post| &post.title).collect::<Vec<_>>());
+    }.boxed()
+}
+
+fn suggest(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let suggestions = demo.repo.suggest("po", 5).await.expect("Unable to suggest titles");
+        println!("suggestions for \"po\": {:?}", suggestions);
+    }.boxed()
+}
+
+fn seed_relevance(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_many([
+            Post::new("Mongo tips", "Assorted advice", &["relevance"]),
+            Post::new("Assorted notes", "Some mongo advice", &["relevance"]),
+        ], None).await.expect("Unable to insert posts");
+    }.boxed()
+}
+
+fn remove_relevance(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["relevance"]).boxed()
+}
+
+/// A title match outranks a message match thanks to the weights.
+fn relevance(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let text_index = repository::TextIndexConfig::default();
+        let hits = demo.repo.search_posts("mongo").await.expect("Unable to search posts");
+        let scores: Vec<_> = hits.iter().map(|hit| (&hit.post.title, hit.score)).collect();
+        println!("relevance with title weight {} vs message weight {}: {:?}",
+            text_index.title_weight, text_index.message_weight, scores);
+        let by_tag = demo.repo.group_by_tag_matching("mongo").await.expect("Unable to aggregate posts");
+        println!("tags of posts mentioning mongo: {:?}", by_tag);
+    }.boxed()
+}
+
+fn seed_legacy(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>("posts").insert_one(doc! {
+            "_id": ObjectId::new(),
+            "title": "Legacy",
+            "message": "Written by an older version",
+            "tags": ["legacy"],
+            "created_at": DateTime::now(),
+            "legacy_views": 42,
+        }, None).await.expect("Unable to insert legacy post");
+    }.boxed()
+}
+
+fn remove_legacy(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["legacy"]).boxed()
+}
+
+/// Legacy fields survive a read-modify-write with the `tolerant-decoding` feature.
+fn legacy(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let mut legacy = demo.find_titled("Legacy").await;
+        legacy.message = "Edited by this version".to_string();
+        demo.col.replace_one(doc! { "_id": legacy.id }, &legacy, None).await.expect("Unable to replace post");
+        let raw = demo.ns.collection::<Document>("posts").find_one(doc! { "_id": legacy.id }, None).await
+            .expect("Unable to find post");
+        println!("legacy_views after round trip: {:?}", raw.and_then(|doc| doc.get("legacy_views").cloned()));
+    }.boxed()
+}
+
+fn seed_broken(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>("posts").insert_one(doc! {
+            "_id": ObjectId::new(),
+            "title": "Broken",
+            "message": "Status isn't a string",
+            "tags": ["broken"],
+            "created_at": DateTime::now(),
+            "status": 7,
+        }, None).await.expect("Unable to insert broken post");
+    }.boxed()
+}
+
+fn remove_broken(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["broken"]).boxed()
+}
+
+/// Decode failures name the document and the offending field.
+fn decode_error(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let broken = demo.ns.collection::<Document>("posts").find_one(doc! { "tags": "broken" }, None).await
+            .expect("Unable to find post")
+            .expect("Broken post is missing");
+        let broken_id = broken.get_object_id("_id").expect("Broken post has no id");
+        if let Err(e) = demo.repo.find_by_id(broken_id).await {
+            println!("decode failed: {}", e);
+        }
+    }.boxed()
+}
+
+fn seed_patch(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let post = Post { lang: Some("en".to_string()), ..Post::new("Patch me", "Partially updated", &["patch"]) };
+        demo.col.insert_one(post, None).await.expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_patch(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["patch"]).boxed()
+}
+
+/// Only the provided fields are written.
+fn patch(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let post = demo.find_titled("Patch me").await;
+        let patched = demo.repo.patch_post(post.id, &repository::PostPatch {
+            title: Some("Patched".to_string()),
+            lang: Some(None),
+            ..Default::default()
+        }).await.expect("Unable to patch post");
+        println!("patched post: {:?}", patched);
+    }.boxed()
+}
+
+/// Missing ids stay visible as `None` in their place.
+fn batch_get(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let hello = demo.find_titled("Hello").await.id;
+        let post_1 = demo.find_titled("Post 1").await.id;
+        let posts = demo.repo.find_by_ids(&[hello, ObjectId::new(), post_1]).await.expect("Unable to find posts");
+        println!("batch: {:?}", posts.iter().map(|post| post.as_ref().map(|post| &post.title)).collect::<Vec<_>>());
+    }.boxed()
+}
+
+/// The server only sends id, title and tags for list views.
+fn summaries(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let summaries = demo.repo.find_summaries_by_tag("tag1").await.expect("Unable to list posts");
+        println!("tag1 summaries: {:?}", summaries);
+        let latest = demo.repo.list_summaries(3).await.expect("Unable to list posts");
+        println!("latest summaries: {:?}", latest);
+    }.boxed()
+}
+
+fn seed_titles(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_many([
+            Post::new("Zebra", "Last in any alphabet", &["sorting"]),
+            Post::new("Äpfel", "Sorts after Zebra byte by byte", &["sorting"]),
+            Post::new("Apfel", "First in any alphabet", &["sorting"]),
+            Post::new("Post 10", "Sorts before Post 2 digit by digit", &["sorting"]),
+            Post::new("Post 2", "Sorts before Post 10 by value", &["sorting"]),
+        ], None).await.expect("Unable to insert posts");
+    }.boxed()
+}
+
+fn remove_titles(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["sorting"]).boxed()
+}
+
+/// Byte order puts "Äpfel" after "Zebra" and "Post 10" before "Post 2";
+/// the German and numeric collations don't.
+fn title_sort(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let sorts = [
+            ("simple", repository::TitleSort::default()),
+            ("de", repository::TitleSort { locale: Some("de".to_string()), numeric: false }),
+            ("numeric", repository::TitleSort { locale: None, numeric: true }),
+        ];
+        for (label, sort) in sorts {
+            let titles = demo.repo.list_by_title(&sort, 10).await.expect("Unable to list posts");
+            println!("titles ({}): {:?}", label, titles.iter().map(|summary| &summary.title).collect::<Vec<_>>());
+        }
+    }.boxed()
+}
+
+fn seed_replace(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_one(Post::new("Replace me", "Replaced later", &["replace"]), None).await
+            .expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_replace(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["replace"]).boxed()
+}
+
+/// The whole document is swapped, guarded by its version.
+fn replace(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let post = demo.find_titled("Replace me").await;
+        let stale = post.clone();
+        let replaced = demo.repo.replace_post(&Post { message: "Replaced wholesale".to_string(), ..post })
+            .await.expect("Unable to replace post");
+        println!("replaced post is at version {}", replaced.version);
+        if let Err(e) = demo.repo.replace_post(&stale).await {
+            println!("stale replace rejected: {}", e);
+        }
+        let too_long = Post { title: "x".repeat(301), ..replaced };
+        if let Err(e) = demo.repo.replace_post(&too_long).await {
+            println!("invalid replace rejected: {}", e);
+        }
+    }.boxed()
+}
+
+fn seed_archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_one(Post::new("Archive me", "Moved to the archive", &["archive"]), None).await
+            .expect("Unable to insert post");
+    }.boxed()
+}
+
+fn remove_archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["archive"]).await;
+        let archive = demo.ns.collection::<Post>(repository::POSTS_ARCHIVE);
+        archive.delete_many(doc! { "tags": "archive" }, None).await
+            .expect("Unable to clean up archive");
+    }.boxed()
+}
+
+/// Moves a post into the archive, then deletes something that isn't there.
+fn archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let id = demo.find_titled("Archive me").await.id;
+        let archive = demo.ns.collection::<Post>(repository::POSTS_ARCHIVE);
+        if let Some(post) = demo.repo.take_by_id(id).await.expect("Unable to take post") {
+            archive.insert_one(&post, None).await.expect("Unable to archive post");
+            println!("moved {:?} to posts_archive", post.title);
+        }
+        if let Err(e) = demo.repo.delete_by_id(id).await {
+            println!("deleting again failed: {}", e);
+        }
+    }.boxed()
+}
+
+fn seed_with_archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.col.insert_one(Post::new("Current", "Still in posts", &["union"]), None).await
+            .expect("Unable to insert post");
+        let archived = Post {
+            created_at: DateTime::parse_rfc3339_str("2020-01-01T00:00:00Z").expect("Invalid date"),
+            ..Post::new("Archived", "Already in the archive", &["union"])
+        };
+        demo.ns.collection::<Post>(repository::POSTS_ARCHIVE).insert_one(archived, None).await
+            .expect("Unable to archive post");
+    }.boxed()
+}
+
+fn remove_with_archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["union"]).await;
+        let archive = demo.ns.collection::<Post>(repository::POSTS_ARCHIVE);
+        archive.delete_many(doc! { "tags": "union" }, None).await
+            .expect("Unable to clean up archive");
+    }.boxed()
+}
+
+/// One filter and one sort over both collections, newest first.
+fn with_archive(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::UnionWith) {
+            println!("skipping query with archive: {}", skip);
+            return;
+        }
+        let posts = demo.repo.find_including_archived(doc! { "tags": "union" }, doc! { "created_at": -1 }).await
+            .expect("Unable to find posts");
+        println!("including archived: {:?}", posts.iter().map(|post| &post.title).collect::<Vec<_>>());
+    }.boxed()
+}
+
+fn remove_import(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["tag1", "tag2", "tag3", "import"]).boxed()
+}
+
+/// Ordered stops at the first bad document, unordered tries them all; the
+/// clashing "hello" is skipped the first time and merged into "Hello" the second.
+fn import(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let runs = [
+            (repository::ImportMode::Ordered, repository::OnDuplicate::Skip, "ordered"),
+            (repository::ImportMode::Unordered, repository::OnDuplicate::Update, "unordered"),
+        ];
+        for (mode, on_duplicate, suffix) in runs {
+            let batch = vec![
+                Post::new(&format!("Import A {}", suffix), "Fine", &["import"]),
+                Post::new("hello", "Clashes with \"Hello\"", &["import"]),
+                Post::new(&format!("Import B {}", suffix), "Fine", &["import"]),
+                Post::new(&format!("Import C {}", suffix), "Tag too short", &["x"]),
+            ];
+            let report = demo.repo.import(batch, mode, on_duplicate).await.expect("Unable to import posts");
+            let counts = (report.inserted, report.updated, report.skipped_duplicates, report.rejected_validation);
+            match mode {
+                repository::ImportMode::Ordered => assert_eq!((counts, report.not_attempted), ((1, 0, 1, 0), 2)),
+                repository::ImportMode::Unordered => assert_eq!((counts, report.not_attempted), ((2, 1, 0, 1), 0)),
+            }
+            println!("{} import: {:?}", suffix, report);
+        }
+    }.boxed()
+}
+
+fn remove_sync(demo: &Demo) -> BoxFuture<'_, ()> {
+    demo.delete_tagged(&["tag1", "tag2", "tag3", "sync"]).boxed()
+}
+
+/// "Post 1" stays, "Post 2" changes, "Hello" goes and "Synced" is new.
+fn sync(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let posts = vec![
+            Post::new("Post 1", "This is post 1", &["tag1"]),
+            Post::new("post 2", "Rewritten by the sync", &["tag1", "tag2"]),
+            Post::new("Synced", "Added by the sync", &["sync"]),
+        ];
+        let report = demo.repo.sync_posts(posts).await.expect("Unable to sync posts");
+        assert_eq!(report, repository::SyncReport { inserted: 1, updated: 1, deleted: 1, unchanged: 1 });
+        println!("sync: {:?}", report);
+    }.boxed()
+}
+
+/// A spent budget fails before anything is sent.
+fn deadlines(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let rushed = demo.repo.with_deadline(deadline::Deadline::after(Duration::ZERO));
+        if let Err(e) = rushed.list_summaries(3).await {
+            println!("rushed listing rejected: {}", e);
+        }
+        let budgeted = demo.repo.with_deadline(deadline::Deadline::after(Duration::from_secs(5)));
+        let first = budgeted.list_summaries(1).await.expect("Unable to list posts");
+        if let Some(summary) = first.first() {
+            let found = budgeted.find_by_id(summary.id).await.expect("Unable to find post");
+            println!("found within budget: {:?}", found.title);
+        }
+    }.boxed()
+}
+
+/// The second identical search is answered from `query_cache`.
+fn query_cache(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let cache = query_cache::QueryCache::new(&demo.ns, Duration::from_secs(60)).await
+            .expect("Unable to create query cache");
+        let filters = repository::SearchFilters { tags: vec!["tag1".to_string()], ..Default::default() };
+        let pipeline = repository::search_pipeline(&filters);
+        for attempt in ["cold", "warm"] {
+            let started = Instant::now();
+            let results: repository::SearchResults = cache
+                .get_or_compute("posts", &pipeline, demo.repo.search(&filters))
+                .await.expect("Unable to search posts");
+            println!("{} search: {} total in {:?}", attempt, results.total, started.elapsed());
+        }
+    }.boxed()
+}
+
+fn related_tags(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let tag_graph = related::TagGraph::new(&demo.ns).await.expect("Unable to create tag graph");
+        tag_graph.rebuild().await.expect("Unable to count related tags");
+        let related = tag_graph.related_tags("tag1", 5).await.expect("Unable to find related tags");
+        println!("tagged together with tag1: {:?}", related);
+    }.boxed()
+}
+
+/// Posts keyed by ULIDs a millisecond apart, paged through on `_id` alone:
+/// the pages come out in creation order with no `created_at` sort.
+fn ulid_pages(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let col = demo.ns.collection::<Document>(ULID_POSTS);
+        let start = chrono::Utc::now().timestamp_millis() as u64;
+        for binary in [false, true] {
+            col.drop(None).await.expect("Unable to drop collection");
+            let posts: Vec<Document> = (0..5u64)
+                .map(|n| {
+                    let random = rand::random();
+                    let id = if binary {
+                        ids::UlidBinaries::encode(start + n, random)
+                    } else {
+                        ids::Ulids::encode(start + n, random).into()
+                    };
+                    doc! { "_id": id, "title": format!("Post {}", n) }
+                })
+                .rev()
+                .collect();
+            // Inserted newest first, so only the ids can put them in order
+            col.insert_many(posts, None).await.expect("Unable to insert posts");
+            let (mut cursor, mut titles) = (None, Vec::new());
+            loop {
+                let page = ids::find_page(&col, doc! {}, cursor, 2).await.expect("Unable to fetch page");
+                titles.extend(page.items.iter().map(|post| post.get_str("title").unwrap_or_default().to_string()));
+                cursor = match page.next_cursor {
+                    Some(next) => Some(next),
+                    None => break,
+                };
+            }
+            println!("{:<6} ids page as {:?}", if binary { "binary" } else { "string" }, titles);
+            assert_eq!(titles, (0..5).map(|n| format!("Post {}", n)).collect::<Vec<_>>());
+        }
+    }.boxed()
+}
+
+fn remove_ulid_posts(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>(ULID_POSTS).drop(None).await.expect("Unable to clean up posts");
+    }.boxed()
+}
+
+fn similar(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let hello = demo.find_titled("Hello").await;
+        let similar = demo.repo.similar_posts(hello.id, 0, 3).await.expect("Unable to find similar posts");
+        println!("similar to {:?}: {:?}", hello.title, similar);
+        // Posts to start from when there is no post to be similar to, popular tags first
+        let samples = demo.repo.sample_by_tag(3).await.expect("Unable to sample posts");
+        let drawn: Vec<(&str, &str)> = samples.iter().map(|s| (s.tag.as_str(), s.post.title.as_str())).collect();
+        println!("drawn by tag: {:?}", drawn);
+    }.boxed()
+}
+
+/// More views of one post in one hour than a bucket holds, so it takes
+/// several, and a few of another post's.
+fn view_buckets(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let views = views::ViewLog::new(&demo.ns).await.expect("Unable to create view log");
+        let (hello, other) = (demo.find_titled("Hello").await, demo.find_titled("Post 1").await);
+        let hour = chrono::Utc::now() - chrono::Duration::hours(1);
+        for n in 0..views::BUCKET_CAPACITY + 50 {
+            let event = views::ViewEvent { at: hour + chrono::Duration::seconds(n), viewer: None };
+            views.record(hello.id, &event).await.expect("Unable to record view");
+        }
+        for n in 0..3 {
+            let viewer = Some("bob".to_string());
+            let event = views::ViewEvent { at: hour + chrono::Duration::seconds(n), viewer };
+            views.record(other.id, &event).await.expect("Unable to record view");
+        }
+        let buckets = demo.ns.collection::<Document>(views::POST_VIEWS)
+            .count_documents(doc! { "post_id": hello.id }, None).await
+            .expect("Unable to count buckets");
+        println!("{} views of {:?} in {} buckets", views::BUCKET_CAPACITY + 50, hello.title, buckets);
+        let top = views.most_viewed(hour, chrono::Utc::now() + chrono::Duration::hours(1), 2).await
+            .expect("Unable to count views");
+        println!("most viewed: {:?}", top);
+        let first = views.events(other.id, hour, hour + chrono::Duration::seconds(2)).await
+            .expect("Unable to read views");
+        println!("first views of {:?}: {:?}", other.title, first);
+    }.boxed()
+}
+
+fn remove_views(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["tag1", "tag2", "tag3"]).await;
+        demo.ns.collection::<Document>(views::POST_VIEWS).drop(None).await.expect("Unable to drop post views");
+    }.boxed()
+}
+
+fn activity_feed(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let feeds = feeds::Feeds::new(&demo.ns);
+        let activity = |kind, post_id| {
+            feeds::Activity { kind, post_id, actor: "ann".to_string(), at: chrono::Utc::now() }
+        };
+        for post in demo.find_tagged("tag1").await {
+            feeds.push("bob", &activity(feeds::ActivityKind::Posted, post.id)).await
+                .expect("Unable to push activity");
+        }
+        let hello = demo.find_titled("Hello").await;
+        for _ in 0..feeds::FEED_LENGTH {
+            feeds.push("bob", &activity(feeds::ActivityKind::Commented, hello.id)).await
+                .expect("Unable to push activity");
+        }
+        let feed = feeds.feed("bob").await.expect("Unable to read feed");
+        println!("bob's feed holds the last {} events, newest: {:?}", feed.len(), feed.first());
+        assert_eq!(feed.len(), feeds::FEED_LENGTH as usize);
+        assert!(feed.iter().all(|event| event.kind == feeds::ActivityKind::Commented));
+    }.boxed()
+}
+
+fn remove_feeds(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>(feeds::FEEDS).drop(None).await.expect("Unable to drop feeds");
+        remove_samples(demo).await;
+    }.boxed()
+}
+
+const COUNTERS: &str = "counters";
+
+const ULID_POSTS: &str = "ulid_posts";
+
+/// Follows `next_cursor` until the last page, which has none.
+fn pagination(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let mut cursor = None;
+        let mut seen = 0;
+        loop {
+            let page = demo.repo.find_posts_paginated(doc! { "tags": "tag1" }, cursor, 2).await
+                .expect("Unable to fetch page");
+            println!("page: {:?}", page.items.iter().map(|post| &post.title).collect::<Vec<_>>());
+            seen += page.items.len();
+            cursor = match page.next_cursor {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+        assert_eq!(seen, 3);
+    }.boxed()
+}
+
+/// Twenty tasks add one to the same counter ten times each, first by reading
+/// it and writing back the incremented value, then with `$inc`. Reads that
+/// interleave with another task's write make its increment vanish; `$inc`
+/// reads and writes in one step on the server and can't lose any.
+fn lost_updates(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        const TASKS: i32 = 20;
+        const INCREMENTS: i32 = 10;
+        const EXPECTED: i32 = TASKS * INCREMENTS;
+        let counters = demo.ns.collection::<Document>(COUNTERS);
+        for atomic in [false, true] {
+            let reset = ReplaceOptions::builder().upsert(true).build();
+            counters.replace_one(doc! { "_id": "race" }, doc! { "count": 0 }, reset).await
+                .expect("Unable to reset counter");
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|_| {
+                    let counters = counters.clone();
+                    tokio::spawn(async move {
+                        for _ in 0..INCREMENTS {
+                            let update = if atomic {
+                                doc! { "$inc": { "count": 1 } }
+                            } else {
+                                let current = read_count(&counters).await?;
+                                doc! { "$set": { "count": current + 1 } }
+                            };
+                            counters.update_one(doc! { "_id": "race" }, update, None).await?;
+                        }
+                        mongodb::error::Result::Ok(())
+                    })
+                })
+                .collect();
+            for task in &tasks {
+                demo.cleanups.abort("incrementing task", task);
+            }
+            for task in tasks {
+                task.await.expect("Incrementing task panicked").expect("Unable to increment counter");
+            }
+            let count = read_count(&counters).await.expect("Unable to read counter");
+            let strategy = if atomic { "$inc" } else { "read-modify-write" };
+            println!("{:<18} expected {}, got {}, lost {}", strategy, EXPECTED, count, EXPECTED - count);
+            if atomic {
+                assert_eq!(count, EXPECTED);
+            }
+        }
+    }.boxed()
+}
+
+const DAILY_COUNTERS: &str = "daily_counters";
+
+const UPSERTED_COUNTERS: &str = "daily_counters_upserted";
+
+/// Counts today's views of the sample posts per hour, once in documents
+/// made ahead of time with a 0 for every hour, and once in documents the
+/// first view of the day upserts. The preallocated ones are only ever
+/// updated in place; the upserted ones take an insert first, then grow by a
+/// field for every hour with views, and are looked for by an upsert each time.
+fn preallocated_counters(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        const VIEWS: usize = 2000;
+        let posts: Vec<ObjectId> = demo.find_tagged("tag1").await.iter().map(|post| post.id).collect();
+        let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+        let preallocated = demo.ns.collection::<Document>(DAILY_COUNTERS);
+        let upserted = demo.ns.collection::<Document>(UPSERTED_COUNTERS);
+        let hours: Document = (0..24).map(|hour: i32| (hour.to_string(), 0.into())).collect();
+        let counters: Vec<Document> = posts.iter()
+            .map(|id| doc! { "_id": { "post_id": id, "day": &day }, "total": 0, "hours": hours.clone() })
+            .collect();
+        preallocated.insert_many(counters, None).await.expect("Unable to preallocate counters");
+
+        let view = |n: usize| {
+            let filter = doc! { "_id": { "post_id": posts[n % posts.len()], "day": &day } };
+            (filter, doc! { "$inc": { "total": 1, format!("hours.{}", n % 24): 1 } })
+        };
+        let started = Instant::now();
+        for n in 0..VIEWS {
+            let (filter, update) = view(n);
+            let result = preallocated.update_one(filter, update, None).await.expect("Unable to count view");
+            assert_eq!(result.matched_count, 1);
+        }
+        let in_place = started.elapsed();
+        let started = Instant::now();
+        for n in 0..VIEWS {
+            let (filter, update) = view(n);
+            let options = UpdateOptions::builder().upsert(true).build();
+            upserted.update_one(filter, update, options).await.expect("Unable to count view");
+        }
+        let on_demand = started.elapsed();
+        println!("preallocated:     {} views in {:?}", VIEWS, in_place);
+        println!("upsert on demand: {} views in {:?}", VIEWS, on_demand);
+        let total = |counters: Collection<Document>| async move {
+            let pipeline = vec![doc! { "$group": { "_id": null, "views": { "$sum": "$total" } } }];
+            let totals: Vec<Document> = counters.aggregate(pipeline, None).await.expect("Unable to sum views")
+                .try_collect().await.expect("Unable to sum views");
+            totals[0].get_i32("views").expect("views are counted in int32")
+        };
+        assert_eq!(total(preallocated).await, VIEWS as i32);
+        assert_eq!(total(upserted).await, VIEWS as i32);
+    }.boxed()
+}
+
+fn remove_daily_counters(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        for name in [DAILY_COUNTERS, UPSERTED_COUNTERS] {
+            demo.ns.collection::<Document>(name).drop(None).await.expect("Unable to drop counters");
+        }
+        remove_samples(demo).await;
+    }.boxed()
+}
+
+async fn read_count(counters: &Collection<Document>) -> mongodb::error::Result<i32> {
+    let counter = counters.find_one(doc! { "_id": "race" }, None).await?;
+    Ok(counter.and_then(|counter| counter.get_i32("count").ok()).unwrap_or(0))
+}
+
+fn remove_counter(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>(COUNTERS).delete_one(doc! { "_id": "race" }, None).await
+            .expect("Unable to clean up counter");
+    }.boxed()
+}
+
+/// The read-modify-write from `lost-updates`, but each increment in its own
+/// transaction: when two overlap, the second to write hits a write conflict,
+/// is aborted and retried by [`transactions::run_in_txn`], so no increment is
+/// lost. The price shows up as extra attempts.
+fn txn_contention(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::Transactions) {
+            println!("skipping transaction contention: {}", skip);
+            return;
+        }
+        const TASKS: u32 = 10;
+        const INCREMENTS: u32 = 5;
+        let counters = demo.ns.collection::<Document>(COUNTERS);
+        let reset = ReplaceOptions::builder().upsert(true).build();
+        counters.replace_one(doc! { "_id": "race" }, doc! { "count": 0 }, reset).await
+            .expect("Unable to reset counter");
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let (client, counters) = (demo.client.clone(), counters.clone());
+                tokio::spawn(async move {
+                    let mut total = transactions::TxnStats::default();
+                    for _ in 0..INCREMENTS {
+                        let stats = increment_in_txn(&client, &counters).await?;
+                        total.attempts += stats.attempts;
+                        total.commit_retries += stats.commit_retries;
+                    }
+                    mongodb::error::Result::Ok(total)
+                })
+            })
+            .collect();
+        for task in &tasks {
+            demo.cleanups.abort("transaction task", task);
+        }
+        let mut total = transactions::TxnStats::default();
+        for task in tasks {
+            let stats = task.await.expect("Transaction task panicked").expect("Unable to run transaction");
+            total.attempts += stats.attempts;
+            total.commit_retries += stats.commit_retries;
+        }
+        let committed = TASKS * INCREMENTS;
+        let aborted = total.attempts - committed;
+        let abort_rate = 100.0 * aborted as f64 / total.attempts as f64;
+        println!("{} transactions committed after {} attempts: {} aborted on conflict ({:.0}%), {} commit retries",
+            committed, total.attempts, aborted, abort_rate, total.commit_retries);
+        let count = read_count(&counters).await.expect("Unable to read counter");
+        assert_eq!(count, committed as i32);
+    }.boxed()
+}
+
+async fn increment_in_txn(
+    client: &Client,
+    counters: &Collection<Document>,
+) -> mongodb::error::Result<transactions::TxnStats> {
+    let ((), stats) = transactions::run_in_txn_with_stats(client, |session| {
+        let counters = counters.clone();
+        async move {
+            let counter = counters.find_one_with_session(doc! { "_id": "race" }, None, session).await?;
+            let current = counter.and_then(|counter| counter.get_i32("count").ok()).unwrap_or(0);
+            let update = doc! { "$set": { "count": current + 1 } };
+            counters.update_one_with_session(doc! { "_id": "race" }, update, None, session).await?;
+            Ok(())
+        }.boxed()
+    }).await?;
+    Ok(stats)
+}
+
+/// A file too big to sensibly embed goes into GridFS in chunks; the post only
+/// records its id, and deleting the attachment clears both.
+fn attachments(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let attachments = attachments::Attachments::new(&demo.ns);
+        let post = demo.find_titled("Hello").await;
+        // A megabyte, four GridFS chunks and a bit
+        let bytes: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let file_id = attachments.upload_attachment(post.id, "hello.bin", &bytes).await
+            .expect("Unable to upload attachment");
+        let post = demo.find_titled("Hello").await;
+        println!("{:?} has attachments {:?}", post.title, post.attachments);
+        assert_eq!(post.attachments, [file_id]);
+        let downloaded = attachments.download_attachment(file_id).await.expect("Unable to download attachment");
+        println!("downloaded {} bytes", downloaded.len());
+        assert_eq!(downloaded, bytes);
+        attachments.delete_attachment(post.id, file_id).await.expect("Unable to delete attachment");
+        assert!(demo.find_titled("Hello").await.attachments.is_empty());
+        assert!(attachments.download_attachment(file_id).await.is_err());
+    }.boxed()
+}
+
+const AUTHORED_POSTS: &str = "authored_posts";
+
+/// Posts sharded on `author_id`: queries through [`sharding::ShardedCollection`]
+/// always name an author and go to one shard, while a plain tag query is
+/// sent to all of them. Without a sharded cluster every plan reads from one
+/// server, so only the targeting shows.
+fn shard_key(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let key = sharding::ShardKey::new(&["author_id"]);
+        let posts = sharding::ShardedCollection::<Document>::new(&demo.ns, AUTHORED_POSTS, key).with_lint(true);
+        if demo.caps.topology == Topology::Sharded {
+            posts.shard(&demo.client).await.expect("Unable to shard collection");
+        } else {
+            println!("not sharded: connected to a {:?} server", demo.caps.topology);
+        }
+        let authored: Vec<Document> = ["ann", "bob", "cy"].iter()
+            .flat_map(|author| (0..3).map(move |n| doc! { "author_id": *author, "tags": [format!("tag{}", n)] }))
+            .collect();
+        demo.ns.collection::<Document>(AUTHORED_POSTS).insert_many(authored, None).await
+            .expect("Unable to insert posts");
+        let anns = posts.find(&doc! { "author_id": "ann" }, doc! { "tags": "tag1" }).await
+            .expect("Unable to find posts");
+        assert_eq!(anns.len(), 1);
+        let by_author = posts.shards_for(&doc! { "author_id": "ann" }).await.expect("Unable to explain query");
+        let by_tag = posts.shards_for(&doc! { "tags": "tag1" }).await.expect("Unable to explain query");
+        println!("by author: {} shard(s), by tag alone: {} shard(s)", by_author, by_tag);
+        let untargeted = posts.count(&doc! {}, doc! { "tags": "tag1" }).await;
+        println!("count without an author: {}", untargeted.expect_err("Untargeted count went through"));
+    }.boxed()
+}
+
+fn remove_authored_posts(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.ns.collection::<Document>(AUTHORED_POSTS).drop(None).await.expect("Unable to clean up posts");
+    }.boxed()
+}
+
+const MERGE_TAGS: [&str; 4] = ["node", "deno", "script", "web"];
+
+/// The merged tag's count only counts the post that had both tags once,
+/// and splitting gives each new tag the full count.
+fn tag_merge(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::Transactions) {
+            println!("skipping tag merge: {}", skip);
+            return;
+        }
+        for post in [
+            Post::new("Merge 1", "Tagged node", &["node"]),
+            Post::new("Merge 2", "Tagged deno", &["deno"]),
+            Post::new("Merge 3", "Tagged both", &["node", "deno"]),
+        ] {
+            transactions::insert_counting_tags(&demo.client, &demo.ns, &post).await
+                .expect("Unable to insert post");
+        }
+        let tag_counts = demo.ns.collection::<Document>(transactions::TAG_COUNTS);
+        let counts = || async {
+            let counts: Vec<Document> = tag_counts.find(doc! { "_id": { "$in": MERGE_TAGS.to_vec() } }, None).await
+                .expect("Unable to read tag counts")
+                .try_collect().await
+                .expect("Unable to collect tag counts");
+            counts.iter()
+                .map(|count| (count.get_str("_id").unwrap_or_default().to_string(), count.get_i32("count").unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+        let merged = transactions::merge_tags(&demo.client, &demo.ns, &["node", "deno"], "script").await
+            .expect("Unable to merge tags");
+        println!("merged node and deno into script on {} posts: {:?}", merged.modified, counts().await);
+        assert_eq!(demo.find_tagged("script").await.len(), 3);
+        let split = transactions::split_tag(&demo.client, &demo.ns, "script", &["script", "web"]).await
+            .expect("Unable to split tag");
+        println!("split script into script and web on {} posts: {:?}", split.modified, counts().await);
+        assert_eq!(demo.find_tagged("web").await.len(), 3);
+    }.boxed()
+}
+
+fn remove_merged_tags(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&MERGE_TAGS).await;
+        demo.ns.collection::<Document>(transactions::TAG_COUNTS)
+            .delete_many(doc! { "_id": { "$in": MERGE_TAGS.to_vec() } }, None).await
+            .expect("Unable to clean up tag counts");
+    }.boxed()
+}
+
+fn comments(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let hello = demo.find_titled("Hello").await;
+        demo.repo.add_comment(hello.id, "ann", "Hello to you too").await.expect("Unable to add comment");
+        demo.repo.add_comment(hello.id, "bob", "Welcome").await.expect("Unable to add comment");
+        let joined = demo.repo.find_posts_with_comments(doc! { "tags": "tag1" }).await
+            .expect("Unable to join comments");
+        for post in &joined {
+            let comments: Vec<_> = post.comments.iter().map(|comment| (&comment.author, &comment.body)).collect();
+            println!("{:?}: {:?}", post.post.title, comments);
+        }
+        assert_eq!(joined.iter().map(|post| post.comments.len()).sum::<usize>(), 2);
+    }.boxed()
+}
+
+fn viral_post(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let hello = demo.find_titled("Hello").await;
+        let total = repository::EMBEDDED_COMMENTS + 5;
+        for n in 0..total {
+            demo.repo.add_comment(hello.id, "ann", &format!("Comment {}", n)).await
+                .expect("Unable to add comment");
+        }
+        let post = demo.find_titled("Hello").await;
+        println!("{} comment ids embedded, overflow: {}", post.comment_ids.len(), post.comments_overflow);
+        assert_eq!(post.comment_count, total as i64);
+        let ids = demo.repo.find_comment_ids(hello.id).await.expect("Unable to read comment ids");
+        let comments = demo.repo.find_comments(hello.id).await.expect("Unable to read comments");
+        assert_eq!(ids, comments.iter().map(|comment| comment.id).collect::<Vec<_>>());
+        println!("{} comment ids read back in order", ids.len());
+    }.boxed()
+}
+
+fn remove_comments(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        let ids: Vec<ObjectId> = demo.find_tagged("tag1").await.iter().map(|post| post.id).collect();
+        demo.ns.collection::<Document>(repository::COMMENT_OVERFLOW)
+            .delete_many(doc! { "post_id": { "$in": &ids } }, None).await
+            .expect("Unable to clean up comment overflow");
+        demo.ns.collection::<Document>(repository::COMMENTS).delete_many(doc! { "post_id": { "$in": ids } }, None).await
+            .expect("Unable to clean up comments");
+        remove_samples(demo).await;
+    }.boxed()
+}
+
+fn quota(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        if let Err(skip) = demo.caps.check(Feature::Transactions) {
+            println!("skipping quota: {}", skip);
+            return;
+        }
+        let insert = |n: u32| async move {
+            let post = Post::new(&format!("Quota {}", n), "Counted against ann", &["quota"]);
+            transactions::insert_within_quota(&demo.client, &demo.ns, "ann", &post, 2).await
+        };
+        let first = insert(1).await.expect("Unable to insert post");
+        insert(2).await.expect("Unable to insert post");
+        let refused = insert(3).await;
+        println!("third post by ann: {:?}", refused.as_ref().map_err(ToString::to_string));
+        assert!(matches!(refused, Err(Error::QuotaExceeded { limit: 2, .. })));
+        transactions::delete_within_quota(&demo.client, &demo.ns, first).await.expect("Unable to delete post");
+        insert(3).await.expect("Unable to insert post once a slot freed up");
+        assert_eq!(demo.find_tagged("quota").await.len(), 2);
+    }.boxed()
+}
+
+fn remove_quota_posts(demo: &Demo) -> BoxFuture<'_, ()> {
+    async move {
+        demo.delete_tagged(&["quota"]).await;
+        demo.ns.collection::<Document>(transactions::AUTHOR_COUNTS).delete_one(doc! { "_id": "ann" }, None).await
+            .expect("Unable to clean up author counts");
+    }.boxed()
+}
@@ -0,0 +1,53 @@
+/// 64-bit FNV-1a. Unlike `std`'s `DefaultHasher` its output is fixed, so
+/// digests can be stored and compared across builds and machines. Not
+/// cryptographic: it detects accidental differences, not tampering.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(bytes: &[u8]) -> String {
+        let mut hasher = Fnv1a::new();
+        hasher.update(bytes);
+        hasher.hex()
+    }
+
+    #[test]
+    fn matches_the_reference_vectors() {
+        assert_eq!(digest(b""), "cbf29ce484222325");
+        assert_eq!(digest(b"a"), "af63dc4c8601ec8c");
+        assert_eq!(digest(b"foobar"), "85944171f73967e8");
+    }
+
+    #[test]
+    fn updates_add_up_to_one_digest_of_everything() {
+        let mut hasher = Fnv1a::default();
+        hasher.update(b"foo");
+        hasher.update(b"bar");
+        assert_eq!(hasher.hex(), digest(b"foobar"));
+    }
+}
@@ -0,0 +1,72 @@
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::{doc, Document};
+
+use crate::capabilities::{Capabilities, Feature};
+use crate::namespace::Namespace;
+
+/// Indexes `setup` creates on `posts`, by their generated names.
+const EXPECTED_INDEXES: &[&str] = &[
+    "tags_1",
+    "title_1",
+    "title_prefixes_1",
+    "title_text_message_text_content.message_text",
+];
+
+struct Check {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+/// Checks the server and the `posts` collection against what the example
+/// needs, prints a pass/fail line per check and returns whether all passed.
+pub async fn run(client: &Client, ns: &Namespace) -> mongodb::error::Result<bool> {
+    let caps = Capabilities::probe(client).await?;
+    let mut checks: Vec<Check> = Feature::ALL.iter()
+        .map(|feature| Check {
+            name: feature.name().to_string(),
+            passed: caps.check(*feature).is_ok(),
+            detail: match caps.check(*feature) {
+                Ok(()) => format!("server is {}", caps.version),
+                Err(unsupported) => unsupported.to_string(),
+            },
+        })
+        .collect();
+
+    let spec = ns.db().list_collections(doc! { "name": ns.name("posts") }, None).await?
+        .try_next().await?;
+    let has_validator = spec.as_ref()
+        .and_then(|spec| spec.options.validator.as_ref())
+        .is_some();
+    checks.push(Check {
+        name: "posts validator".to_string(),
+        passed: has_validator,
+        detail: if spec.is_none() { "collection is missing" } else { "$jsonSchema validator" }.to_string(),
+    });
+
+    let index_names = if spec.is_some() {
+        ns.collection::<Document>("posts").list_index_names().await?
+    } else {
+        Vec::new()
+    };
+    for expected in EXPECTED_INDEXES {
+        checks.push(Check {
+            name: format!("posts index {}", expected),
+            passed: index_names.iter().any(|name| name == expected),
+            detail: String::new(),
+        });
+    }
+
+    for check in &checks {
+        let status = if check.passed { "PASS" } else { "FAIL" };
+        if check.detail.is_empty() {
+            println!("[{}] {}", status, check.name);
+        } else {
+            println!("[{}] {} ({})", status, check.name, check.detail);
+        }
+    }
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    println!("{} of {} checks passed", checks.len() - failed, checks.len());
+    Ok(failed == 0)
+}
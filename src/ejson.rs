@@ -0,0 +1,108 @@
+use std::io::{BufRead, Read, Write};
+
+use mongodb::bson::{self, Bson};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Which Extended JSON to write. Reading takes either.
+///
+/// Canonical keeps every type, e.g. `{"$numberLong": "1"}` for an `i64`, so
+/// a value reads back exactly as it was. Relaxed writes numbers and recent
+/// dates as plain JSON (`1`, `{"$date": "2024-01-01T00:00:00Z"}`), which is
+/// easier to read and edit but reads an `i64` that fits back as an `i32`;
+/// `Post`'s fields don't care, a raw [`Document`](bson::Document) does.
+/// Ids and dates round-trip in both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    Canonical,
+    #[default]
+    Relaxed,
+}
+
+/// `value` as it would be stored, in `mode`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T, mode: Mode) -> Result<serde_json::Value> {
+    let value = bson::to_bson(value)?;
+    Ok(match mode {
+        Mode::Canonical => value.into_canonical_extjson(),
+        Mode::Relaxed => value.into_relaxed_extjson(),
+    })
+}
+
+pub fn to_string<T: Serialize + ?Sized>(value: &T, mode: Mode) -> Result<String> {
+    Ok(to_value(value, mode)?.to_string())
+}
+
+pub fn to_string_pretty<T: Serialize + ?Sized>(value: &T, mode: Mode) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&to_value(value, mode)?)?)
+}
+
+/// `value` as one line of JSON Lines.
+pub fn write_line<T: Serialize + ?Sized>(mut out: impl Write, value: &T, mode: Mode) -> Result<()> {
+    serde_json::to_writer(&mut out, &to_value(value, mode)?)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+pub fn from_value<T: DeserializeOwned>(value: serde_json::Value) -> Result<T> {
+    Ok(bson::from_bson(Bson::try_from(value)?)?)
+}
+
+pub fn from_str<T: DeserializeOwned>(json: &str) -> Result<T> {
+    from_value(serde_json::from_str(json)?)
+}
+
+pub fn from_reader<T: DeserializeOwned>(reader: impl Read) -> Result<T> {
+    from_value(serde_json::from_reader(reader)?)
+}
+
+/// One value from all of stdin.
+pub fn from_stdin<T: DeserializeOwned>() -> Result<T> {
+    from_reader(std::io::stdin().lock())
+}
+
+/// The values of JSON Lines, read lazily and skipping blank lines. A line
+/// that doesn't read comes out as an error naming it.
+pub fn read_lines<T: DeserializeOwned>(reader: impl BufRead) -> impl Iterator<Item = Result<T>> {
+    reader.lines().enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(index, line)| {
+            let value = line.map_err(Into::into).and_then(|line| from_str(&line));
+            value.map_err(|e| format!("line {}: {}", index + 1, e).into())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, DateTime, Document};
+
+    use super::*;
+    use crate::Post;
+
+    #[test]
+    fn posts_round_trip_in_both_modes() {
+        let post = Post {
+            created_at: DateTime::from_millis(1_700_000_000_123),
+            version: 3,
+            ..Post::new("Hello", "World", &["rust"])
+        };
+        for mode in [Mode::Canonical, Mode::Relaxed] {
+            let read: Post = from_str(&to_string(&post, mode).unwrap()).unwrap();
+            assert_eq!((read.id, read.created_at, read.version), (post.id, post.created_at, post.version));
+        }
+        let canonical = to_value(&post, Mode::Canonical).unwrap();
+        assert_eq!(canonical["_id"]["$oid"], post.id.to_hex());
+        assert_eq!(canonical["version"]["$numberLong"], "3");
+        assert_eq!(to_value(&post, Mode::Relaxed).unwrap()["version"], 3);
+    }
+
+    #[test]
+    fn lines_skip_blanks_and_name_the_bad_one() {
+        let input = "{\"a\": {\"$numberLong\": \"1\"}}\n\n{\"a\": 2}\nnot json\n";
+        let read: Vec<Result<Document>> = read_lines(input.as_bytes()).collect();
+        assert_eq!(read[0].as_ref().unwrap(), &doc! { "a": 1_i64 });
+        assert_eq!(read[1].as_ref().unwrap(), &doc! { "a": 2 });
+        assert!(read[2].as_ref().unwrap_err().to_string().starts_with("line 4: "));
+    }
+}
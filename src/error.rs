@@ -0,0 +1,182 @@
+use std::fmt;
+use std::time::Duration;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+
+use crate::PostStatus;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Mongo(mongodb::error::Error),
+    /// No server could be selected or reached, or the connection broke.
+    Connection(mongodb::error::Error),
+    /// A value couldn't be turned into BSON.
+    Serialization(String),
+    NotFound,
+    DuplicateTitle(String),
+    /// The write was rejected by the collection's validator.
+    Validation(String),
+    /// The post changed since it was read at version `expected`.
+    VersionConflict { expected: i64, actual: i64 },
+    /// A stored document doesn't fit the model; `path` points at the field.
+    Decode { id: Option<Bson>, path: String, message: String },
+    IllegalTransition { from: PostStatus, to: PostStatus },
+    /// The operation's deadline passed, before it was sent or on the server.
+    DeadlineExceeded,
+    /// The operation's cancellation token was cancelled, or the server killed it.
+    Cancelled,
+    /// The database looks down; calls fail fast until `retry_in` has passed.
+    CircuitOpen { retry_in: Duration },
+    /// Too many calls in flight; this one waited `waited` for a slot and gave up.
+    Overloaded { waited: Duration },
+    /// A query on a sharded collection left out this field of the shard key.
+    MissingShardKey(String),
+    /// `author` already has the `limit` posts they're allowed.
+    QuotaExceeded { author: String, limit: u32 },
+    /// `user` already has a saved search called `name`.
+    DuplicateSearch { user: String, name: String },
+    /// The query's plan reads all of `collection`, which has more than the
+    /// `limit` documents a [`ScanGuard`](crate::query_guard::ScanGuard) allows.
+    CollectionScan { collection: String, documents: u64, limit: u64 },
+    /// A call to the [Data API](crate::data_api::DataApiRepository) failed or
+    /// was answered with an error.
+    DataApi(String),
+    /// The [offline store](crate::offline::OfflineStore)'s file couldn't be
+    /// read or written.
+    Offline(String),
+    /// A [validator rollout](crate::rollout) can't take this step.
+    Rollout(String),
+    /// `find` was meant to be answered from an index alone, yet read
+    /// `docs_examined` documents.
+    NotCovered { find: Document, docs_examined: i64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Mongo(e) => write!(f, "database error: {}", e),
+            Error::Connection(e) => write!(f, "unable to reach the database: {}", e),
+            Error::Serialization(message) => write!(f, "unable to serialize to BSON: {}", message),
+            Error::NotFound => write!(f, "not found"),
+            Error::DuplicateTitle(title) => write!(f, "a post titled {:?} already exists", title),
+            Error::Validation(message) => write!(f, "document failed validation: {}", message),
+            Error::VersionConflict { expected, actual } => {
+                write!(f, "post was modified concurrently (expected version {}, found {})", expected, actual)
+            }
+            Error::Decode { id, path, message } => match id {
+                Some(id) => write!(f, "document {} doesn't decode at `{}`: {}", id, path, message),
+                None => write!(f, "document doesn't decode at `{}`: {}", path, message),
+            },
+            Error::IllegalTransition { from, to } => {
+                write!(f, "illegal status transition from {} to {}", from.as_str(), to.as_str())
+            }
+            Error::DeadlineExceeded => write!(f, "deadline exceeded"),
+            Error::Cancelled => write!(f, "operation cancelled"),
+            Error::CircuitOpen { retry_in } => {
+                write!(f, "database unavailable, retrying in {}ms", retry_in.as_millis())
+            }
+            Error::Overloaded { waited } => {
+                write!(f, "too many concurrent database calls, gave up after {}ms", waited.as_millis())
+            }
+            Error::MissingShardKey(field) => write!(f, "query doesn't include shard key field `{}`", field),
+            Error::QuotaExceeded { author, limit } => {
+                write!(f, "{} already has the maximum of {} posts", author, limit)
+            }
+            Error::DuplicateSearch { user, name } => write!(f, "{} already has a search named {:?}", user, name),
+            Error::CollectionScan { collection, documents, limit } => write!(f,
+                "query scans all of `{}`, whose {} documents are more than the {} allowed without an index",
+                collection, documents, limit),
+            Error::DataApi(message) => write!(f, "Data API error: {}", message),
+            Error::Offline(message) => write!(f, "offline store error: {}", message),
+            Error::Rollout(message) => write!(f, "validator rollout: {}", message),
+            Error::NotCovered { find, docs_examined } => {
+                write!(f, "{} isn't covered by an index, it read {} documents", find, docs_examined)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Mongo(e) | Error::Connection(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        // MaxTimeMSExpired: the server gave up once `maxTimeMS` ran out
+        if matches!(e.kind.as_ref(), ErrorKind::Command(command_error) if command_error.code == 50) {
+            return Error::DeadlineExceeded;
+        }
+        // Interrupted: `killOp`, from this process or someone else's
+        if matches!(e.kind.as_ref(), ErrorKind::Command(command_error) if command_error.code == 11601) {
+            return Error::Cancelled;
+        }
+        match e.kind.as_ref() {
+            ErrorKind::Io(_) | ErrorKind::ServerSelection { .. } | ErrorKind::ConnectionPoolCleared { .. } => {
+                Error::Connection(e)
+            }
+            _ => Error::Mongo(e),
+        }
+    }
+}
+
+impl From<bson::ser::Error> for Error {
+    fn from(e: bson::ser::Error) -> Self {
+        Error::Serialization(e.to_string())
+    }
+}
+
+impl Error {
+    /// Whether trying again may succeed: the connection failed, or the server
+    /// labelled the error as safe to retry.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Connection(_) => true,
+            Error::Mongo(e) => {
+                e.contains_label(RETRYABLE_WRITE_ERROR) || e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a write that failed this way can be sent again without being
+    /// applied twice: no server was reached for it, or the server labelled
+    /// it retryable. A connection that broke while the write was in flight
+    /// may have been carrying it out, so it's [transient](Error::is_transient)
+    /// but not this.
+    pub fn is_retryable_write(&self) -> bool {
+        match self {
+            Error::Connection(e) | Error::Mongo(e) if e.contains_label(RETRYABLE_WRITE_ERROR) => true,
+            Error::Connection(e) => matches!(e.kind.as_ref(), ErrorKind::ServerSelection { .. }),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `err` is a unique index rejecting a write.
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+/// Whether `err` is the collection's validator rejecting a write.
+pub fn is_validation_error(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 121
+    )
+}
+
+/// Whether `err` is the driver failing to deserialize a returned document.
+pub fn is_decode_error(err: &mongodb::error::Error) -> bool {
+    matches!(err.kind.as_ref(), ErrorKind::BsonDeserialization(_))
+}
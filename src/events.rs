@@ -0,0 +1,246 @@
+use futures::TryStreamExt;
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::Result;
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, ReplaceOptions};
+
+use crate::Post;
+use crate::error::{self, is_duplicate_key};
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+pub const EVENTS: &str = "events";
+pub const SNAPSHOTS: &str = "snapshots";
+
+/// Everything that can happen to a post.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PostEvent {
+    Created { title: String, message: String, tags: Vec<String> },
+    TitleChanged { title: String },
+    TagAdded { tag: String },
+    TagRemoved { tag: String },
+    Deleted,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct StoredEvent {
+    aggregate_id: ObjectId,
+    #[serde(with = "serde_helpers::int")]
+    seq: i64,
+    payload: PostEvent,
+}
+
+/// Latest known state of an aggregate, so loading doesn't have to replay
+/// its whole history. One document per aggregate, keyed by its id.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Snapshot {
+    #[serde(rename = "_id")]
+    aggregate_id: ObjectId,
+    #[serde(with = "serde_helpers::int")]
+    version: i64,
+    state: Option<Post>,
+}
+
+/// Current state of an aggregate together with the sequence number of the
+/// last event applied to it, which is what the next append must expect.
+#[derive(Debug)]
+pub struct Aggregate {
+    pub state: Option<Post>,
+    pub version: i64,
+}
+
+/// Append-only event log keyed by `(aggregate_id, seq)`.
+///
+/// An append checks that the last stored event is the one the writer loaded,
+/// and the unique index on that pair settles the race that check leaves: two
+/// writers that loaded the same version both try to insert `version + 1`,
+/// and only one insert can win.
+///
+/// With [`EventStore::snapshot_every`] set, every n-th event also refreshes the
+/// aggregate's snapshot, and loading replays only the events after it.
+pub struct EventStore {
+    events: Collection<StoredEvent>,
+    snapshots: Collection<Snapshot>,
+    snapshot_every: Option<i64>,
+}
+
+impl EventStore {
+    pub async fn new(ns: &Namespace) -> Result<Self> {
+        let events = ns.collection(EVENTS);
+        let index_model = IndexModel::builder()
+            .keys(doc! { "aggregate_id": 1, "seq": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        events.create_index(index_model, None).await?;
+        Ok(EventStore { events, snapshots: ns.collection(SNAPSHOTS), snapshot_every: None })
+    }
+
+    /// Takes a snapshot whenever an aggregate's version is a multiple of `n`.
+    pub fn snapshot_every(self, n: i64) -> Self {
+        EventStore { snapshot_every: Some(n).filter(|n| *n > 0), ..self }
+    }
+
+    /// Appends `event` after `expected_version` and returns the new version.
+    /// Fails with [`error::Error::VersionConflict`] (see [`is_conflict`]) if
+    /// the aggregate's last event isn't `expected_version`: another writer
+    /// got there first, or the writer skipped ahead of the log.
+    pub async fn append(
+        &self,
+        aggregate_id: ObjectId,
+        expected_version: i64,
+        event: PostEvent,
+    ) -> error::Result<i64> {
+        let conflict = |actual| error::Error::VersionConflict { expected: expected_version, actual };
+        let actual = self.last_seq(aggregate_id).await?;
+        if actual != expected_version {
+            return Err(conflict(actual));
+        }
+        let seq = expected_version + 1;
+        match self.events.insert_one(StoredEvent { aggregate_id, seq, payload: event }, None).await {
+            Ok(_) => {}
+            Err(e) if is_duplicate_key(&e) => return Err(conflict(self.last_seq(aggregate_id).await?)),
+            Err(e) => return Err(e.into()),
+        }
+        if matches!(self.snapshot_every, Some(n) if seq % n == 0) {
+            let aggregate = self.load(aggregate_id).await?;
+            self.save_snapshot(aggregate_id, aggregate).await?;
+        }
+        Ok(seq)
+    }
+
+    /// The `seq` of the aggregate's last event, or 0 before its first.
+    async fn last_seq(&self, aggregate_id: ObjectId) -> Result<i64> {
+        let options = FindOneOptions::builder().sort(doc! { "seq": -1 }).build();
+        let last = self.events.find_one(doc! { "aggregate_id": aggregate_id }, options).await?;
+        Ok(last.map_or(0, |stored| stored.seq))
+    }
+
+    /// Rebuilds an aggregate from its latest snapshot plus the events after it.
+    pub async fn load(&self, aggregate_id: ObjectId) -> Result<Aggregate> {
+        let from = match self.snapshots.find_one(doc! { "_id": aggregate_id }, None).await? {
+            Some(snapshot) => Aggregate { state: snapshot.state, version: snapshot.version },
+            None => Aggregate { state: None, version: 0 },
+        };
+        self.replay(aggregate_id, from).await
+    }
+
+    /// Snapshots are only a shortcut: if a racing writer stores an older one
+    /// over a newer one, loading just replays a few more events.
+    async fn save_snapshot(&self, aggregate_id: ObjectId, aggregate: Aggregate) -> Result<()> {
+        let snapshot = Snapshot { aggregate_id, version: aggregate.version, state: aggregate.state };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.snapshots.replace_one(doc! { "_id": aggregate_id }, snapshot, options).await?;
+        Ok(())
+    }
+
+    /// Applies the events recorded after `from.version` on top of `from`.
+    async fn replay(&self, aggregate_id: ObjectId, from: Aggregate) -> Result<Aggregate> {
+        let options = FindOptions::builder().sort(doc! { "seq": 1 }).build();
+        let mut cursor = self.events
+            .find(doc! { "aggregate_id": aggregate_id, "seq": { "$gt": from.version } }, options)
+            .await?;
+        let mut aggregate = from;
+        while let Some(stored) = cursor.try_next().await? {
+            aggregate.state = apply(aggregate_id, aggregate.state, stored.payload);
+            aggregate.version = stored.seq;
+        }
+        Ok(aggregate)
+    }
+}
+
+/// Whether `err` is [`EventStore::append`] rejecting an append after the
+/// wrong version.
+pub fn is_conflict(err: &error::Error) -> bool {
+    matches!(err, error::Error::VersionConflict { .. })
+}
+
+fn apply(id: ObjectId, state: Option<Post>, event: PostEvent) -> Option<Post> {
+    match (state, event) {
+        (_, PostEvent::Created { title, message, tags }) => {
+            Some(Post { id, title, message, tags, ..Post::new("", "", &[]) })
+        }
+        (Some(post), PostEvent::TitleChanged { title }) => {
+            Some(Post { title_prefixes: Post::title_prefixes(&title), title, ..post })
+        }
+        (Some(mut post), PostEvent::TagAdded { tag }) => {
+            if !post.tags.contains(&tag) {
+                post.tags.push(tag);
+            }
+            Some(post)
+        }
+        (Some(mut post), PostEvent::TagRemoved { tag }) => {
+            post.tags.retain(|t| *t != tag);
+            Some(post)
+        }
+        (_, PostEvent::Deleted) => None,
+        (None, _) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    fn created() -> PostEvent {
+        PostEvent::Created {
+            title: "Sourced".to_string(),
+            message: "body".to_string(),
+            tags: vec!["a".to_string()],
+        }
+    }
+
+    fn replay(id: ObjectId, events: Vec<PostEvent>) -> Option<Post> {
+        events.into_iter().fold(None, |state, event| apply(id, state, event))
+    }
+
+    #[test]
+    fn replaying_builds_the_post_up() {
+        let id = ObjectId::new();
+        let post = replay(id, vec![
+            created(),
+            PostEvent::TitleChanged { title: "Renamed post".to_string() },
+            PostEvent::TagAdded { tag: "b".to_string() },
+            PostEvent::TagAdded { tag: "b".to_string() },
+            PostEvent::TagRemoved { tag: "a".to_string() },
+        ]).unwrap();
+        assert_eq!(post.id, id);
+        assert_eq!(post.title, "Renamed post");
+        assert_eq!(post.title_prefixes, Post::title_prefixes("Renamed post"));
+        assert_eq!(post.tags, ["b"]);
+    }
+
+    #[test]
+    fn events_before_creation_or_after_deletion_are_ignored() {
+        let id = ObjectId::new();
+        assert!(replay(id, vec![PostEvent::TagAdded { tag: "a".to_string() }]).is_none());
+        assert!(replay(id, vec![created(), PostEvent::Deleted, PostEvent::TitleChanged { title: "x".into() }])
+            .is_none());
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn appends_only_follow_the_last_event() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let store = EventStore::new(&Namespace::new(sandbox.database(), "")).await.unwrap().snapshot_every(2);
+        let id = ObjectId::new();
+        assert_eq!(store.append(id, 0, created()).await.unwrap(), 1);
+        assert_eq!(store.append(id, 1, PostEvent::TagAdded { tag: "b".to_string() }).await.unwrap(), 2);
+        let stale = store.append(id, 1, PostEvent::Deleted).await.unwrap_err();
+        assert!(matches!(stale, error::Error::VersionConflict { expected: 1, actual: 2 }));
+        let ahead = store.append(id, 5, PostEvent::Deleted).await.unwrap_err();
+        assert!(matches!(ahead, error::Error::VersionConflict { expected: 5, actual: 2 }));
+        store.append(id, 2, PostEvent::TitleChanged { title: "After snapshot".to_string() }).await.unwrap();
+        let aggregate = store.load(id).await.unwrap();
+        assert_eq!(aggregate.version, 3);
+        let post = aggregate.state.unwrap();
+        assert_eq!((post.title.as_str(), post.tags), ("After snapshot", vec!["a".to_string(), "b".to_string()]));
+    }
+}
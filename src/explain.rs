@@ -0,0 +1,252 @@
+use mongodb::Database;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::options::{FindOptions, Hint};
+
+use crate::error::{Error, Result};
+use crate::ids::number;
+
+/// What the winning plans of an `explain` do, as far as indexes go.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// The index scanned, the first one found when there are several; none
+    /// for a collection scan.
+    pub index: Option<String>,
+    /// Whether a plan has a `SORT` stage, sorting in memory.
+    pub sorts: bool,
+    /// Whether a plan has a `COLLSCAN` stage, reading the whole collection.
+    pub scans: bool,
+    /// Shards the query is sent to: more than one when mongos merges the
+    /// answers of several, 1 when not behind mongos at all.
+    pub shards: usize,
+}
+
+impl Plan {
+    /// Reads an `explain` of a find or an aggregation. Plans turn up in
+    /// several places: under `queryPlanner`, in the `$cursor` stage an
+    /// aggregation starts with, once per shard behind mongos, and one level
+    /// further down in `queryPlan` when slot-based; so every `winningPlan` is
+    /// looked through, and nothing else, as `rejectedPlans` scan all the time.
+    pub fn of(explain: &Document) -> Plan {
+        let mut plan = Plan { index: None, sorts: false, scans: false, shards: 1 };
+        plan.find_winning(explain);
+        // An aggregation through mongos explains its part on each shard
+        if let Ok(shards) = explain.get_document("shards") {
+            plan.shards = shards.len();
+        }
+        plan
+    }
+
+    fn find_winning(&mut self, explain: &Document) {
+        for (key, value) in explain {
+            match value {
+                Bson::Document(winning) if key == "winningPlan" => self.visit(winning),
+                _ if key == "rejectedPlans" => {}
+                Bson::Document(inner) => self.find_winning(inner),
+                Bson::Array(items) => {
+                    items.iter().filter_map(Bson::as_document).for_each(|item| self.find_winning(item));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn visit(&mut self, stage: &Document) {
+        match stage.get_str("stage") {
+            Ok("IXSCAN") if self.index.is_none() => {
+                self.index = stage.get_str("indexName").ok().map(str::to_string);
+            }
+            Ok("SORT") => self.sorts = true,
+            Ok("COLLSCAN") => self.scans = true,
+            Ok("SHARD_MERGE" | "SINGLE_SHARD") => self.shards = stage.get_array("shards").map_or(0, Vec::len),
+            _ => {}
+        }
+        // Input stages, a slot-based plan's `queryPlan`, and each shard's
+        // plan inside the stage merging them
+        for (key, value) in stage {
+            match value {
+                _ if key == "rejectedPlans" => {}
+                Bson::Document(inner) => self.visit(inner),
+                Bson::Array(items) => {
+                    items.iter().filter_map(Bson::as_document).for_each(|item| self.visit(item));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What running a find took, from an `explain` at `executionStats` verbosity.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Execution {
+    pub returned: i64,
+    pub keys_examined: i64,
+    pub docs_examined: i64,
+}
+
+impl Execution {
+    pub fn of(explain: &Document) -> Execution {
+        let stats = explain.get_document("executionStats").ok();
+        let stat = |field: &str| stats.map_or(0, |stats| number(stats.get(field)));
+        Execution {
+            returned: stat("nReturned"),
+            keys_examined: stat("totalKeysExamined"),
+            docs_examined: stat("totalDocsExamined"),
+        }
+    }
+
+    /// Whether the index answered the query alone: documents came back,
+    /// yet none was read. A query returning nothing reads nothing either,
+    /// so it doesn't count.
+    pub fn covered(&self) -> bool {
+        self.returned > 0 && self.docs_examined == 0
+    }
+}
+
+/// The `find` command for `filter` and the options that shape its plan
+/// (projection, sort, skip, limit, hint and collation), so that a query can
+/// be explained with the very options it runs with.
+pub fn find_command(collection: &str, filter: Document, options: &FindOptions) -> Document {
+    let mut find = doc! { "find": collection, "filter": filter };
+    if let Some(projection) = &options.projection {
+        find.insert("projection", projection.clone());
+    }
+    if let Some(sort) = &options.sort {
+        find.insert("sort", sort.clone());
+    }
+    if let Some(skip) = options.skip {
+        find.insert("skip", skip as i64);
+    }
+    if let Some(limit) = options.limit {
+        find.insert("limit", limit);
+    }
+    match &options.hint {
+        Some(Hint::Keys(keys)) => { find.insert("hint", keys.clone()); }
+        Some(Hint::Name(name)) => { find.insert("hint", name.as_str()); }
+        _ => {}
+    }
+    if let Some(collation) = &options.collation {
+        find.insert("collation", bson::to_document(collation).expect("a collation serializes"));
+    }
+    find
+}
+
+/// Runs `find`, a [`find_command`], and reports what it took.
+pub async fn execution(db: &Database, find: Document) -> Result<Execution> {
+    let explain = db.run_command(doc! { "explain": find, "verbosity": "executionStats" }, None).await?;
+    Ok(Execution::of(&explain))
+}
+
+/// Runs `find` and fails with [`Error::NotCovered`] unless it is covered,
+/// as [`Execution::covered`] says. For queries meant to be answered from an
+/// index alone: a field added to their projection, or an index changed under
+/// them, would make them read every document again without anything else
+/// noticing.
+pub async fn ensure_covered(db: &Database, find: Document) -> Result<Execution> {
+    let execution = execution(db, find.clone()).await?;
+    if !execution.covered() {
+        return Err(Error::NotCovered { find, docs_examined: execution.docs_examined });
+    }
+    Ok(execution)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    #[test]
+    fn plans_are_read_from_classic_and_slot_based_explains() {
+        let classic = doc! { "queryPlanner": { "winningPlan": {
+            "stage": "LIMIT",
+            "inputStage": { "stage": "FETCH", "inputStage": {
+                "stage": "IXSCAN", "indexName": "status_1_priority_-1_run_at_1",
+            } },
+        } } };
+        let plan = Plan {
+            index: Some("status_1_priority_-1_run_at_1".to_string()),
+            sorts: false,
+            scans: false,
+            shards: 1,
+        };
+        assert_eq!(Plan::of(&classic), plan);
+        let slot_based = doc! { "queryPlanner": { "winningPlan": { "queryPlan": {
+            "stage": "SORT", "inputStage": { "stage": "IXSCAN", "indexName": "status_1_available_at_1" },
+        } } } };
+        let plan = Plan {
+            index: Some("status_1_available_at_1".to_string()),
+            sorts: true,
+            scans: false,
+            shards: 1,
+        };
+        assert_eq!(Plan::of(&slot_based), plan);
+    }
+
+    #[test]
+    fn collection_scans_are_found_in_every_winning_plan() {
+        let ixscan = doc! { "stage": "FETCH", "inputStage": { "stage": "IXSCAN", "indexName": "tags_1" } };
+        let classic = doc! { "queryPlanner": {
+            "winningPlan": ixscan.clone(),
+            "rejectedPlans": [{ "stage": "COLLSCAN" }],
+        }};
+        assert!(!Plan::of(&classic).scans);
+        let aggregate = doc! { "stages": [
+            { "$cursor": { "queryPlanner": { "winningPlan": { "stage": "PROJECTION_SIMPLE", "inputStage": {
+                "stage": "COLLSCAN",
+            }}}}},
+            { "$sortByCount": "$tags" },
+        ]};
+        assert!(Plan::of(&aggregate).scans);
+        let slot_based = doc! { "queryPlanner": { "winningPlan": { "queryPlan": { "stage": "COLLSCAN" } } } };
+        assert!(Plan::of(&slot_based).scans);
+        let sharded = doc! { "queryPlanner": { "winningPlan": { "stage": "SHARD_MERGE", "shards": [
+            { "shardName": "a", "winningPlan": ixscan.clone() },
+            { "shardName": "b", "winningPlan": ixscan.clone(), "rejectedPlans": [{ "stage": "COLLSCAN" }] },
+        ]}}};
+        let plan = Plan::of(&sharded);
+        assert_eq!((plan.index.as_deref(), plan.scans, plan.shards), (Some("tags_1"), false, 2));
+        let sharded = doc! { "queryPlanner": { "winningPlan": { "stage": "SHARD_MERGE", "shards": [
+            { "shardName": "a", "winningPlan": ixscan },
+            { "shardName": "b", "winningPlan": { "stage": "COLLSCAN" } },
+        ]}}};
+        assert!(Plan::of(&sharded).scans);
+    }
+
+    #[test]
+    fn plans_count_the_shards_they_go_to() {
+        let explain = doc! { "queryPlanner": { "winningPlan": {
+            "stage": "SHARD_MERGE",
+            "shards": [{ "shardName": "a" }, { "shardName": "b" }],
+        }}};
+        assert_eq!(Plan::of(&explain).shards, 2);
+        let explain = doc! { "queryPlanner": { "winningPlan": {
+            "stage": "SINGLE_SHARD",
+            "shards": [{ "shardName": "a", "winningPlan": { "stage": "COLLSCAN" } }],
+        }}};
+        assert_eq!(Plan::of(&explain).shards, 1);
+        let explain = doc! { "queryPlanner": { "winningPlan": { "stage": "COLLSCAN" } } };
+        assert_eq!(Plan::of(&explain).shards, 1);
+        let aggregate = doc! { "splitPipeline": {}, "shards": { "a": { "stages": [] }, "b": { "stages": [] } } };
+        assert_eq!(Plan::of(&aggregate).shards, 2);
+    }
+
+    #[test]
+    fn find_commands_carry_the_options_that_shape_the_plan() {
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .sort(doc! { "_id": -1 })
+            .limit(5)
+            .hint(Hint::Name("author_1__id_-1".to_string()))
+            .batch_size(100)
+            .build();
+        let find = find_command("posts", doc! { "author": "ann" }, &options);
+        assert_eq!(find, doc! {
+            "find": "posts",
+            "filter": { "author": "ann" },
+            "projection": { "_id": 1 },
+            "sort": { "_id": -1 },
+            "limit": 5_i64,
+            "hint": "author_1__id_-1",
+        });
+    }
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, Utc};
+use mongodb::Collection;
+use mongodb::bson::{self, doc};
+use mongodb::options::{FindOneOptions, UpdateOptions};
+
+use crate::PostId;
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// One [`Feed`] per user, keyed by the user.
+pub const FEEDS: &str = "feeds";
+
+/// Events a feed keeps; older ones are dropped as new ones come in.
+pub const FEED_LENGTH: i32 = 50;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    Posted,
+    Commented,
+}
+
+/// Something that happened on a post, as a feed shows it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Activity {
+    pub kind: ActivityKind,
+    pub post_id: PostId,
+    /// Who did it.
+    pub actor: String,
+    #[serde(with = "serde_helpers::chrono_datetime")]
+    pub at: DateTime<Utc>,
+}
+
+/// A user's recent activity: the last [`FEED_LENGTH`] events, oldest first.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Feed {
+    #[serde(rename = "_id")]
+    pub user: String,
+    #[serde(default)]
+    pub events: Vec<Activity>,
+}
+
+/// Recent-activity feeds kept as one bounded array per user: every event is
+/// pushed onto the array with `$slice`, which trims it back to the last
+/// [`FEED_LENGTH`] in the same update. Reading a feed is then a single
+/// lookup by `_id`, with no query over an event log, and no feed document
+/// grows past a known size.
+#[derive(Clone)]
+pub struct Feeds {
+    feeds: Collection<Feed>,
+}
+
+impl Feeds {
+    pub fn new(ns: &Namespace) -> Self {
+        Feeds { feeds: ns.collection(FEEDS) }
+    }
+
+    /// Adds `activity` to `user`'s feed, starting the feed if they have none.
+    pub async fn push(&self, user: &str, activity: &Activity) -> Result<()> {
+        let update = doc! {
+            "$push": { "events": { "$each": [bson::to_bson(activity)?], "$slice": -FEED_LENGTH } },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.feeds.update_one(doc! { "_id": user }, update, options).await?;
+        Ok(())
+    }
+
+    /// `user`'s last [`FEED_LENGTH`] events, newest first; none for a user
+    /// without a feed.
+    pub async fn feed(&self, user_id: &str) -> Result<Vec<Activity>> {
+        let options = FindOneOptions::builder().projection(doc! { "events": 1 }).build();
+        let feed = self.feeds.find_one(doc! { "_id": user_id }, options).await?;
+        let mut events = feed.map(|feed| feed.events).unwrap_or_default();
+        events.reverse();
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn feeds_keep_the_newest_events() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let feeds = Feeds::new(&Namespace::new(sandbox.database(), ""));
+        let start = Utc::now();
+        for n in 0..FEED_LENGTH + 10 {
+            let at = start + chrono::Duration::seconds(n.into());
+            let actor = "ann".to_string();
+            let activity = Activity { kind: ActivityKind::Posted, post_id: PostId::new(), actor, at };
+            feeds.push("bob", &activity).await.unwrap();
+        }
+        let feed = feeds.feed("bob").await.unwrap();
+        assert_eq!(feed.len(), FEED_LENGTH as usize);
+        let newest = start + chrono::Duration::seconds((FEED_LENGTH + 9).into());
+        assert_eq!(feed[0].at.timestamp_millis(), newest.timestamp_millis());
+        assert!(feeds.feed("cy").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{Collection, Database, IndexModel};
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOptions, IndexOptions, UpdateOptions};
+
+use crate::Post;
+use crate::error::{Error, Result};
+use crate::explain::Plan;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// Who follows whom, one [`Follow`] per edge.
+pub const FOLLOWS: &str = "follows";
+
+/// Authors per `$in` in [`Follows::posts_from_followed`]. Every author is
+/// an index range of its own that the server merges, so a user following
+/// thousands costs several queries rather than one with a huge merge.
+pub const IN_BATCH: usize = 200;
+
+/// `follower` follows `followee`. Users are the strings posts carry as
+/// [`Post::author`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Follow {
+    pub follower: String,
+    pub followee: String,
+    #[serde(with = "serde_helpers::chrono_datetime")]
+    pub since: DateTime<Utc>,
+}
+
+/// How the queries for one user run, from [`Follows::plans`].
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct FollowPlans {
+    pub following: Plan,
+    pub followers: Plan,
+    pub posts: Plan,
+}
+
+/// The follower graph, indexed for both directions of an edge: by
+/// `(follower, followee)`, unique, for whom someone follows, and by
+/// `(followee, follower)` for who follows someone. Both lists read only the
+/// index. The posts of followed authors are read through the posts' `(author,
+/// _id)` index (migration 15), newest first without sorting.
+#[derive(Clone)]
+pub struct Follows {
+    db: Database,
+    follows: Collection<Follow>,
+    posts: Collection<Post>,
+}
+
+impl Follows {
+    pub async fn new(ns: &Namespace) -> Result<Self> {
+        let follows = ns.collection::<Follow>(FOLLOWS);
+        let indexes = [
+            IndexModel::builder()
+                .keys(doc! { "follower": 1, "followee": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder().keys(doc! { "followee": 1, "follower": 1 }).build(),
+        ];
+        follows.create_indexes(indexes, None).await?;
+        Ok(Follows { db: ns.db().clone(), follows, posts: ns.collection("posts") })
+    }
+
+    /// Makes `follower` follow `followee`; following someone again changes nothing.
+    pub async fn follow(&self, follower: &str, followee: &str) -> Result<()> {
+        let since = mongodb::bson::DateTime::now();
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.follows.update_one(
+            doc! { "follower": follower, "followee": followee },
+            doc! { "$setOnInsert": { "since": since } },
+            options,
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn unfollow(&self, follower: &str, followee: &str) -> Result<()> {
+        self.follows.delete_one(doc! { "follower": follower, "followee": followee }, None).await?;
+        Ok(())
+    }
+
+    /// Whom `user` follows, in name order.
+    pub async fn following(&self, user: &str) -> Result<Vec<String>> {
+        self.list(doc! { "follower": user }, "followee").await
+    }
+
+    /// Who follows `user`, in name order.
+    pub async fn followers(&self, user: &str) -> Result<Vec<String>> {
+        self.list(doc! { "followee": user }, "follower").await
+    }
+
+    /// `field` of the edges matching `filter`, projected so the index covers it.
+    async fn list(&self, filter: Document, field: &str) -> Result<Vec<String>> {
+        let options = list_options(&filter, field);
+        let edges: Vec<Document> = self.follows.clone_with_type::<Document>().find(filter, options).await?
+            .try_collect().await?;
+        Ok(edges.iter().filter_map(|edge| edge.get_str(field).ok().map(str::to_string)).collect())
+    }
+
+    /// The newest `limit` posts by anyone `user` follows: their names from
+    /// the follows index, then their posts [`IN_BATCH`] authors at a time,
+    /// each batch sorted and limited by the server and the batches merged.
+    /// A `limit` that isn't positive is an [`Error::Validation`].
+    pub async fn posts_from_followed(&self, user: &str, limit: i64) -> Result<Vec<Post>> {
+        check_limit(limit)?;
+        let following = self.following(user).await?;
+        let mut posts = Vec::new();
+        for authors in following.chunks(IN_BATCH) {
+            // One index range per author, merged in `_id` order rather than sorted
+            let options = FindOptions::builder().sort(doc! { "_id": -1 }).limit(limit).build();
+            let batch: Vec<Post> = self.posts.find(doc! { "author": { "$in": authors } }, options).await?
+                .try_collect().await?;
+            posts.extend(batch);
+        }
+        posts.sort_by_key(|post| std::cmp::Reverse(post.id));
+        posts.truncate(limit as usize);
+        Ok(posts)
+    }
+
+    /// [`Follows::posts_from_followed`] in one aggregation: the edges of
+    /// `user`, each joined by `$lookup` with the newest `limit` posts of
+    /// its followee, which the `(author, _id)` index serves per followee.
+    /// One round trip, but every followee is looked up even when most of
+    /// their posts are cut by the final limit. Needs MongoDB 5.0, for
+    /// `localField` together with `pipeline`.
+    pub async fn posts_from_followed_by_lookup(&self, user: &str, limit: i64) -> Result<Vec<Post>> {
+        check_limit(limit)?;
+        let pipeline = vec![
+            doc! { "$match": { "follower": user } },
+            doc! { "$lookup": {
+                "from": self.posts.name(),
+                "localField": "followee",
+                "foreignField": "author",
+                "pipeline": [{ "$sort": { "_id": -1 } }, { "$limit": limit }],
+                "as": "posts",
+            }},
+            doc! { "$unwind": "$posts" },
+            doc! { "$replaceWith": "$posts" },
+            doc! { "$sort": { "_id": -1 } },
+            doc! { "$limit": limit },
+        ];
+        let posts = self.follows.aggregate(pipeline, None).await?.with_type::<Post>().try_collect().await?;
+        Ok(posts)
+    }
+
+    /// The winning plans of the follows lists of `user` and of the first
+    /// batch of posts of whom they follow, to check that none of them scans
+    /// the collection or sorts in memory.
+    pub async fn plans(&self, user: &str) -> Result<FollowPlans> {
+        let following = self.following(user).await?;
+        let authors = &following[..following.len().min(IN_BATCH)];
+        let explain = |collection: &str, filter: Document, sort: Document, projection: Option<Document>| {
+            let mut find = doc! { "find": collection, "filter": filter, "sort": sort };
+            if let Some(projection) = projection {
+                find.insert("projection", projection);
+            }
+            let db = self.db.clone();
+            async move {
+                let explain = db.run_command(doc! { "explain": find, "verbosity": "queryPlanner" }, None).await?;
+                Result::Ok(Plan::of(&explain))
+            }
+        };
+        let follows = self.follows.name();
+        let (following, followers) = (doc! { "follower": user }, doc! { "followee": user });
+        Ok(FollowPlans {
+            following: explain(follows, following.clone(), list_order(&following, "followee"),
+                Some(doc! { "_id": 0, "followee": 1 })).await?,
+            followers: explain(follows, followers.clone(), list_order(&followers, "follower"),
+                Some(doc! { "_id": 0, "follower": 1 })).await?,
+            posts: explain(self.posts.name(), doc! { "author": { "$in": authors } },
+                doc! { "_id": -1 }, None).await?,
+        })
+    }
+}
+
+/// A driver limit of 0 means none, and a negative one a single batch.
+#[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+fn check_limit(limit: i64) -> Result<()> {
+    if limit <= 0 {
+        return Err(Error::Validation(format!("limit must be positive, not {}", limit)));
+    }
+    Ok(())
+}
+
+/// The projection and order of a list of `field` of the edges matching `filter`.
+fn list_options(filter: &Document, field: &str) -> FindOptions {
+    FindOptions::builder()
+        .projection(doc! { "_id": 0, field: 1 })
+        .sort(list_order(filter, field))
+        .build()
+}
+
+/// The order of a list of edges: that of the index whose first field
+/// `filter` matches, so the list is read off it without sorting.
+fn list_order(filter: &Document, field: &str) -> Document {
+    let matched = filter.keys().next().map(String::as_str).unwrap_or_default();
+    doc! { matched: 1, field: 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::explain::find_command;
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn lists_are_ordered_like_the_index_they_read() {
+        assert_eq!(list_order(&doc! { "follower": "ann" }, "followee"), doc! { "follower": 1, "followee": 1 });
+        assert_eq!(list_order(&doc! { "followee": "ann" }, "follower"), doc! { "followee": 1, "follower": 1 });
+    }
+
+    #[test]
+    fn limits_must_be_positive() {
+        assert!(check_limit(1).is_ok());
+        assert!(matches!(check_limit(0), Err(Error::Validation(_))));
+        assert!(matches!(check_limit(-5), Err(Error::Validation(_))));
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn followed_posts_come_from_indexes_both_ways() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        crate::migrations::posts().run(&ns).await.unwrap();
+        let follows = Follows::new(&ns).await.unwrap();
+        let posts: Vec<Post> = ["ann", "bob", "cy", "ann"].iter().enumerate()
+            .map(|(n, author)| Post {
+                author: Some(author.to_string()),
+                ..Post::new(&format!("Post {}", n), "Followed", &["follows"])
+            })
+            .collect();
+        ns.collection::<Post>("posts").insert_many(&posts, None).await.unwrap();
+        follows.follow("dee", "ann").await.unwrap();
+        follows.follow("dee", "bob").await.unwrap();
+        follows.follow("dee", "bob").await.unwrap();
+
+        let titles = |posts: Vec<Post>| posts.into_iter().map(|post| post.title).collect::<Vec<_>>();
+        assert_eq!(titles(follows.posts_from_followed("dee", 2).await.unwrap()), ["Post 3", "Post 1"]);
+        assert_eq!(titles(follows.posts_from_followed_by_lookup("dee", 2).await.unwrap()), ["Post 3", "Post 1"]);
+        assert_eq!(follows.followers("bob").await.unwrap(), ["dee"]);
+
+        let plans = follows.plans("dee").await.unwrap();
+        assert_eq!(plans.following.index.as_deref(), Some("follower_1_followee_1"));
+        assert_eq!(plans.followers.index.as_deref(), Some("followee_1_follower_1"));
+        assert_eq!(plans.posts.index.as_deref(), Some("author_1__id_-1"));
+        assert!(!plans.following.sorts && !plans.followers.sorts && !plans.posts.sorts);
+        let lists = [(doc! { "follower": "dee" }, "followee"), (doc! { "followee": "bob" }, "follower")];
+        for (filter, field) in lists {
+            let options = list_options(&filter, field);
+            let find = find_command(&ns.name(FOLLOWS), filter, &options);
+            crate::explain::ensure_covered(ns.db(), find).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::time::Instant;
+
+use futures::TryStreamExt;
+use futures::future::{BoxFuture, FutureExt};
+use mongodb::Collection;
+use mongodb::bson::{self, doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::FindOptions;
+
+use crate::Post;
+use crate::bench::LatencyReport;
+use crate::error::{Error, Result};
+use crate::ids::number;
+use crate::namespace::Namespace;
+use crate::repository::{PostStore, PostSummary, UpdateSummary};
+
+/// Where [`SplitPosts`] keeps everything of a post but its body.
+pub const HOT_POSTS: &str = "posts_hot";
+
+/// Where [`SplitPosts`] keeps the bodies, under the `_id` of their post.
+pub const POST_BODIES: &str = "post_bodies";
+
+/// The fields of a post that go to the bodies: the message, and what is
+/// derived from it or translates it. Everything else is small and stays hot.
+pub const COLD_FIELDS: [&str; 4] = ["message", "content", "rendered_html", "message_hash"];
+
+/// Posts stored in two collections: one with the small fields that lists,
+/// filters and counts read (title, tags, status, dates, ...), one with the
+/// large [`COLD_FIELDS`]. A list view then only pulls the hot documents into
+/// the cache, so many more of them fit in it than whole posts would.
+///
+/// The methods hide the split: posts go in and come out whole, their body
+/// joined back in with `$lookup`. Bodies are written before and deleted
+/// after their hot document, so a post is never listed without its body; an
+/// interrupted write leaves a body behind at worst, which nothing reads.
+/// Like [`MonthlyPartitions`](crate::partition::MonthlyPartitions), the
+/// collections carry no validator or indexes of their own.
+#[derive(Clone)]
+pub struct SplitPosts {
+    hot: Collection<Document>,
+    bodies: Collection<Document>,
+    /// Prefixed name of the bodies, for `$lookup`.
+    bodies_name: String,
+}
+
+/// `post` as its hot document and its body.
+#[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+fn split(post: &Post) -> Result<(Document, Document)> {
+    let mut hot = bson::to_document(&*post.rendered())?;
+    let mut body = doc! { "_id": post.id };
+    for field in COLD_FIELDS {
+        if let Some(value) = hot.remove(field) {
+            body.insert(field, value);
+        }
+    }
+    Ok((hot, body))
+}
+
+impl SplitPosts {
+    pub fn new(ns: &Namespace, hot: &str, bodies: &str) -> Self {
+        SplitPosts { hot: ns.collection(hot), bodies: ns.collection(bodies), bodies_name: ns.name(bodies) }
+    }
+
+    pub async fn insert(&self, post: &Post) -> Result<ObjectId> {
+        let (hot, body) = split(post)?;
+        self.bodies.insert_one(body, None).await?;
+        self.hot.insert_one(hot, None).await?;
+        Ok(post.id)
+    }
+
+    pub async fn insert_many(&self, posts: &[Post]) -> Result<()> {
+        let (hot, bodies): (Vec<Document>, Vec<Document>) = posts.iter()
+            .map(split)
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        self.bodies.insert_many(bodies, None).await?;
+        self.hot.insert_many(hot, None).await?;
+        Ok(())
+    }
+
+    /// The posts matching `filter` on their hot fields, bodies joined in.
+    async fn find_whole(&self, filter: Document) -> Result<Vec<Post>> {
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$lookup": {
+                "from": &self.bodies_name,
+                "localField": "_id",
+                "foreignField": "_id",
+                "as": "body",
+            }},
+            doc! { "$replaceWith": { "$mergeObjects": [{ "$first": "$body" }, "$$ROOT"] } },
+            doc! { "$unset": "body" },
+        ];
+        let posts = self.hot.aggregate(pipeline, None).await?.with_type::<Post>().try_collect().await?;
+        Ok(posts)
+    }
+
+    /// The post with `id`, or [`Error::NotFound`] if there is none.
+    pub async fn find_by_id(&self, id: ObjectId) -> Result<Post> {
+        self.find_whole(doc! { "_id": id }).await?.pop().ok_or(Error::NotFound)
+    }
+
+    pub async fn find_by_tag(&self, tag: &str) -> Result<Vec<Post>> {
+        self.find_whole(doc! { "tags": tag }).await
+    }
+
+    /// The newest `limit` posts' summaries, from the hot documents alone.
+    pub async fn list_summaries(&self, limit: i64) -> Result<Vec<PostSummary>> {
+        let options = FindOptions::builder()
+            .projection(doc! { "title": 1, "tags": 1 })
+            .sort(doc! { "_id": -1 })
+            .limit(limit)
+            .build();
+        let summaries = self.hot.clone_with_type::<PostSummary>().find(None, options).await?
+            .try_collect().await?;
+        Ok(summaries)
+    }
+
+    /// Retitles every post tagged `tag`; bodies are left alone.
+    pub async fn update_title_by_tag(&self, tag: &str, title: &str) -> Result<UpdateSummary> {
+        let update = doc! {
+            "$set": { "title": title, "title_prefixes": Post::title_prefixes(title) },
+            "$inc": { "version": 1 },
+        };
+        Ok(self.hot.update_many(doc! { "tags": tag }, update, None).await?.into())
+    }
+
+    pub async fn delete_by_tag(&self, tag: &str) -> Result<u64> {
+        let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        let ids: Vec<Document> = self.hot.find(doc! { "tags": tag }, options).await?.try_collect().await?;
+        let ids: Vec<_> = ids.iter().filter_map(|id| id.get("_id").cloned()).collect();
+        let deleted = self.hot.delete_many(doc! { "_id": { "$in": &ids } }, None).await?.deleted_count;
+        self.bodies.delete_many(doc! { "_id": { "$in": ids } }, None).await?;
+        Ok(deleted)
+    }
+
+    /// Deletes the post with `id`, or fails with [`Error::NotFound`] if there is none.
+    pub async fn delete_by_id(&self, id: ObjectId) -> Result<()> {
+        let deleted = self.hot.delete_one(doc! { "_id": id }, None).await?.deleted_count;
+        self.bodies.delete_one(doc! { "_id": id }, None).await?;
+        match deleted {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PostStore for SplitPosts {
+    fn insert<'a>(&'a self, post: &'a Post) -> BoxFuture<'a, Result<ObjectId>> {
+        SplitPosts::insert(self, post).boxed()
+    }
+
+    fn find_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<Post>> {
+        SplitPosts::find_by_id(self, id).boxed()
+    }
+
+    fn find_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<Vec<Post>>> {
+        SplitPosts::find_by_tag(self, tag).boxed()
+    }
+
+    fn update_title_by_tag<'a>(&'a self, tag: &'a str, title: &'a str) -> BoxFuture<'a, Result<UpdateSummary>> {
+        SplitPosts::update_title_by_tag(self, tag, title).boxed()
+    }
+
+    fn delete_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<u64>> {
+        SplitPosts::delete_by_tag(self, tag).boxed()
+    }
+
+    fn delete_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<()>> {
+        SplitPosts::delete_by_id(self, id).boxed()
+    }
+}
+
+/// List-view latency over whole posts and over split ones, from
+/// [`bench`]; `*_bytes` are the uncompressed sizes of what a list view
+/// reads, i.e. of whole posts and of the hot documents.
+#[derive(Debug)]
+pub struct HotColdReport {
+    pub whole: LatencyReport,
+    pub split: LatencyReport,
+    pub whole_bytes: i64,
+    pub hot_bytes: i64,
+}
+
+/// Writes `posts` posts with `message_bytes` long messages both whole and
+/// split into scratch collections, then times `samples` list views of the
+/// newest 20 on each, and drops the collections again. The gap only opens
+/// once the whole posts outgrow the server's cache and the hot documents
+/// don't: below that, both list views are served from memory.
+pub async fn bench(ns: &Namespace, posts: usize, message_bytes: usize, samples: usize) -> Result<HotColdReport> {
+    const BATCH: usize = 1000;
+    let whole = ns.collection::<Post>("hot_cold_bench_whole");
+    let split = SplitPosts::new(ns, "hot_cold_bench_hot", "hot_cold_bench_bodies");
+    let scratch = [ns.name("hot_cold_bench_whole"), ns.name("hot_cold_bench_hot")];
+    whole.drop(None).await?;
+    split.hot.drop(None).await?;
+    split.bodies.drop(None).await?;
+    let message = "x".repeat(message_bytes);
+    for batch_start in (0..posts).step_by(BATCH) {
+        let batch: Vec<Post> = (batch_start..posts.min(batch_start + BATCH))
+            .map(|n| Post::new(&format!("Post {}", n), &message, &["bench"]))
+            .collect();
+        whole.insert_many(&batch, None).await?;
+        split.insert_many(&batch).await?;
+    }
+
+    let options = FindOptions::builder()
+        .projection(doc! { "title": 1, "tags": 1 })
+        .sort(doc! { "_id": -1 })
+        .limit(20)
+        .build();
+    let summaries = whole.clone_with_type::<PostSummary>();
+    let (mut whole_latencies, mut split_latencies) = (Vec::new(), Vec::new());
+    for _ in 0..samples {
+        let started = Instant::now();
+        let _: Vec<PostSummary> = summaries.find(None, options.clone()).await?.try_collect().await?;
+        whole_latencies.push(started.elapsed());
+        let started = Instant::now();
+        split.list_summaries(20).await?;
+        split_latencies.push(started.elapsed());
+    }
+    let mut sizes = Vec::new();
+    for name in &scratch {
+        let stats = ns.db().run_command(doc! { "collStats": name }, None).await?;
+        sizes.push(number(stats.get("size")));
+    }
+    whole.drop(None).await?;
+    split.hot.drop(None).await?;
+    split.bodies.drop(None).await?;
+    Ok(HotColdReport {
+        whole: LatencyReport::from_samples(whole_latencies),
+        split: LatencyReport::from_samples(split_latencies),
+        whole_bytes: sizes[0],
+        hot_bytes: sizes[1],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_take_the_cold_fields_and_nothing_else() {
+        let post = Post::new("Hello", "World", &["rust"]);
+        let (hot, body) = split(&post).unwrap();
+        assert_eq!(body.keys().collect::<Vec<_>>(), ["_id", "message", "rendered_html", "message_hash"]);
+        assert_eq!(body.get_object_id("_id"), Ok(post.id));
+        assert!(COLD_FIELDS.iter().all(|field| !hot.contains_key(field)));
+        assert_eq!(hot.get_str("title"), Ok("Hello"));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Binary, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::options::FindOptions;
+use rand::{Rng, RngCore};
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::repository::Page;
+
+/// Makes `_id` values. Every strategy here starts its ids with the creation
+/// time, so new documents land at the right edge of the `_id` index (cheap
+/// inserts, a small hot set of index pages) and "created since" is an `_id`
+/// range query; they differ in size, type and how they order within one
+/// time unit.
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> Bson;
+
+    /// The smallest id this generator can make at `at` or later, to bound an
+    /// `_id` range by time.
+    fn first_id_at(&self, at: SystemTime) -> Bson;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    /// 12 bytes: seconds, then per-process random and a counter. Ordered to
+    /// the second only, and across processes not even strictly within one.
+    ObjectId,
+    /// 16-byte binary (subtype 4): milliseconds, then 74 random bits, so ids
+    /// made in the same millisecond are in random order.
+    UuidV7,
+    /// 8-byte integer: milliseconds since 2020, a 10-bit node and a 12-bit
+    /// sequence, so strictly increasing per node, but nodes need distinct numbers.
+    Snowflake,
+    /// 26-character string: milliseconds then 80 random bits in Crockford
+    /// base32, sortable as plain text but twice the size of an ObjectId.
+    Ulid,
+    /// The same 128 bits as [`IdStrategy::Ulid`] as 16-byte binary (subtype
+    /// 0), which sorts the same way: BSON compares binaries of one length
+    /// and subtype byte by byte.
+    #[serde(rename = "ulid-binary")]
+    UlidBinary,
+}
+
+impl IdStrategy {
+    pub const ALL: [IdStrategy; 5] = [
+        IdStrategy::ObjectId,
+        IdStrategy::UuidV7,
+        IdStrategy::Snowflake,
+        IdStrategy::Ulid,
+        IdStrategy::UlidBinary,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdStrategy::ObjectId => "objectid",
+            IdStrategy::UuidV7 => "uuidv7",
+            IdStrategy::Snowflake => "snowflake",
+            IdStrategy::Ulid => "ulid",
+            IdStrategy::UlidBinary => "ulid-binary",
+        }
+    }
+
+    pub fn generator(&self) -> Box<dyn IdGenerator> {
+        match self {
+            IdStrategy::ObjectId => Box::new(ObjectIds),
+            IdStrategy::UuidV7 => Box::new(UuidV7s),
+            IdStrategy::Snowflake => Box::new(Snowflakes::new(0)),
+            IdStrategy::Ulid => Box::new(Ulids),
+            IdStrategy::UlidBinary => Box::new(UlidBinaries),
+        }
+    }
+}
+
+impl fmt::Display for IdStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        IdStrategy::ALL.into_iter()
+            .find(|strategy| strategy.as_str() == s)
+            .ok_or_else(|| {
+                format!("unknown id strategy {:?}, try objectid, uuidv7, snowflake, ulid or ulid-binary", s)
+            })
+    }
+}
+
+fn unix_millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+pub struct ObjectIds;
+
+impl IdGenerator for ObjectIds {
+    fn next_id(&self) -> Bson {
+        ObjectId::new().into()
+    }
+
+    fn first_id_at(&self, at: SystemTime) -> Bson {
+        // Round up: an id from earlier in the same second is older than `at`
+        let secs = unix_millis(at).div_ceil(1000) as u32;
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&secs.to_be_bytes());
+        ObjectId::from_bytes(bytes).into()
+    }
+}
+
+pub struct UuidV7s;
+
+impl UuidV7s {
+    fn with_random(millis: u64, random: [u8; 10]) -> Bson {
+        let mut bytes = [0; 16];
+        bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+        bytes[6..].copy_from_slice(&random);
+        bytes[6] = 0x70 | (bytes[6] & 0x0f);
+        bytes[8] = 0x80 | (bytes[8] & 0x3f);
+        Binary { subtype: BinarySubtype::Uuid, bytes: bytes.to_vec() }.into()
+    }
+}
+
+impl IdGenerator for UuidV7s {
+    fn next_id(&self) -> Bson {
+        let mut random = [0; 10];
+        rand::thread_rng().fill_bytes(&mut random);
+        UuidV7s::with_random(unix_millis(SystemTime::now()), random)
+    }
+
+    fn first_id_at(&self, at: SystemTime) -> Bson {
+        UuidV7s::with_random(unix_millis(at), [0; 10])
+    }
+}
+
+/// Start of the snowflake clock, 2020-01-01T00:00:00Z, leaving 41 bits of
+/// milliseconds for about 69 years.
+const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+
+pub struct Snowflakes {
+    node: u64,
+    /// Last millisecond handed out and the sequence within it.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflakes {
+    /// `node` must be unique among the processes writing at the same time;
+    /// only its low 10 bits count.
+    pub fn new(node: u16) -> Self {
+        Snowflakes { node: node as u64 & 0x3ff, last: Mutex::new((0, 0)) }
+    }
+
+    fn compose(millis: u64, node: u64, sequence: u64) -> Bson {
+        Bson::Int64((((millis.saturating_sub(SNOWFLAKE_EPOCH_MS)) << 22) | (node << 12) | sequence) as i64)
+    }
+}
+
+impl IdGenerator for Snowflakes {
+    fn next_id(&self) -> Bson {
+        let mut last = self.last.lock().unwrap();
+        let mut millis = unix_millis(SystemTime::now()).max(last.0);
+        let sequence = if millis == last.0 { last.1 + 1 } else { 0 };
+        let sequence = if sequence > 0xfff {
+            // 4096 ids in one millisecond: borrow the next one
+            millis += 1;
+            0
+        } else {
+            sequence
+        };
+        *last = (millis, sequence);
+        Snowflakes::compose(millis, self.node, sequence)
+    }
+
+    fn first_id_at(&self, at: SystemTime) -> Bson {
+        Snowflakes::compose(unix_millis(at), 0, 0)
+    }
+}
+
+pub struct Ulids;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl Ulids {
+    /// 48 bits of milliseconds, then 80 of randomness.
+    fn value(millis: u64, random: u128) -> u128 {
+        ((millis as u128 & 0xffff_ffff_ffff) << 80) | (random & ((1 << 80) - 1))
+    }
+
+    /// The 128 bits as 26 base32 digits, the first of which only carries 3 bits.
+    pub fn encode(millis: u64, random: u128) -> String {
+        let value = Ulids::value(millis, random);
+        (0..26).rev().map(|digit| CROCKFORD[((value >> (digit * 5)) & 0x1f) as usize] as char).collect()
+    }
+}
+
+impl IdGenerator for Ulids {
+    fn next_id(&self) -> Bson {
+        Ulids::encode(unix_millis(SystemTime::now()), rand::thread_rng().gen()).into()
+    }
+
+    fn first_id_at(&self, at: SystemTime) -> Bson {
+        Ulids::encode(unix_millis(at), 0).into()
+    }
+}
+
+pub struct UlidBinaries;
+
+impl UlidBinaries {
+    pub fn encode(millis: u64, random: u128) -> Bson {
+        let bytes = Ulids::value(millis, random).to_be_bytes().to_vec();
+        Binary { subtype: BinarySubtype::Generic, bytes }.into()
+    }
+}
+
+impl IdGenerator for UlidBinaries {
+    fn next_id(&self) -> Bson {
+        UlidBinaries::encode(unix_millis(SystemTime::now()), rand::thread_rng().gen())
+    }
+
+    fn first_id_at(&self, at: SystemTime) -> Bson {
+        UlidBinaries::encode(unix_millis(at), 0)
+    }
+}
+
+/// Up to `limit` documents of `col` matching `filter` in `_id` order,
+/// starting after `cursor`, the `next_cursor` of the previous page.
+///
+/// With ids from any generator here that is creation order, so this pages
+/// by time on the `_id` index alone: no `created_at` sort key, no compound
+/// `{ created_at, _id }` index and no tie-breaking on equal timestamps, since
+/// `_id` is unique. The order within one time unit is the generator's, e.g.
+/// random for ULIDs made in the same millisecond.
+pub async fn find_page(
+    col: &Collection<Document>,
+    filter: Document,
+    cursor: Option<Bson>,
+    limit: usize,
+) -> Result<Page<Document, Bson>> {
+    let filter = match cursor {
+        Some(cursor) => doc! { "$and": [filter, { "_id": { "$gt": cursor } }] },
+        None => filter,
+    };
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).limit(limit as i64 + 1).build();
+    let documents: Vec<Document> = col.find(filter, options).await?.try_collect().await?;
+    Ok(Page::from_overfetched(documents, limit, |document| {
+        document.get("_id").cloned().unwrap_or(Bson::Null)
+    }))
+}
+
+/// What inserting with one strategy cost, and whether a time range over
+/// `_id` found the right documents.
+#[derive(serde::Serialize, Debug)]
+pub struct IdBenchReport {
+    pub strategy: IdStrategy,
+    pub documents: usize,
+    pub insert_time: Duration,
+    /// Size of the `_id` index after the inserts.
+    pub id_index_bytes: i64,
+    /// Documents inserted in the second half of the run.
+    pub second_half: usize,
+    /// Documents the `_id` range from the middle of the run matched. Equal to
+    /// `second_half` up to the strategy's time resolution.
+    pub range_matched: i64,
+    /// Index keys the range read: about `range_matched` when it was an index
+    /// scan over `_id`, which all of these strategies allow.
+    pub range_keys_examined: i64,
+}
+
+/// Inserts `documents` small documents with ids from `strategy` into a
+/// scratch collection in batches, then reads back the second half of the run
+/// as an `_id` range, and drops the collection again.
+pub async fn bench(ns: &Namespace, strategy: IdStrategy, documents: usize) -> Result<IdBenchReport> {
+    const BATCH: usize = 1000;
+    let name = format!("id_bench_{}", strategy);
+    let col = ns.collection::<Document>(&name);
+    col.drop(None).await?;
+    let generator = strategy.generator();
+    let started = Instant::now();
+    let mut midpoint = None;
+    for batch_start in (0..documents).step_by(BATCH) {
+        if midpoint.is_none() && batch_start >= documents / 2 {
+            midpoint = Some((batch_start, SystemTime::now()));
+        }
+        let batch: Vec<Document> = (batch_start..documents.min(batch_start + BATCH))
+            .map(|n| doc! { "_id": generator.next_id(), "n": n as i64 })
+            .collect();
+        col.insert_many(batch, None).await?;
+    }
+    let insert_time = started.elapsed();
+    let (half, at) = midpoint.unwrap_or((documents, SystemTime::now()));
+
+    let stats = ns.db().run_command(doc! { "collStats": ns.name(&name) }, None).await?;
+    let id_index_bytes = stats.get_document("indexSizes").ok()
+        .map_or(0, |sizes| number(sizes.get("_id_")));
+    let range = doc! { "_id": { "$gte": generator.first_id_at(at) } };
+    let explain = ns.db().run_command(doc! {
+        "explain": { "find": ns.name(&name), "filter": range },
+        "verbosity": "executionStats",
+    }, None).await?;
+    let execution = explain.get_document("executionStats").ok();
+    col.drop(None).await?;
+    Ok(IdBenchReport {
+        strategy,
+        documents,
+        insert_time,
+        id_index_bytes,
+        second_half: documents - half,
+        range_matched: execution.map_or(0, |stats| number(stats.get("nReturned"))),
+        range_keys_examined: execution.map_or(0, |stats| number(stats.get("totalKeysExamined"))),
+    })
+}
+
+/// Server statistics come as whichever number type fits.
+pub(crate) fn number(value: Option<&Bson>) -> i64 {
+    match value {
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulid_encodes_time_first() {
+        assert_eq!(Ulids::encode(0, 0), "00000000000000000000000000");
+        assert_eq!(Ulids::encode(1, 0), "00000000010000000000000000");
+        assert!(Ulids::encode(1_700_000_000_000, u128::MAX) < Ulids::encode(1_700_000_000_001, 0));
+    }
+
+    #[test]
+    fn ulid_binaries_order_like_their_strings() {
+        let pairs = [(5, u128::MAX), (6, 0), (6, 1), (1_700_000_000_000, 42)];
+        for pair in pairs.windows(2) {
+            let ((a_millis, a_random), (b_millis, b_random)) = (pair[0], pair[1]);
+            assert!(Ulids::encode(a_millis, a_random) < Ulids::encode(b_millis, b_random));
+            let bytes = |id: Bson| match id {
+                Bson::Binary(binary) => binary.bytes,
+                other => panic!("expected binary, got {:?}", other),
+            };
+            let (a, b) = (UlidBinaries::encode(a_millis, a_random), UlidBinaries::encode(b_millis, b_random));
+            assert!(bytes(a) < bytes(b));
+        }
+    }
+
+    #[test]
+    fn snowflakes_increase_within_a_millisecond() {
+        let generator = Snowflakes::new(7);
+        let ids: Vec<i64> = (0..10_000).map(|_| generator.next_id().as_i64().unwrap()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn uuid_v7_sets_version_and_variant() {
+        let id = UuidV7s.next_id();
+        let bytes = match &id {
+            Bson::Binary(binary) => &binary.bytes,
+            other => panic!("expected binary, got {:?}", other),
+        };
+        assert_eq!(bytes[6] >> 4, 7);
+        assert_eq!(bytes[8] >> 6, 0b10);
+    }
+
+    #[test]
+    fn strategies_parse_from_their_names() {
+        for strategy in IdStrategy::ALL {
+            assert_eq!(strategy.as_str().parse::<IdStrategy>(), Ok(strategy));
+        }
+        assert!("uuid".parse::<IdStrategy>().is_err());
+    }
+}
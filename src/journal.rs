@@ -0,0 +1,73 @@
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::FindOptions;
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+pub const OP_JOURNAL: &str = "op_journal";
+
+/// A destructive operation the CLI was about to run, recorded before it ran.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct JournalEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// e.g. `delete`, `drop` or `archive`.
+    pub op: String,
+    /// Unprefixed name of the collection operated on.
+    pub collection: String,
+    pub filter: Document,
+    /// Documents matching `filter` when the intent was recorded.
+    #[serde(with = "serde_helpers::int")]
+    pub matched: u64,
+    #[serde(with = "serde_helpers::datetime")]
+    pub at: DateTime,
+    /// Documents actually affected. Missing when the operation failed or was
+    /// interrupted, which is exactly what the journal is there to reveal.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_helpers::optional_int")]
+    pub affected: Option<u64>,
+}
+
+/// Write-ahead journal of destructive operations in `op_journal`: the intent
+/// goes in first, the outcome is filled in afterwards.
+pub struct Journal {
+    ns: Namespace,
+    col: Collection<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(ns: &Namespace) -> Self {
+        Journal { ns: ns.clone(), col: ns.collection(OP_JOURNAL) }
+    }
+
+    /// Counts what `filter` matches in `collection` and records the intent to
+    /// run `op` on it, before anything is touched.
+    pub async fn intend(&self, op: &str, collection: &str, filter: Document) -> Result<JournalEntry> {
+        let matched = self.ns.collection::<Document>(collection).count_documents(filter.clone(), None).await?;
+        let entry = JournalEntry {
+            id: ObjectId::new(),
+            op: op.to_string(),
+            collection: collection.to_string(),
+            filter,
+            matched,
+            at: DateTime::now(),
+            affected: None,
+        };
+        self.col.insert_one(&entry, None).await?;
+        Ok(entry)
+    }
+
+    pub async fn completed(&self, entry: &JournalEntry, affected: u64) -> Result<()> {
+        self.col.update_one(doc! { "_id": entry.id }, doc! { "$set": { "affected": affected as i64 } }, None).await?;
+        Ok(())
+    }
+
+    /// The latest `limit` entries, newest first.
+    pub async fn recent(&self, limit: i64) -> Result<Vec<JournalEntry>> {
+        let options = FindOptions::builder().sort(doc! { "_id": -1 }).limit(limit).build();
+        Ok(self.col.find(None, options).await?.try_collect().await?)
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use mongodb::bson::{Bson, Document};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+
+const MAX_MICROS: u64 = 3_600_000_000;
+
+/// Latency histograms of every command sent, one per repository method.
+///
+/// Commands are told apart by the `op` of their comment (see
+/// [`PostRepository`](crate::repository::PostRepository)); commands without
+/// one, like a cursor's `getMore`, are filed under their command name. Values
+/// are microseconds with three significant digits, so percentiles are exact to
+/// within 0.1%.
+#[derive(Default)]
+pub struct LatencyHistograms {
+    /// Op of every command that started but hasn't finished, by request id.
+    in_flight: Mutex<HashMap<i32, String>>,
+    ops: Mutex<HashMap<String, Histogram<u64>>>,
+}
+
+/// Percentiles of one op, as printed by [`print_summary`] and served at
+/// `/metrics/latency`.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct OpLatency {
+    pub op: String,
+    pub count: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl LatencyHistograms {
+    pub fn new() -> Self {
+        LatencyHistograms::default()
+    }
+
+    pub fn record(&self, op: &str, latency: Duration) {
+        let mut ops = self.ops.lock().unwrap();
+        let histogram = ops.entry(op.to_string())
+            .or_insert_with(|| Histogram::new_with_bounds(1, MAX_MICROS, 3).expect("bounds are valid"));
+        // Anything slower than an hour is counted as an hour
+        histogram.saturating_record(latency.as_micros().clamp(1, MAX_MICROS as u128) as u64);
+    }
+
+    /// One entry per op seen so far, sorted by op.
+    pub fn summary(&self) -> Vec<OpLatency> {
+        let ops = self.ops.lock().unwrap();
+        let mut summary: Vec<OpLatency> = ops.iter()
+            .map(|(op, histogram)| OpLatency {
+                op: op.clone(),
+                count: histogram.len(),
+                p50_us: histogram.value_at_quantile(0.5),
+                p90_us: histogram.value_at_quantile(0.9),
+                p99_us: histogram.value_at_quantile(0.99),
+                max_us: histogram.max(),
+            })
+            .collect();
+        summary.sort_by(|a, b| a.op.cmp(&b.op));
+        summary
+    }
+
+    fn finish(&self, request_id: i32, command_name: &str, duration: Duration) {
+        let op = self.in_flight.lock().unwrap().remove(&request_id);
+        self.record(op.as_deref().unwrap_or(command_name), duration);
+    }
+}
+
+impl CommandEventHandler for LatencyHistograms {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        if let Some(op) = op_of(&event.command) {
+            self.in_flight.lock().unwrap().insert(event.request_id, op);
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finish(event.request_id, &event.command_name, event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finish(event.request_id, &event.command_name, event.duration);
+    }
+}
+
+/// The `op` of a command's comment, i.e. the repository method that sent it.
+pub(crate) fn op_of(command: &Document) -> Option<String> {
+    match command.get("comment") {
+        Some(Bson::Document(comment)) => comment.get_str("op").ok().map(str::to_string),
+        _ => None,
+    }
+}
+
+pub fn print_summary(summary: &[OpLatency]) {
+    if summary.is_empty() {
+        return;
+    }
+    let ms = |us: u64| us as f64 / 1000.0;
+    println!("{:<24} {:>7} {:>9} {:>9} {:>9} {:>9}", "op", "count", "p50 ms", "p90 ms", "p99 ms", "max ms");
+    for op in summary {
+        println!("{:<24} {:>7} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            op.op, op.count, ms(op.p50_us), ms(op.p90_us), ms(op.p99_us), ms(op.max_us));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_has_percentiles_per_op() {
+        let histograms = LatencyHistograms::new();
+        for ms in 1..=100 {
+            histograms.record("find_by_tag", Duration::from_millis(ms));
+        }
+        histograms.record("insert", Duration::from_millis(3));
+        let summary = histograms.summary();
+        assert_eq!(summary.iter().map(|op| op.op.as_str()).collect::<Vec<_>>(), ["find_by_tag", "insert"]);
+        let find = &summary[0];
+        assert_eq!(find.count, 100);
+        // Within the histogram's 0.1% precision
+        assert!(find.p50_us.abs_diff(50_000) <= 50, "p50 was {}", find.p50_us);
+        assert!(find.p99_us.abs_diff(99_000) <= 99, "p99 was {}", find.p99_us);
+        assert!(find.max_us.abs_diff(100_000) <= 100, "max was {}", find.max_us);
+    }
+}
@@ -0,0 +1,340 @@
+//! A MongoDB example: posts with tags stored through [`repository::PostRepository`],
+//! served over HTTP by [`server`] and walked through step by step by [`demo`].
+//! [`mongo_repository::MongoRepository`] is the same pattern for any collection, and
+//! [`scoped_repository::ScopedRepository`] the same limited to one tenant's or owner's documents.
+
+use mongodb::bson::DateTime;
+#[cfg(feature = "tolerant-decoding")]
+use mongodb::bson::Document;
+use mongodb::bson::oid::ObjectId;
+
+pub mod access;
+pub mod admin;
+pub mod advisor;
+pub mod analytics;
+pub mod attachments;
+pub mod attributes;
+pub mod backfill;
+pub mod backup;
+pub mod batch_jobs;
+pub mod bench;
+pub mod bulkhead;
+pub mod cancellation;
+pub mod capabilities;
+pub mod change_bridge;
+pub mod chaos;
+pub mod circuit_breaker;
+pub mod clusters;
+pub mod codec;
+pub mod columnar;
+pub mod config;
+pub mod conflicts;
+pub mod consistency;
+pub mod data_api;
+pub mod deadline;
+pub mod decoding_cursor;
+pub mod demo;
+pub mod digest;
+pub mod doctor;
+pub mod ejson;
+pub mod error;
+pub mod events;
+pub mod explain;
+pub mod feeds;
+pub mod follows;
+pub mod hot_cold;
+pub mod ids;
+pub mod journal;
+pub mod latency;
+pub mod loadgen;
+pub mod migrations;
+pub mod log_sink;
+pub mod metrics;
+pub mod mongo_repository;
+pub mod namespace;
+pub mod notifications;
+pub mod offline;
+pub mod partition;
+pub mod pipeline_lint;
+pub mod projection;
+pub mod queries;
+pub mod query_cache;
+pub mod query_counter;
+pub mod query_guard;
+pub mod related;
+pub mod repository;
+pub mod retry;
+pub mod rollout;
+pub mod saga;
+pub mod sandbox;
+pub mod saved_searches;
+pub mod scheduler;
+pub mod schema;
+pub mod scoped_repository;
+pub mod seed;
+pub mod serde_helpers;
+pub mod sql;
+pub mod server;
+pub mod sharding;
+pub mod storage_quota;
+pub mod telemetry;
+pub mod timelines;
+pub mod title_filter;
+pub mod transactions;
+pub mod transfer;
+pub mod unit_of_work;
+pub mod views;
+pub mod watcher;
+pub mod webhooks;
+
+#[cfg(all(feature = "tolerant-decoding", feature = "strict-decoding"))]
+compile_error!("`tolerant-decoding` and `strict-decoding` are mutually exclusive");
+
+/// Identifier of a post, its `_id`.
+pub type PostId = ObjectId;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "strict-decoding", serde(deny_unknown_fields))]
+pub struct Post {
+    #[serde(rename = "_id")]
+    pub id: PostId,
+    pub title: String,
+    pub message: String,
+    pub tags: Vec<String>,
+    /// Lowercased prefixes of every word in `title`, backing title suggestions.
+    /// Any write that changes `title` has to rewrite this too.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub title_prefixes: Vec<String>,
+    /// Always stored in UTC; convert with a timezone at query time. Posts
+    /// from before it existed get it from migration 16 of
+    /// [`migrations::posts`].
+    #[serde(with = "serde_helpers::datetime")]
+    pub created_at: DateTime,
+    /// Bumped by every write made through the repository, so whole-document
+    /// replaces can detect that they'd overwrite someone else's change.
+    #[serde(default, with = "serde_helpers::int")]
+    pub version: i64,
+    #[serde(default)]
+    pub status: PostStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_helpers::optional_datetime")]
+    pub publish_at: Option<DateTime>,
+    /// Language of `message`; the text index's default applies when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// Translations of `message`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<LocalizedContent>,
+    /// GridFS ids of the post's files; see [`attachments::Attachments`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<ObjectId>,
+    /// Ids of the post's first [`repository::EMBEDDED_COMMENTS`] comments,
+    /// oldest first. The ids of any later ones are in
+    /// [`repository::COMMENT_OVERFLOW`], which `comments_overflow` flags;
+    /// [`repository::PostRepository::find_comment_ids`] reads them all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comment_ids: Vec<ObjectId>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub comments_overflow: bool,
+    /// How many comments the post has, kept by the repository's comment
+    /// writes; see [`repository::PostRepository::comment_count_drift`].
+    #[serde(default, with = "serde_helpers::int")]
+    pub comment_count: i64,
+    /// Metadata of any shape, as `{k, v}` pairs behind one index; see
+    /// [`attributes`] for the filters on it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<attributes::Attribute>,
+    /// Who wrote the post, counted against their quota by
+    /// [`transactions::insert_within_quota`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Only readable by some roles; see [`access::ReadPolicy`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_email: Option<String>,
+    /// `message` rendered from Markdown, so readers don't render it on every
+    /// read. Stale unless `message_hash` is the hash of `message`; the
+    /// repository renders it again on any write where it isn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendered_html: Option<String>,
+    /// [`Post::message_hash`] of the `message` that `rendered_html` was made from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_hash: Option<String>,
+    /// Fields this version doesn't know about (e.g. left behind by older
+    /// versions), kept so writing the post back doesn't silently drop them.
+    #[cfg(feature = "tolerant-decoding")]
+    #[serde(flatten)]
+    pub extra: Document,
+}
+
+/// A comment on a post, stored in `comments` rather than inside the post:
+/// a post's comments can grow without bound and are read separately, while
+/// a document is capped at 16MB and is always read whole.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Comment {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub post_id: PostId,
+    pub author: String,
+    pub body: String,
+    #[serde(with = "serde_helpers::datetime")]
+    pub created_at: DateTime,
+}
+
+/// One translation of a post's message.
+///
+/// Stored as an array of `{ lang, message }` rather than a `{ en, de }` map:
+/// a collection can only have one text index, and an embedded `lang` field is
+/// what lets that single index stem each translation in its own language.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct LocalizedContent {
+    pub lang: String,
+    pub message: String,
+}
+
+impl Post {
+    pub fn new(title: &str, message: &str, tags: &[&str]) -> Post {
+        Post {
+            id: ObjectId::new(),
+            title: title.to_string(),
+            title_prefixes: Post::title_prefixes(title),
+            message: message.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            created_at: DateTime::now(),
+            version: 0,
+            status: PostStatus::Published,
+            publish_at: None,
+            lang: None,
+            content: Vec::new(),
+            attachments: Vec::new(),
+            comment_ids: Vec::new(),
+            comments_overflow: false,
+            comment_count: 0,
+            metadata: Vec::new(),
+            author: None,
+            author_email: None,
+            rendered_html: Some(Post::render_message(message)),
+            message_hash: Some(Post::message_hash(message)),
+            #[cfg(feature = "tolerant-decoding")]
+            extra: Document::new(),
+        }
+    }
+
+    /// `message` as HTML. Markdown only: HTML in the message is escaped
+    /// rather than passed through, so a post can't inject markup.
+    pub fn render_message(message: &str) -> String {
+        let events = pulldown_cmark::Parser::new(message).map(|event| match event {
+            pulldown_cmark::Event::Html(html) | pulldown_cmark::Event::InlineHtml(html) => {
+                pulldown_cmark::Event::Text(html)
+            }
+            event => event,
+        });
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, events);
+        html
+    }
+
+    /// Hex SHA-256 of `message`.
+    pub fn message_hash(message: &str) -> String {
+        use sha2::Digest;
+        sha2::Sha256::digest(message.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// The post with `rendered_html` made from its current `message`:
+    /// borrowed when it already is, rendered again when the hash differs.
+    pub fn rendered(&self) -> std::borrow::Cow<'_, Post> {
+        let hash = Post::message_hash(&self.message);
+        if self.rendered_html.is_some() && self.message_hash.as_deref() == Some(hash.as_str()) {
+            return std::borrow::Cow::Borrowed(self);
+        }
+        std::borrow::Cow::Owned(Post {
+            rendered_html: Some(Post::render_message(&self.message)),
+            message_hash: Some(hash),
+            ..self.clone()
+        })
+    }
+
+    /// Prefixes are capped in length so long words don't bloat the index;
+    /// suggestions stop narrowing after that many characters.
+    pub fn title_prefixes(title: &str) -> Vec<String> {
+        const MAX_PREFIX_LEN: usize = 15;
+        let mut prefixes: Vec<String> = title
+            .split(|c: char| !c.is_alphanumeric())
+            .flat_map(|word| {
+                let word = word.to_lowercase();
+                let ends: Vec<usize> = word.char_indices().map(|(i, c)| i + c.len_utf8()).collect();
+                ends.into_iter().take(MAX_PREFIX_LEN).map(move |end| word[..end].to_string())
+            })
+            .collect();
+        prefixes.sort();
+        prefixes.dedup();
+        prefixes
+    }
+}
+
+/// Lifecycle of a post: Draft → Review → Published → Archived.
+///
+/// Documents written before `status` existed are treated as published.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PostStatus {
+    Draft,
+    Review,
+    #[default]
+    Published,
+    Archived,
+}
+
+impl PostStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PostStatus::Draft => "draft",
+            PostStatus::Review => "review",
+            PostStatus::Published => "published",
+            PostStatus::Archived => "archived",
+        }
+    }
+
+    /// Statuses a post may move to `self` from.
+    pub fn predecessors(self) -> &'static [PostStatus] {
+        match self {
+            PostStatus::Draft => &[],
+            PostStatus::Review => &[PostStatus::Draft],
+            PostStatus::Published => &[PostStatus::Review],
+            PostStatus::Archived => &[PostStatus::Published],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn title_prefixes_cover_every_word_lowercased() {
+        assert_eq!(Post::title_prefixes("Hi Bob"), ["b", "bo", "bob", "h", "hi"]);
+    }
+
+    #[test]
+    fn title_prefixes_are_capped() {
+        let prefixes = Post::title_prefixes(&"a".repeat(40));
+        assert_eq!(prefixes.len(), 15);
+        assert_eq!(prefixes.last().map(String::len), Some(15));
+    }
+
+    #[test]
+    fn messages_render_again_only_when_they_change() {
+        let post = Post::new("Rendered", "Some *emphasis* and <script>x</script>", &[]);
+        assert_eq!(
+            post.rendered_html.as_deref(),
+            Some("<p>Some <em>emphasis</em> and &lt;script&gt;x&lt;/script&gt;</p>\n"),
+        );
+        assert!(matches!(post.rendered(), std::borrow::Cow::Borrowed(_)));
+        let edited = Post { message: "**New**".to_string(), ..post };
+        assert_eq!(edited.rendered().rendered_html.as_deref(), Some("<p><strong>New</strong></p>\n"));
+    }
+
+    #[test]
+    fn published_is_only_reached_from_review() {
+        assert_eq!(PostStatus::Published.predecessors(), [PostStatus::Review]);
+        assert!(PostStatus::Draft.predecessors().is_empty());
+    }
+}
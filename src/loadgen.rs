@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant};
+
+use mongodb::bson::oid::ObjectId;
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+
+use crate::Post;
+use crate::bench::LatencyReport;
+use crate::error::Result;
+use crate::repository::PostRepository;
+
+/// Tag of the posts the generator writes, removed again once it's done.
+pub const LOADGEN_TAG: &str = "loadgen";
+
+/// How many posts reads are spread over.
+const READ_TARGETS: u64 = 100;
+
+/// A mix of operations issued at a fixed rate. The ratios are relative
+/// weights, e.g. 8:1:1 for a read-heavy workload.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub reads: u32,
+    pub writes: u32,
+    pub aggregations: u32,
+    pub ops_per_sec: f64,
+    pub duration: Duration,
+    /// Length of the windows latencies are reported over.
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// One of [`READ_TARGETS`] posts drawn with
+    /// [`PostRepository::sample_by_tag`], so popular tags are read more, as
+    /// they would be; the first page of post summaries when there are none.
+    Read,
+    /// One new post tagged [`LOADGEN_TAG`].
+    Write,
+    /// Every tag with its posts.
+    Aggregation,
+}
+
+impl Op {
+    pub const ALL: [Op; 3] = [Op::Read, Op::Write, Op::Aggregation];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Aggregation => "aggregation",
+        }
+    }
+}
+
+impl Workload {
+    /// One cycle of `reads + writes + aggregations` operations, holding each
+    /// kind in its ratio but spread out rather than bunched together (smooth
+    /// weighted round robin).
+    pub fn cycle(&self) -> Vec<Op> {
+        let weights = [self.reads, self.writes, self.aggregations].map(i64::from);
+        let total: i64 = weights.iter().sum();
+        assert!(total > 0, "a workload needs at least one non-zero ratio");
+        let mut credit = [0; 3];
+        (0..total)
+            .map(|_| {
+                for (credit, weight) in credit.iter_mut().zip(weights) {
+                    *credit += weight;
+                }
+                let next = (0..3).max_by_key(|&i| (credit[i], std::cmp::Reverse(i))).expect("three kinds");
+                credit[next] -= total;
+                Op::ALL[next]
+            })
+            .collect()
+    }
+}
+
+/// Latencies of the operations that finished during one window.
+#[derive(Debug)]
+pub struct Window {
+    /// When the window ended, counted from the start of the run.
+    pub elapsed: Duration,
+    /// One entry per kind that ran at least once.
+    pub latencies: Vec<(Op, LatencyReport)>,
+    pub errors: usize,
+}
+
+/// Issues `workload` against `repo` and hands every finished window to
+/// `on_window`. Operations are started on schedule regardless of how many
+/// are still running, so a slow server shows up as latency rather than as
+/// a lower rate. Failed operations are counted, not retried.
+pub async fn run(repo: &PostRepository, workload: &Workload, mut on_window: impl FnMut(&Window)) -> Result<()> {
+    assert!(workload.ops_per_sec > 0.0, "a workload needs a positive rate");
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / workload.ops_per_sec));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let started = Instant::now();
+    let mut window_started = started;
+    let mut in_flight = JoinSet::new();
+    let mut finished = Vec::new();
+    let cycle = workload.cycle();
+    let mut ops = cycle.iter().copied().cycle();
+    let targets: Vec<ObjectId> = repo.sample_by_tag(READ_TARGETS).await?.into_iter()
+        .map(|sample| sample.post.id)
+        .collect();
+    let mut targets = targets.iter().copied().cycle();
+    while started.elapsed() < workload.duration {
+        ticks.tick().await;
+        let op = ops.next().expect("the cycle repeats forever");
+        let target = if op == Op::Read { targets.next() } else { None };
+        let repo = repo.clone();
+        in_flight.spawn(async move {
+            let issued = Instant::now();
+            let outcome = execute(&repo, op, target).await;
+            (op, issued.elapsed(), outcome.is_ok())
+        });
+        while let Some(done) = in_flight.try_join_next() {
+            finished.push(done.expect("load generator tasks don't panic"));
+        }
+        if window_started.elapsed() >= workload.window {
+            on_window(&summarize(started.elapsed(), std::mem::take(&mut finished)));
+            window_started = Instant::now();
+        }
+    }
+    while let Some(done) = in_flight.join_next().await {
+        finished.push(done.expect("load generator tasks don't panic"));
+    }
+    if !finished.is_empty() {
+        on_window(&summarize(started.elapsed(), finished));
+    }
+    repo.delete_by_tag(LOADGEN_TAG).await?;
+    Ok(())
+}
+
+async fn execute(repo: &PostRepository, op: Op, target: Option<ObjectId>) -> Result<()> {
+    match (op, target) {
+        (Op::Read, Some(id)) => {
+            repo.find_by_id(id).await?;
+        }
+        (Op::Read, None) => {
+            repo.list_summaries(20).await?;
+        }
+        (Op::Write, _) => {
+            let title = format!("Load {}", ObjectId::new().to_hex());
+            repo.insert(&Post::new(&title, "Generated load", &[LOADGEN_TAG])).await?;
+        }
+        (Op::Aggregation, _) => {
+            repo.group_by_tag().await?;
+        }
+    }
+    Ok(())
+}
+
+fn summarize(elapsed: Duration, finished: Vec<(Op, Duration, bool)>) -> Window {
+    let errors = finished.iter().filter(|(_, _, ok)| !ok).count();
+    let latencies = Op::ALL.into_iter()
+        .filter_map(|op| {
+            let samples: Vec<Duration> = finished.iter()
+                .filter(|(kind, _, ok)| *kind == op && *ok)
+                .map(|(_, latency, _)| *latency)
+                .collect();
+            (!samples.is_empty()).then(|| (op, LatencyReport::from_samples(samples)))
+        })
+        .collect();
+    Window { elapsed, latencies, errors }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workload(reads: u32, writes: u32, aggregations: u32) -> Workload {
+        Workload {
+            reads,
+            writes,
+            aggregations,
+            ops_per_sec: 1.0,
+            duration: Duration::ZERO,
+            window: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn cycle_holds_each_kind_in_its_ratio() {
+        let cycle = workload(8, 1, 1).cycle();
+        assert_eq!(cycle.len(), 10);
+        assert_eq!(cycle.iter().filter(|op| **op == Op::Read).count(), 8);
+        assert_eq!(cycle.iter().filter(|op| **op == Op::Write).count(), 1);
+        assert_eq!(cycle.iter().filter(|op| **op == Op::Aggregation).count(), 1);
+    }
+
+    #[test]
+    fn cycle_spreads_kinds_out() {
+        use Op::*;
+        assert_eq!(workload(2, 2, 0).cycle(), [Read, Write, Read, Write]);
+    }
+}
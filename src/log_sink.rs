@@ -0,0 +1,158 @@
+use std::fmt;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{CreateCollectionOptions, CursorType, FindOptions};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::namespace::Namespace;
+
+pub const APP_LOGS: &str = "app_logs";
+/// The capped collection keeps roughly the last 16 MiB of log events.
+const APP_LOGS_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Creates the capped `app_logs` collection unless it already exists.
+pub async fn create_collection(ns: &Namespace) -> mongodb::error::Result<()> {
+    let options = CreateCollectionOptions::builder()
+        .capped(true)
+        .size(APP_LOGS_SIZE)
+        .build();
+    match ns.db().create_collection(ns.name(APP_LOGS), options).await {
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 48) => Ok(()),
+        result => result,
+    }
+}
+
+/// Tracing layer that stores every event as a document in `app_logs`.
+///
+/// Events are handed to a background task over a channel so logging never
+/// waits on the database. Driver command events (emitted by
+/// [`crate::telemetry::CommandTracer`]) are skipped, otherwise writing a log
+/// entry would itself produce log entries.
+pub struct MongoLogLayer {
+    sender: UnboundedSender<Document>,
+}
+
+impl MongoLogLayer {
+    /// Must be called from within the Tokio runtime.
+    pub fn new(ns: &Namespace) -> Self {
+        let col: Collection<Document> = ns.collection(APP_LOGS);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Document>();
+        tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                if let Err(e) = col.insert_one(entry, None).await {
+                    eprintln!("unable to write log entry: {}", e);
+                }
+            }
+        });
+        MongoLogLayer { sender }
+    }
+}
+
+impl<S> Layer<S> for MongoLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target() == crate::telemetry::COMMAND_TARGET {
+            return;
+        }
+        let mut fields = FieldsToDocument::default();
+        event.record(&mut fields);
+        let spans: Vec<Bson> = ctx.event_scope(event)
+            .map(|scope| scope.from_root().map(|span| Bson::String(span.name().to_string())).collect())
+            .unwrap_or_default();
+        let _ = self.sender.send(doc! {
+            "at": DateTime::now(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "message": fields.message,
+            "fields": fields.fields,
+            "spans": spans,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldsToDocument {
+    message: String,
+    fields: Document,
+}
+
+impl Visit for FieldsToDocument {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name(), value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name(), value as i64);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name(), value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.insert(field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// Follows `app_logs` with a tailable cursor, printing entries as they are
+/// written. Runs until interrupted.
+pub async fn tail(ns: &Namespace) -> mongodb::error::Result<()> {
+    let col: Collection<Document> = ns.collection(APP_LOGS);
+    let mut last_seen: Option<Bson> = None;
+    loop {
+        let filter = last_seen.as_ref().map(|id| doc! { "_id": { "$gt": id } });
+        let options = FindOptions::builder()
+            .cursor_type(CursorType::TailableAwait)
+            .max_await_time(Duration::from_secs(5))
+            .build();
+        let mut cursor = col.find(filter, options).await?;
+        while let Some(entry) = cursor.try_next().await? {
+            println!("{}", format_entry(&entry));
+            last_seen = entry.get("_id").cloned();
+        }
+        // A tailable cursor dies when the collection is empty or the cursor
+        // falls behind the capped window; wait a bit and reopen it.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn format_entry(entry: &Document) -> String {
+    let at = entry.get_datetime("at").map(|at| at.to_string()).unwrap_or_default();
+    let spans = entry.get_array("spans")
+        .map(|spans| spans.iter().filter_map(Bson::as_str).collect::<Vec<_>>().join(":"))
+        .unwrap_or_default();
+    format!(
+        "{} {:>5} {}{}{} {} {}",
+        at,
+        entry.get_str("level").unwrap_or_default(),
+        entry.get_str("target").unwrap_or_default(),
+        if spans.is_empty() { "" } else { " " },
+        spans,
+        entry.get_str("message").unwrap_or_default(),
+        entry.get_document("fields").map(|fields| fields.to_string()).unwrap_or_default(),
+    )
+}
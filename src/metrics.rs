@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+
+use crate::latency::{op_of, OpLatency};
+use crate::storage_quota::StorageUsage;
+
+/// Counters of every command sent, by command name and repository method:
+/// how many were sent, how many failed, and how many documents they returned
+/// or wrote. Methods are told apart by their comment like in
+/// [`LatencyHistograms`](crate::latency::LatencyHistograms).
+#[derive(Default)]
+pub struct OpMetrics {
+    /// Op of every command that started but hasn't finished, by request id.
+    in_flight: Mutex<HashMap<i32, String>>,
+    counters: Mutex<BTreeMap<(String, String), Counters>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Counters {
+    commands: u64,
+    errors: u64,
+    documents: u64,
+}
+
+/// The counters of one command sent by one op, e.g. the `insert`s of `import`.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct OpCounters {
+    pub command: String,
+    pub op: String,
+    pub commands: u64,
+    pub errors: u64,
+    pub documents: u64,
+}
+
+impl OpMetrics {
+    pub fn new() -> Self {
+        OpMetrics::default()
+    }
+
+    /// One entry per command and op seen so far, sorted by command, then op.
+    pub fn counters(&self) -> Vec<OpCounters> {
+        self.counters.lock().unwrap().iter()
+            .map(|((command, op), counters)| OpCounters {
+                command: command.clone(),
+                op: op.clone(),
+                commands: counters.commands,
+                errors: counters.errors,
+                documents: counters.documents,
+            })
+            .collect()
+    }
+
+    fn finish(&self, request_id: i32, command_name: &str, update: impl FnOnce(&mut Counters)) {
+        let op = self.in_flight.lock().unwrap().remove(&request_id);
+        let op = op.unwrap_or_else(|| command_name.to_string());
+        let mut counters = self.counters.lock().unwrap();
+        let counters = counters.entry((command_name.to_string(), op)).or_default();
+        counters.commands += 1;
+        update(counters);
+    }
+}
+
+impl CommandEventHandler for OpMetrics {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        if let Some(op) = op_of(&event.command) {
+            self.in_flight.lock().unwrap().insert(event.request_id, op);
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finish(event.request_id, &event.command_name, |counters| {
+            counters.documents += documents(&event.reply);
+        });
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finish(event.request_id, &event.command_name, |counters| counters.errors += 1);
+    }
+}
+
+/// How many documents a command's reply returned (a cursor's batch) or says
+/// it wrote or counted (`n`).
+pub fn documents(reply: &Document) -> u64 {
+    if let Ok(cursor) = reply.get_document("cursor") {
+        let batch = cursor.get_array("firstBatch").or_else(|_| cursor.get_array("nextBatch"));
+        return batch.map_or(0, |batch| batch.len() as u64);
+    }
+    match reply.get("n") {
+        Some(Bson::Int32(n)) => *n as u64,
+        Some(Bson::Int64(n)) => *n as u64,
+        Some(Bson::Double(n)) => *n as u64,
+        _ => 0,
+    }
+}
+
+/// The collection a command is about: the value of its command name for
+/// most commands, `collection` for a `getMore`.
+pub fn collection<'a>(command_name: &str, command: &'a Document) -> Option<&'a str> {
+    match command.get(command_name) {
+        Some(Bson::String(collection)) => Some(collection),
+        _ => command.get_str("collection").ok(),
+    }
+}
+
+/// `counters`, `latency` and, once checked, `storage` in the Prometheus text
+/// format, as served at `/metrics`.
+pub fn prometheus(counters: &[OpCounters], latency: &[OpLatency], storage: Option<&StorageUsage>) -> String {
+    let mut out = String::new();
+    let counter = |out: &mut String, name: &str, help: &str, value: fn(&OpCounters) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for op in counters {
+            let (command, op_name) = (label(&op.command), label(&op.op));
+            let _ = writeln!(out, "{}{{command=\"{}\",op=\"{}\"}} {}", name, command, op_name, value(op));
+        }
+    };
+    counter(&mut out, "mongodb_commands_total", "Commands sent.", |op| op.commands);
+    counter(&mut out, "mongodb_command_errors_total", "Commands that failed.", |op| op.errors);
+    counter(&mut out, "mongodb_documents_total", "Documents returned, written or counted.", |op| op.documents);
+    let name = "mongodb_command_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Command latency.\n# TYPE {} summary", name, name);
+    for op in latency {
+        let op_name = label(&op.op);
+        for (quantile, us) in [("0.5", op.p50_us), ("0.9", op.p90_us), ("0.99", op.p99_us)] {
+            let _ = writeln!(out, "{}{{op=\"{}\",quantile=\"{}\"}} {}", name, op_name, quantile, us as f64 / 1e6);
+        }
+        let _ = writeln!(out, "{}_count{{op=\"{}\"}} {}", name, op_name, op.count);
+    }
+    if let Some(storage) = storage {
+        let gauge = |out: &mut String, name: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+        };
+        gauge(&mut out, "mongodb_storage_used_bytes", "Database storage and indexes on disk.",
+            storage.used_bytes as f64);
+        gauge(&mut out, "mongodb_storage_quota_bytes", "Soft quota on storage.", storage.quota_bytes as f64);
+    }
+    out
+}
+
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+    use crate::storage_quota::QuotaLevel;
+
+    #[test]
+    fn documents_are_counted_from_batches_and_write_results() {
+        assert_eq!(documents(&doc! { "cursor": { "firstBatch": [{}, {}], "id": 0_i64 }, "ok": 1 }), 2);
+        assert_eq!(documents(&doc! { "cursor": { "nextBatch": [{}], "id": 0_i64 }, "ok": 1 }), 1);
+        assert_eq!(documents(&doc! { "n": 3, "ok": 1 }), 3);
+        assert_eq!(documents(&doc! { "ok": 1 }), 0);
+        assert_eq!(collection("find", &doc! { "find": "posts", "filter": {} }), Some("posts"));
+        assert_eq!(collection("getMore", &doc! { "getMore": 7_i64, "collection": "posts" }), Some("posts"));
+    }
+
+    #[test]
+    fn prometheus_has_a_sample_per_command_and_op() {
+        let counters = [OpCounters {
+            command: "find".to_string(),
+            op: "find_by_\"tag\"".to_string(),
+            commands: 3,
+            errors: 1,
+            documents: 20,
+        }];
+        let latency = [OpLatency {
+            op: "insert".to_string(),
+            count: 2,
+            p50_us: 1500,
+            p90_us: 2000,
+            p99_us: 2000,
+            max_us: 2000,
+        }];
+        let text = prometheus(&counters, &latency, None);
+        assert!(text.contains("mongodb_commands_total{command=\"find\",op=\"find_by_\\\"tag\\\"\"} 3\n"));
+        assert!(text.contains("mongodb_command_errors_total{command=\"find\",op=\"find_by_\\\"tag\\\"\"} 1\n"));
+        assert!(text.contains("mongodb_command_duration_seconds{op=\"insert\",quantile=\"0.5\"} 0.0015\n"));
+        assert!(text.contains("mongodb_command_duration_seconds_count{op=\"insert\"} 2\n"));
+        assert!(!text.contains("mongodb_storage"));
+        let level = QuotaLevel::Warning;
+        let storage = StorageUsage { data_bytes: 10, used_bytes: 800, quota_bytes: 1000, level };
+        let text = prometheus(&counters, &latency, Some(&storage));
+        assert!(text.contains("# TYPE mongodb_storage_used_bytes gauge\nmongodb_storage_used_bytes 800\n"));
+        assert!(text.contains("mongodb_storage_quota_bytes 1000\n"));
+    }
+}
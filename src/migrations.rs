@@ -0,0 +1,332 @@
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{
+    CreateCollectionOptions, FindOptions, IndexOptions, UpdateOptions, ValidationAction, ValidationLevel,
+};
+
+use crate::attachments::{ATTACHMENTS, ATTACHMENT_BLOBS};
+use crate::attributes;
+use crate::batch_jobs::{BatchJob, JobKind, BATCH_JOBS};
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::notifications::{DEAD_NOTIFICATIONS, NOTIFICATIONS};
+use crate::repository::{COMMENTS, COMMENT_OVERFLOW};
+use crate::saved_searches::SAVED_SEARCHES;
+use crate::schema;
+use crate::serde_helpers;
+use crate::webhooks::WEBHOOK_DELIVERIES;
+
+/// Applied migrations, one document per migration keyed by its id.
+pub const MIGRATIONS: &str = "_migrations";
+
+type Up = Box<dyn for<'a> Fn(&'a Namespace) -> BoxFuture<'a, Result<()>> + Send + Sync>;
+
+/// One step of schema history. `up` runs against the namespace, whose
+/// [`Namespace::db`] is the database and whose [`Namespace::name`] gives the
+/// prefixed collection names.
+pub struct Migration {
+    pub id: u32,
+    pub name: &'static str,
+    up: Up,
+}
+
+/// What `_migrations` records about an applied migration.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id", with = "serde_helpers::int")]
+    pub id: u32,
+    pub name: String,
+    #[serde(with = "serde_helpers::datetime")]
+    pub applied_at: DateTime,
+    #[serde(with = "serde_helpers::int")]
+    pub took_ms: i64,
+}
+
+/// An ordered list of migrations. Each runs once per namespace: [`Migrations::run`]
+/// skips those `_migrations` already records and applies the rest by id.
+///
+/// A migration is recorded only after it succeeded, so one that fails
+/// halfway runs again from the start next time and has to cope with finding
+/// its own partial work. Two processes migrating at once may both run a
+/// migration; the second to finish fails on recording it.
+#[derive(Default)]
+pub struct Migrations {
+    migrations: Vec<Migration>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Migrations::default()
+    }
+
+    /// Adds a migration; ids have to increase in registration order.
+    pub fn register<F>(mut self, id: u32, name: &'static str, up: F) -> Self
+    where
+        F: for<'a> Fn(&'a Namespace) -> BoxFuture<'a, Result<()>> + Send + Sync + 'static,
+    {
+        if let Some(last) = self.migrations.last() {
+            assert!(id > last.id, "migration {} registered after {}", id, last.id);
+        }
+        self.migrations.push(Migration { id, name, up: Box::new(up) });
+        self
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    /// What `_migrations` records, oldest first.
+    pub async fn applied(&self, ns: &Namespace) -> Result<Vec<AppliedMigration>> {
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        Ok(applied(ns).find(None, options).await?.try_collect().await?)
+    }
+
+    /// Registered migrations not yet applied, in the order they would run.
+    pub async fn pending(&self, ns: &Namespace) -> Result<Vec<&Migration>> {
+        let applied: Vec<u32> = self.applied(ns).await?.iter().map(|migration| migration.id).collect();
+        Ok(self.migrations.iter().filter(|migration| !applied.contains(&migration.id)).collect())
+    }
+
+    /// Applies the pending migrations, stopping at the first that fails.
+    pub async fn run(&self, ns: &Namespace) -> Result<Vec<AppliedMigration>> {
+        let mut done = Vec::new();
+        for migration in self.pending(ns).await? {
+            let started = Instant::now();
+            (migration.up)(ns).await?;
+            let record = AppliedMigration {
+                id: migration.id,
+                name: migration.name.to_string(),
+                applied_at: DateTime::now(),
+                took_ms: started.elapsed().as_millis() as i64,
+            };
+            applied(ns).insert_one(&record, None).await?;
+            tracing::info!(id = migration.id, name = migration.name, "applied migration");
+            done.push(record);
+        }
+        Ok(done)
+    }
+}
+
+fn applied(ns: &Namespace) -> Collection<AppliedMigration> {
+    ns.collection(MIGRATIONS)
+}
+
+/// The history of `posts`, `comments` and `saved_searches`. The end state of `posts` is
+/// [`schema::posts_schema`]: a change there needs a new migration getting
+/// existing databases to it.
+pub fn posts() -> Migrations {
+    Migrations::new()
+        .register(1, "create posts with a validator", |ns| create_posts(ns).boxed())
+        .register(2, "index tags", |ns| async move {
+            let index = IndexModel::builder().keys(doc! { "tags": 1 }).build();
+            ns.collection::<Document>("posts").create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(3, "validate the full post", |ns| update_posts_validator(ns).boxed())
+        .register(4, "index titles, title prefixes and text", |ns| async move {
+            // `create_indexes` leaves alone those that exist with the same definition
+            let indexes = schema::posts_schema().indexes.into_iter()
+                .map(|index| mongodb::bson::from_document(index).map_err(mongodb::error::Error::from))
+                .collect::<std::result::Result<Vec<IndexModel>, _>>()?;
+            ns.collection::<Document>("posts").create_indexes(indexes, None).await?;
+            Ok(())
+        }.boxed())
+        .register(5, "index comments by post", |ns| async move {
+            let index = IndexModel::builder().keys(doc! { "post_id": 1, "created_at": 1 }).build();
+            ns.collection::<Document>(COMMENTS).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(6, "index saved searches by user and name", |ns| async move {
+            let index = IndexModel::builder()
+                .keys(doc! { "user": 1, "name": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            ns.collection::<Document>(SAVED_SEARCHES).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(7, "index webhook deliveries by status and due time", |ns| async move {
+            let index = IndexModel::builder().keys(doc! { "status": 1, "next_attempt_at": 1 }).build();
+            ns.collection::<Document>(WEBHOOK_DELIVERIES).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(8, "index notifications by status and due time", |ns| async move {
+            let index = IndexModel::builder().keys(doc! { "status": 1, "available_at": 1 }).build();
+            ns.collection::<Document>(NOTIFICATIONS).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(9, "queue notifications by priority and run_at", |ns| queue_by_priority(ns).boxed())
+        .register(10, "move backfill checkpoints to batch jobs", |ns| move_backfill_checkpoints(ns).boxed())
+        .register(11, "index attachment blobs by file and thumbnails by original", |ns| async move {
+            let index = IndexModel::builder()
+                .keys(doc! { "file_id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            ns.collection::<Document>(ATTACHMENT_BLOBS).create_index(index, None).await?;
+            let index = IndexModel::builder().keys(doc! { "metadata.thumbnail_of": 1 }).build();
+            let files = format!("{}.files", ATTACHMENTS);
+            ns.collection::<Document>(&files).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(12, "cap embedded comment ids and index their overflow by post", |ns| async move {
+            update_posts_validator(ns).await?;
+            let index = IndexModel::builder().keys(doc! { "post_id": 1, "_id": 1 }).build();
+            ns.collection::<Document>(COMMENT_OVERFLOW).create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(13, "keep comment counts on posts", |ns| update_posts_validator(ns).boxed())
+        .register(14, "validate and index post metadata", |ns| async move {
+            update_posts_validator(ns).await?;
+            ns.collection::<Document>("posts").create_index(attributes::index(), None).await?;
+            Ok(())
+        }.boxed())
+        .register(15, "index posts by author, newest first", |ns| async move {
+            let index = IndexModel::builder().keys(doc! { "author": 1, "_id": -1 }).build();
+            ns.collection::<Document>("posts").create_index(index, None).await?;
+            Ok(())
+        }.boxed())
+        .register(16, "backfill created_at from the id", |ns| async move {
+            // Posts from before `created_at` don't decode without it; the
+            // `_id` holds the second they were inserted
+            ns.collection::<Document>("posts").update_many(
+                doc! { "created_at": { "$exists": false } },
+                vec![doc! { "$set": { "created_at": { "$toDate": "$_id" } } }],
+                None,
+            ).await?;
+            Ok(())
+        }.boxed())
+}
+
+/// Renames `available_at` to `run_at`, and replaces the index by status and
+/// due time with one that also orders by priority, which the claim sorts by.
+async fn queue_by_priority(ns: &Namespace) -> Result<()> {
+    for name in [NOTIFICATIONS, DEAD_NOTIFICATIONS] {
+        ns.collection::<Document>(name).update_many(
+            doc! { "available_at": { "$exists": true } },
+            doc! { "$rename": { "available_at": "run_at" } },
+            None,
+        ).await?;
+    }
+    let notifications = ns.collection::<Document>(NOTIFICATIONS);
+    let index = IndexModel::builder().keys(doc! { "status": 1, "priority": -1, "run_at": 1 }).build();
+    notifications.create_index(index, None).await?;
+    match notifications.drop_index("status_1_available_at_1", None).await {
+        // IndexNotFound, dropped by an earlier run that then failed
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 27) => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Turns each `{ _id, last_id, scanned, updated, conflicts, rejected,
+/// finished }` of `backfill_checkpoints` into a [`BatchJob`], then drops it.
+async fn move_backfill_checkpoints(ns: &Namespace) -> Result<()> {
+    let checkpoints = ns.collection::<Document>("backfill_checkpoints");
+    let jobs = ns.collection::<BatchJob>(BATCH_JOBS);
+    let mut cursor = checkpoints.find(None, None).await?;
+    while let Some(checkpoint) = cursor.try_next().await? {
+        let Ok(name) = checkpoint.get_str("_id") else {
+            continue;
+        };
+        let number = |field: &str| checkpoint.get(field).and_then(Bson::as_i64)
+            .or_else(|| checkpoint.get(field).and_then(Bson::as_i32).map(i64::from))
+            .unwrap_or(0);
+        let mut job = BatchJob::new(name, JobKind::Backfill, None);
+        job.done = number("scanned") as u64;
+        job.checkpoint = checkpoint.get("last_id").filter(|last_id| !matches!(last_id, Bson::Null)).cloned();
+        job.counts = doc! {
+            "updated": number("updated"),
+            "conflicts": number("conflicts"),
+            "rejected": number("rejected"),
+        };
+        if checkpoint.get_bool("finished") == Ok(true) {
+            job.finished_at = Some(job.updated_at);
+        }
+        // Left alone if a run since this version was deployed saved one already
+        let options = UpdateOptions::builder().upsert(true).build();
+        jobs.update_one(doc! { "_id": name }, doc! { "$setOnInsert": mongodb::bson::to_document(&job)? }, options)
+            .await?;
+    }
+    checkpoints.drop(None).await?;
+    Ok(())
+}
+
+/// `posts` as first released: title, message and tags, nothing else checked.
+/// A `posts` made before there were migrations is kept as it is; migration 3
+/// replaces its validator anyway.
+async fn create_posts(ns: &Namespace) -> Result<()> {
+    let options = CreateCollectionOptions::builder()
+        .validator(doc! { "$jsonSchema": {
+            "required": ["title", "message", "tags"],
+            "properties": {
+                "title": { "bsonType": "string" },
+                "message": { "bsonType": "string" },
+                "tags": { "bsonType": "array", "items": { "bsonType": "string" } },
+            },
+        }})
+        .validation_level(ValidationLevel::Strict)
+        .validation_action(ValidationAction::Error)
+        .build();
+    match ns.db().create_collection(ns.name("posts"), options).await {
+        // NamespaceExists
+        Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 48) => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// `collMod` to the validator of [`schema::posts_schema`]. Documents already
+/// stored aren't checked; they only have to pass once they are next written.
+async fn update_posts_validator(ns: &Namespace) -> Result<()> {
+    let options = schema::posts_schema().options;
+    let mut coll_mod = doc! { "collMod": ns.name("posts") };
+    for field in ["validator", "validationLevel", "validationAction"] {
+        if let Some(value) = options.get(field) {
+            coll_mod.insert(field, value.clone());
+        }
+    }
+    ns.db().run_command(coll_mod, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    fn noop(_: &Namespace) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+
+    #[test]
+    fn posts_migrations_are_numbered_in_order() {
+        let ids: Vec<u32> = posts().migrations().iter().map(|migration| migration.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+    }
+
+    #[test]
+    #[should_panic(expected = "migration 2 registered after 3")]
+    fn registering_out_of_order_panics() {
+        Migrations::new().register(3, "third", noop).register(2, "second", noop);
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn migrations_run_once() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let migrations = posts();
+        assert_eq!(migrations.run(&ns).await.unwrap().len(), 16);
+        assert!(migrations.run(&ns).await.unwrap().is_empty());
+        let later = posts().register(100, "later", noop);
+        let pending: Vec<u32> = later.pending(&ns).await.unwrap().iter().map(|migration| migration.id).collect();
+        assert_eq!(pending, [100]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{
+    AggregateOptions, DeleteOptions, FindOneOptions, FindOptions, InsertManyOptions, InsertOneOptions,
+    UpdateModifications, UpdateOptions,
+};
+use mongodb::results::UpdateResult;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{Comment, Post};
+use crate::deadline::Deadline;
+use crate::error::{is_validation_error, Error, Result};
+use crate::namespace::Namespace;
+use crate::repository::{APP_NAME, COMMENTS};
+use crate::retry::RetryPolicy;
+
+/// The operations any collection of `T` needs, for copying into projects
+/// with their own models: [`PostRepository`](crate::repository::PostRepository)
+/// adds what is particular to posts on top of the same conventions.
+///
+/// Like there, every operation is retried by a [`RetryPolicy`], bounded by
+/// an optional [`Deadline`] and carries a comment
+/// `{ app, op: "<collection>.<method>", ctx }`. Rejections by the
+/// collection's validator come back as [`Error::Validation`].
+pub struct MongoRepository<T: Send + Sync> {
+    col: Collection<T>,
+    context: Option<String>,
+    deadline: Option<Deadline>,
+    retry: RetryPolicy,
+}
+
+// Derived `Clone` would need `T: Clone`, which a collection handle doesn't
+impl<T: Send + Sync> Clone for MongoRepository<T> {
+    fn clone(&self) -> Self {
+        MongoRepository {
+            col: self.col.clone(),
+            context: self.context.clone(),
+            deadline: self.deadline,
+            retry: self.retry,
+        }
+    }
+}
+
+/// `posts` without the post-specific queries.
+pub fn posts(ns: &Namespace) -> MongoRepository<Post> {
+    MongoRepository::new(ns, "posts")
+}
+
+pub fn comments(ns: &Namespace) -> MongoRepository<Comment> {
+    MongoRepository::new(ns, COMMENTS)
+}
+
+impl<T> MongoRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+{
+    /// The collection `name`, prefixed by `ns`.
+    pub fn new(ns: &Namespace, name: &str) -> Self {
+        MongoRepository { col: ns.collection(name), context: None, deadline: None, retry: RetryPolicy::default() }
+    }
+
+    pub fn with_context(&self, context: impl Into<String>) -> Self {
+        MongoRepository { context: Some(context.into()), ..self.clone() }
+    }
+
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        MongoRepository { deadline: Some(deadline), ..self.clone() }
+    }
+
+    pub fn with_retry(&self, retry: RetryPolicy) -> Self {
+        MongoRepository { retry, ..self.clone() }
+    }
+
+    pub fn collection(&self) -> &Collection<T> {
+        &self.col
+    }
+
+    async fn retrying<R, F, Fut>(&self, attempt: F) -> Result<R>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        self.retry.run(attempt).await
+    }
+
+    #[allow(clippy::result_large_err)] // same `Result` as every other method here
+    fn max_time(&self) -> Result<Option<Duration>> {
+        match self.deadline {
+            Some(deadline) => deadline.remaining().map(Some).ok_or(Error::DeadlineExceeded),
+            None => Ok(None),
+        }
+    }
+
+    fn comment(&self, op: &str) -> Bson {
+        let mut comment = doc! { "app": APP_NAME, "op": format!("{}.{}", self.col.name(), op) };
+        if let Some(context) = &self.context {
+            comment.insert("ctx", context);
+        }
+        comment.into()
+    }
+
+    /// Inserts `document` and returns its `_id`.
+    pub async fn insert(&self, document: &T) -> Result<Bson> {
+        self.retrying(|| async {
+            self.max_time()?;
+            let options = InsertOneOptions::builder().comment(self.comment("insert")).build();
+            Ok(self.col.insert_one(document, options).await.map_err(write_error)?.inserted_id)
+        }).await
+    }
+
+    /// Inserts `documents` in order, stopping at the first that fails, and
+    /// returns their `_id`s in the same order.
+    pub async fn insert_many(&self, documents: &[T]) -> Result<Vec<Bson>> {
+        self.retrying(|| async {
+            self.max_time()?;
+            let options = InsertManyOptions::builder().comment(self.comment("insert_many")).build();
+            let result = self.col.insert_many(documents, options).await.map_err(write_error)?;
+            let mut ids: Vec<(usize, Bson)> = result.inserted_ids.into_iter().collect();
+            ids.sort_by_key(|(index, _)| *index);
+            Ok(ids.into_iter().map(|(_, id)| id).collect())
+        }).await
+    }
+
+    /// The document with `id`, or [`Error::NotFound`].
+    pub async fn find_one_by_id(&self, id: impl Into<Bson>) -> Result<T> {
+        let id = id.into();
+        self.retrying(|| async {
+            let options = FindOneOptions::builder()
+                .comment_bson(self.comment("find_one_by_id"))
+                .max_time(self.max_time()?)
+                .build();
+            self.col.find_one(doc! { "_id": id.clone() }, options).await?.ok_or(Error::NotFound)
+        }).await
+    }
+
+    pub async fn find(&self, filter: Document) -> Result<Vec<T>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("find"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.col.find(filter.clone(), options).await?.try_collect().await?)
+        }).await
+    }
+
+    /// Applies `update`, an update document or pipeline, to every document
+    /// matching `filter`.
+    pub async fn update(&self, filter: Document, update: impl Into<UpdateModifications>) -> Result<UpdateResult> {
+        let update = update.into();
+        self.retrying(|| async {
+            self.max_time()?;
+            let options = UpdateOptions::builder().comment(self.comment("update")).build();
+            self.col.update_many(filter.clone(), update.clone(), options).await.map_err(write_error)
+        }).await
+    }
+
+    /// Deletes every document matching `filter` and returns how many there were.
+    pub async fn delete(&self, filter: Document) -> Result<u64> {
+        self.retrying(|| async {
+            self.max_time()?;
+            let options = DeleteOptions::builder().comment(self.comment("delete")).build();
+            Ok(self.col.delete_many(filter.clone(), options).await?.deleted_count)
+        }).await
+    }
+
+    /// Runs `pipeline` and reads its results as `U`, which needn't be `T`:
+    /// a `$group` or `$project` changes the shape.
+    pub async fn aggregate_as<U>(&self, pipeline: Vec<Document>) -> Result<Vec<U>>
+    where
+        U: DeserializeOwned + Unpin + Send + Sync,
+    {
+        self.retrying(|| async {
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("aggregate_as"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.col.aggregate(pipeline.clone(), options).await?.with_type::<U>().try_collect().await?)
+        }).await
+    }
+}
+
+fn write_error(e: mongodb::error::Error) -> Error {
+    if is_validation_error(&e) {
+        Error::Validation(e.to_string())
+    } else {
+        e.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn the_same_operations_work_for_posts_and_comments() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let (posts, comments) = (posts(&ns), comments(&ns));
+        let post = Post::new("Generic", "Stored through MongoRepository", &["generic"]);
+        assert_eq!(posts.insert(&post).await.unwrap(), Bson::ObjectId(post.id));
+        let comment = Comment {
+            id: mongodb::bson::oid::ObjectId::new(),
+            post_id: post.id,
+            author: "ann".to_string(),
+            body: "Nice".to_string(),
+            created_at: mongodb::bson::DateTime::now(),
+        };
+        comments.insert_many(std::slice::from_ref(&comment)).await.unwrap();
+        assert_eq!(comments.find(doc! { "post_id": post.id }).await.unwrap(), [comment]);
+        let renamed = doc! { "$set": { "title": "Renamed" } };
+        let updated = posts.update(doc! { "_id": post.id }, renamed).await.unwrap();
+        assert_eq!(updated.modified_count, 1);
+        assert_eq!(posts.find_one_by_id(post.id).await.unwrap().title, "Renamed");
+        #[derive(serde::Deserialize)]
+        struct Count {
+            count: i64,
+        }
+        let counts: Vec<Count> = comments.aggregate_as(vec![doc! { "$count": "count" }]).await.unwrap();
+        assert_eq!(counts[0].count, 1);
+        assert_eq!(comments.delete(doc! { "post_id": post.id }).await.unwrap(), 1);
+        assert!(matches!(posts.find_one_by_id(Bson::Null).await, Err(Error::NotFound)));
+    }
+}
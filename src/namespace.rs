@@ -0,0 +1,45 @@
+use mongodb::{Collection, Database};
+use mongodb::options::CollectionOptions;
+
+/// The database the example works in plus a prefix prepended to every
+/// collection it touches, so it can run inside a shared database (e.g. with
+/// prefix `demo_`) without colliding with real data.
+///
+/// Names given to [`Namespace::collection`] and [`Namespace::name`] are the
+/// unprefixed ones (`"posts"`); anything that names a collection inside a
+/// command or pipeline, like `$lookup` or `$merge`, has to go through
+/// [`Namespace::name`] too.
+#[derive(Clone, Debug)]
+pub struct Namespace {
+    db: Database,
+    prefix: String,
+}
+
+impl Namespace {
+    pub fn new(db: Database, prefix: impl Into<String>) -> Self {
+        Namespace { db, prefix: prefix.into() }
+    }
+
+    /// The same prefix over another handle to a database, e.g. one from a
+    /// differently configured client.
+    pub fn with_database(&self, db: Database) -> Self {
+        Namespace { db, prefix: self.prefix.clone() }
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
+
+    /// The prefixed name of `collection`.
+    pub fn name(&self, collection: &str) -> String {
+        format!("{}{}", self.prefix, collection)
+    }
+
+    pub fn collection<T>(&self, collection: &str) -> Collection<T> {
+        self.db.collection(&self.name(collection))
+    }
+
+    pub fn collection_with_options<T>(&self, collection: &str, options: CollectionOptions) -> Collection<T> {
+        self.db.collection_with_options(&self.name(collection), options)
+    }
+}
@@ -0,0 +1,515 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::TryStreamExt;
+use futures::future::BoxFuture;
+use mongodb::{Client, ClientSession, Collection, Database};
+use mongodb::bson::{doc, oid::ObjectId, DateTime, Document};
+use mongodb::error::Result;
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReplaceOptions, ReturnDocument};
+
+use crate::{Post, PostId};
+use crate::events::PostEvent;
+use crate::explain::Plan;
+use crate::namespace::Namespace;
+use crate::retry::RetryPolicy;
+use crate::serde_helpers;
+use crate::transactions::run_in_txn;
+
+/// Notifications to post authors, written in the same transaction as the
+/// change they are about, and indexed by status, priority and due time
+/// (migration 9) for [`NotificationWorker`]'s claim.
+pub const NOTIFICATIONS: &str = "notifications";
+/// Notifications that failed `max_attempts` times, moved out of
+/// [`NOTIFICATIONS`] so they never hold up the claim query, until an
+/// operator requeues them.
+pub const DEAD_NOTIFICATIONS: &str = "notifications_dlq";
+
+/// How long a claimed notification is left to its worker before another may
+/// take it over, should the first have crashed mid-send.
+const LEASE: Duration = Duration::from_secs(60);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationStatus {
+    /// Not sent yet, or due again at `run_at` after failing.
+    Pending,
+    Sent,
+    /// Failed `max_attempts` times; only found in [`DEAD_NOTIFICATIONS`].
+    Dead,
+}
+
+impl NotificationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationStatus::Pending => "pending",
+            NotificationStatus::Sent => "sent",
+            NotificationStatus::Dead => "dead",
+        }
+    }
+}
+
+impl fmt::Display for NotificationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for NotificationStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        [NotificationStatus::Pending, NotificationStatus::Sent, NotificationStatus::Dead].into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown status {:?}, try pending, sent or dead", s))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// The author's email address.
+    pub to: String,
+    pub post_id: PostId,
+    /// The post's title when the event happened.
+    pub title: String,
+    pub event: PostEvent,
+    pub status: NotificationStatus,
+    /// Of the notifications due, those with the highest priority are sent
+    /// first, and the longest due of those first.
+    #[serde(default, with = "serde_helpers::int")]
+    pub priority: i32,
+    /// Sends started, counted when claimed, so one that keeps crashing its
+    /// worker ends up dead too.
+    #[serde(with = "serde_helpers::int")]
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// When a worker may claim it next.
+    #[serde(with = "serde_helpers::datetime")]
+    pub run_at: DateTime,
+    #[serde(with = "serde_helpers::datetime")]
+    pub created_at: DateTime,
+    #[serde(default, with = "serde_helpers::optional_datetime")]
+    pub sent_at: Option<DateTime>,
+}
+
+impl Notification {
+    /// A pending notification of `event` to `post`'s author, due and
+    /// prioritized as `schedule` says, or none for a post without an email
+    /// address to send it to.
+    pub fn about(post: &Post, event: PostEvent, schedule: Schedule) -> Option<Notification> {
+        let now = DateTime::now();
+        Some(Notification {
+            id: ObjectId::new(),
+            to: post.author_email.clone()?,
+            post_id: post.id,
+            title: post.title.clone(),
+            event,
+            status: NotificationStatus::Pending,
+            priority: schedule.priority,
+            attempts: 0,
+            last_error: None,
+            run_at: DateTime::from_millis(now.timestamp_millis() + schedule.delay.as_millis() as i64),
+            created_at: now,
+            sent_at: None,
+        })
+    }
+
+    pub fn subject(&self) -> String {
+        match &self.event {
+            PostEvent::Created { .. } => format!("Your post \"{}\" is up", self.title),
+            PostEvent::TitleChanged { title } => format!("Your post \"{}\" is now \"{}\"", self.title, title),
+            PostEvent::TagAdded { tag } => format!("Your post \"{}\" was tagged {}", self.title, tag),
+            PostEvent::TagRemoved { tag } => format!("Your post \"{}\" is no longer tagged {}", self.title, tag),
+            PostEvent::Deleted => format!("Your post \"{}\" was deleted", self.title),
+        }
+    }
+}
+
+/// How urgent a queued notification is, and how long it waits before it is
+/// first due.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Schedule {
+    pub priority: i32,
+    pub delay: Duration,
+}
+
+/// Where a [`NotificationWorker`] sends notifications: an email service, a
+/// chat, or just the terminal with [`StdoutSink`]. An error is retried.
+pub trait NotificationSink: Sync {
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, std::result::Result<(), String>>;
+}
+
+/// Prints each notification as an email would read: the console transport,
+/// so it writes to stdout on purpose rather than logging through `tracing`.
+pub struct StdoutSink;
+
+impl NotificationSink for StdoutSink {
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, std::result::Result<(), String>> {
+        async move {
+            println!("To: {}\nSubject: {}\n", notification.to, notification.subject());
+            Ok(())
+        }.boxed()
+    }
+}
+
+async fn queue(
+    notifications: &Collection<Notification>,
+    session: &mut ClientSession,
+    post: &Post,
+    event: PostEvent,
+    schedule: Schedule,
+) -> Result<()> {
+    if let Some(notification) = Notification::about(post, event, schedule) {
+        notifications.insert_one_with_session(notification, None, session).await?;
+    }
+    Ok(())
+}
+
+/// Inserts `post` and queues its author's notification in one transaction:
+/// no notification for an insert that failed, and no insert whose
+/// notification got lost.
+pub async fn insert_notifying(client: &Client, ns: &Namespace, post: &Post, schedule: Schedule) -> Result<()> {
+    let posts = ns.collection::<Post>("posts");
+    let notifications = ns.collection::<Notification>(NOTIFICATIONS);
+    let post = post.rendered().into_owned();
+    run_in_txn(client, |session| {
+        let (posts, notifications, post) = (posts.clone(), notifications.clone(), post.clone());
+        async move {
+            posts.insert_one_with_session(&post, None, session).await?;
+            let event = PostEvent::Created {
+                title: post.title.clone(),
+                message: post.message.clone(),
+                tags: post.tags.clone(),
+            };
+            queue(&notifications, session, &post, event, schedule).await
+        }.boxed()
+    }).await
+}
+
+/// Changes the title of the post with `id`, queueing the notification with
+/// it; whether there was such a post.
+pub async fn retitle_notifying(
+    client: &Client,
+    ns: &Namespace,
+    id: PostId,
+    title: &str,
+    schedule: Schedule,
+) -> Result<bool> {
+    let posts = ns.collection::<Post>("posts");
+    let notifications = ns.collection::<Notification>(NOTIFICATIONS);
+    let update = doc! {
+        "$set": { "title": title, "title_prefixes": Post::title_prefixes(title) },
+        "$inc": { "version": 1 },
+    };
+    let event = PostEvent::TitleChanged { title: title.to_string() };
+    run_in_txn(client, |session| {
+        let (posts, notifications) = (posts.clone(), notifications.clone());
+        let (update, event) = (update.clone(), event.clone());
+        async move {
+            // The post as it was, so the notification names the old title
+            let old = posts.find_one_and_update_with_session(doc! { "_id": id }, update, None, session).await?;
+            let Some(post) = old else {
+                return Ok(false);
+            };
+            queue(&notifications, session, &post, event, schedule).await?;
+            Ok(true)
+        }.boxed()
+    }).await
+}
+
+/// Deletes the post with `id`, queueing the notification with it; whether
+/// there was such a post.
+pub async fn delete_notifying(client: &Client, ns: &Namespace, id: PostId, schedule: Schedule) -> Result<bool> {
+    let posts = ns.collection::<Post>("posts");
+    let notifications = ns.collection::<Notification>(NOTIFICATIONS);
+    run_in_txn(client, |session| {
+        let (posts, notifications) = (posts.clone(), notifications.clone());
+        async move {
+            let deleted = posts.find_one_and_delete_with_session(doc! { "_id": id }, None, session).await?;
+            let Some(post) = deleted else {
+                return Ok(false);
+            };
+            queue(&notifications, session, &post, PostEvent::Deleted, schedule).await?;
+            Ok(true)
+        }.boxed()
+    }).await
+}
+
+/// Claims due notifications and sends them through a [`NotificationSink`],
+/// retrying failures with `policy`'s backoff. One that has been tried
+/// `policy.max_attempts` times is dead-lettered: moved to
+/// [`DEAD_NOTIFICATIONS`], where it stays until requeued.
+///
+/// Any number of workers can run at once. A notification is sent at least
+/// once: a worker that crashes after sending but before recording it leaves
+/// it to be sent again once its lease is up.
+pub struct NotificationWorker {
+    db: Database,
+    notifications: Collection<Notification>,
+    dead: Collection<Notification>,
+    policy: RetryPolicy,
+}
+
+impl NotificationWorker {
+    /// Five attempts over about two and a half minutes.
+    pub const DEFAULT_POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 5,
+        initial_backoff: Duration::from_secs(10),
+        max_backoff: Duration::from_secs(300),
+    };
+
+    pub fn new(ns: &Namespace, policy: RetryPolicy) -> Self {
+        NotificationWorker {
+            db: ns.db().clone(),
+            notifications: ns.collection(NOTIFICATIONS),
+            dead: ns.collection(DEAD_NOTIFICATIONS),
+            policy,
+        }
+    }
+
+    /// Sends what is due every `poll`; only returns on a database error.
+    pub async fn run(&self, sink: &impl NotificationSink, poll: Duration) -> Result<()> {
+        loop {
+            self.send_due(sink).await?;
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    /// Sends every notification due now, and returns how many were tried.
+    pub async fn send_due(&self, sink: &impl NotificationSink) -> Result<usize> {
+        let mut tried = 0;
+        while let Some(notification) = self.claim().await? {
+            tried += 1;
+            let update = match sink.send(&notification).await {
+                Ok(()) => doc! {
+                    "$set": { "status": NotificationStatus::Sent.as_str(), "sent_at": DateTime::now() },
+                },
+                Err(error) if notification.attempts >= self.policy.max_attempts => {
+                    tracing::warn!(notification = %notification.id, "dead-lettering notification: {}", error);
+                    self.dead_letter(notification, error).await?;
+                    continue;
+                }
+                Err(error) => {
+                    tracing::warn!(notification = %notification.id, "sending notification failed: {}", error);
+                    retry_update(&self.policy, notification.attempts, &error, DateTime::now())
+                }
+            };
+            self.notifications.update_one(doc! { "_id": notification.id }, update, None).await?;
+        }
+        Ok(tried)
+    }
+
+    /// The next due notification, leased so no other worker takes it meanwhile.
+    async fn claim(&self) -> Result<Option<Notification>> {
+        let now = DateTime::now();
+        let lease_end = DateTime::from_millis(now.timestamp_millis() + LEASE.as_millis() as i64);
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(claim_order())
+            .return_document(ReturnDocument::After)
+            .build();
+        self.notifications.find_one_and_update(
+            claim_filter(now),
+            doc! { "$set": { "run_at": lease_end }, "$inc": { "attempts": 1 } },
+            options,
+        ).await
+    }
+
+    /// How the server runs the claim query: which index it uses, and
+    /// whether it has to sort what it reads. With migration 9 applied the
+    /// claim should read `status_1_priority_-1_run_at_1` only, sorted.
+    pub async fn claim_plan(&self) -> Result<ClaimPlan> {
+        let find = doc! {
+            "find": self.notifications.name(),
+            "filter": claim_filter(DateTime::now()),
+            "sort": claim_order(),
+            "limit": 1,
+        };
+        let explain = self.db.run_command(doc! { "explain": find, "verbosity": "queryPlanner" }, None).await?;
+        Ok(ClaimPlan::of(&explain))
+    }
+
+    /// Moves `notification` to the dead letters. Copied before it is
+    /// deleted, and by replacing any copy there already, so a worker
+    /// crashing halfway leaves it to be tried and moved again.
+    async fn dead_letter(&self, notification: Notification, error: String) -> Result<()> {
+        let id = notification.id;
+        let dead = Notification { status: NotificationStatus::Dead, last_error: Some(error), ..notification };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.dead.replace_one(doc! { "_id": id }, dead, options).await?;
+        self.notifications.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+
+    /// The latest `limit` dead letters, newest first.
+    pub async fn dead_letters(&self, limit: i64) -> Result<Vec<Notification>> {
+        let options = FindOptions::builder().sort(doc! { "created_at": -1 }).limit(limit).build();
+        self.dead.find(None, options).await?.try_collect().await
+    }
+
+    /// Moves the dead letter with `id`, or every one when `None`, back to
+    /// the queue to be tried `max_attempts` times again, due now. Returns
+    /// how many were requeued.
+    pub async fn requeue(&self, id: Option<ObjectId>) -> Result<u64> {
+        let filter = id.map(|id| doc! { "_id": id });
+        let mut dead = self.dead.find(filter, None).await?;
+        let mut requeued = 0;
+        while let Some(notification) = dead.try_next().await? {
+            let id = notification.id;
+            let pending = Notification {
+                status: NotificationStatus::Pending,
+                attempts: 0,
+                run_at: DateTime::now(),
+                ..notification
+            };
+            // Same order as dead-lettering: a crash in between leaves a copy in both, not in neither
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.notifications.replace_one(doc! { "_id": id }, pending, options).await?;
+            self.dead.delete_one(doc! { "_id": id }, None).await?;
+            requeued += 1;
+        }
+        Ok(requeued)
+    }
+
+    /// The latest `limit` notifications, newest first, only those in
+    /// `status` if given.
+    pub async fn list(&self, status: Option<NotificationStatus>, limit: i64) -> Result<Vec<Notification>> {
+        let filter = status.map(|status| doc! { "status": status.as_str() });
+        let options = FindOptions::builder().sort(doc! { "created_at": -1 }).limit(limit).build();
+        self.notifications.find(filter, options).await?.try_collect().await
+    }
+}
+
+/// The update recording that attempt number `attempts`, not the last,
+/// failed: due again after the policy's backoff.
+fn retry_update(policy: &RetryPolicy, attempts: u32, error: &str, now: DateTime) -> Document {
+    let backoff = policy.backoff(attempts.saturating_sub(1));
+    let run_at = DateTime::from_millis(now.timestamp_millis() + backoff.as_millis() as i64);
+    doc! { "$set": { "last_error": error, "run_at": run_at } }
+}
+
+/// Pending notifications due at `now`.
+fn claim_filter(now: DateTime) -> Document {
+    doc! { "status": NotificationStatus::Pending.as_str(), "run_at": { "$lte": now } }
+}
+
+/// Most urgent first, then longest due. With the status matched exactly,
+/// the `(status, priority, run_at)` index returns due notifications in this
+/// order, so claiming one reads its first entries instead of sorting.
+fn claim_order() -> Document {
+    doc! { "priority": -1, "run_at": 1 }
+}
+
+/// What [`NotificationWorker::claim_plan`] found in the winning plan.
+pub type ClaimPlan = Plan;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::migrations;
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn only_posts_with_an_author_email_notify() {
+        let mut post = Post::new("Quiet", "Nobody to tell", &[]);
+        assert_eq!(Notification::about(&post, PostEvent::Deleted, Schedule::default()), None);
+        post.author_email = Some("ann@example.com".to_string());
+        let event = PostEvent::TitleChanged { title: "Loud".to_string() };
+        let schedule = Schedule { priority: 2, delay: Duration::from_secs(60) };
+        let notification = Notification::about(&post, event, schedule).unwrap();
+        assert_eq!(notification.to, "ann@example.com");
+        assert_eq!(notification.priority, 2);
+        assert_eq!(notification.run_at.timestamp_millis() - notification.created_at.timestamp_millis(), 60_000);
+        assert_eq!(notification.subject(), "Your post \"Quiet\" is now \"Loud\"");
+    }
+
+    #[test]
+    fn failures_back_off() {
+        let policy = NotificationWorker::DEFAULT_POLICY;
+        let now = DateTime::from_millis(1_000_000);
+        let retry = retry_update(&policy, 2, "mailbox full", now);
+        let run_at = DateTime::from_millis(1_020_000);
+        assert_eq!(retry, doc! { "$set": { "last_error": "mailbox full", "run_at": run_at } });
+    }
+
+    struct Failing(AtomicU32);
+
+    impl NotificationSink for Failing {
+        fn send<'a>(&'a self, _: &'a Notification) -> BoxFuture<'a, std::result::Result<(), String>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            async { Err("unreachable".to_string()) }.boxed()
+        }
+    }
+
+    /// Needs a replica set at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn notifications_are_dead_lettered_after_max_attempts() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        migrations::posts().run(&ns).await.unwrap();
+        let post = Post { author_email: Some("ann@example.com".to_string()), ..Post::new("Noted", "Hi", &[]) };
+        insert_notifying(&client, &ns, &post, Schedule::default()).await.unwrap();
+        assert!(delete_notifying(&client, &ns, post.id, Schedule::default()).await.unwrap());
+        assert!(!delete_notifying(&client, &ns, post.id, Schedule::default()).await.unwrap());
+
+        let policy = RetryPolicy { max_attempts: 2, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO };
+        let (worker, sink) = (NotificationWorker::new(&ns, policy), Failing(AtomicU32::new(0)));
+        worker.send_due(&sink).await.unwrap();
+        assert_eq!(sink.0.load(Ordering::SeqCst), 4);
+        assert!(worker.list(None, 10).await.unwrap().is_empty());
+        let dead = worker.dead_letters(10).await.unwrap();
+        assert_eq!(dead.len(), 2);
+        assert!(dead.iter().all(|notification| notification.attempts == 2));
+        assert_eq!(dead[0].last_error.as_deref(), Some("unreachable"));
+        assert_eq!(worker.send_due(&StdoutSink).await.unwrap(), 0);
+
+        assert_eq!(worker.requeue(Some(dead[0].id)).await.unwrap(), 1);
+        assert_eq!(worker.send_due(&StdoutSink).await.unwrap(), 1);
+        assert_eq!(worker.list(Some(NotificationStatus::Sent), 10).await.unwrap().len(), 1);
+        assert_eq!(worker.requeue(None).await.unwrap(), 1);
+        assert!(worker.dead_letters(10).await.unwrap().is_empty());
+    }
+
+    struct Recording(std::sync::Mutex<Vec<String>>);
+
+    impl NotificationSink for Recording {
+        fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, std::result::Result<(), String>> {
+            self.0.lock().unwrap().push(notification.title.clone());
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    /// Needs a replica set at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn urgent_notifications_go_first_and_delayed_ones_wait() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        migrations::posts().run(&ns).await.unwrap();
+        let schedules = [
+            ("Routine", Schedule::default()),
+            ("Urgent", Schedule { priority: 10, ..Schedule::default() }),
+            ("Later", Schedule { priority: 10, delay: Duration::from_secs(3600) }),
+        ];
+        for (title, schedule) in schedules {
+            let post = Post { author_email: Some("ann@example.com".to_string()), ..Post::new(title, "Hi", &[]) };
+            insert_notifying(&client, &ns, &post, schedule).await.unwrap();
+        }
+
+        let (worker, sink) = (NotificationWorker::new(&ns, RetryPolicy::NEVER), Recording(Default::default()));
+        assert_eq!(worker.send_due(&sink).await.unwrap(), 2);
+        assert_eq!(*sink.0.lock().unwrap(), ["Urgent", "Routine"]);
+        let plan = worker.claim_plan().await.unwrap();
+        assert_eq!(plan.index.as_deref(), Some("status_1_priority_-1_run_at_1"));
+        assert!(!plan.sorts);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use futures::future::{BoxFuture, FutureExt};
+use mongodb::Collection;
+use mongodb::bson::{self, doc};
+
+use crate::{Post, PostId};
+use crate::conflicts::{ConflictResolver, Resolution};
+use crate::ejson::{self, Mode};
+use crate::error::{is_duplicate_key, Error, Result};
+use crate::namespace::Namespace;
+use crate::repository::{PostStore, UpdateSummary};
+
+/// Where `--offline` keeps its posts.
+pub const OFFLINE_FILE: &str = "offline.json";
+
+/// What is on disk: the posts, and which of them changed or went away
+/// since the last [`OfflineStore::sync`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+struct Contents {
+    posts: Vec<Post>,
+    changed: BTreeSet<PostId>,
+    deleted: BTreeSet<PostId>,
+    /// Each post as it was when last synced, by hex `_id` since BSON keys
+    /// are strings. The database's has to still have its version for the
+    /// sync to change it there, and conflicts are resolved against it.
+    #[serde(default)]
+    synced: BTreeMap<String, Post>,
+}
+
+/// What [`OfflineStore::sync`] did.
+#[derive(serde::Serialize, Debug, Default, Clone, PartialEq)]
+pub struct OfflineSync {
+    pub written: usize,
+    pub deleted: usize,
+    /// Of the written and deleted, those that conflicted, as resolved.
+    pub resolved: usize,
+    /// Posts changed in the database too since they were last synced, which
+    /// the resolver left pending.
+    pub conflicts: Vec<Conflict>,
+    /// Posts the collection refused, by title, with why; they stay pending.
+    pub failed: Vec<(String, String)>,
+}
+
+/// A post changed or deleted both offline and in the database since its last
+/// sync that was left pending. Resolve it by changing the post offline again
+/// once the database's version is right, or by syncing with a
+/// [`ConflictResolver`] that resolves it.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub id: PostId,
+    pub title: String,
+    /// The version it had at the last sync, `None` if it never was.
+    pub synced: Option<i64>,
+    /// `None` when the database doesn't have it (any more).
+    pub database: Option<i64>,
+    /// Whether the offline change was deleting it.
+    pub deleted: bool,
+}
+
+/// A [`PostStore`] in a JSON file instead of a database, for trying the CLI
+/// without a server, and [`OfflineStore::sync`] to push what was done to
+/// one later.
+///
+/// Every write rewrites the whole file (relaxed Extended JSON), so it only
+/// suits a few thousand posts. Title uniqueness is checked like the unique
+/// index would, case-insensitively; nothing else the collection's validator
+/// checks is.
+pub struct OfflineStore {
+    path: PathBuf,
+    contents: Mutex<Contents>,
+}
+
+#[allow(clippy::result_large_err)] // the same `Result` as the repositories'
+impl OfflineStore {
+    /// The store in `path`, empty if there is no such file yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(json) => ejson::from_str(&json).map_err(|e| offline_error(path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Contents::default(),
+            Err(e) => return Err(offline_error(path, e)),
+        };
+        Ok(OfflineStore { path: path.to_path_buf(), contents: Mutex::new(contents) })
+    }
+
+    /// Posts changed and deleted since the last sync.
+    pub fn pending(&self) -> (usize, usize) {
+        let contents = self.contents.lock().unwrap();
+        (contents.changed.len(), contents.deleted.len())
+    }
+
+    /// Writes to `file`'s sibling first and renames it over, so a crash
+    /// halfway leaves the last complete version.
+    fn save(&self, contents: &Contents) -> Result<()> {
+        let json = ejson::to_string_pretty(contents, Mode::Relaxed).map_err(|e| offline_error(&self.path, e))?;
+        let partial = self.path.with_extension("json.partial");
+        std::fs::write(&partial, json)
+            .and_then(|_| std::fs::rename(&partial, &self.path))
+            .map_err(|e| offline_error(&self.path, e))
+    }
+
+    /// Applies `change` to the contents and saves them, or leaves both as
+    /// they were when it fails.
+    fn change<T>(&self, change: impl FnOnce(&mut Contents) -> Result<T>) -> Result<T> {
+        let mut contents = self.contents.lock().unwrap();
+        let saved = bson::to_bson(&*contents)?;
+        let result = change(&mut contents)?;
+        if let Err(e) = self.save(&contents) {
+            *contents = bson::from_bson(saved).expect("contents read back as they were written");
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    pub fn insert(&self, post: &Post) -> Result<PostId> {
+        let post = post.rendered().into_owned();
+        self.change(|contents| {
+            if contents.posts.iter().any(|other| other.title.to_lowercase() == post.title.to_lowercase()) {
+                return Err(Error::DuplicateTitle(post.title.clone()));
+            }
+            contents.changed.insert(post.id);
+            contents.deleted.remove(&post.id);
+            let id = post.id;
+            contents.posts.push(post);
+            Ok(id)
+        })
+    }
+
+    pub fn find_by_id(&self, id: PostId) -> Result<Post> {
+        let contents = self.contents.lock().unwrap();
+        contents.posts.iter().find(|post| post.id == id).cloned().ok_or(Error::NotFound)
+    }
+
+    pub fn find_by_tag(&self, tag: &str) -> Vec<Post> {
+        let contents = self.contents.lock().unwrap();
+        contents.posts.iter().filter(|post| post.tags.iter().any(|other| other == tag)).cloned().collect()
+    }
+
+    pub fn update_title_by_tag(&self, tag: &str, title: &str) -> Result<UpdateSummary> {
+        self.change(|contents| {
+            let tagged = |post: &Post| post.tags.iter().any(|other| other == tag);
+            let matched = contents.posts.iter().filter(|post| tagged(post)).count();
+            let taken = contents.posts.iter()
+                .any(|post| !tagged(post) && post.title.to_lowercase() == title.to_lowercase());
+            if matched > 1 || (matched == 1 && taken) {
+                return Err(Error::DuplicateTitle(title.to_string()));
+            }
+            for post in contents.posts.iter_mut().filter(|post| tagged(post)) {
+                post.title = title.to_string();
+                post.title_prefixes = Post::title_prefixes(title);
+                post.version += 1;
+                contents.changed.insert(post.id);
+            }
+            Ok(UpdateSummary { matched: matched as u64, modified: matched as u64, upserted_id: None })
+        })
+    }
+
+    pub fn delete_by_tag(&self, tag: &str) -> Result<u64> {
+        self.change(|contents| Ok(remove(contents, |post| post.tags.iter().any(|other| other == tag))))
+    }
+
+    pub fn delete_by_id(&self, id: PostId) -> Result<()> {
+        self.change(|contents| match remove(contents, |post| post.id == id) {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        })
+    }
+
+    /// Replays what was done offline since the last sync on `ns`: replaces
+    /// the changed posts by `_id`, inserting those never synced, and deletes
+    /// the deleted ones, but only where the database's post still has the
+    /// version it had at the last sync. The others are conflicts, which
+    /// `resolver` decides; those it leaves pending, and posts the collection
+    /// refuses, say for a title another post there has, stay pending.
+    pub async fn sync(&self, ns: &Namespace, resolver: &dyn ConflictResolver) -> Result<OfflineSync> {
+        let (pending, synced) = {
+            let contents = self.contents.lock().unwrap();
+            let changed = contents.posts.iter()
+                .filter(|post| contents.changed.contains(&post.id))
+                .map(|post| (post.id, Some(post.clone())));
+            let deleted = contents.deleted.iter().map(|id| (*id, None));
+            let pending: Vec<(PostId, Option<Post>)> = changed.chain(deleted).collect();
+            (pending, contents.synced.clone())
+        };
+        let col = ns.collection::<Post>("posts");
+        let mut report = OfflineSync::default();
+        let mut done: Vec<(PostId, Option<Post>)> = Vec::new();
+        for (id, offline) in pending {
+            let base = synced.get(&id.to_hex());
+            let title = offline.as_ref().or(base).map(|post| post.title.clone()).unwrap_or_default();
+            let database = match write(&col, id, offline.as_ref(), base.map(|post| post.version)).await {
+                Ok(Written::Done) => {
+                    done.push((id, offline));
+                    continue;
+                }
+                Ok(Written::Conflict(database)) => database,
+                Err(e) => {
+                    report.failed.push((title, e.to_string()));
+                    continue;
+                }
+            };
+            let resolved = match resolver.resolve(base, offline.as_ref(), database.as_deref()) {
+                Resolution::Resolved(resolved) => resolved.map(|post| *post),
+                Resolution::Pending => {
+                    report.conflicts.push(conflict(id, title, base, offline.is_none(), database.as_deref()));
+                    continue;
+                }
+            };
+            let expected = database.as_ref().map(|post| post.version);
+            let taken = matches!((&resolved, &database), (Some(post), Some(database)) if same(post, database));
+            let resolved = resolved.map(|mut post| {
+                if !taken {
+                    post.version = post.version.max(expected.unwrap_or_default()) + 1;
+                }
+                post
+            });
+            // Nothing to write when it is the database's
+            let written = match taken {
+                true => Ok(Written::Done),
+                false => write(&col, id, resolved.as_ref(), expected).await,
+            };
+            match written {
+                Ok(Written::Done) => {
+                    report.resolved += 1;
+                    done.push((id, resolved));
+                }
+                // Changed again since it was read
+                Ok(Written::Conflict(database)) => {
+                    report.conflicts.push(conflict(id, title, base, offline.is_none(), database.as_deref()));
+                }
+                Err(e) => report.failed.push((title, e.to_string())),
+            }
+        }
+        report.written = done.iter().filter(|(_, post)| post.is_some()).count();
+        report.deleted = done.len() - report.written;
+        self.change(|contents| {
+            for (id, post) in &done {
+                contents.changed.remove(id);
+                contents.deleted.remove(id);
+                let at = contents.posts.iter().position(|other| other.id == *id);
+                match (post, at) {
+                    (Some(post), Some(at)) => contents.posts[at] = post.clone(),
+                    (Some(post), None) => contents.posts.push(post.clone()),
+                    (None, Some(at)) => {
+                        contents.posts.remove(at);
+                    }
+                    (None, None) => {}
+                }
+                match post {
+                    Some(post) => contents.synced.insert(id.to_hex(), post.clone()),
+                    None => contents.synced.remove(&id.to_hex()),
+                };
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+}
+
+/// What [`write`] did.
+enum Written {
+    Done,
+    /// The database's post, `None` if there is none, didn't have the
+    /// expected version; nothing was written.
+    Conflict(Option<Box<Post>>),
+}
+
+/// Makes the database's post `id` be `post`, or deletes it for `None`, if it
+/// still has the `expected` version, or for `None`, if there is none.
+#[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+async fn write(
+    col: &Collection<Post>,
+    id: PostId,
+    post: Option<&Post>,
+    expected: Option<i64>,
+) -> Result<Written> {
+    let result = match (post, expected) {
+        (Some(post), Some(version)) => col.replace_one(doc! { "_id": id, "version": version }, post, None).await
+            .map(|result| result.matched_count > 0),
+        (Some(post), None) => col.insert_one(post, None).await.map(|_| true),
+        (None, Some(version)) => col.delete_one(doc! { "_id": id, "version": version }, None).await
+            .map(|result| result.deleted_count > 0),
+        (None, None) => Ok(false),
+    };
+    let database = match result {
+        Ok(true) => return Ok(Written::Done),
+        Ok(false) => col.find_one(doc! { "_id": id }, None).await?.map(Box::new),
+        // The title of another post, or, inserting, the `_id` of a post made elsewhere
+        Err(e) if is_duplicate_key(&e) => match col.find_one(doc! { "_id": id }, None).await? {
+            Some(database) if expected.is_none() => Some(Box::new(database)),
+            _ => return Err(Error::DuplicateTitle(post.map(|post| post.title.clone()).unwrap_or_default())),
+        },
+        Err(e) => return Err(e.into()),
+    };
+    match (post, database) {
+        // Deleted on both sides
+        (None, None) => Ok(Written::Done),
+        (_, database) => Ok(Written::Conflict(database)),
+    }
+}
+
+fn conflict(id: PostId, title: String, synced: Option<&Post>, deleted: bool, database: Option<&Post>) -> Conflict {
+    Conflict {
+        id,
+        title,
+        synced: synced.map(|post| post.version),
+        database: database.map(|post| post.version),
+        deleted,
+    }
+}
+
+/// Whether `a` and `b` would be stored the same.
+fn same(a: &Post, b: &Post) -> bool {
+    match (bson::to_document(a), bson::to_document(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Removes the posts `matches` picks, marking those synced before deleted,
+/// and counts them.
+fn remove(contents: &mut Contents, matches: impl Fn(&Post) -> bool) -> u64 {
+    let mut removed = 0;
+    for post in contents.posts.iter().filter(|post| matches(post)) {
+        contents.changed.remove(&post.id);
+        if contents.synced.contains_key(&post.id.to_hex()) {
+            contents.deleted.insert(post.id);
+        }
+        removed += 1;
+    }
+    contents.posts.retain(|post| !matches(post));
+    removed
+}
+
+fn offline_error(path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::Offline(format!("{}: {}", path.display(), e))
+}
+
+impl PostStore for OfflineStore {
+    fn insert<'a>(&'a self, post: &'a Post) -> BoxFuture<'a, Result<PostId>> {
+        async move { OfflineStore::insert(self, post) }.boxed()
+    }
+
+    fn find_by_id(&self, id: PostId) -> BoxFuture<'_, Result<Post>> {
+        async move { OfflineStore::find_by_id(self, id) }.boxed()
+    }
+
+    fn find_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<Vec<Post>>> {
+        async move { Ok(OfflineStore::find_by_tag(self, tag)) }.boxed()
+    }
+
+    fn update_title_by_tag<'a>(&'a self, tag: &'a str, title: &'a str) -> BoxFuture<'a, Result<UpdateSummary>> {
+        async move { OfflineStore::update_title_by_tag(self, tag, title) }.boxed()
+    }
+
+    fn delete_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<u64>> {
+        async move { OfflineStore::delete_by_tag(self, tag) }.boxed()
+    }
+
+    fn delete_by_id(&self, id: PostId) -> BoxFuture<'_, Result<()>> {
+        async move { OfflineStore::delete_by_id(self, id) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_survive_reopening_and_only_synced_posts_are_deleted_on_sync() {
+        let path = std::env::temp_dir().join(format!("offline-{}.json", PostId::new()));
+        let store = OfflineStore::open(&path).unwrap();
+        let first = store.insert(&Post::new("Hello", "World", &["rust"])).unwrap();
+        let second = store.insert(&Post::new("Other", "Post", &["go"])).unwrap();
+        assert!(matches!(store.insert(&Post::new("hello", "Again", &[])), Err(Error::DuplicateTitle(_))));
+        assert_eq!(store.update_title_by_tag("rust", "Hello again").unwrap().matched, 1);
+        assert!(matches!(store.update_title_by_tag("rust", "OTHER"), Err(Error::DuplicateTitle(_))));
+        assert_eq!(store.delete_by_tag("go").unwrap(), 1);
+
+        let store = OfflineStore::open(&path).unwrap();
+        let post = store.find_by_id(first).unwrap();
+        assert_eq!((post.title.as_str(), post.version), ("Hello again", 1));
+        assert!(matches!(store.find_by_id(second), Err(Error::NotFound)));
+        // Never synced, so there is nothing to delete in the database
+        assert_eq!(store.pending(), (1, 0));
+
+        store.contents.lock().unwrap().synced.insert(first.to_hex(), post);
+        assert_eq!(store.delete_by_tag("rust").unwrap(), 1);
+        assert_eq!(store.pending(), (0, 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use futures::TryStreamExt;
+use mongodb::Collection;
+use mongodb::bson::doc;
+
+use crate::Post;
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::repository::created_between;
+
+/// Posts split into one collection per UTC month of `created_at`, e.g.
+/// `posts_2024_06`, so old months can be dropped or archived as a whole.
+///
+/// Partitions are created implicitly by their first insert and so carry no
+/// validator or indexes of their own; apply them with `schema apply` when needed.
+#[derive(Clone)]
+pub struct MonthlyPartitions {
+    ns: Namespace,
+    base: String,
+}
+
+impl MonthlyPartitions {
+    pub fn new(ns: &Namespace, base: &str) -> Self {
+        MonthlyPartitions { ns: ns.clone(), base: base.to_string() }
+    }
+
+    /// Unprefixed name of the partition holding posts created at `at`.
+    pub fn partition_name(&self, at: DateTime<Utc>) -> String {
+        format!("{}_{:04}_{:02}", self.base, at.year(), at.month())
+    }
+
+    fn partition(&self, at: DateTime<Utc>) -> Collection<Post> {
+        self.ns.collection(&self.partition_name(at))
+    }
+
+    /// Writes `post` into the partition of its `created_at` month.
+    pub async fn insert(&self, post: &Post) -> Result<()> {
+        self.partition(post.created_at.to_chrono()).insert_one(post, None).await?;
+        Ok(())
+    }
+
+    /// Unprefixed names of the partitions that can hold posts created in
+    /// `[from, to)`, oldest first.
+    pub fn partitions_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut month = Utc.with_ymd_and_hms(from.year(), from.month(), 1, 0, 0, 0).unwrap();
+        while month < to {
+            names.push(self.partition_name(month));
+            month = match month.month() {
+                12 => Utc.with_ymd_and_hms(month.year() + 1, 1, 1, 0, 0, 0).unwrap(),
+                m => Utc.with_ymd_and_hms(month.year(), m + 1, 1, 0, 0, 0).unwrap(),
+            };
+        }
+        names
+    }
+
+    /// Posts created in `[from, to)`, oldest first. Reads the first partition
+    /// and pulls the others in with `$unionWith`, each filtered before the
+    /// union, so it needs MongoDB 4.4+. Missing partitions read as empty.
+    pub async fn find_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Post>> {
+        let names = self.partitions_between(from, to);
+        let (first, rest) = match names.split_first() {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        let filter = created_between(from, to);
+        let mut pipeline = vec![doc! { "$match": filter.clone() }];
+        for name in rest {
+            pipeline.push(doc! { "$unionWith": {
+                "coll": self.ns.name(name),
+                "pipeline": [{ "$match": filter.clone() }],
+            }});
+        }
+        pipeline.push(doc! { "$sort": { "created_at": 1, "_id": 1 } });
+        let posts = self.ns.collection::<Post>(first).aggregate(pipeline, None).await?
+            .with_type::<Post>()
+            .try_collect().await?;
+        Ok(posts)
+    }
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+
+use mongodb::bson::{Bson, Document};
+
+/// Stages the server knows, for catching typos before the pipeline is sent.
+const STAGES: [&str; 33] = [
+    "$addFields", "$bucket", "$bucketAuto", "$changeStream", "$collStats", "$count", "$densify", "$documents",
+    "$facet", "$fill", "$geoNear", "$graphLookup", "$group", "$indexStats", "$limit", "$lookup", "$match",
+    "$merge", "$out", "$project", "$redact", "$replaceRoot", "$replaceWith", "$sample", "$search", "$set",
+    "$setWindowFields", "$skip", "$sort", "$sortByCount", "$unionWith", "$unset", "$unwind",
+];
+
+/// Stages that only work at the start of a pipeline.
+const FIRST_STAGES: [&str; 5] = ["$changeStream", "$collStats", "$documents", "$geoNear", "$indexStats"];
+
+/// Stages that only work at the end of a pipeline.
+const LAST_STAGES: [&str; 2] = ["$merge", "$out"];
+
+/// What is wrong with one stage of a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintError {
+    /// Zero-based; stages inside a `$facet` are numbered within their facet.
+    pub stage: usize,
+    /// The stage's operator, when it has exactly one.
+    pub name: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for LintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "stage {} ({}): {}", self.stage, name, self.message),
+            None => write!(f, "stage {}: {}", self.stage, self.message),
+        }
+    }
+}
+
+/// Every problem [`lint`] found, one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintErrors(pub Vec<LintError>);
+
+impl fmt::Display for LintErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pipeline:")?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LintErrors {}
+
+/// Checks a user-supplied pipeline before it is run: each stage is one known
+/// operator with an argument of the right shape, in a position it may take.
+///
+/// Only the shape is checked, not expressions, so a pipeline that passes can
+/// still fail on the server; but the usual mistakes (a misspelt stage, a
+/// `$limit` of `"10"`, an `$unwind` path without its `$`) are reported with
+/// the index of their stage instead of as one server error about the
+/// first of them.
+pub fn lint(pipeline: &[Document]) -> std::result::Result<(), LintErrors> {
+    let mut errors = Vec::new();
+    lint_into(pipeline, &mut errors);
+    if errors.is_empty() { Ok(()) } else { Err(LintErrors(errors)) }
+}
+
+fn lint_into(pipeline: &[Document], errors: &mut Vec<LintError>) {
+    for (index, stage) in pipeline.iter().enumerate() {
+        let mut error = |name: Option<&str>, message: String| {
+            errors.push(LintError { stage: index, name: name.map(str::to_string), message });
+        };
+        let mut keys = stage.iter();
+        let (name, argument) = match (keys.next(), keys.next()) {
+            (Some(only), None) => only,
+            (None, _) => {
+                error(None, "a stage needs an operator, e.g. { \"$match\": { ... } }".to_string());
+                continue;
+            }
+            (Some(_), Some(_)) => {
+                let names: Vec<&str> = stage.keys().map(String::as_str).collect();
+                error(None, format!("a stage has exactly one operator, found {:?}; split it into several", names));
+                continue;
+            }
+        };
+        if !STAGES.contains(&name.as_str()) {
+            let message = match closest_stage(name) {
+                Some(closest) => format!("unknown stage, did you mean {}?", closest),
+                None => "unknown stage".to_string(),
+            };
+            error(Some(name), message);
+            continue;
+        }
+        if FIRST_STAGES.contains(&name.as_str()) && index != 0 {
+            error(Some(name), "has to be the first stage".to_string());
+        }
+        if LAST_STAGES.contains(&name.as_str()) && index + 1 != pipeline.len() {
+            error(Some(name), "has to be the last stage".to_string());
+        }
+        let name = name.as_str();
+        if let Err(message) = check_argument(name, argument, index, errors) {
+            errors.push(LintError { stage: index, name: Some(name.to_string()), message });
+        }
+    }
+}
+
+fn check_argument(name: &str, argument: &Bson, index: usize, errors: &mut Vec<LintError>) -> Result<(), String> {
+    match name {
+        "$match" => {
+            let filter = document(argument)?;
+            if index != 0 && contains_key(filter, "$text") {
+                return Err("a $text match has to be the first stage".to_string());
+            }
+            Ok(())
+        }
+        "$project" | "$addFields" | "$set" | "$group" | "$sort" | "$replaceRoot" | "$sample" | "$bucket"
+        | "$bucketAuto" | "$lookup" | "$graphLookup" | "$facet" | "$setWindowFields" => {
+            let argument = non_empty_document(argument)?;
+            match name {
+                "$group" => require(argument, &["_id"]),
+                "$sort" => check_sort(argument),
+                "$replaceRoot" => require(argument, &["newRoot"]),
+                "$sample" => positive_integer(argument.get("size").ok_or("needs a `size`")?).map(|_| ()),
+                "$bucket" => require(argument, &["groupBy", "boundaries"]),
+                "$bucketAuto" => require(argument, &["groupBy", "buckets"]),
+                "$lookup" => check_lookup(argument),
+                "$graphLookup" => {
+                    require(argument, &["from", "startWith", "connectFromField", "connectToField", "as"])
+                }
+                "$facet" => check_facet(argument, errors),
+                _ => Ok(()),
+            }
+        }
+        "$limit" | "$skip" => positive_integer(argument).map(|_| ()),
+        "$count" => match argument {
+            Bson::String(field) if !field.is_empty() && !field.starts_with('$') && !field.contains('.') => Ok(()),
+            _ => Err("takes the name of the output field, without `$` or `.`".to_string()),
+        },
+        "$unwind" => match argument {
+            Bson::String(path) => field_path(path),
+            Bson::Document(options) => match options.get("path") {
+                Some(Bson::String(path)) => field_path(path),
+                _ => Err("needs a `path` like \"$tags\"".to_string()),
+            },
+            _ => Err("takes a field path like \"$tags\" or { path: ... }".to_string()),
+        },
+        "$sortByCount" => match argument {
+            Bson::String(path) => field_path(path),
+            Bson::Document(_) => Ok(()),
+            _ => Err("takes an expression like \"$tags\"".to_string()),
+        },
+        "$unset" => match argument {
+            Bson::String(_) => Ok(()),
+            Bson::Array(fields) if !fields.is_empty() && fields.iter().all(|f| f.as_str().is_some()) => Ok(()),
+            _ => Err("takes a field name or an array of them".to_string()),
+        },
+        "$unionWith" => match argument {
+            Bson::String(_) => Ok(()),
+            Bson::Document(options) => require(options, &["coll"]),
+            _ => Err("takes a collection name or { coll, pipeline }".to_string()),
+        },
+        "$out" => match argument {
+            Bson::String(_) | Bson::Document(_) => Ok(()),
+            _ => Err("takes a collection name or { db, coll }".to_string()),
+        },
+        "$merge" => match argument {
+            Bson::String(_) => Ok(()),
+            Bson::Document(options) => require(options, &["into"]),
+            _ => Err("takes a collection name or { into, ... }".to_string()),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn document(argument: &Bson) -> Result<&Document, String> {
+    argument.as_document().ok_or_else(|| format!("takes a document, not {}", kind(argument)))
+}
+
+fn non_empty_document(argument: &Bson) -> Result<&Document, String> {
+    let argument = document(argument)?;
+    if argument.is_empty() {
+        return Err("takes a non-empty document".to_string());
+    }
+    Ok(argument)
+}
+
+fn require(argument: &Document, fields: &[&str]) -> Result<(), String> {
+    let missing: Vec<&str> = fields.iter().filter(|field| !argument.contains_key(field)).copied().collect();
+    match missing.as_slice() {
+        [] => Ok(()),
+        [field] => Err(format!("needs a `{}`", field)),
+        fields => {
+            let fields: Vec<String> = fields.iter().map(|field| format!("`{}`", field)).collect();
+            Err(format!("needs {}", fields.join(", ")))
+        }
+    }
+}
+
+fn positive_integer(argument: &Bson) -> Result<i64, String> {
+    let n = match argument {
+        Bson::Int32(n) => *n as i64,
+        Bson::Int64(n) => *n,
+        Bson::Double(n) if n.fract() == 0.0 => *n as i64,
+        _ => return Err(format!("takes a positive integer, not {}", kind(argument))),
+    };
+    if n <= 0 {
+        return Err(format!("takes a positive integer, not {}", n));
+    }
+    Ok(n)
+}
+
+fn field_path(path: &str) -> Result<(), String> {
+    if path.len() > 1 && path.starts_with('$') && !path.starts_with("$$") {
+        Ok(())
+    } else {
+        let field = path.trim_start_matches('$');
+        Err(format!("{:?} isn't a field path; prefix the field with `$`, e.g. \"${}\"", path, field))
+    }
+}
+
+fn check_sort(sort: &Document) -> Result<(), String> {
+    for (field, order) in sort {
+        let valid = match order {
+            Bson::Int32(1 | -1) | Bson::Int64(1 | -1) => true,
+            Bson::Double(order) => *order == 1.0 || *order == -1.0,
+            Bson::Document(meta) => meta.contains_key("$meta"),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("`{}` has to be sorted by 1, -1 or {{ $meta: ... }}", field));
+        }
+    }
+    Ok(())
+}
+
+fn check_lookup(lookup: &Document) -> Result<(), String> {
+    require(lookup, &["from", "as"])?;
+    let by_field = lookup.contains_key("localField") || lookup.contains_key("foreignField");
+    if by_field {
+        require(lookup, &["localField", "foreignField"])
+    } else if lookup.contains_key("pipeline") {
+        Ok(())
+    } else {
+        Err("needs `localField` and `foreignField`, or a `pipeline`".to_string())
+    }
+}
+
+fn check_facet(facets: &Document, errors: &mut Vec<LintError>) -> Result<(), String> {
+    for (facet, pipeline) in facets {
+        let Bson::Array(stages) = pipeline else {
+            return Err(format!("facet `{}` has to be an array of stages", facet));
+        };
+        let stages: Option<Vec<Document>> = stages.iter().map(|stage| stage.as_document().cloned()).collect();
+        let Some(stages) = stages else {
+            return Err(format!("facet `{}` has to be an array of stages", facet));
+        };
+        let mut inner = Vec::new();
+        lint_into(&stages, &mut inner);
+        errors.extend(inner.into_iter().map(|error| LintError {
+            message: format!("in facet `{}`: {}", facet, error.message),
+            ..error
+        }));
+    }
+    Ok(())
+}
+
+fn contains_key(filter: &Document, key: &str) -> bool {
+    filter.iter().any(|(field, value)| {
+        field == key || matches!(value, Bson::Array(clauses) if clauses.iter()
+            .any(|clause| clause.as_document().is_some_and(|clause| contains_key(clause, key))))
+    })
+}
+
+fn kind(value: &Bson) -> &'static str {
+    match value {
+        Bson::String(_) => "a string",
+        Bson::Document(_) => "a document",
+        Bson::Array(_) => "an array",
+        Bson::Null => "null",
+        Bson::Boolean(_) => "a boolean",
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => "a number",
+        _ => "that",
+    }
+}
+
+/// The known stage within two edits of `name`, ignoring case.
+fn closest_stage(name: &str) -> Option<&'static str> {
+    let name = if name.starts_with('$') { name.to_lowercase() } else { format!("${}", name.to_lowercase()) };
+    STAGES.iter()
+        .map(|stage| (edit_distance(&name, &stage.to_lowercase()), *stage))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, stage)| stage)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    fn messages(pipeline: &[Document]) -> Vec<String> {
+        match lint(pipeline) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.0.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_pipelines_pass() {
+        let pipeline = [
+            doc! { "$match": { "$text": { "$search": "mongo" } } },
+            doc! { "$unwind": "$tags" },
+            doc! { "$group": { "_id": "$tags", "count": { "$sum": 1 } } },
+            doc! { "$sort": { "count": -1, "_id": 1 } },
+            doc! { "$limit": 10 },
+            doc! { "$facet": { "top": [{ "$limit": 3 }], "total": [{ "$count": "n" }] } },
+        ];
+        assert_eq!(messages(&pipeline), Vec::<String>::new());
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_stage() {
+        let pipeline = [
+            doc! { "$mach": { "tags": "rust" } },
+            doc! { "$unwind": "tags" },
+            doc! { "$limit": "10" },
+            doc! { "$out": "copy" },
+            doc! { "$sort": { "n": "desc" }, "$limit": 1 },
+            doc! { "$facet": { "page": [{ "$skip": -1 }] } },
+            doc! { "$match": { "$text": { "$search": "late" } } },
+        ];
+        assert_eq!(messages(&pipeline), [
+            "stage 0 ($mach): unknown stage, did you mean $match?",
+            "stage 1 ($unwind): \"tags\" isn't a field path; prefix the field with `$`, e.g. \"$tags\"",
+            "stage 2 ($limit): takes a positive integer, not a string",
+            "stage 3 ($out): has to be the last stage",
+            "stage 4: a stage has exactly one operator, found [\"$sort\", \"$limit\"]; split it into several",
+            "stage 0 ($skip): in facet `page`: takes a positive integer, not -1",
+            "stage 6 ($match): a $text match has to be the first stage",
+        ]);
+    }
+}
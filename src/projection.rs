@@ -0,0 +1,163 @@
+use futures::{StreamExt, TryStreamExt};
+use mongodb::Collection;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::change_stream::event::OperationType;
+use mongodb::error::Result;
+
+use crate::namespace::Namespace;
+
+pub const READ_MODEL: &str = "post_read_model";
+
+/// Denormalized, query-ready view of a post.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PostReadModel {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub title: String,
+    pub tags: Vec<String>,
+    pub author_name: Option<String>,
+    pub comment_count: i64,
+}
+
+/// Maintains `post_read_model` from the `posts` collection.
+///
+/// Both the full rebuild and the incremental updates go through the same
+/// `$merge` pipeline, so the two paths can't drift apart. The author name is
+/// the post's own `author`, and the comment count is looked up from
+/// `comments` via `post_id`.
+#[derive(Clone)]
+pub struct Projector {
+    posts: Collection<Document>,
+    comments: Collection<Document>,
+    read_model: Collection<Document>,
+    /// Prefixed names the pipeline refers to.
+    comments_name: String,
+    read_model_name: String,
+}
+
+/// Which collection a change came from.
+#[derive(Clone, Copy)]
+enum Source {
+    Posts,
+    Comments,
+}
+
+impl Projector {
+    pub fn new(ns: &Namespace) -> Self {
+        Projector {
+            posts: ns.collection("posts"),
+            comments: ns.collection("comments"),
+            read_model: ns.collection(READ_MODEL),
+            comments_name: ns.name("comments"),
+            read_model_name: ns.name(READ_MODEL),
+        }
+    }
+
+    /// Throws the read model away and projects every post again.
+    pub async fn rebuild(&self) -> Result<()> {
+        self.read_model.drop(None).await?;
+        self.project(doc! {}).await
+    }
+
+    /// Follows the change streams of `posts` and `comments` and keeps the
+    /// read model up to date: a changed post is projected again, and so is
+    /// the post a new comment is on. A deleted comment's event doesn't
+    /// say which post it was on; [`PostRepository::delete_comment`] updates
+    /// that post too, and its event re-projects it.
+    /// Only returns on error, e.g. when the server doesn't support change streams.
+    ///
+    /// [`PostRepository::delete_comment`]: crate::repository::PostRepository::delete_comment
+    pub async fn run(&self) -> Result<()> {
+        let mut events = futures::stream::select_all([
+            self.posts.watch(None, None).await?.map_ok(|event| (Source::Posts, event)).boxed(),
+            self.comments.watch(None, None).await?.map_ok(|event| (Source::Comments, event)).boxed(),
+        ]);
+        while let Some((source, event)) = events.try_next().await? {
+            let id = match event.document_key.as_ref().and_then(|key| key.get("_id")) {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+            match (source, event.operation_type) {
+                (Source::Posts, OperationType::Insert | OperationType::Update | OperationType::Replace) => {
+                    self.project(doc! { "_id": id }).await?;
+                }
+                (Source::Posts, OperationType::Delete) => {
+                    self.read_model.delete_one(doc! { "_id": id }, None).await?;
+                }
+                (Source::Comments, OperationType::Insert) => {
+                    let post_id = event.full_document.as_ref().and_then(|comment| comment.get("post_id"));
+                    if let Some(post_id) = post_id {
+                        self.project(doc! { "_id": post_id }).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn project(&self, filter: Document) -> Result<()> {
+        self.posts.aggregate(self.pipeline(filter), None).await?;
+        Ok(())
+    }
+
+    fn pipeline(&self, filter: Document) -> Vec<Document> {
+        vec![
+            doc! { "$match": filter },
+            doc! { "$lookup": {
+                "from": &self.comments_name,
+                "let": { "post_id": "$_id" },
+                "pipeline": [
+                    { "$match": { "$expr": { "$eq": ["$post_id", "$$post_id"] } } },
+                    { "$count": "count" },
+                ],
+                "as": "comment_stats",
+            }},
+            doc! { "$project": {
+                "title": 1,
+                "tags": 1,
+                "author_name": "$author",
+                "comment_count": { "$ifNull": [{ "$arrayElemAt": ["$comment_stats.count", 0] }, Bson::Int64(0)] },
+            }},
+            doc! { "$merge": {
+                "into": &self.read_model_name,
+                "on": "_id",
+                "whenMatched": "replace",
+                "whenNotMatched": "insert",
+            }},
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::Post;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn projected_posts_carry_their_author_and_comment_count() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let post = Post { author: Some("ann".to_string()), ..Post::new("Projected", "By ann", &["cqrs"]) };
+        let anonymous = Post::new("Anonymous", "By nobody", &["cqrs"]);
+        ns.collection::<Post>("posts").insert_many([&post, &anonymous], None).await.unwrap();
+        let comments = ["First", "Second"].map(|body| doc! { "post_id": post.id, "body": body });
+        ns.collection::<Document>("comments").insert_many(comments, None).await.unwrap();
+
+        Projector::new(&ns).rebuild().await.unwrap();
+        let read_model = ns.collection::<PostReadModel>(READ_MODEL);
+        let projected = read_model.find_one(doc! { "_id": post.id }, None).await.unwrap().unwrap();
+        assert_eq!((projected.author_name.as_deref(), projected.comment_count), (Some("ann"), 2));
+        let projected = read_model.find_one(doc! { "_id": anonymous.id }, None).await.unwrap().unwrap();
+        assert_eq!((projected.author_name, projected.comment_count), (None, 0));
+        sandbox.cleanup().await;
+    }
+}
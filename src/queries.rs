@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use mongodb::bson::{doc, Bson, Document};
+use serde_json::Value;
+
+use crate::pipeline_lint;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Where `query` looks for named queries when not told otherwise.
+pub const QUERIES_FILE: &str = "queries.toml";
+
+/// Named queries, so new reports need an edit to `queries.toml` rather than
+/// a new build:
+///
+/// ```toml
+/// [queries.tagged]
+/// description = "Newest posts with a tag"
+/// filter = '{ "tags": "{{tag}}" }'
+/// sort = '{ "created_at": -1 }'
+/// limit = 20
+///
+/// [queries.since]
+/// pipeline = '''[
+///   { "$match": { "created_at": { "$gte": "{{from:date}}" } } },
+///   { "$sortByCount": "$tags" }
+/// ]'''
+/// ```
+///
+/// Filters, sorts and pipelines are Extended JSON. A string that is just a
+/// placeholder becomes the parameter's value: a string for `{{name}}`, or
+/// parsed as `{{name:int}}`, `{{name:number}}`, `{{name:bool}}` or
+/// `{{name:date}}` (RFC 3339). Placeholders inside a longer string are
+/// replaced by the text of the parameter. Values are only ever put in
+/// strings' places, so a parameter can't change the query's structure.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QueryFile {
+    #[serde(default)]
+    pub queries: BTreeMap<String, NamedQuery>,
+}
+
+/// One query of a [`QueryFile`]: either a `filter`, with an optional `sort`
+/// and `limit`, or a `pipeline`.
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NamedQuery {
+    pub description: Option<String>,
+    /// Unprefixed, like every collection name; `posts` when left out.
+    #[serde(default = "posts")]
+    pub collection: String,
+    pub filter: Option<String>,
+    pub sort: Option<String>,
+    pub limit: Option<i64>,
+    pub pipeline: Option<String>,
+}
+
+fn posts() -> String {
+    "posts".to_string()
+}
+
+impl QueryFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        QueryFile::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let file: QueryFile = toml::from_str(toml)?;
+        for (name, query) in &file.queries {
+            let is_find = query.filter.is_some() || query.sort.is_some() || query.limit.is_some();
+            if is_find == query.pipeline.is_some() {
+                return Err(format!("query {:?} needs either a filter or a pipeline", name).into());
+            }
+            // Catches malformed JSON when loading rather than when the query is run
+            query.templates()?;
+        }
+        Ok(file)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&NamedQuery> {
+        self.queries.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.queries.keys().map(String::as_str).collect();
+            format!("no query named {:?}, try one of {:?}", name, names).into()
+        })
+    }
+}
+
+impl NamedQuery {
+    /// The query as a pipeline with `params` filled in, checked by
+    /// [`pipeline_lint::lint`]. Every placeholder needs a parameter and every
+    /// parameter a placeholder.
+    pub fn pipeline(&self, params: &HashMap<String, String>) -> Result<Vec<Document>> {
+        let mut used = BTreeSet::new();
+        let mut fill = |template: Value| -> Result<Document> {
+            match Bson::try_from(fill(template, params, &mut used)?)? {
+                Bson::Document(doc) => Ok(doc),
+                _ => Err("expected a JSON object".into()),
+            }
+        };
+        let templates = self.templates()?;
+        let pipeline = match (templates.pipeline, templates.filter) {
+            (Some(Value::Array(stages)), _) => stages.into_iter().map(fill).collect::<Result<Vec<_>>>()?,
+            (Some(_), _) => return Err("a pipeline is a JSON array of stages".into()),
+            (None, filter) => {
+                let mut pipeline = vec![doc! { "$match": filter.map(&mut fill).transpose()?.unwrap_or_default() }];
+                if let Some(sort) = templates.sort {
+                    pipeline.push(doc! { "$sort": fill(sort)? });
+                }
+                if let Some(limit) = self.limit {
+                    pipeline.push(doc! { "$limit": limit });
+                }
+                pipeline
+            }
+        };
+        if let Some(unused) = params.keys().find(|param| !used.contains(param.as_str())) {
+            return Err(format!("the query has no parameter {:?}", unused).into());
+        }
+        pipeline_lint::lint(&pipeline)?;
+        Ok(pipeline)
+    }
+
+    /// Names of the parameters the query takes, sorted.
+    pub fn params(&self) -> Result<BTreeSet<String>> {
+        let mut names = BTreeSet::new();
+        let templates = self.templates()?;
+        for template in [templates.filter, templates.sort, templates.pipeline].iter().flatten() {
+            collect_placeholders(template, &mut names);
+        }
+        Ok(names)
+    }
+
+    fn templates(&self) -> Result<Templates> {
+        let parse = |json: &Option<String>| -> Result<Option<Value>> {
+            Ok(json.as_deref().map(serde_json::from_str).transpose()?)
+        };
+        Ok(Templates { filter: parse(&self.filter)?, sort: parse(&self.sort)?, pipeline: parse(&self.pipeline)? })
+    }
+}
+
+struct Templates {
+    filter: Option<Value>,
+    sort: Option<Value>,
+    pipeline: Option<Value>,
+}
+
+/// One `{{name}}` or `{{name:type}}`, found in `text` at `start..end`.
+struct Placeholder<'a> {
+    start: usize,
+    end: usize,
+    name: &'a str,
+    kind: Option<&'a str>,
+}
+
+fn placeholders(text: &str) -> Vec<Placeholder<'_>> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(start) = text[from..].find("{{").map(|start| from + start) {
+        let Some(end) = text[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        let inner = text[start + 2..end - 2].trim();
+        let (name, kind) = match inner.split_once(':') {
+            Some((name, kind)) => (name.trim(), Some(kind.trim())),
+            None => (inner, None),
+        };
+        found.push(Placeholder { start, end, name, kind });
+        from = end;
+    }
+    found
+}
+
+fn collect_placeholders(template: &Value, names: &mut BTreeSet<String>) {
+    match template {
+        Value::String(text) => {
+            names.extend(placeholders(text).iter().map(|placeholder| placeholder.name.to_string()));
+        }
+        Value::Array(items) => items.iter().for_each(|item| collect_placeholders(item, names)),
+        Value::Object(fields) => fields.values().for_each(|value| collect_placeholders(value, names)),
+        _ => {}
+    }
+}
+
+fn fill<'a>(template: Value, params: &'a HashMap<String, String>, used: &mut BTreeSet<&'a str>) -> Result<Value> {
+    let mut param = |name: &str| -> Result<&'a str> {
+        let (name, value) = params.get_key_value(name).ok_or_else(|| format!("missing parameter {:?}", name))?;
+        used.insert(name.as_str());
+        Ok(value.as_str())
+    };
+    Ok(match template {
+        Value::String(text) => {
+            let found = placeholders(&text);
+            match found.as_slice() {
+                [whole] if whole.start == 0 && whole.end == text.len() => typed(param(whole.name)?, whole)?,
+                _ => {
+                    let mut filled = String::new();
+                    let mut from = 0;
+                    for placeholder in &found {
+                        if let Some(kind) = placeholder.kind {
+                            let placeholder = format!("{{{{{}:{}}}}}", placeholder.name, kind);
+                            return Err(format!("{} has to be a whole string", placeholder).into());
+                        }
+                        filled.push_str(&text[from..placeholder.start]);
+                        filled.push_str(param(placeholder.name)?);
+                        from = placeholder.end;
+                    }
+                    filled.push_str(&text[from..]);
+                    Value::String(filled)
+                }
+            }
+        }
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(|item| fill(item, params, used)).collect::<Result<_>>()?)
+        }
+        Value::Object(fields) => Value::Object(
+            fields.into_iter().map(|(key, value)| Ok((key, fill(value, params, used)?))).collect::<Result<_>>()?,
+        ),
+        value => value,
+    })
+}
+
+fn typed(value: &str, placeholder: &Placeholder) -> Result<Value> {
+    let invalid = |e: &dyn std::fmt::Display| format!("parameter {:?}={:?}: {}", placeholder.name, value, e);
+    Ok(match placeholder.kind {
+        None => Value::String(value.to_string()),
+        Some("int") => Value::from(value.parse::<i64>().map_err(|e| invalid(&e))?),
+        Some("number") => Value::from(value.parse::<f64>().map_err(|e| invalid(&e))?),
+        Some("bool") => Value::from(value.parse::<bool>().map_err(|e| invalid(&e))?),
+        Some("date") => {
+            chrono::DateTime::parse_from_rfc3339(value).map_err(|e| invalid(&e))?;
+            serde_json::json!({ "$date": value })
+        }
+        Some(kind) => {
+            return Err(format!("unknown parameter type {:?}, try int, number, bool or date", kind).into());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    const QUERIES: &str = r#"
+        [queries.tagged]
+        filter = '{ "tags": "{{tag}}", "title": { "$regex": "^{{prefix}}" } }'
+        sort = '{ "created_at": -1 }'
+        limit = 5
+
+        [queries.since]
+        collection = "comments"
+        pipeline = '[{ "$match": { "created_at": { "$gte": "{{from:date}}" }, "n": "{{n:int}}" } }]'
+    "#;
+
+    #[test]
+    fn placeholders_are_filled_in_with_their_types() {
+        let file = QueryFile::from_toml(QUERIES).unwrap();
+        let tagged = file.get("tagged").unwrap();
+        assert_eq!(tagged.collection, "posts");
+        assert_eq!(tagged.pipeline(&params(&[("tag", "tag1"), ("prefix", "Hel")])).unwrap(), [
+            doc! { "$match": { "tags": "tag1", "title": { "$regex": "^Hel" } } },
+            doc! { "$sort": { "created_at": -1 } },
+            doc! { "$limit": 5_i64 },
+        ]);
+        let since = file.get("since").unwrap().pipeline(&params(&[("from", "2024-01-01T00:00:00Z"), ("n", "3")]))
+            .unwrap();
+        let filter = since[0].get_document("$match").unwrap();
+        assert!(matches!(filter.get_document("created_at").unwrap().get("$gte"), Some(Bson::DateTime(_))));
+        assert_eq!(filter.get_i32("n"), Ok(3));
+    }
+
+    #[test]
+    fn parameters_have_to_match_placeholders() {
+        let file = QueryFile::from_toml(QUERIES).unwrap();
+        let tagged = file.get("tagged").unwrap();
+        assert_eq!(tagged.params().unwrap().into_iter().collect::<Vec<_>>(), ["prefix", "tag"]);
+        assert!(tagged.pipeline(&params(&[("tag", "tag1")])).is_err());
+        assert!(tagged.pipeline(&params(&[("tag", "tag1"), ("prefix", "H"), ("other", "x")])).is_err());
+        let since = file.get("since").unwrap();
+        assert!(since.pipeline(&params(&[("from", "yesterday"), ("n", "3")])).is_err());
+        assert!(file.get("missing").is_err());
+    }
+
+    #[test]
+    fn a_query_is_a_filter_or_a_pipeline() {
+        assert!(QueryFile::from_toml("[queries.empty]\n").is_err());
+        assert!(QueryFile::from_toml("[queries.both]\nfilter = '{}'\npipeline = '[]'\n").is_err());
+        assert!(QueryFile::from_toml("[queries.broken]\nfilter = '{ tags: 1 }'\n").is_err());
+    }
+}
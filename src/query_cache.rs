@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::time::Duration;
+
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::{self, doc, Bson, DateTime, Document};
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::digest::Fnv1a;
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+pub const QUERY_CACHE: &str = "query_cache";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheEntry {
+    /// Hash of the collection name and the pipeline, see [`cache_key`].
+    #[serde(rename = "_id")]
+    key: String,
+    result: Bson,
+    #[serde(with = "serde_helpers::datetime")]
+    expires_at: DateTime,
+}
+
+/// Stores results of expensive aggregations in `query_cache`, keyed by a hash
+/// of the pipeline that produced them.
+///
+/// A TTL index on `expires_at` removes stale entries. The TTL monitor only
+/// runs about once a minute, so reads check `expires_at` themselves too. The
+/// cache is best effort: if it can't be read or written, the aggregation just
+/// runs and the failure is logged.
+#[derive(Clone)]
+pub struct QueryCache {
+    entries: Collection<CacheEntry>,
+    ttl: Duration,
+}
+
+impl QueryCache {
+    pub async fn new(ns: &Namespace, ttl: Duration) -> Result<Self> {
+        let entries = ns.collection::<CacheEntry>(QUERY_CACHE);
+        let index_model = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        entries.create_index(index_model, None).await?;
+        Ok(QueryCache { entries, ttl })
+    }
+
+    /// The cached result of running `pipeline` on `collection`, or the result
+    /// of `compute` (which should run exactly that pipeline), cached for next time.
+    pub async fn get_or_compute<T, F>(&self, collection: &str, pipeline: &[Document], compute: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T>>,
+    {
+        let key = cache_key(collection, pipeline);
+        let filter = doc! { "_id": &key, "expires_at": { "$gt": DateTime::now() } };
+        match self.entries.find_one(filter, None).await {
+            Ok(Some(entry)) => match bson::from_bson(entry.result) {
+                Ok(value) => return Ok(value),
+                Err(e) => tracing::warn!(key = %key, "ignoring undecodable cache entry: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => tracing::warn!(key = %key, "unable to read query cache: {}", e),
+        }
+
+        let value = compute.await?;
+        let entry = CacheEntry {
+            key: key.clone(),
+            result: bson::to_bson(&value)?,
+            expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + self.ttl.as_millis() as i64),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        if let Err(e) = self.entries.replace_one(doc! { "_id": &key }, &entry, options).await {
+            tracing::warn!(key = %key, "unable to write query cache: {}", e);
+        }
+        Ok(value)
+    }
+}
+
+/// Hash of the collection name and the pipeline's canonical extended JSON.
+/// [`Fnv1a`] is stable across builds, so entries written by one build are
+/// still found by the next.
+fn cache_key(collection: &str, pipeline: &[Document]) -> String {
+    let pipeline: Vec<Bson> = pipeline.iter().cloned().map(Bson::Document).collect();
+    let mut hasher = Fnv1a::new();
+    hasher.update(collection.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(Bson::Array(pipeline).into_canonical_extjson().to_string().as_bytes());
+    hasher.hex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_keys_tell_collections_and_pipelines_apart() {
+        let pipeline = [doc! { "$match": { "tags": "rust" } }];
+        let key = cache_key("posts", &pipeline);
+        assert_eq!(key.len(), 16);
+        assert_eq!(key, cache_key("posts", &[doc! { "$match": { "tags": "rust" } }]));
+        assert_ne!(key, cache_key("archive", &pipeline));
+        assert_ne!(key, cache_key("posts", &[doc! { "$match": { "tags": "mongodb" } }]));
+    }
+
+    #[test]
+    fn cache_keys_tell_bson_types_apart() {
+        let int = cache_key("posts", &[doc! { "$limit": 5 }]);
+        assert_ne!(int, cache_key("posts", &[doc! { "$limit": 5_i64 }]));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use mongodb::bson::{Bson, Document};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+
+use crate::latency::op_of;
+use crate::metrics;
+
+/// Contexts counted at most; beyond that the counts are dropped, so the
+/// contexts nobody calls [`QueryCounter::take`] for can't pile up.
+const MAX_CONTEXTS: usize = 10_000;
+
+/// Counts the commands sent under each context of the comment (see
+/// [`PostRepository`](crate::repository::PostRepository)), by command,
+/// collection and repository method, so that a request sending the same
+/// query over and over, once per post of a list say, can be told apart from
+/// one that batches them with `$in` or `$lookup`.
+///
+/// A cursor's `getMore` belongs to the query that opened it, so it isn't
+/// counted.
+pub struct QueryCounter {
+    /// Times a context may send the same kind of query before
+    /// [`QueryCounter::take`] reports it.
+    threshold: u64,
+    contexts: Mutex<HashMap<String, BTreeMap<QueryKind, u64>>>,
+}
+
+/// Queries that count as the same one repeated: the same command on the same
+/// collection from the same repository method, whatever its filter.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QueryKind {
+    pub command: String,
+    pub collection: String,
+    pub op: String,
+}
+
+/// A kind of query sent more often than allowed under one context.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct RepeatedQuery {
+    #[serde(flatten)]
+    pub kind: QueryKind,
+    pub count: u64,
+}
+
+impl QueryCounter {
+    pub fn new(threshold: u64) -> Self {
+        QueryCounter { threshold, contexts: Mutex::new(HashMap::new()) }
+    }
+
+    /// The queries sent under `context` more than `threshold` times, most
+    /// sent first, and forgets its counts.
+    pub fn take(&self, context: &str) -> Vec<RepeatedQuery> {
+        let counts = self.contexts.lock().unwrap().remove(context).unwrap_or_default();
+        let mut repeated: Vec<RepeatedQuery> = counts.into_iter()
+            .filter(|(_, count)| *count > self.threshold)
+            .map(|(kind, count)| RepeatedQuery { kind, count })
+            .collect();
+        repeated.sort_by_key(|repeated| std::cmp::Reverse(repeated.count));
+        repeated
+    }
+
+    fn record(&self, command_name: &str, command: &Document) {
+        let Some(Bson::Document(comment)) = command.get("comment") else {
+            return;
+        };
+        let Ok(context) = comment.get_str("ctx") else {
+            return;
+        };
+        let kind = QueryKind {
+            command: command_name.to_string(),
+            collection: metrics::collection(command_name, command).unwrap_or("").to_string(),
+            op: op_of(command).unwrap_or_default(),
+        };
+        let mut contexts = self.contexts.lock().unwrap();
+        if contexts.len() >= MAX_CONTEXTS && !contexts.contains_key(context) {
+            contexts.clear();
+        }
+        *contexts.entry(context.to_string()).or_default().entry(kind).or_default() += 1;
+    }
+}
+
+impl CommandEventHandler for QueryCounter {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        if event.command_name != "getMore" {
+            self.record(&event.command_name, &event.command);
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, _: CommandSucceededEvent) {}
+
+    fn handle_command_failed_event(&self, _: CommandFailedEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_per_context() {
+        let counter = QueryCounter::new(10);
+        let find = |ctx: &str| doc! { "find": "comments", "comment": { "op": "comments", "ctx": ctx } };
+        for _ in 0..12 {
+            counter.record("find", &find("request-1"));
+        }
+        counter.record("find", &find("request-2"));
+        counter.record("find", &doc! { "find": "comments" });
+        let other = doc! { "find": "posts", "comment": { "op": "list_summaries", "ctx": "request-1" } };
+        counter.record("find", &other);
+
+        let repeated = counter.take("request-1");
+        let kind = QueryKind {
+            command: "find".to_string(),
+            collection: "comments".to_string(),
+            op: "comments".to_string(),
+        };
+        assert_eq!(repeated, [RepeatedQuery { kind, count: 12 }]);
+        assert!(counter.take("request-1").is_empty());
+        let counter = QueryCounter::new(0);
+        counter.record("find", &find("request-2"));
+        assert_eq!(counter.take("request-2").len(), 1);
+    }
+}
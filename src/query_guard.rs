@@ -0,0 +1,46 @@
+use mongodb::bson::{doc, Document};
+
+use crate::error::{Error, Result};
+use crate::explain::Plan;
+use crate::namespace::Namespace;
+
+/// Collections of up to this many documents may be scanned whole: reading
+/// them costs about what an index would save.
+pub const DEFAULT_SCAN_LIMIT: u64 = 10_000;
+
+/// Rejects ad-hoc queries that would read a whole collection of more than
+/// `limit` documents. The query is only planned, with an `explain` at
+/// `queryPlanner` verbosity, so a rejected query costs the server nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanGuard {
+    limit: u64,
+}
+
+impl Default for ScanGuard {
+    fn default() -> Self {
+        ScanGuard::new(DEFAULT_SCAN_LIMIT)
+    }
+}
+
+impl ScanGuard {
+    pub fn new(limit: u64) -> Self {
+        ScanGuard { limit }
+    }
+
+    /// [`Error::CollectionScan`] if `pipeline` on `collection` would scan
+    /// it and it holds more than `limit` documents, by its metadata count.
+    pub async fn check(&self, ns: &Namespace, collection: &str, pipeline: &[Document]) -> Result<()> {
+        let explain = ns.db().run_command(doc! {
+            "explain": { "aggregate": ns.name(collection), "pipeline": pipeline.to_vec(), "cursor": {} },
+            "verbosity": "queryPlanner",
+        }, None).await?;
+        if !Plan::of(&explain).scans {
+            return Ok(());
+        }
+        let documents = ns.collection::<Document>(collection).estimated_document_count(None).await?;
+        if documents > self.limit {
+            return Err(Error::CollectionScan { collection: collection.to_string(), documents, limit: self.limit });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use futures::TryStreamExt;
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::FindOptions;
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+
+pub const TAG_COOCCURRENCE: &str = "tag_cooccurrence";
+
+/// A tag seen on the same posts as another one, and on how many.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct RelatedTag {
+    pub tag: String,
+    pub count: i64,
+}
+
+/// Precomputed "users also tagged" counts, one document per ordered pair of
+/// tags that appear together on at least one post:
+/// `{ _id: { tag, related }, count, computed_at }`.
+///
+/// The counts are only as fresh as the last [`TagGraph::rebuild`]; computing
+/// them per request would mean unwinding every post's tags twice.
+#[derive(Clone)]
+pub struct TagGraph {
+    posts: Collection<Document>,
+    pairs: Collection<Document>,
+    pairs_name: String,
+}
+
+impl TagGraph {
+    pub async fn new(ns: &Namespace) -> Result<Self> {
+        let pairs = ns.collection::<Document>(TAG_COOCCURRENCE);
+        let index_model = IndexModel::builder()
+            .keys(doc! { "_id.tag": 1, "count": -1 })
+            .build();
+        pairs.create_index(index_model, None).await?;
+        Ok(TagGraph { posts: ns.collection("posts"), pairs, pairs_name: ns.name(TAG_COOCCURRENCE) })
+    }
+
+    /// Recounts every pair from the posts and drops pairs that no longer occur.
+    pub async fn rebuild(&self) -> Result<()> {
+        let computed_at = DateTime::now();
+        let pipeline = vec![
+            doc! { "$project": { "tag": "$tags", "related": "$tags" } },
+            doc! { "$unwind": "$tag" },
+            doc! { "$unwind": "$related" },
+            doc! { "$match": { "$expr": { "$ne": ["$tag", "$related"] } } },
+            doc! { "$group": { "_id": { "tag": "$tag", "related": "$related" }, "count": { "$sum": 1 } } },
+            doc! { "$set": { "computed_at": computed_at } },
+            doc! { "$merge": {
+                "into": &self.pairs_name,
+                "on": "_id",
+                "whenMatched": "replace",
+                "whenNotMatched": "insert",
+            }},
+        ];
+        self.posts.aggregate(pipeline, None).await?;
+        self.pairs.delete_many(doc! { "computed_at": { "$lt": computed_at } }, None).await?;
+        Ok(())
+    }
+
+    /// The `limit` tags most often found together with `tag`, most frequent first.
+    pub async fn related_tags(&self, tag: &str, limit: i64) -> Result<Vec<RelatedTag>> {
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 0, "tag": "$_id.related", "count": 1 })
+            .sort(doc! { "count": -1, "_id.related": 1 })
+            .limit(limit)
+            .build();
+        let related = self.pairs.clone_with_type::<RelatedTag>()
+            .find(doc! { "_id.tag": tag }, options).await?
+            .try_collect().await?;
+        Ok(related)
+    }
+}
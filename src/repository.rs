@@ -0,0 +1,2614 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use futures::future::{BoxFuture, FutureExt};
+use mongodb::{ClientSession, Collection, IndexModel};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::ErrorKind;
+use mongodb::results::UpdateResult;
+use mongodb::options::{
+    AggregateOptions, Collation, CollationStrength, CollectionOptions, CountOptions, DeleteOptions,
+    FindOneAndDeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, HedgedReadOptions, IndexOptions,
+    InsertManyOptions, InsertOneOptions, ReadPreference, ReadPreferenceOptions, ReplaceOptions, ReturnDocument,
+    SelectionCriteria, UpdateOptions,
+};
+use tokio::sync::OnceCell;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Comment, Post, PostId, PostStatus};
+use crate::access::{ReadPolicy, Role};
+use crate::attributes::{self, Attribute};
+use crate::capabilities::{Capabilities, Feature};
+use crate::cancellation::{self, Cancellation};
+use crate::clusters::{Clusters, ANALYTICS};
+use crate::deadline::Deadline;
+use crate::error::{is_decode_error, is_duplicate_key, is_validation_error, Error, Result};
+use crate::namespace::Namespace;
+use crate::retry::RetryPolicy;
+use crate::title_filter::TitleFilter;
+use crate::transactions::run_in_txn;
+
+/// Collation of the unique title index: case-insensitive, accent-sensitive.
+/// Title lookups have to use the same collation or they can't use the index.
+pub fn title_collation() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Locale-aware ordering for title listings, e.g. `de` sorts "Äpfel" next to
+/// "Apfel" instead of after "Zebra" as plain byte order does.
+#[derive(Debug, Clone, Default)]
+pub struct TitleSort {
+    /// ICU locale such as `de` or `sv`; byte order when unset.
+    pub locale: Option<String>,
+    /// Compare runs of digits by their value, so "Post 2" sorts before
+    /// "Post 10" rather than after it.
+    pub numeric: bool,
+}
+
+impl TitleSort {
+    pub fn collation(&self) -> Collation {
+        // The `simple` locale ignores every other option, so numeric ordering
+        // needs a real one
+        let locale = match (&self.locale, self.numeric) {
+            (Some(locale), _) => locale.as_str(),
+            (None, true) => "en",
+            (None, false) => "simple",
+        };
+        Collation::builder()
+            .locale(locale)
+            .numeric_ordering(self.numeric.then_some(true))
+            .build()
+    }
+}
+
+/// How the posts text index analyzes and ranks documents.
+#[derive(Debug, Clone)]
+pub struct TextIndexConfig {
+    /// Stemmer and stop words used when a document doesn't name its language.
+    pub default_language: String,
+    /// Field on a post (or on one of its translations) naming its language.
+    pub language_override: String,
+    pub title_weight: i32,
+    /// Applies to the message and to its translations.
+    pub message_weight: i32,
+}
+
+impl Default for TextIndexConfig {
+    fn default() -> Self {
+        TextIndexConfig {
+            default_language: "english".to_string(),
+            language_override: "lang".to_string(),
+            title_weight: 10,
+            message_weight: 1,
+        }
+    }
+}
+
+impl TextIndexConfig {
+    /// A collection can only have one text index, so this covers every
+    /// searchable field.
+    pub fn index_model(&self) -> IndexModel {
+        IndexModel::builder()
+            .keys(doc! { "title": "text", "message": "text", "content.message": "text" })
+            .options(IndexOptions::builder()
+                .default_language(self.default_language.clone())
+                .language_override(self.language_override.clone())
+                .weights(doc! {
+                    "title": self.title_weight,
+                    "message": self.message_weight,
+                    "content.message": self.message_weight,
+                })
+                .build())
+            .build()
+    }
+}
+
+/// Number of posts created on a calendar day in some timezone.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct DailyCount {
+    /// `YYYY-MM-DD` in the timezone the counts were computed for.
+    #[serde(rename = "_id")]
+    pub day: String,
+    pub count: i64,
+}
+
+/// `created_at` as a `YYYY-MM-DD` string in `timezone` (an Olson name like
+/// `Europe/Berlin` or a UTC offset like `+02:00`), evaluated by the server.
+fn local_day(timezone: &str) -> Document {
+    doc! { "$dateToString": { "format": "%Y-%m-%d", "date": "$created_at", "timezone": timezone } }
+}
+
+/// The ids of the posts carrying a tag.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct TagWithPosts {
+    #[serde(rename = "_id")]
+    pub tag: String,
+    pub post_ids: Vec<PostId>,
+}
+
+/// Pipeline update (MongoDB 4.2+) putting `targets` in place of any of
+/// `sources` in a post's tags, keeping the order and the first occurrence of
+/// each tag, and bumping the version.
+pub(crate) fn replace_tags_update(sources: &[&str], targets: &[&str]) -> Vec<Document> {
+    let replaced = doc! { "$reduce": {
+        "input": "$tags",
+        "initialValue": [],
+        "in": { "$concatArrays": [
+            "$$value",
+            { "$cond": [{ "$in": ["$$this", sources] }, targets, ["$$this"]] },
+        ]},
+    }};
+    let deduplicated = doc! { "$reduce": {
+        "input": replaced,
+        "initialValue": [],
+        "in": { "$cond": [
+            { "$in": ["$$this", "$$value"] },
+            "$$value",
+            { "$concatArrays": ["$$value", ["$$this"]] },
+        ]},
+    }};
+    vec![doc! { "$set": {
+        "tags": deduplicated,
+        "version": { "$add": [{ "$ifNull": ["$version", 0] }, 1] },
+    }}]
+}
+
+/// What [`PostRepository::rename_tag`] changed.
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+pub struct TagRename {
+    /// Posts that carried the old tag.
+    pub matched: u64,
+    pub modified: u64,
+    /// Of those, posts that already had the new tag too and now carry it once.
+    pub merged: u64,
+}
+
+/// Number of posts created on a UTC day.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct DayBucket {
+    /// Midnight UTC at the start of the day.
+    #[serde(rename = "_id", with = "crate::serde_helpers::chrono_datetime")]
+    pub day: DateTime<Utc>,
+    #[serde(with = "crate::serde_helpers::int")]
+    pub count: i64,
+}
+
+/// A post among the most commented of its tag in a [`WeeklyDigest`].
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct DigestPost {
+    /// Hex `_id`, for links in the mail.
+    pub id: String,
+    pub title: String,
+    pub comments: i64,
+}
+
+/// One tag's part of a [`WeeklyDigest`].
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct TagDigest {
+    pub tag: String,
+    pub new_posts: i64,
+    pub top_posts: Vec<DigestPost>,
+}
+
+/// What a mailer needs for the weekly digest: per tag, how many posts were
+/// published in `[from, to)` and the most commented of them.
+#[derive(serde::Serialize, Debug)]
+pub struct WeeklyDigest {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Busiest tag first.
+    pub tags: Vec<TagDigest>,
+}
+
+/// Filter for `created_at` in the half-open range `[from, to)`.
+pub fn created_between(from: DateTime<Utc>, to: DateTime<Utc>) -> Document {
+    doc! { "created_at": {
+        "$gte": bson::DateTime::from_chrono(from),
+        "$lt": bson::DateTime::from_chrono(to),
+    }}
+}
+
+/// The pipeline behind [`PostRepository::weekly_digest`]. The week's posts
+/// are joined with their `comments` to count them, then sorted by that count
+/// once so each tag's group collects its posts already ranked.
+fn digest_pipeline(comments: &str, from: DateTime<Utc>, to: DateTime<Utc>, top: i64) -> Vec<Document> {
+    let mut published = created_between(from, to);
+    // Posts stored before there were statuses have none and count as published
+    published.insert("status", doc! { "$in": [PostStatus::Published.as_str(), Bson::Null] });
+    vec![
+        doc! { "$match": published },
+        doc! { "$lookup": { "from": comments, "localField": "_id", "foreignField": "post_id", "as": "comments" } },
+        doc! { "$project": { "title": 1, "tags": 1, "created_at": 1, "comments": { "$size": "$comments" } } },
+        doc! { "$unwind": "$tags" },
+        doc! { "$sort": { "comments": -1, "created_at": -1, "_id": 1 } },
+        doc! { "$group": {
+            "_id": "$tags",
+            "new_posts": { "$sum": 1 },
+            "top_posts": { "$push": { "id": { "$toString": "$_id" }, "title": "$title", "comments": "$comments" } },
+        }},
+        doc! { "$project": {
+            "_id": 0,
+            "tag": "$_id",
+            "new_posts": 1,
+            "top_posts": { "$slice": ["$top_posts", top] },
+        }},
+        doc! { "$sort": { "new_posts": -1, "tag": 1 } },
+    ]
+}
+
+/// What the filters sidebar sends: all criteria are optional and combined with AND.
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+    /// Posts must carry every one of these tags.
+    pub tags: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub text: Option<String>,
+    /// Zero-based.
+    pub page: u64,
+    pub per_page: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct TagCount {
+    #[serde(rename = "_id")]
+    pub tag: String,
+    pub count: i64,
+}
+
+/// One page of matches plus the counts for the whole match set.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SearchResults {
+    pub items: Vec<Post>,
+    pub total: i64,
+    pub tag_counts: Vec<TagCount>,
+}
+
+/// What list views need from a post. Reading through this type projects
+/// away `message` and its translations on the server, which are by far the
+/// largest part of a post.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PostSummary {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub title: String,
+    pub tags: Vec<String>,
+}
+
+fn summary_projection() -> Document {
+    doc! { "title": 1, "tags": 1 }
+}
+
+/// A full-text search hit with its relevance, higher for more (and more
+/// heavily weighted) matching words.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ScoredPost {
+    pub post: Post,
+    /// The `textScore` of the `$text` match.
+    pub score: f64,
+}
+
+/// A post joined with its comments, oldest comment first.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PostWithComments {
+    pub post: Post,
+    pub comments: Vec<Comment>,
+}
+
+/// A post whose `comment_count` isn't the number of its comments, from
+/// [`PostRepository::comment_count_drift`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CommentCountDrift {
+    #[serde(rename = "_id")]
+    pub post_id: PostId,
+    /// `comment_count` as stored, 0 where it is missing.
+    #[serde(with = "crate::serde_helpers::int")]
+    pub stored: i64,
+    /// Comments in [`COMMENTS`] naming the post.
+    #[serde(with = "crate::serde_helpers::int")]
+    pub actual: i64,
+}
+
+/// A post recommended for sharing tags with another one.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SimilarPost {
+    #[serde(flatten)]
+    pub summary: PostSummary,
+    /// How many of the other post's tags this one has too.
+    pub shared_tags: i64,
+}
+
+/// A post drawn by [`PostRepository::sample_by_tag`], with the tag it was
+/// drawn for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TaggedSample {
+    pub tag: String,
+    pub post: Post,
+}
+
+/// How many of `size` samples each tag gets, in proportion to its count and
+/// by largest remainder, so they add up to `size` (or to every post when
+/// there are fewer). Ties go to the tag listed first. Tags whose share
+/// rounds to nothing are left out.
+fn tag_quotas(counts: &[TagCount], size: u64) -> Vec<(String, u64)> {
+    let counts: Vec<(&str, u64)> = counts.iter().map(|c| (c.tag.as_str(), c.count.max(0) as u64)).collect();
+    let total: u64 = counts.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return Vec::new();
+    }
+    let size = size.min(total);
+    let mut quotas: Vec<u64> = counts.iter().map(|(_, count)| size * count / total).collect();
+    let mut by_remainder: Vec<usize> = (0..counts.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(size * counts[i].1 % total));
+    let left = size - quotas.iter().sum::<u64>();
+    for &i in by_remainder.iter().take(left as usize) {
+        quotas[i] += 1;
+    }
+    counts.iter().zip(quotas)
+        .filter(|(_, quota)| *quota > 0)
+        .map(|((tag, _), quota)| (tag.to_string(), quota))
+        .collect()
+}
+
+/// Outcome of an update, as reported by the server.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct UpdateSummary {
+    pub matched: u64,
+    pub modified: u64,
+    pub upserted_id: Option<Bson>,
+}
+
+impl From<UpdateResult> for UpdateSummary {
+    fn from(result: UpdateResult) -> Self {
+        UpdateSummary {
+            matched: result.matched_count,
+            modified: result.modified_count,
+            upserted_id: result.upserted_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Stop at the first failing document; later ones are not attempted.
+    Ordered,
+    /// Attempt every document and report all failures.
+    Unordered,
+}
+
+/// What an import does with a post whose title is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnDuplicate {
+    /// Leave the stored post alone.
+    #[default]
+    Skip,
+    /// Overwrite the stored post's content with the imported one's.
+    Update,
+}
+
+/// Why one document of an import was rejected.
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+pub struct ImportFailure {
+    /// Position of the document in the input.
+    pub index: usize,
+    pub title: String,
+    pub code: i32,
+    pub message: String,
+}
+
+/// Outcome of an import: every document ends up counted exactly once, as
+/// inserted, updated, skipped, rejected or not attempted.
+#[derive(serde::Serialize, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub inserted: usize,
+    /// Duplicates merged into the stored post, with [`OnDuplicate::Update`].
+    pub updated: usize,
+    /// Duplicates left alone, with [`OnDuplicate::Skip`].
+    pub skipped_duplicates: usize,
+    /// Documents the collection's validator rejected; also listed in `failed`.
+    pub rejected_validation: usize,
+    /// Every document that was neither inserted nor merged, and why.
+    pub failed: Vec<ImportFailure>,
+    /// Documents skipped because an ordered import stopped early.
+    pub not_attempted: usize,
+}
+
+/// What [`PostRepository::sync_posts`] did to make `posts` match its input.
+#[derive(serde::Serialize, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+}
+
+/// One page of [`PostRepository::find_posts_paginated`], or of
+/// [`ids::find_page`](crate::ids::find_page) with any `_id` type `C`.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct Page<T, C = PostId> {
+    pub items: Vec<T>,
+    /// Pass this back to get the next page; `None` on the last one.
+    pub next_cursor: Option<C>,
+}
+
+impl<T, C> Page<T, C> {
+    /// Trims `fetched`, asked for with `limit + 1`, to `limit` items; the
+    /// extra one only tells whether there is another page.
+    pub(crate) fn from_overfetched(mut fetched: Vec<T>, limit: usize, id: impl Fn(&T) -> C) -> Self {
+        let next_cursor = if fetched.len() > limit {
+            fetched.truncate(limit);
+            fetched.last().map(id)
+        } else {
+            None
+        };
+        Page { items: fetched, next_cursor }
+    }
+}
+
+/// Fields `sync_posts` makes match its input. The id, creation time, version
+/// and any unknown fields stay as stored.
+const SYNCED_FIELDS: [&str; 10] = [
+    "title", "title_prefixes", "message", "rendered_html", "message_hash", "tags", "status", "publish_at", "lang",
+    "content",
+];
+
+#[allow(clippy::result_large_err)] // same `Result` as the repository methods
+fn synced_fields(post: &Post) -> Result<Document> {
+    let doc = bson::to_document(&*post.rendered())?;
+    Ok(SYNCED_FIELDS.iter()
+        .filter_map(|field| doc.get(*field).map(|value| (field.to_string(), value.clone())))
+        .collect())
+}
+
+/// Update setting `fields` (a subset of [`SYNCED_FIELDS`]), removing the
+/// other synced fields except those in `keep`, and bumping the version.
+fn synced_update(fields: Document, keep: &[&str]) -> Document {
+    let unset: Document = SYNCED_FIELDS.iter()
+        .filter(|field| !fields.contains_key(**field) && !keep.contains(*field))
+        .map(|field| (field.to_string(), Bson::String(String::new())))
+        .collect();
+    let mut update = doc! { "$set": fields, "$inc": { "version": 1 } };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    update
+}
+
+/// A write command run through `run_command` succeeds even when some of its
+/// statements fail; those are only listed under `writeErrors` in the reply.
+#[allow(clippy::result_large_err)] // same `Result` as the repository methods
+fn check_write_errors(reply: &Document) -> Result<()> {
+    let first = match reply.get_array("writeErrors").ok().and_then(|errors| errors.first()) {
+        Some(Bson::Document(first)) => first,
+        _ => return Ok(()),
+    };
+    let message = first.get_str("errmsg").unwrap_or_default().to_string();
+    match first.get_i32("code") {
+        Ok(121) => Err(Error::Validation(message)),
+        _ => Err(mongodb::error::Error::from(std::io::Error::other(message)).into()),
+    }
+}
+
+/// Partial update of a post: `None` leaves a field alone. For optional
+/// fields, `Some(None)` removes the field.
+#[derive(Debug, Default, Clone)]
+pub struct PostPatch {
+    pub title: Option<String>,
+    pub message: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub lang: Option<Option<String>>,
+    pub publish_at: Option<Option<bson::DateTime>>,
+}
+
+impl PostPatch {
+    /// The `$set`/`$unset` update for the provided fields, plus the usual
+    /// `version` bump, or `None` if the patch is empty.
+    pub fn to_update(&self) -> Option<Document> {
+        let mut set = Document::new();
+        let mut unset = Document::new();
+        if let Some(title) = &self.title {
+            set.insert("title", title);
+            set.insert("title_prefixes", Post::title_prefixes(title));
+        }
+        if let Some(message) = &self.message {
+            set.insert("message", message);
+            set.insert("rendered_html", Post::render_message(message));
+            set.insert("message_hash", Post::message_hash(message));
+        }
+        if let Some(tags) = &self.tags {
+            set.insert("tags", tags);
+        }
+        match &self.lang {
+            Some(Some(lang)) => { set.insert("lang", lang); }
+            Some(None) => { unset.insert("lang", ""); }
+            None => {}
+        }
+        match self.publish_at {
+            Some(Some(publish_at)) => { set.insert("publish_at", publish_at); }
+            Some(None) => { unset.insert("publish_at", ""); }
+            None => {}
+        }
+
+        if set.is_empty() && unset.is_empty() {
+            return None;
+        }
+        let mut update = doc! { "$inc": { "version": 1 } };
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+        Some(update)
+    }
+}
+
+/// Reads from the nearest member, with hedging switched on or off.
+///
+/// With hedging, mongos sends each read to two members and returns whichever
+/// answers first, trading extra load for a shorter latency tail. It only
+/// applies to sharded clusters on 4.4+; elsewhere this is a plain `nearest`.
+/// `nearest` hedges by default, so turning it off has to be explicit.
+pub fn nearest_reads(hedge: bool) -> CollectionOptions {
+    let options = ReadPreferenceOptions::builder()
+        .hedge(HedgedReadOptions::with_enabled(hedge))
+        .build();
+    CollectionOptions::builder()
+        .selection_criteria(SelectionCriteria::ReadPreference(ReadPreference::Nearest { options }))
+        .build()
+}
+
+/// The pipeline behind [`PostRepository::search`]: one `$match` for the
+/// filters, then a `$facet` for the page, the total and the tag counts.
+pub fn search_pipeline(filters: &SearchFilters) -> Vec<Document> {
+    let mut filter = Document::new();
+    if let Some(text) = &filters.text {
+        filter.insert("$text", doc! { "$search": text });
+    }
+    if !filters.tags.is_empty() {
+        filter.insert("tags", doc! { "$all": &filters.tags });
+    }
+    let mut created_at = Document::new();
+    if let Some(from) = filters.from {
+        created_at.insert("$gte", bson::DateTime::from_chrono(from));
+    }
+    if let Some(to) = filters.to {
+        created_at.insert("$lt", bson::DateTime::from_chrono(to));
+    }
+    if !created_at.is_empty() {
+        filter.insert("created_at", created_at);
+    }
+
+    let per_page = i64::try_from(filters.per_page.max(1)).unwrap_or(i64::MAX);
+    vec![
+        doc! { "$match": filter },
+        doc! { "$facet": {
+            "items": [
+                { "$sort": { "created_at": -1, "_id": -1 } },
+                // A page too far for `$skip` is past the last match anyway
+                { "$skip": page_offset(filters.page, filters.per_page).unwrap_or(i64::MAX) },
+                { "$limit": per_page },
+            ],
+            "total": [{ "$count": "count" }],
+            "tag_counts": [
+                { "$unwind": "$tags" },
+                { "$sortByCount": "$tags" },
+            ],
+        }},
+    ]
+}
+
+/// How many matches come before zero-based `page` of `per_page` (at least 1)
+/// each, or `None` when that's more than `$skip` takes.
+pub fn page_offset(page: u64, per_page: u64) -> Option<i64> {
+    i64::try_from(page).ok()?.checked_mul(i64::try_from(per_page.max(1)).ok()?)
+}
+
+/// Where archived posts are moved to; same shape as `posts`.
+pub const POSTS_ARCHIVE: &str = "posts_archive";
+
+/// Comments on posts, each referencing its post by `post_id`.
+pub const COMMENTS: &str = "comments";
+
+/// How many comment ids a post embeds in [`Post::comment_ids`].
+pub const EMBEDDED_COMMENTS: usize = 100;
+
+/// The ids of the comments of posts with more than [`EMBEDDED_COMMENTS`],
+/// in [`CommentOverflow`] buckets.
+pub const COMMENT_OVERFLOW: &str = "comment_overflow";
+
+/// Ids per [`CommentOverflow`] bucket.
+pub const OVERFLOW_BUCKET: i64 = 1000;
+
+/// Ids of some comments of a post past its first [`EMBEDDED_COMMENTS`].
+/// Buckets are filled one after the other, so in `_id` order they list the
+/// comments about in the order they were made.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CommentOverflow {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub post_id: PostId,
+    /// `comment_ids.len()`, for filling a bucket to be matched on.
+    #[serde(with = "crate::serde_helpers::int")]
+    pub count: i64,
+    pub comment_ids: Vec<ObjectId>,
+}
+
+/// What [`PostRepository::find_comment_ids`] reads of a post.
+#[derive(serde::Deserialize)]
+struct EmbeddedComments {
+    #[serde(default)]
+    comment_ids: Vec<ObjectId>,
+    #[serde(default)]
+    comments_overflow: bool,
+}
+
+/// Writes that skipped the validator, one [`ValidationBypass`] each; see
+/// [`PostRepository::with_legacy_bypass`].
+pub const VALIDATION_BYPASSES: &str = "validation_bypasses";
+
+/// A legacy post re-saved without validation, and why the validator
+/// rejected it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ValidationBypass {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub post_id: ObjectId,
+    /// The method that wrote it, as in the `op` of its comment.
+    pub op: String,
+    /// The handle's context, as in the `ctx` of its comment.
+    pub context: Option<String>,
+    /// The validator's error for the write.
+    pub error: String,
+    #[serde(with = "crate::serde_helpers::chrono_datetime")]
+    pub at: DateTime<Utc>,
+}
+
+/// Application name stamped on every operation's comment.
+pub const APP_NAME: &str = "rust-mongodb-example";
+
+/// Options of the reads [`PostRepository::with_role`] restricts.
+trait Projected {
+    fn projection_mut(&mut self) -> &mut Option<Document>;
+}
+
+impl Projected for FindOptions {
+    fn projection_mut(&mut self) -> &mut Option<Document> {
+        &mut self.projection
+    }
+}
+
+impl Projected for FindOneOptions {
+    fn projection_mut(&mut self) -> &mut Option<Document> {
+        &mut self.projection
+    }
+}
+
+impl Projected for FindOneAndUpdateOptions {
+    fn projection_mut(&mut self) -> &mut Option<Document> {
+        &mut self.projection
+    }
+}
+
+impl Projected for FindOneAndDeleteOptions {
+    fn projection_mut(&mut self) -> &mut Option<Document> {
+        &mut self.projection
+    }
+}
+
+/// The cluster each repository method that doesn't run on the primary one
+/// targets, by the `op` of its comment: the reporting aggregations, which
+/// scan much of the collection and can live with slightly stale data.
+pub const CLUSTER_ROUTES: &[(&str, &str)] = &[
+    ("group_by_tag", ANALYTICS),
+    ("group_by_tag_matching", ANALYTICS),
+    ("daily_counts", ANALYTICS),
+    ("count_per_day", ANALYTICS),
+    ("weekly_digest", ANALYTICS),
+];
+
+/// The basic reads and writes of posts, for callers that work with either
+/// [`PostRepository`] or [`DataApiRepository`](crate::data_api::DataApiRepository),
+/// whichever [`Backend`](crate::data_api::Backend) is configured. Each method
+/// behaves like the one of the same name on [`PostRepository`].
+pub trait PostStore: Sync {
+    fn insert<'a>(&'a self, post: &'a Post) -> BoxFuture<'a, Result<ObjectId>>;
+    fn find_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<Post>>;
+    fn find_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<Vec<Post>>>;
+    fn update_title_by_tag<'a>(&'a self, tag: &'a str, title: &'a str) -> BoxFuture<'a, Result<UpdateSummary>>;
+    fn delete_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<u64>>;
+    fn delete_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<()>>;
+}
+
+impl PostStore for PostRepository {
+    fn insert<'a>(&'a self, post: &'a Post) -> BoxFuture<'a, Result<ObjectId>> {
+        PostRepository::insert(self, post).boxed()
+    }
+
+    fn find_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<Post>> {
+        PostRepository::find_by_id(self, id).boxed()
+    }
+
+    fn find_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<Vec<Post>>> {
+        PostRepository::find_by_tag(self, tag).boxed()
+    }
+
+    fn update_title_by_tag<'a>(&'a self, tag: &'a str, title: &'a str) -> BoxFuture<'a, Result<UpdateSummary>> {
+        PostRepository::update_title_by_tag(self, tag, title).boxed()
+    }
+
+    fn delete_by_tag<'a>(&'a self, tag: &'a str) -> BoxFuture<'a, Result<u64>> {
+        PostRepository::delete_by_tag(self, tag).boxed()
+    }
+
+    fn delete_by_id(&self, id: ObjectId) -> BoxFuture<'_, Result<()>> {
+        PostRepository::delete_by_id(self, id).boxed()
+    }
+}
+
+/// Typed access to the `posts` collection.
+///
+/// Every operation carries a `comment` of the form
+/// `{ app, op: "<method>", ctx: "<context>" }`, which shows up in the server
+/// log, the profiler and `$currentOp`, so slow or failing operations can be
+/// traced back to the method and the feature or request that issued them.
+#[derive(Clone)]
+pub struct PostRepository {
+    col: Collection<Post>,
+    /// Prefixed name of [`POSTS_ARCHIVE`], for `$unionWith`.
+    archive: String,
+    /// [`TAG_COUNTS`](crate::transactions::TAG_COUNTS), kept right by [`PostRepository::rename_tag`].
+    tag_counts: Collection<Document>,
+    comments: Collection<Comment>,
+    comment_overflow: Collection<CommentOverflow>,
+    context: Option<String>,
+    deadline: Option<Deadline>,
+    retry: RetryPolicy,
+    /// Who is reading, for [`ReadPolicy::posts`]; everything is readable when unset.
+    role: Option<Role>,
+    /// `posts` on the cluster of each method of [`CLUSTER_ROUTES`] whose
+    /// cluster is configured.
+    routed: Arc<HashMap<&'static str, Collection<Post>>>,
+    cancellation: Option<Cancellation>,
+    /// Whether [`PostRepository::with_legacy_bypass`] applies.
+    legacy_bypass: bool,
+    validation_bypasses: Collection<ValidationBypass>,
+    /// Consulted by [`PostRepository::title_taken`] and told of every title written here.
+    titles: Option<Arc<TitleFilter>>,
+    /// Whether the server has transactions, probed by the first write that
+    /// uses one and shared by every handle made from this repository.
+    transactions: Arc<OnceCell<bool>>,
+}
+
+impl PostRepository {
+    pub fn new(ns: &Namespace) -> Self {
+        PostRepository::with_options(ns, CollectionOptions::default())
+    }
+
+    /// A repository whose operations default to `options`, e.g. [`nearest_reads`].
+    pub fn with_options(ns: &Namespace, options: CollectionOptions) -> Self {
+        PostRepository {
+            col: ns.collection_with_options("posts", options),
+            archive: ns.name(POSTS_ARCHIVE),
+            tag_counts: ns.collection(crate::transactions::TAG_COUNTS),
+            comments: ns.collection(COMMENTS),
+            comment_overflow: ns.collection(COMMENT_OVERFLOW),
+            context: None,
+            deadline: None,
+            retry: RetryPolicy::default(),
+            role: None,
+            routed: Arc::default(),
+            cancellation: None,
+            legacy_bypass: false,
+            validation_bypasses: ns.collection(VALIDATION_BYPASSES),
+            titles: None,
+            transactions: Arc::default(),
+        }
+    }
+
+    /// A handle whose operations are tagged with `context`, e.g. a feature
+    /// name or a request id. Cheap: the underlying collection is shared.
+    pub fn with_context(&self, context: impl Into<String>) -> Self {
+        PostRepository { context: Some(context.into()), ..self.clone() }
+    }
+
+    /// A handle whose operations all have to finish before `deadline`.
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        PostRepository { deadline: Some(deadline), ..self.clone() }
+    }
+
+    /// A handle that retries operations failing with a transient error as
+    /// `retry` says; [`RetryPolicy::default`] otherwise.
+    ///
+    /// Every public method retries as a whole except [`PostRepository::import`],
+    /// whose report would miscount what an interrupted attempt inserted,
+    /// [`PostRepository::archive_by_tag`] and [`PostRepository::sync_posts`],
+    /// whose steps retry one by one, and the comment writes, whose
+    /// transaction retries instead.
+    /// Writes that would be applied twice, or answer differently, if they
+    /// were sent again after the server carried them out only retry errors
+    /// that [rule that out](Error::is_retryable_write). A deadline bounds the
+    /// retries too: an attempt that would start after it fails with
+    /// [`Error::DeadlineExceeded`], which isn't retried.
+    pub fn with_retry(&self, retry: RetryPolicy) -> Self {
+        PostRepository { retry, ..self.clone() }
+    }
+
+    /// A handle that leaves out of every post it reads the fields `role` may
+    /// not see under [`ReadPolicy::posts`], by narrowing the query's
+    /// projection or pipeline, so they are never sent over the wire.
+    /// Summaries, counts and ids are read the same way, so a pipeline that
+    /// groups by a hidden field sees it as missing. A post read this way
+    /// lacks those fields, so [`PostRepository::replace_post`] with it would
+    /// erase them; patch it instead.
+    pub fn with_role(&self, role: Role) -> Self {
+        PostRepository { role: Some(role), ..self.clone() }
+    }
+
+    /// A handle whose methods of [`CLUSTER_ROUTES`] run on their cluster in
+    /// `clusters`, or where they ran so far when it isn't configured. Routed
+    /// methods use the cluster's own defaults, not the options this handle
+    /// was made with.
+    pub fn with_clusters(&self, clusters: &Clusters) -> Self {
+        let routed = CLUSTER_ROUTES.iter()
+            .filter_map(|(op, cluster)| Some((*op, clusters.get(cluster)?.collection("posts"))))
+            .collect();
+        PostRepository { routed: Arc::new(routed), ..self.clone() }
+    }
+
+    /// A handle whose aggregations give up once `token` is cancelled: the
+    /// call fails with [`Error::Cancelled`] right away, and the aggregation
+    /// is killed on every cluster this handle uses, found by the tag its
+    /// `comment` carries. Other operations are short and run to the end.
+    pub fn with_cancellation(&self, token: CancellationToken) -> Self {
+        PostRepository { cancellation: Some(Cancellation::new(token)), ..self.clone() }
+    }
+
+    /// A handle whose [`PostRepository::replace_post`] still saves a post the
+    /// validator rejects if the stored post fails the validator too: a legacy
+    /// post, written before the validator got stricter, that would otherwise
+    /// be stuck until someone fixes it by hand. The write is recorded in
+    /// [`VALIDATION_BYPASSES`] first, so none goes unaudited; a record whose
+    /// write then failed is left behind. Posts the validator accepts as
+    /// stored keep failing with [`Error::Validation`].
+    pub fn with_legacy_bypass(&self) -> Self {
+        PostRepository { legacy_bypass: true, ..self.clone() }
+    }
+
+    /// A handle whose [`PostRepository::title_taken`] answers from `titles`
+    /// where it can, and which adds the titles it writes to it. Handles made
+    /// from this one share the filter.
+    pub fn with_title_filter(&self, titles: Arc<TitleFilter>) -> Self {
+        PostRepository { titles: Some(titles), ..self.clone() }
+    }
+
+    /// Adds `title`, just written, to the handle's title filter if it has one.
+    fn took_title(&self, title: &str) {
+        if let Some(titles) = &self.titles {
+            titles.insert(title);
+        }
+    }
+
+    /// `posts` on the cluster `op` is routed to.
+    fn col_for(&self, op: &str) -> &Collection<Post> {
+        self.routed.get(op).unwrap_or(&self.col)
+    }
+
+    /// `options` with the projection narrowed for the handle's role.
+    fn visible<O: Projected>(&self, mut options: O) -> O {
+        if let Some(role) = self.role {
+            ReadPolicy::posts().restrict_projection(role, options.projection_mut());
+        }
+        options
+    }
+
+    /// `pipeline` without the fields the handle's role may not read.
+    fn visible_pipeline(&self, pipeline: Vec<Document>) -> Vec<Document> {
+        match self.role {
+            Some(role) => ReadPolicy::posts().restrict_pipeline(role, pipeline),
+            None => pipeline,
+        }
+    }
+
+    async fn retrying<T, F, Fut>(&self, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry.run(attempt).await
+    }
+
+    /// [`PostRepository::retrying`] for writes that aren't idempotent, such as
+    /// an `$inc`, or whose result a resent write would change.
+    async fn retrying_write<T, F, Fut>(&self, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry.run_write(attempt).await
+    }
+
+    /// [`PostRepository::retrying`] for aggregations, which can run for long:
+    /// stops waiting for them once the handle's token is cancelled.
+    async fn aggregating<T, F, Fut>(&self, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let Some(cancellation) = &self.cancellation else {
+            return self.retrying(attempt).await;
+        };
+        tokio::select! {
+            biased;
+            _ = cancellation.token().cancelled() => {
+                // Dropping the call only closes its cursor once it has one;
+                // the server would carry on with the first batch regardless
+                for col in std::iter::once(&self.col).chain(self.routed.values()) {
+                    if let Err(e) = cancellation::kill(col.client(), cancellation.tag()).await {
+                        tracing::warn!("unable to kill a cancelled aggregation: {}", e);
+                    }
+                }
+                Err(Error::Cancelled)
+            }
+            result = self.retrying(attempt) => result,
+        }
+    }
+
+    /// What is left of the deadline, for the `maxTimeMS` of the next operation.
+    /// Fails with [`Error::DeadlineExceeded`] once it has passed, so the rest of
+    /// a sequence fails fast; writes, which take no `maxTimeMS` in this driver,
+    /// call it just for that check.
+    #[allow(clippy::result_large_err)] // same `Result` as every other method here
+    fn max_time(&self) -> Result<Option<Duration>> {
+        match self.deadline {
+            Some(deadline) => deadline.remaining().map(Some).ok_or(Error::DeadlineExceeded),
+            None => Ok(None),
+        }
+    }
+
+    fn comment(&self, op: &str) -> Bson {
+        let mut comment = doc! { "app": APP_NAME, "op": op };
+        if let Some(context) = &self.context {
+            comment.insert("ctx", context);
+        }
+        if let Some(cancellation) = &self.cancellation {
+            comment.insert("cancel", cancellation.tag());
+        }
+        comment.into()
+    }
+
+    /// Inserts `post` and returns its id, failing with [`Error::DuplicateTitle`]
+    /// if its title only differs in case from an existing one.
+    ///
+    /// A retry after an attempt whose reply was lost may find the post already
+    /// there, inserted by that attempt: if its `_id` is stored, it counts as
+    /// inserted.
+    pub async fn insert(&self, post: &Post) -> Result<ObjectId> {
+        let post = post.rendered();
+        let post = &*post;
+        let resent = AtomicBool::new(false);
+        self.retrying(|| async {
+            self.max_time()?;
+            let options = InsertOneOptions::builder().comment(self.comment("insert")).build();
+            let inserted = match self.col.insert_one(post, options).await {
+                Ok(result) => Ok(result.inserted_id.as_object_id().unwrap_or(post.id)),
+                Err(e) if is_duplicate_key(&e) && resent.load(Ordering::Relaxed) => {
+                    let options = FindOneOptions::builder().projection(doc! { "_id": 1 }).build();
+                    let ids = self.col.clone_with_type::<Document>();
+                    match ids.find_one(doc! { "_id": post.id }, options).await? {
+                        Some(_) => Ok(post.id),
+                        None => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            resent.store(true, Ordering::Relaxed);
+            match inserted {
+                Ok(id) => {
+                    self.took_title(&post.title);
+                    Ok(id)
+                }
+                Err(e) if is_duplicate_key(&e) => Err(Error::DuplicateTitle(post.title.clone())),
+                Err(e) => Err(e.into()),
+            }
+        }).await
+    }
+
+    /// The post with `id`, or [`Error::NotFound`] if there is none.
+    pub async fn find_by_id(&self, id: ObjectId) -> Result<Post> {
+        self.retrying(|| async {
+            let options = FindOneOptions::builder()
+                .comment_bson(self.comment("find_by_id"))
+                .max_time(self.max_time()?)
+                .build();
+            match self.col.find_one(doc! { "_id": id }, self.visible(options)).await {
+                Ok(post) => post.ok_or(Error::NotFound),
+                Err(e) if is_decode_error(&e) => {
+                    Err(self.explain_decode_failure(doc! { "_id": id }, e).await)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }).await
+    }
+
+    /// The posts with `ids` in one `$in` query, in input order: the result has
+    /// one entry per id, `None` where there is no such post.
+    pub async fn find_by_ids(&self, ids: &[PostId]) -> Result<Vec<Option<Post>>> {
+        self.retrying(|| async {
+            let filter = doc! { "_id": { "$in": ids } };
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("find_by_ids"))
+                .max_time(self.max_time()?)
+                .build();
+            let cursor = self.col.find(filter.clone(), self.visible(options)).await?;
+            let found: Vec<Post> = match cursor.try_collect().await {
+                Ok(posts) => posts,
+                Err(e) if is_decode_error(&e) => return Err(self.explain_decode_failure(filter, e).await),
+                Err(e) => return Err(e.into()),
+            };
+            // Cloned rather than moved out, so an id asked for twice gets its post twice
+            let by_id: HashMap<PostId, Post> = found.into_iter().map(|post| (post.id, post)).collect();
+            Ok(ids.iter().map(|id| by_id.get(id).cloned()).collect())
+        }).await
+    }
+
+    /// Re-reads the documents matching `filter` untyped and reports the first
+    /// field that doesn't decode into a `Post`, since the driver's own error
+    /// names neither the document nor the field.
+    async fn explain_decode_failure(&self, filter: Document, original: mongodb::error::Error) -> Error {
+        let options = FindOptions::builder()
+            .comment_bson(self.comment("explain_decode_failure"))
+            .max_time(self.max_time().ok().flatten())
+            .build();
+        let mut cursor = match self.col.clone_with_type::<Document>().find(filter, options).await {
+            Ok(cursor) => cursor,
+            Err(_) => return original.into(),
+        };
+        while let Ok(Some(doc)) = cursor.try_next().await {
+            let id = doc.get("_id").cloned();
+            let deserializer = bson::Deserializer::new(Bson::Document(doc));
+            if let Err(e) = serde_path_to_error::deserialize::<_, Post>(deserializer) {
+                return Error::Decode { id, path: e.path().to_string(), message: e.inner().to_string() };
+            }
+        }
+        // Whatever failed has changed since; the original error is all there is.
+        original.into()
+    }
+
+    /// Applies only the fields present in `patch` and returns the updated post.
+    /// Fields not in the patch keep whatever is stored, including changes made
+    /// by other writers since the caller read the post. Fails with
+    /// [`Error::DuplicateTitle`] if the new title only differs in case from
+    /// another post's, and with [`Error::Validation`] if the result fails the
+    /// validator.
+    pub async fn patch_post(&self, id: ObjectId, patch: &PostPatch) -> Result<Post> {
+        self.retrying_write(|| async {
+            let update = match patch.to_update() {
+                Some(update) => update,
+                None => return self.find_by_id(id).await,
+            };
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .comment(self.comment("patch_post"))
+                .max_time(self.max_time()?)
+                .build();
+            match self.col.find_one_and_update(doc! { "_id": id }, update, self.visible(options)).await {
+                Ok(Some(post)) => {
+                    if let Some(title) = &patch.title {
+                        self.took_title(title);
+                    }
+                    Ok(post)
+                }
+                Ok(None) => Err(Error::NotFound),
+                // Only a new title can collide
+                Err(e) if is_duplicate_key(&e) => {
+                    Err(Error::DuplicateTitle(patch.title.clone().unwrap_or_default()))
+                }
+                Err(e) if is_validation_error(&e) => Err(Error::Validation(e.to_string())),
+                Err(e) => Err(e.into()),
+            }
+        }).await
+    }
+
+    /// Up to `limit` posts matching `filter` in `_id` order, starting after
+    /// `cursor`, which is the `next_cursor` of the previous page or `None` for
+    /// the first. Unlike skip/limit, each page is one index range scan however
+    /// deep it is, and posts inserted or deleted meanwhile don't shift pages
+    /// into repeating or missing posts.
+    pub async fn find_posts_paginated(
+        &self,
+        filter: Document,
+        cursor: Option<PostId>,
+        limit: usize,
+    ) -> Result<Page<Post>> {
+        self.retrying(|| async {
+            let filter = match cursor {
+                // `$and` rather than inserting `_id`, which `filter` may constrain itself
+                Some(cursor) => doc! { "$and": [filter.clone(), { "_id": { "$gt": cursor } }] },
+                None => filter.clone(),
+            };
+            let options = FindOptions::builder()
+                .sort(doc! { "_id": 1 })
+                .limit(limit as i64 + 1)
+                .comment_bson(self.comment("find_posts_paginated"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts: Vec<Post> = self.col.find(filter, self.visible(options)).await?.try_collect().await?;
+            Ok(Page::from_overfetched(posts, limit, |post| post.id))
+        }).await
+    }
+
+    /// Summaries of posts tagged `tag`, newest first.
+    pub async fn find_summaries_by_tag(&self, tag: &str) -> Result<Vec<PostSummary>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .projection(summary_projection())
+                .sort(doc! { "_id": -1 })
+                .comment_bson(self.comment("find_summaries_by_tag"))
+                .max_time(self.max_time()?)
+                .build();
+            let summaries = self.col.clone_with_type::<PostSummary>()
+                .find(doc! { "tags": tag }, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(summaries)
+        }).await
+    }
+
+    /// The `limit` newest summaries.
+    pub async fn list_summaries(&self, limit: i64) -> Result<Vec<PostSummary>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .projection(summary_projection())
+                .sort(doc! { "_id": -1 })
+                .limit(limit)
+                .comment_bson(self.comment("list_summaries"))
+                .max_time(self.max_time()?)
+                .build();
+            let summaries = self.col.clone_with_type::<PostSummary>()
+                .find(None, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(summaries)
+        }).await
+    }
+
+    /// The first `limit` posts in title order under `sort`'s collation.
+    pub async fn list_by_title(&self, sort: &TitleSort, limit: i64) -> Result<Vec<PostSummary>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .projection(summary_projection())
+                .sort(doc! { "title": 1, "_id": 1 })
+                .collation(sort.collation())
+                .limit(limit)
+                .comment_bson(self.comment("list_by_title"))
+                .max_time(self.max_time()?)
+                .build();
+            let summaries = self.col.clone_with_type::<PostSummary>()
+                .find(None, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(summaries)
+        }).await
+    }
+
+    /// Swaps the stored document for `post` as a whole, the counterpart of
+    /// [`PostRepository::patch_post`]: every field comes from `post`, so it is
+    /// only safe if `post` was read at the stored `version`. Otherwise this
+    /// fails with [`Error::VersionConflict`] rather than overwrite someone
+    /// else's changes, and with [`Error::DuplicateTitle`] if the new title
+    /// only differs in case from another post's. On success the stored
+    /// version is `post.version + 1`.
+    pub async fn replace_post(&self, post: &Post) -> Result<Post> {
+        self.retrying_write(|| async {
+            let expected: Bson = if post.version == 0 {
+                // Posts written before `version` existed match as version 0.
+                doc! { "$in": [0, Bson::Null] }.into()
+            } else {
+                post.version.into()
+            };
+            let replacement = Post { version: post.version + 1, ..post.rendered().into_owned() };
+            let filter = doc! { "_id": post.id, "version": expected };
+            self.max_time()?;
+            let options = ReplaceOptions::builder().comment(self.comment("replace_post")).build();
+            let mut replaced = self.col.replace_one(filter.clone(), &replacement, options).await;
+            if let Err(e) = &replaced {
+                if is_validation_error(e) && self.legacy_bypass && self.is_legacy(filter.clone()).await? {
+                    self.record_bypass(post.id, "replace_post", e).await?;
+                    let options = ReplaceOptions::builder()
+                        .comment(self.comment("replace_post"))
+                        .bypass_document_validation(true)
+                        .build();
+                    replaced = self.col.replace_one(filter, &replacement, options).await;
+                }
+            }
+            match replaced {
+                Ok(result) if result.matched_count == 1 => {
+                    self.took_title(&replacement.title);
+                    Ok(replacement)
+                }
+                Ok(_) => {
+                    let current = self.find_by_id(post.id).await?;
+                    Err(Error::VersionConflict { expected: post.version, actual: current.version })
+                }
+                Err(e) if is_duplicate_key(&e) => Err(Error::DuplicateTitle(post.title.clone())),
+                Err(e) if is_validation_error(&e) => Err(Error::Validation(e.to_string())),
+                Err(e) => Err(e.into()),
+            }
+        }).await
+    }
+
+    /// Whether the stored post matching `filter` fails the collection's
+    /// validator as it is.
+    async fn is_legacy(&self, filter: Document) -> Result<bool> {
+        let namespace = self.col.namespace();
+        let db = self.col.client().database(&namespace.db);
+        let spec = db.list_collections(doc! { "name": &namespace.coll }, None).await?.try_next().await?;
+        let Some(validator) = spec.and_then(|spec| spec.options.validator) else {
+            return Ok(false);
+        };
+        let filter = doc! { "$and": [filter, { "$nor": [validator] }] };
+        let options = FindOneOptions::builder()
+            .comment_bson(self.comment("is_legacy"))
+            .projection(doc! { "_id": 1 })
+            .max_time(self.max_time()?)
+            .build();
+        Ok(self.col.clone_with_type::<Document>().find_one(filter, options).await?.is_some())
+    }
+
+    async fn record_bypass(&self, post_id: ObjectId, op: &str, e: &mongodb::error::Error) -> Result<()> {
+        let bypass = ValidationBypass {
+            id: ObjectId::new(),
+            post_id,
+            op: op.to_string(),
+            context: self.context.clone(),
+            error: e.to_string(),
+            at: Utc::now(),
+        };
+        let options = InsertOneOptions::builder().comment(self.comment("record_bypass")).build();
+        self.validation_bypasses.insert_one(&bypass, options).await?;
+        Ok(())
+    }
+
+    /// Inserts `posts` and returns their ids in input order.
+    pub async fn insert_many(&self, posts: &[Post]) -> Result<Vec<ObjectId>> {
+        let rendered: Vec<Cow<Post>> = posts.iter().map(Post::rendered).collect();
+        self.retrying_write(|| async {
+            self.max_time()?;
+            let options = InsertManyOptions::builder().comment(self.comment("insert_many")).build();
+            let result = self.col.insert_many(rendered.iter().map(|post| &**post), options).await?;
+            let mut ids: Vec<(usize, ObjectId)> = result.inserted_ids.into_iter()
+                .filter_map(|(index, id)| id.as_object_id().map(|id| (index, id)))
+                .collect();
+            ids.sort_by_key(|(index, _)| *index);
+            Ok(ids.into_iter().map(|(_, id)| id).collect())
+        }).await
+    }
+
+    pub async fn find_by_tag(&self, tag: &str) -> Result<Vec<Post>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("find_by_tag"))
+                .max_time(self.max_time()?)
+                .build();
+            let filter = doc! { "tags": tag };
+            match self.col.find(filter.clone(), self.visible(options)).await?.try_collect().await {
+                Ok(posts) => Ok(posts),
+                Err(e) if is_decode_error(&e) => Err(self.explain_decode_failure(filter, e).await),
+                Err(e) => Err(e.into()),
+            }
+        }).await
+    }
+
+    /// Posts with every one of `attributes` in their metadata, through the
+    /// [`attributes::index`].
+    pub async fn find_by_attributes(&self, attributes: &[Attribute]) -> Result<Vec<Post>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("find_by_attributes"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.col.find(attributes::with_all(attributes), self.visible(options)).await?.try_collect().await?)
+        }).await
+    }
+
+    /// Every tag in use with the ids of its posts.
+    pub async fn group_by_tag(&self) -> Result<Vec<TagWithPosts>> {
+        self.group_tags(None, "group_by_tag").await
+    }
+
+    /// [`PostRepository::group_by_tag`] over just the posts matching the
+    /// full-text `query`.
+    pub async fn group_by_tag_matching(&self, query: &str) -> Result<Vec<TagWithPosts>> {
+        self.group_tags(Some(query), "group_by_tag_matching").await
+    }
+
+    async fn group_tags(&self, query: Option<&str>, op: &str) -> Result<Vec<TagWithPosts>> {
+        self.aggregating(|| async {
+            // A `$text` match has to be the first stage of the pipeline
+            let mut pipeline: Vec<Document> = query.iter()
+                .map(|query| doc! { "$match": { "$text": { "$search": *query } } })
+                .collect();
+            pipeline.push(doc! { "$unwind": "$tags" });
+            pipeline.push(doc! { "$group": {
+                "_id": "$tags",
+                "post_ids": { "$addToSet": "$_id" }
+            }});
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment(op))
+                .max_time(self.max_time()?)
+                .build();
+            let groups = self.col_for(op).aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<TagWithPosts>()
+                .try_collect().await?;
+            Ok(groups)
+        }).await
+    }
+
+    /// Retitles every post tagged `tag`.
+    pub async fn update_title_by_tag(&self, tag: &str, title: &str) -> Result<UpdateSummary> {
+        self.retrying_write(|| async {
+            let update = doc! {
+                "$set": { "title": title, "title_prefixes": Post::title_prefixes(title) },
+                "$inc": { "version": 1 },
+            };
+            self.max_time()?;
+            let options = UpdateOptions::builder().comment(self.comment("update_title_by_tag")).build();
+            let result = self.col.update_many(doc! { "tags": tag }, update, options).await?;
+            if result.modified_count > 0 {
+                self.took_title(title);
+            }
+            Ok(result.into())
+        }).await
+    }
+
+    /// Replaces `old` with `new` in the tags of every post, keeping its
+    /// position and dropping it where the post already had `new`, then moves
+    /// `old`'s entry in `tag_counts` to `new`.
+    ///
+    /// Each post is rewritten atomically by a pipeline update (MongoDB 4.2+),
+    /// but not the posts as a whole: a failure partway leaves some renamed,
+    /// and running it again finishes the job. `tag_cooccurrence` is only
+    /// right again after its next rebuild. See [`crate::transactions::merge_tags`]
+    /// for several tags at once, all or nothing.
+    pub async fn rename_tag(&self, old: &str, new: &str) -> Result<TagRename> {
+        self.retrying_write(|| async {
+            let count_options = |max_time| CountOptions::builder()
+                .comment(self.comment("rename_tag"))
+                .max_time(max_time)
+                .build();
+            let both = doc! { "tags": { "$all": [old, new] } };
+            let merged = self.col.count_documents(both, count_options(self.max_time()?)).await?;
+            let update = replace_tags_update(&[old], &[new]);
+            self.max_time()?;
+            let options = UpdateOptions::builder().comment(self.comment("rename_tag")).build();
+            let result = match self.col.update_many(doc! { "tags": old }, update, options).await {
+                Ok(result) => result,
+                Err(e) if is_validation_error(&e) => return Err(Error::Validation(e.to_string())),
+                Err(e) => return Err(e.into()),
+            };
+            // Counted afresh rather than added up, which would count merged posts twice
+            let options = FindOneAndDeleteOptions::builder()
+                .comment(self.comment("rename_tag"))
+                .max_time(self.max_time()?)
+                .build();
+            if self.tag_counts.find_one_and_delete(doc! { "_id": old }, options).await?.is_some() {
+                let count = self.col.count_documents(doc! { "tags": new }, count_options(self.max_time()?)).await?;
+                let count = i64::try_from(count).unwrap_or(i64::MAX);
+                self.max_time()?;
+                let upsert = UpdateOptions::builder().upsert(true).comment(self.comment("rename_tag")).build();
+                self.tag_counts.update_one(doc! { "_id": new }, doc! { "$set": { "count": count } }, upsert).await?;
+            }
+            Ok(TagRename { matched: result.matched_count, modified: result.modified_count, merged })
+        }).await
+    }
+
+    /// Deletes every post tagged `tag` and returns how many there were.
+    pub async fn delete_by_tag(&self, tag: &str) -> Result<u64> {
+        self.retrying_write(|| async {
+            self.max_time()?;
+            let options = DeleteOptions::builder().comment(self.comment("delete_by_tag")).build();
+            Ok(self.col.delete_many(doc! { "tags": tag }, options).await?.deleted_count)
+        }).await
+    }
+
+    /// Inserts `posts` with one `insert_many` and reports exactly which
+    /// documents failed and why, instead of a single opaque bulk error.
+    /// Duplicate titles are then skipped or merged as `on_duplicate` says.
+    pub async fn import(
+        &self,
+        posts: Vec<Post>,
+        mode: ImportMode,
+        on_duplicate: OnDuplicate,
+    ) -> Result<ImportReport> {
+        let total = posts.len();
+        self.max_time()?;
+        let options = InsertManyOptions::builder()
+            .ordered(mode == ImportMode::Ordered)
+            .comment(self.comment("import"))
+            .build();
+        let rendered: Vec<Cow<Post>> = posts.iter().map(Post::rendered).collect();
+        let failure = match self.col.insert_many(rendered.iter().map(|post| &**post), options).await {
+            Ok(result) => {
+                posts.iter().for_each(|post| self.took_title(&post.title));
+                return Ok(ImportReport { inserted: result.inserted_ids.len(), ..Default::default() });
+            }
+            Err(e) => match *e.kind {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => failure,
+                _ => return Err(e.into()),
+            },
+        };
+        let write_errors = failure.write_errors.unwrap_or_default();
+        // An ordered insert stops at its only failure, so everything before it
+        // went in; an unordered one attempts every document.
+        let (inserted, not_attempted) = match (mode, write_errors.first()) {
+            (ImportMode::Ordered, Some(first)) => {
+                posts[..first.index].iter().for_each(|post| self.took_title(&post.title));
+                (first.index, total - first.index - 1)
+            }
+            _ => {
+                let failed: Vec<usize> = write_errors.iter().map(|error| error.index).collect();
+                let written = posts.iter().enumerate().filter(|(index, _)| !failed.contains(index));
+                written.for_each(|(_, post)| self.took_title(&post.title));
+                (total - write_errors.len(), 0)
+            }
+        };
+        let mut report = ImportReport { inserted, not_attempted, ..Default::default() };
+        for error in write_errors {
+            let post = &posts[error.index];
+            match error.code {
+                11000 if on_duplicate == OnDuplicate::Skip => report.skipped_duplicates += 1,
+                11000 => {
+                    self.merge_by_title(post).await?;
+                    report.updated += 1;
+                }
+                code => {
+                    if code == 121 {
+                        report.rejected_validation += 1;
+                    }
+                    report.failed.push(ImportFailure {
+                        index: error.index,
+                        title: post.title.clone(),
+                        code,
+                        message: error.message,
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Overwrites the content of the stored post titled like `post`, keeping
+    /// its id, creation time and title spelling.
+    async fn merge_by_title(&self, post: &Post) -> Result<()> {
+        const TITLE_FIELDS: [&str; 2] = ["title", "title_prefixes"];
+        let mut fields = synced_fields(post)?;
+        for field in TITLE_FIELDS {
+            fields.remove(field);
+        }
+        let update = synced_update(fields, &TITLE_FIELDS);
+        let options = UpdateOptions::builder()
+            .collation(title_collation())
+            .comment(self.comment("merge_by_title"))
+            .build();
+        self.max_time()?;
+        self.col.update_one(doc! { "title": &post.title }, update, options).await?;
+        Ok(())
+    }
+
+    /// Makes the collection hold exactly `posts`, matched to stored posts by
+    /// title (case-insensitively, like the unique index): new titles are
+    /// inserted, changed posts updated in place, and posts whose title isn't
+    /// in `posts` deleted. Of repeated titles the last one wins.
+    ///
+    /// The 2.x driver has no `bulk_write`, so the changes go out as one
+    /// unordered `update` command (inserts are upserts by id) plus one
+    /// `delete` command, rather than one round trip per post. The comparison
+    /// retries like a read; each command only when it can't have been
+    /// carried out, as the report counts what was sent.
+    pub async fn sync_posts(&self, posts: Vec<Post>) -> Result<SyncReport> {
+        let (report, updates, stale, titles) = self.retrying(|| async {
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("sync_posts"))
+                .max_time(self.max_time()?)
+                .build();
+            let stored: Vec<Post> = self.col.find(None, self.visible(options)).await?.try_collect().await?;
+            let mut stored: HashMap<String, Post> = stored.into_iter()
+                .map(|post| (post.title.to_lowercase(), post))
+                .collect();
+            let mut order = Vec::new();
+            let mut wanted: HashMap<String, Post> = HashMap::new();
+            for post in posts.iter().cloned() {
+                let key = post.title.to_lowercase();
+                if wanted.insert(key.clone(), post).is_none() {
+                    order.push(key);
+                }
+            }
+
+            let mut report = SyncReport::default();
+            let mut updates = Vec::new();
+            let mut titles = Vec::new();
+            for key in order {
+                let post = wanted.remove(&key).expect("every key has a post");
+                let fields = synced_fields(&post)?;
+                match stored.remove(&key) {
+                    Some(current) if synced_fields(&current)? == fields => report.unchanged += 1,
+                    Some(current) => {
+                        updates.push(doc! { "q": { "_id": current.id }, "u": synced_update(fields, &[]) });
+                        titles.push(post.title);
+                        report.updated += 1;
+                    }
+                    None => {
+                        titles.push(post.title.clone());
+                        let post = bson::to_document(&Post { version: 0, ..post })?;
+                        updates.push(doc! { "q": { "_id": post.get("_id") }, "u": post, "upsert": true });
+                        report.inserted += 1;
+                    }
+                }
+            }
+            let stale: Vec<PostId> = stored.into_values().map(|post| post.id).collect();
+            report.deleted = stale.len();
+            Ok((report, updates, stale, titles))
+        }).await?;
+
+        let db = self.col.client().database(&self.col.namespace().db);
+        if !updates.is_empty() {
+            self.retrying_write(|| async {
+                self.max_time()?;
+                let command = doc! {
+                    "update": self.col.name(),
+                    "updates": updates.clone(),
+                    "ordered": false,
+                    "comment": self.comment("sync_posts"),
+                };
+                check_write_errors(&db.run_command(command, None).await?)
+            }).await?;
+            titles.iter().for_each(|title| self.took_title(title));
+        }
+        if !stale.is_empty() {
+            self.retrying_write(|| async {
+                self.max_time()?;
+                let command = doc! {
+                    "delete": self.col.name(),
+                    "deletes": [{ "q": { "_id": { "$in": stale.clone() } }, "limit": 0 }],
+                    "comment": self.comment("sync_posts"),
+                };
+                check_write_errors(&db.run_command(command, None).await?)
+            }).await?;
+        }
+        Ok(report)
+    }
+
+    /// Deletes the post, failing with [`Error::NotFound`] if there was none to delete.
+    pub async fn delete_by_id(&self, id: ObjectId) -> Result<()> {
+        self.retrying_write(|| async {
+            self.max_time()?;
+            let options = DeleteOptions::builder().comment(self.comment("delete_by_id")).build();
+            let result = self.col.delete_one(doc! { "_id": id }, options).await?;
+            match result.deleted_count {
+                0 => Err(Error::NotFound),
+                _ => Ok(()),
+            }
+        }).await
+    }
+
+    /// Moves every post tagged `tag` into `posts_archive`, one at a time,
+    /// and returns how many were moved.
+    ///
+    /// Each post is copied before it is deleted, so a failure in between
+    /// leaves it in both collections rather than in neither, and running it
+    /// again finishes the job: the copy replaces the archived one with its
+    /// `_id`. The delete only matches the version copied; a post changed in
+    /// between is copied again.
+    pub async fn archive_by_tag(&self, tag: &str) -> Result<u64> {
+        // Moved as stored, not as the handle's role may read them
+        let posts = self.col.clone_with_type::<Document>();
+        let archive: Collection<Document> = self.col.client()
+            .database(&self.col.namespace().db)
+            .collection(&self.archive);
+        let mut moved = 0;
+        for summary in self.find_summaries_by_tag(tag).await? {
+            let id = summary.id;
+            loop {
+                let post = self.retrying(|| async {
+                    let options = FindOneOptions::builder()
+                        .comment_bson(self.comment("archive_by_tag"))
+                        .max_time(self.max_time()?)
+                        .build();
+                    Ok(posts.find_one(doc! { "_id": id }, options).await?)
+                }).await?;
+                let Some(post) = post else {
+                    break;
+                };
+                self.retrying(|| async {
+                    self.max_time()?;
+                    let options = ReplaceOptions::builder()
+                        .upsert(true)
+                        .comment(self.comment("archive_by_tag"))
+                        .build();
+                    archive.replace_one(doc! { "_id": id }, &post, options).await?;
+                    Ok(())
+                }).await?;
+                let copied = doc! { "_id": id, "version": post.get("version").cloned().unwrap_or(Bson::Null) };
+                let deleted = self.retrying_write(|| async {
+                    self.max_time()?;
+                    let options = DeleteOptions::builder().comment(self.comment("archive_by_tag")).build();
+                    Ok(posts.delete_one(copied.clone(), options).await?.deleted_count)
+                }).await?;
+                if deleted > 0 {
+                    moved += 1;
+                    break;
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Deletes the post and hands it back, e.g. to move it elsewhere.
+    /// Read and delete are one atomic operation, so two callers can never
+    /// both take the same post.
+    pub async fn take_by_id(&self, id: ObjectId) -> Result<Option<Post>> {
+        self.retrying_write(|| async {
+            let options = FindOneAndDeleteOptions::builder()
+                .comment(self.comment("take_by_id"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.col.find_one_and_delete(doc! { "_id": id }, self.visible(options)).await?)
+        }).await
+    }
+
+    /// Posts matching `filter` in both `posts` and `posts_archive`, sorted by
+    /// `sort` across the two. The filter runs in each collection before the
+    /// `$unionWith`, so both can use their indexes. Needs MongoDB 4.4+.
+    pub async fn find_including_archived(&self, filter: Document, sort: Document) -> Result<Vec<Post>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$match": filter.clone() },
+                doc! { "$unionWith": { "coll": &self.archive, "pipeline": [{ "$match": filter.clone() }] } },
+                doc! { "$sort": sort.clone() },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("find_including_archived"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<Post>()
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Adds a comment to post `post_id`, or fails with [`Error::NotFound`]
+    /// when there is no such post. Nothing enforces the reference later on:
+    /// deleting a post leaves its comments behind.
+    ///
+    /// The comment's id goes into the post's [`Post::comment_ids`] while
+    /// there is room, and into [`COMMENT_OVERFLOW`] once there is none, with
+    /// the post flagged as `comments_overflow`. So the handful of viral posts
+    /// with thousands of comments don't grow towards the document size limit,
+    /// while every other post keeps all of its ids to itself.
+    ///
+    /// [`Post::comment_count`] goes up in the same update of the post that
+    /// references the comment, so the count always matches the ids. The
+    /// comment and the post are written in one transaction where the server
+    /// has them; on a standalone server a comment written right before a
+    /// failure goes uncounted, which [`PostRepository::comment_count_drift`]
+    /// finds.
+    pub async fn add_comment(&self, post_id: PostId, author: &str, body: &str) -> Result<Comment> {
+        let comment = Comment {
+            id: ObjectId::new(),
+            post_id,
+            author: author.to_string(),
+            body: body.to_string(),
+            created_at: bson::DateTime::now(),
+        };
+        self.in_transaction(|session| {
+            let (repo, comment) = (self.clone(), comment.clone());
+            async move { repo.write_comment(comment, session).await }.boxed()
+        }).await
+    }
+
+    async fn write_comment(
+        &self,
+        comment: Comment,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<Option<Comment>> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .comment_bson(self.comment("add_comment"))
+            .build();
+        let posts = self.col.clone_with_type::<Document>();
+        if posts.find_one_with_session(doc! { "_id": comment.post_id }, options, session).await?.is_none() {
+            return Ok(None);
+        }
+        let options = InsertOneOptions::builder().comment(self.comment("add_comment")).build();
+        self.comments.insert_one_with_session(&comment, options, session).await?;
+        // Referenced once it exists, so every id read leads to a comment
+        let options = UpdateOptions::builder().comment(self.comment("add_comment")).build();
+        let has_room = format!("comment_ids.{}", EMBEDDED_COMMENTS - 1);
+        let embedded = self.col.update_one_with_session(
+            // Not once there is overflow, even where a delete made room, so
+            // the embedded ids stay the oldest
+            doc! { "_id": comment.post_id, has_room: { "$exists": false }, "comments_overflow": { "$ne": true } },
+            doc! { "$push": { "comment_ids": comment.id }, "$inc": { "version": 1, "comment_count": 1 } },
+            options.clone(),
+            session,
+        ).await?;
+        if embedded.matched_count == 0 {
+            // Flagged first: a reader may look for overflow there is none of
+            // yet, but never miss overflow there is
+            self.col.update_one_with_session(
+                doc! { "_id": comment.post_id },
+                doc! { "$set": { "comments_overflow": true }, "$inc": { "version": 1, "comment_count": 1 } },
+                options,
+                session,
+            ).await?;
+            let options = UpdateOptions::builder()
+                .upsert(true)
+                .comment(self.comment("add_comment"))
+                .build();
+            self.comment_overflow.update_one_with_session(
+                doc! { "post_id": comment.post_id, "count": { "$lt": OVERFLOW_BUCKET } },
+                doc! { "$push": { "comment_ids": comment.id }, "$inc": { "count": 1 } },
+                options,
+                session,
+            ).await?;
+        }
+        Ok(Some(comment))
+    }
+
+    /// Deletes the comment with `id`, or fails with [`Error::NotFound`] if
+    /// there is none, taking its id off its post or out of the overflow and
+    /// counting it off the post's [`Post::comment_count`], in one transaction
+    /// where the server has them.
+    pub async fn delete_comment(&self, id: ObjectId) -> Result<()> {
+        self.in_transaction(|session| {
+            let repo = self.clone();
+            async move { repo.unlink_comment(id, session).await }.boxed()
+        }).await
+    }
+
+    async fn unlink_comment(
+        &self,
+        id: ObjectId,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<Option<()>> {
+        let options = FindOneAndDeleteOptions::builder().comment(self.comment("delete_comment")).build();
+        let deleted = self.comments.find_one_and_delete_with_session(doc! { "_id": id }, options, session).await?;
+        let Some(comment) = deleted else {
+            return Ok(None);
+        };
+        let options = UpdateOptions::builder().comment(self.comment("delete_comment")).build();
+        let embedded = self.col.update_one_with_session(
+            doc! { "_id": comment.post_id, "comment_ids": id },
+            doc! { "$pull": { "comment_ids": id }, "$inc": { "version": 1, "comment_count": -1 } },
+            options.clone(),
+            session,
+        ).await?;
+        if embedded.matched_count == 0 {
+            self.comment_overflow.update_one_with_session(
+                doc! { "post_id": comment.post_id, "comment_ids": id },
+                doc! { "$pull": { "comment_ids": id }, "$inc": { "count": -1 } },
+                options.clone(),
+                session,
+            ).await?;
+            // Comments from before the counts were kept were never counted
+            self.col.update_one_with_session(
+                doc! { "_id": comment.post_id, "comment_count": { "$gt": 0 } },
+                doc! { "$inc": { "version": 1, "comment_count": -1 } },
+                options,
+                session,
+            ).await?;
+        }
+        Ok(Some(()))
+    }
+
+    /// Runs `steps`, which come up with `None` for [`Error::NotFound`], in
+    /// one transaction where the server has them, retried as a whole by
+    /// [`run_in_txn`] since an aborted attempt leaves nothing behind.
+    /// Elsewhere they run once on a plain session: a retry after some of the
+    /// steps went through would apply those twice.
+    async fn in_transaction<T, F>(&self, mut steps: F) -> Result<T>
+    where
+        F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, mongodb::error::Result<Option<T>>>,
+    {
+        self.max_time()?;
+        let client = self.col.client();
+        let transactions = self.transactions.get_or_try_init(|| async {
+            Ok::<_, mongodb::error::Error>(Capabilities::probe(client).await?.check(Feature::Transactions).is_ok())
+        }).await?;
+        let written = if *transactions {
+            run_in_txn(client, steps).await?
+        } else {
+            steps(&mut client.start_session(None).await?).await?
+        };
+        written.ok_or(Error::NotFound)
+    }
+
+    /// The posts whose [`Post::comment_count`] differs from the number of
+    /// their comments, e.g. after a comment write was interrupted, or for a
+    /// post commented on before the counts were kept. Counts every post's
+    /// comments through the `post_id` index: a job for off-peak hours.
+    pub async fn comment_count_drift(&self) -> Result<Vec<CommentCountDrift>> {
+        self.retrying(|| async {
+            let pipeline = vec![
+                doc! { "$project": { "comment_count": 1 } },
+                doc! { "$lookup": {
+                    "from": self.comments.name(),
+                    "let": { "post_id": "$_id" },
+                    "pipeline": [
+                        { "$match": { "$expr": { "$eq": ["$post_id", "$$post_id"] } } },
+                        { "$count": "count" },
+                    ],
+                    "as": "actual",
+                }},
+                doc! { "$project": {
+                    "stored": { "$ifNull": ["$comment_count", 0] },
+                    "actual": { "$ifNull": [{ "$first": "$actual.count" }, 0] },
+                }},
+                doc! { "$match": { "$expr": { "$ne": ["$stored", "$actual"] } } },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("comment_count_drift"))
+                .max_time(self.max_time()?)
+                .build();
+            let drift = self.col.aggregate(pipeline, options).await?
+                .with_type::<CommentCountDrift>()
+                .try_collect().await?;
+            Ok(drift)
+        }).await
+    }
+
+    /// Sets the `comment_count` of each post in `drift` to its `actual`,
+    /// unless it changed since: a comment written meanwhile counted itself.
+    /// Returns how many were set.
+    pub async fn repair_comment_counts(&self, drift: &[CommentCountDrift]) -> Result<u64> {
+        let mut repaired = 0;
+        for post in drift {
+            let stored = match post.stored {
+                0 => doc! { "$in": [0, Bson::Null] },
+                stored => doc! { "$eq": stored },
+            };
+            let options = UpdateOptions::builder().comment(self.comment("repair_comment_counts")).build();
+            let update = self.col.update_one(
+                doc! { "_id": post.post_id, "comment_count": stored },
+                doc! { "$set": { "comment_count": post.actual }, "$inc": { "version": 1 } },
+                options,
+            ).await?;
+            repaired += update.modified_count;
+        }
+        Ok(repaired)
+    }
+
+    /// Ids of the comments on post `post_id`, oldest first, or
+    /// [`Error::NotFound`] when there is no such post: the embedded ones, then
+    /// those in [`COMMENT_OVERFLOW`]. Only posts flagged as having overflow
+    /// cost a second read.
+    pub async fn find_comment_ids(&self, post_id: PostId) -> Result<Vec<ObjectId>> {
+        self.retrying(|| async {
+            let options = FindOneOptions::builder()
+                .projection(doc! { "comment_ids": 1, "comments_overflow": 1 })
+                .comment_bson(self.comment("find_comment_ids"))
+                .max_time(self.max_time()?)
+                .build();
+            let post = self.col.clone_with_type::<EmbeddedComments>().find_one(doc! { "_id": post_id }, options)
+                .await?
+                .ok_or(Error::NotFound)?;
+            let mut ids = post.comment_ids;
+            if post.comments_overflow {
+                let options = FindOptions::builder()
+                    .sort(doc! { "_id": 1 })
+                    .comment_bson(self.comment("find_comment_ids"))
+                    .max_time(self.max_time()?)
+                    .build();
+                let mut buckets = self.comment_overflow.find(doc! { "post_id": post_id }, options).await?;
+                while let Some(bucket) = buckets.try_next().await? {
+                    ids.extend(bucket.comment_ids);
+                }
+            }
+            Ok(ids)
+        }).await
+    }
+
+    /// Comments on post `post_id`, oldest first.
+    pub async fn find_comments(&self, post_id: PostId) -> Result<Vec<Comment>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .sort(doc! { "created_at": 1, "_id": 1 })
+                .comment_bson(self.comment("find_comments"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.comments.find(doc! { "post_id": post_id }, options).await?.try_collect().await?)
+        }).await
+    }
+
+    /// Posts matching `filter` in `_id` order, each joined with its comments
+    /// by a `$lookup` on `comments.post_id`, which its index keeps to one
+    /// index range per post. The comments are `$unwind`-ed to sort them and
+    /// grouped back; posts without comments are kept with none.
+    pub async fn find_posts_with_comments(&self, filter: Document) -> Result<Vec<PostWithComments>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$match": filter.clone() },
+                doc! { "$lookup": {
+                    "from": self.comments.name(),
+                    "localField": "_id",
+                    "foreignField": "post_id",
+                    "as": "comments",
+                }},
+                doc! { "$unwind": { "path": "$comments", "preserveNullAndEmptyArrays": true } },
+                doc! { "$sort": { "_id": 1, "comments.created_at": 1, "comments._id": 1 } },
+                // `$push` skips the missing `comments` of a post without any
+                doc! { "$group": {
+                    "_id": "$_id",
+                    "post": { "$first": "$$ROOT" },
+                    "comments": { "$push": "$comments" },
+                }},
+                doc! { "$project": { "post.comments": 0 } },
+                doc! { "$sort": { "_id": 1 } },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("find_posts_with_comments"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<PostWithComments>()
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    pub async fn find_by_title(&self, title: &str) -> Result<Option<Post>> {
+        self.retrying(|| async {
+            let options = FindOneOptions::builder()
+                .collation(title_collation())
+                .comment_bson(self.comment("find_by_title"))
+                .max_time(self.max_time()?)
+                .build();
+            Ok(self.col.find_one(doc! { "title": title }, self.visible(options)).await?)
+        }).await
+    }
+
+    /// The ids of `author`'s newest `limit` posts, newest first. A covered
+    /// query: the `(author, _id)` index holds everything it filters on,
+    /// sorts by and returns, so no post is read.
+    pub async fn ids_by_author(&self, author: &str, limit: i64) -> Result<Vec<PostId>> {
+        self.retrying(|| async {
+            let (filter, mut options) = ids_by_author_query(author, limit);
+            options.comment_bson = Some(self.comment("ids_by_author"));
+            options.max_time = self.max_time()?;
+            let posts: Vec<Document> = self.col.clone_with_type::<Document>().find(filter, options).await?
+                .try_collect().await?;
+            Ok(posts.iter().filter_map(|post| post.get_object_id("_id").ok()).collect())
+        }).await
+    }
+
+    /// Whether a post is titled `title`, ignoring case like the unique index.
+    /// With a [`TitleFilter`] that has never seen `title`, that is taken to
+    /// be a no without asking the server; see there for when that is wrong,
+    /// and [`PostRepository::insert`] for what then happens.
+    pub async fn title_taken(&self, title: &str) -> Result<bool> {
+        if self.titles.as_ref().is_some_and(|titles| !titles.may_contain(title)) {
+            return Ok(false);
+        }
+        self.retrying(|| async {
+            let options = FindOneOptions::builder()
+                .collation(title_collation())
+                .projection(doc! { "_id": 1 })
+                .comment_bson(self.comment("title_taken"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.clone_with_type::<Document>();
+            Ok(posts.find_one(doc! { "title": title }, options).await?.is_some())
+        }).await
+    }
+
+    /// Moves a post to `to`, but only from a status that may precede it.
+    ///
+    /// The check happens on the server as part of the update filter, so a
+    /// concurrent transition can't sneak in between reading and writing.
+    pub async fn transition(&self, id: ObjectId, to: PostStatus) -> Result<Post> {
+        self.retrying_write(|| async {
+            let mut from: Vec<Bson> = to.predecessors().iter().map(|status| status.as_str().into()).collect();
+            if to.predecessors().contains(&PostStatus::Published) {
+                // Posts written before `status` existed are published.
+                from.push(Bson::Null);
+            }
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .comment(self.comment("transition"))
+                .max_time(self.max_time()?)
+                .build();
+            let updated = self.col.find_one_and_update(
+                doc! { "_id": id, "status": { "$in": from } },
+                doc! { "$set": { "status": to.as_str() }, "$inc": { "version": 1 } },
+                self.visible(options),
+            ).await?;
+            if let Some(post) = updated {
+                return Ok(post);
+            }
+            let options = FindOneOptions::builder()
+                .comment_bson(self.comment("transition"))
+                .max_time(self.max_time()?)
+                .build();
+            match self.col.find_one(doc! { "_id": id }, self.visible(options)).await? {
+                Some(post) => Err(Error::IllegalTransition { from: post.status, to }),
+                None => Err(Error::NotFound),
+            }
+        }).await
+    }
+
+    /// Posts tagged `tag` with `message` replaced by its `lang` translation,
+    /// or left as the default-language message when there is none.
+    pub async fn find_by_tag_localized(&self, tag: &str, lang: &str) -> Result<Vec<Post>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$match": { "tags": tag } },
+                doc! { "$addFields": { "message": { "$ifNull": [
+                    { "$arrayElemAt": [
+                        { "$map": {
+                            "input": { "$filter": {
+                                "input": { "$ifNull": ["$content", []] },
+                                "cond": { "$eq": ["$$this.lang", lang] },
+                            }},
+                            "in": "$$this.message",
+                        }},
+                        0,
+                    ]},
+                    "$message",
+                ]}}},
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("find_by_tag_localized"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<Post>()
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Posts matching the full-text `query` on title, message or a
+    /// translation, most relevant first. See [`TextIndexConfig`] for how
+    /// words are weighted.
+    pub async fn search_posts(&self, query: &str) -> Result<Vec<ScoredPost>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$match": { "$text": { "$search": query } } },
+                doc! { "$sort": { "score": { "$meta": "textScore" }, "_id": 1 } },
+                // Nested rather than a `score` next to the post's fields, which
+                // `strict-decoding` would reject as unknown
+                doc! { "$replaceRoot": {
+                    "newRoot": { "post": "$$ROOT", "score": { "$meta": "textScore" } }
+                }},
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("search_posts"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<ScoredPost>()
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Full-text search stemmed with `lang`'s rules instead of the index default.
+    pub async fn search_in_language(&self, query: &str, lang: &str) -> Result<Vec<Post>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("search_in_language"))
+                .max_time(self.max_time()?)
+                .build();
+            let filter = doc! { "$text": { "$search": query, "$language": lang } };
+            let posts = self.col.find(filter, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Post counts per local calendar day, oldest day first.
+    pub async fn daily_counts(&self, timezone: &str) -> Result<Vec<DailyCount>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$group": { "_id": local_day(timezone), "count": { "$sum": 1 } } },
+                doc! { "$sort": { "_id": 1 } },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("daily_counts"))
+                .max_time(self.max_time()?)
+                .build();
+            let counts = self.col_for("daily_counts").aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<DailyCount>()
+                .try_collect().await?;
+            Ok(counts)
+        }).await
+    }
+
+    /// Posts created on `day` (`YYYY-MM-DD`) as seen from `timezone`.
+    pub async fn find_created_on(&self, day: &str, timezone: &str) -> Result<Vec<Post>> {
+        self.retrying(|| async {
+            let filter = doc! { "$expr": { "$eq": [local_day(timezone), day] } };
+            let options = FindOptions::builder()
+                .comment_bson(self.comment("find_created_on"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.find(filter, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Posts created in `[from, to)`, oldest first.
+    pub async fn find_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Post>> {
+        self.retrying(|| async {
+            let options = FindOptions::builder()
+                .sort(doc! { "created_at": 1 })
+                .comment_bson(self.comment("find_between"))
+                .max_time(self.max_time()?)
+                .build();
+            let posts = self.col.find(created_between(from, to), self.visible(options)).await?
+                .try_collect().await?;
+            Ok(posts)
+        }).await
+    }
+
+    /// Post counts per UTC day for posts created in `[from, to)`. Days without
+    /// posts are omitted. Uses `$dateTrunc`, so it needs MongoDB 5.0+.
+    pub async fn count_per_day(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<DayBucket>> {
+        self.aggregating(|| async {
+            let pipeline = vec![
+                doc! { "$match": created_between(from, to) },
+                doc! { "$group": {
+                    "_id": { "$dateTrunc": { "date": "$created_at", "unit": "day" } },
+                    "count": { "$sum": 1 },
+                }},
+                doc! { "$sort": { "_id": 1 } },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("count_per_day"))
+                .max_time(self.max_time()?)
+                .build();
+            let buckets = self.col_for("count_per_day").aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<DayBucket>()
+                .try_collect().await?;
+            Ok(buckets)
+        }).await
+    }
+
+    /// The digest of the posts published in `[from, to)`, with the `top` most
+    /// commented for each tag, in one aggregation.
+    pub async fn weekly_digest(&self, from: DateTime<Utc>, to: DateTime<Utc>, top: i64) -> Result<WeeklyDigest> {
+        self.aggregating(|| async {
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("weekly_digest"))
+                .max_time(self.max_time()?)
+                .build();
+            let pipeline = digest_pipeline(self.comments.name(), from, to, top);
+            let tags = self.col_for("weekly_digest").aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<TagDigest>()
+                .try_collect().await?;
+            Ok(WeeklyDigest { from, to, tags })
+        }).await
+    }
+
+    /// Runs the filters as one `$match` followed by a `$facet` that returns
+    /// the requested page, the total and per-tag counts in a single round trip.
+    #[tracing::instrument(name = "PostRepository::search", skip(self))]
+    pub async fn search(&self, filters: &SearchFilters) -> Result<SearchResults> {
+        self.aggregating(|| async {
+            let pipeline = search_pipeline(filters);
+
+            #[derive(serde::Deserialize)]
+            struct Total {
+                count: i64,
+            }
+            #[derive(serde::Deserialize)]
+            struct Facets {
+                items: Vec<Post>,
+                total: Vec<Total>,
+                tag_counts: Vec<TagCount>,
+            }
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("search"))
+                .max_time(self.max_time()?)
+                .build();
+            let facets: Option<Facets> = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<Facets>()
+                .try_next().await?;
+            Ok(match facets {
+                Some(facets) => SearchResults {
+                    items: facets.items,
+                    total: facets.total.first().map_or(0, |total| total.count),
+                    tag_counts: facets.tag_counts,
+                },
+                None => SearchResults { items: Vec::new(), total: 0, tag_counts: Vec::new() },
+            })
+        }).await
+    }
+
+    /// Titles with a word starting with `prefix`, via the `title_prefixes` index.
+    #[tracing::instrument(name = "PostRepository::suggest", skip(self))]
+    pub async fn suggest(&self, prefix: &str, limit: i64) -> Result<Vec<String>> {
+        self.retrying(|| async {
+            #[derive(serde::Deserialize)]
+            struct Title {
+                title: String,
+            }
+            let options = FindOptions::builder()
+                .projection(doc! { "_id": 0, "title": 1 })
+                .sort(doc! { "title": 1 })
+                .limit(limit)
+                .comment_bson(self.comment("suggest"))
+                .max_time(self.max_time()?)
+                .build();
+            let titles: Vec<Title> = self.col.clone_with_type::<Title>()
+                .find(doc! { "title_prefixes": prefix.to_lowercase() }, self.visible(options)).await?
+                .try_collect().await?;
+            Ok(titles.into_iter().map(|t| t.title).collect())
+        }).await
+    }
+
+    /// Posts sharing the most tags with post `id`, best match first; ties go
+    /// to the newer post. Fails with [`Error::NotFound`] if there is no such post.
+    pub async fn similar_posts(&self, id: ObjectId, page: u64, per_page: u64) -> Result<Vec<SimilarPost>> {
+        self.aggregating(|| async {
+            let options = FindOneOptions::builder()
+                .projection(summary_projection())
+                .comment_bson(self.comment("similar_posts"))
+                .max_time(self.max_time()?)
+                .build();
+            let post = self.col.clone_with_type::<PostSummary>()
+                .find_one(doc! { "_id": id }, self.visible(options)).await?
+                .ok_or(Error::NotFound)?;
+
+            let skip = page_offset(page, per_page).unwrap_or(i64::MAX);
+            let per_page = i64::try_from(per_page.max(1)).unwrap_or(i64::MAX);
+            let pipeline = vec![
+                doc! { "$match": { "_id": { "$ne": id }, "tags": { "$in": &post.tags } } },
+                doc! { "$project": {
+                    "title": 1,
+                    "tags": 1,
+                    "created_at": 1,
+                    "shared_tags": { "$size": { "$setIntersection": ["$tags", &post.tags] } },
+                }},
+                doc! { "$sort": { "shared_tags": -1, "created_at": -1, "_id": -1 } },
+                doc! { "$skip": skip },
+                doc! { "$limit": per_page },
+                doc! { "$unset": "created_at" },
+            ];
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("similar_posts"))
+                .max_time(self.max_time()?)
+                .build();
+            let similar = self.col.aggregate(self.visible_pipeline(pipeline), options).await?
+                .with_type::<SimilarPost>()
+                .try_collect().await?;
+            Ok(similar)
+        }).await
+    }
+
+    /// `size` posts drawn at random, each tag getting a share of them in
+    /// proportion to how many posts carry it, so popular tags come up as
+    /// often as they would in real traffic: one pass counts the tags, and a
+    /// second `$sample`s each tag's share within a `$facet`. A post with
+    /// several tags can be drawn once for each; fewer come back than asked
+    /// for only when there are fewer posts.
+    pub async fn sample_by_tag(&self, size: u64) -> Result<Vec<TaggedSample>> {
+        self.aggregating(|| async {
+            let options = AggregateOptions::builder()
+                .comment_bson(self.comment("sample_by_tag"))
+                .max_time(self.max_time()?)
+                .build();
+            let pipeline = vec![
+                doc! { "$unwind": "$tags" },
+                doc! { "$group": { "_id": "$tags", "count": { "$sum": 1 } } },
+                doc! { "$sort": { "_id": 1 } },
+            ];
+            let counts: Vec<TagCount> = self.col.aggregate(self.visible_pipeline(pipeline), options.clone()).await?
+                .with_type::<TagCount>()
+                .try_collect().await?;
+            let quotas = tag_quotas(&counts, size);
+            if quotas.is_empty() {
+                return Ok(Vec::new());
+            }
+            // Tags can hold `.` and `$`, which field names can't, so facets go by position
+            let facets: Document = quotas.iter().enumerate()
+                .map(|(i, (tag, quota))| {
+                    let stages = vec![
+                        doc! { "$match": { "tags": tag } },
+                        doc! { "$sample": { "size": *quota as i64 } },
+                    ];
+                    (i.to_string(), Bson::from(self.visible_pipeline(stages)))
+                })
+                .collect();
+            let mut drawn = self.col.aggregate(vec![doc! { "$facet": facets }], options).await?
+                .with_type::<HashMap<String, Vec<Post>>>()
+                .try_next().await?
+                .unwrap_or_default();
+            let samples = quotas.iter().enumerate()
+                .flat_map(|(i, (tag, _))| {
+                    let posts = drawn.remove(&i.to_string()).unwrap_or_default();
+                    posts.into_iter().map(|post| TaggedSample { tag: tag.clone(), post })
+                })
+                .collect();
+            Ok(samples)
+        }).await
+    }
+}
+
+/// Filter and options of [`PostRepository::ids_by_author`], projected down
+/// to what the index holds.
+fn ids_by_author_query(author: &str, limit: i64) -> (Document, FindOptions) {
+    let options = FindOptions::builder()
+        .projection(doc! { "_id": 1 })
+        .sort(doc! { "_id": -1 })
+        .limit(limit)
+        .build();
+    (doc! { "author": author }, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+    use mongodb::options::CreateCollectionOptions;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn an_empty_patch_has_no_update() {
+        assert_eq!(PostPatch::default().to_update(), None);
+    }
+
+    #[test]
+    fn a_patch_sets_and_unsets_only_its_fields() {
+        let patch = PostPatch {
+            title: Some("Hi".to_string()),
+            lang: Some(None),
+            ..PostPatch::default()
+        };
+        let expected = doc! {
+            "$inc": { "version": 1 },
+            "$set": { "title": "Hi", "title_prefixes": ["h", "hi"] },
+            "$unset": { "lang": "" },
+        };
+        assert_eq!(patch.to_update(), Some(expected));
+    }
+
+    #[test]
+    fn a_patched_message_is_rendered_again() {
+        let update = PostPatch { message: Some("*new*".to_string()), ..PostPatch::default() }.to_update().unwrap();
+        let set = update.get_document("$set").unwrap();
+        assert_eq!(set.get_str("rendered_html"), Ok("<p><em>new</em></p>\n"));
+        assert_eq!(set.get_str("message_hash").ok(), Some(&*Post::message_hash("*new*")));
+        assert!(!update.contains_key("$unset"));
+    }
+
+    #[test]
+    fn page_offset_refuses_what_skip_cant_take() {
+        assert_eq!(page_offset(3, 20), Some(60));
+        assert_eq!(page_offset(3, 0), Some(3));
+        assert_eq!(page_offset(u64::MAX, 20), None);
+        assert_eq!(page_offset(i64::MAX as u64, 2), None);
+    }
+
+    #[test]
+    fn default_title_sort_is_byte_order() {
+        let collation = TitleSort::default().collation();
+        assert_eq!(collation.locale, "simple");
+        assert_eq!(collation.numeric_ordering, None);
+    }
+
+    #[test]
+    fn numeric_title_sort_needs_a_real_locale() {
+        let collation = TitleSort { locale: None, numeric: true }.collation();
+        assert_eq!(collation.locale, "en");
+        assert_eq!(collation.numeric_ordering, Some(true));
+    }
+
+    #[test]
+    fn numeric_title_sort_keeps_the_locale() {
+        let collation = TitleSort { locale: Some("de".to_string()), numeric: true }.collation();
+        assert_eq!(collation.locale, "de");
+        assert_eq!(collation.numeric_ordering, Some(true));
+    }
+
+    #[tokio::test]
+    async fn cancellable_handles_tag_their_comments() {
+        // The client connects lazily, and `comment` doesn't need it to
+        let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+        let repo = PostRepository::new(&Namespace::new(client.database("blog"), ""));
+        assert!(repo.comment("find_by_id").as_document().unwrap().get("cancel").is_none());
+        let cancellable = repo.with_cancellation(CancellationToken::new());
+        let tag = cancellable.cancellation.as_ref().unwrap().tag();
+        assert_eq!(cancellable.comment("search").as_document().unwrap().get_object_id("cancel"), Ok(tag));
+    }
+
+    #[test]
+    fn tag_quotas_follow_tag_counts_and_add_up() {
+        let counts = |counts: &[(&str, i64)]| -> Vec<TagCount> {
+            counts.iter().map(|(tag, count)| TagCount { tag: tag.to_string(), count: *count }).collect()
+        };
+        let quotas = tag_quotas(&counts(&[("rust", 6), ("mongo", 3), ("misc", 1)]), 5);
+        assert_eq!(quotas, [("rust".to_string(), 3), ("mongo".to_string(), 2)]);
+        assert_eq!(tag_quotas(&counts(&[("a", 1), ("b", 1)]), 10), [("a".to_string(), 1), ("b".to_string(), 1)]);
+        assert!(tag_quotas(&counts(&[]), 3).is_empty());
+    }
+
+    #[test]
+    fn overfetched_page_points_at_its_last_item() {
+        let ids: Vec<PostId> = (0..3).map(|_| ObjectId::new()).collect();
+        let page = Page::from_overfetched(ids.clone(), 2, |id| *id);
+        assert_eq!(page, Page { items: ids[..2].to_vec(), next_cursor: Some(ids[1]) });
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let ids: Vec<PostId> = (0..2).map(|_| ObjectId::new()).collect();
+        let page = Page::from_overfetched(ids.clone(), 2, |id| *id);
+        assert_eq!(page, Page { items: ids, next_cursor: None });
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn import_report_counts_duplicates() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let title_index = IndexModel::builder()
+            .keys(doc! { "title": 1 })
+            .options(IndexOptions::builder().unique(true).collation(title_collation()).build())
+            .build();
+        ns.collection::<Post>("posts").create_index(title_index, None).await.unwrap();
+        let repo = PostRepository::new(&ns);
+        repo.insert(&Post::new("Taken", "Stored first", &["import"])).await.unwrap();
+
+        let batch = || vec![
+            Post::new("taken", "Imported", &["import"]),
+            Post::new("Free", "Imported", &["import"]),
+        ];
+        let skipped = repo.import(batch(), ImportMode::Unordered, OnDuplicate::Skip).await.unwrap();
+        assert_eq!(skipped, ImportReport { inserted: 1, skipped_duplicates: 1, ..Default::default() });
+        // "Free" went in the first time, so now both clash
+        let merged = repo.import(batch(), ImportMode::Unordered, OnDuplicate::Update).await.unwrap();
+        assert_eq!(merged, ImportReport { updated: 2, ..Default::default() });
+        let taken = repo.find_by_title("Taken").await.unwrap().unwrap();
+        assert_eq!((taken.title.as_str(), taken.message.as_str()), ("Taken", "Imported"));
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn a_filter_backed_handle_learns_every_title_it_writes() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        crate::schema::setup_posts(&ns).await.unwrap();
+        let titles = Arc::new(TitleFilter::with_capacity(0));
+        let repo = PostRepository::new(&ns).with_title_filter(titles.clone());
+        // Written past the filter, so it only learns the titles below
+        let id = PostRepository::new(&ns).insert(&Post::new("Unfiltered", "Before", &[])).await.unwrap();
+
+        let patch = PostPatch { title: Some("Patched".to_string()), ..Default::default() };
+        let patched = repo.patch_post(id, &patch).await.unwrap();
+        repo.replace_post(&Post { title: "Replaced".to_string(), ..patched }).await.unwrap();
+        let imported = vec![Post::new("Imported", "Imported", &[])];
+        repo.import(imported, ImportMode::Ordered, OnDuplicate::Skip).await.unwrap();
+        repo.sync_posts(vec![Post::new("Synced", "Synced", &[])]).await.unwrap();
+
+        for title in ["Patched", "Replaced", "Imported", "Synced"] {
+            assert!(titles.may_contain(title), "{} was written, yet looks free", title);
+        }
+        assert!(!titles.may_contain("Unfiltered"));
+        sandbox.cleanup().await;
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn ids_by_author_come_from_the_index_alone() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        crate::schema::setup_posts(&ns).await.unwrap();
+        let repo = PostRepository::new(&ns);
+        let mut ids = Vec::new();
+        for (n, author) in ["ann", "bob", "ann"].iter().enumerate() {
+            let author = Some(author.to_string());
+            let post = Post { author, ..Post::new(&format!("By {}", n), "Covered", &[]) };
+            ids.push(repo.insert(&post).await.unwrap());
+        }
+        assert_eq!(repo.ids_by_author("ann", 10).await.unwrap(), [ids[2], ids[0]]);
+
+        let (filter, options) = ids_by_author_query("ann", 10);
+        let find = crate::explain::find_command(&ns.name("posts"), filter, &options);
+        crate::explain::ensure_covered(ns.db(), find).await.unwrap();
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn a_post_the_archive_rejects_stays_in_posts() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let repo = PostRepository::new(&ns);
+        let id = repo.insert(&Post::new("Kept", "Not archived", &["archive"])).await.unwrap();
+        // A validator no post passes, so the copy into the archive fails
+        let options = CreateCollectionOptions::builder()
+            .validator(doc! { "never_set": { "$exists": true } })
+            .build();
+        ns.db().create_collection(ns.name(POSTS_ARCHIVE), options).await.unwrap();
+
+        assert!(repo.archive_by_tag("archive").await.is_err());
+        assert_eq!(repo.find_by_id(id).await.unwrap().title, "Kept");
+        let archive = ns.collection::<Document>(POSTS_ARCHIVE);
+        assert_eq!(archive.count_documents(None, None).await.unwrap(), 0);
+
+        archive.drop(None).await.unwrap();
+        assert_eq!(repo.archive_by_tag("archive").await.unwrap(), 1);
+        assert!(matches!(repo.find_by_id(id).await, Err(Error::NotFound)));
+        assert_eq!(archive.count_documents(doc! { "_id": id }, None).await.unwrap(), 1);
+        sandbox.cleanup().await;
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn legacy_posts_are_resaved_past_the_validator_and_audited() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        crate::schema::setup_posts(&ns).await.unwrap();
+        // Stored before the validator capped titles at 300 characters
+        let legacy = Post::new(&"Long ".repeat(100), "Legacy", &["legacy"]);
+        let options = InsertOneOptions::builder().bypass_document_validation(true).build();
+        ns.collection::<Post>("posts").insert_one(&legacy, options).await.unwrap();
+        let edited = Post { message: "Edited".to_string(), ..legacy.clone() };
+
+        let repo = PostRepository::new(&ns);
+        assert!(matches!(repo.replace_post(&edited).await, Err(Error::Validation(_))));
+        let saved = repo.with_legacy_bypass().replace_post(&edited).await.unwrap();
+        assert_eq!(repo.find_by_id(legacy.id).await.unwrap().version, saved.version);
+        let bypasses: Vec<ValidationBypass> = repo.validation_bypasses.find(None, None).await.unwrap()
+            .try_collect().await.unwrap();
+        assert_eq!((bypasses.len(), bypasses[0].post_id), (1, legacy.id));
+
+        // A post the validator accepts as stored isn't legacy
+        let valid = Post::new("Short", "Valid", &["legacy"]);
+        repo.insert(&valid).await.unwrap();
+        let too_long = Post { title: "Long ".repeat(100), ..valid };
+        assert!(matches!(repo.with_legacy_bypass().replace_post(&too_long).await, Err(Error::Validation(_))));
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn numeric_title_sort_puts_post_2_before_post_10() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let repo = PostRepository::new(&ns);
+        let posts: Vec<Post> = ["Post 10", "Post 2", "Post 1"].iter()
+            .map(|title| Post::new(title, "Sorted", &["numeric"]))
+            .collect();
+        repo.insert_many(&posts).await.unwrap();
+
+        let titles = |summaries: Vec<PostSummary>| summaries.into_iter().map(|s| s.title).collect::<Vec<_>>();
+        let plain = repo.list_by_title(&TitleSort::default(), 10).await.unwrap();
+        assert_eq!(titles(plain), ["Post 1", "Post 10", "Post 2"]);
+        let numeric = repo.list_by_title(&TitleSort { locale: None, numeric: true }, 10).await.unwrap();
+        assert_eq!(titles(numeric), ["Post 1", "Post 2", "Post 10"]);
+    }
+}
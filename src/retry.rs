@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// How often, and how patiently, to repeat an operation that failed with a
+/// [transient](crate::error::Error::is_transient) error. Any other error, or
+/// the last attempt's, is returned as is.
+///
+/// This sits on top of the driver's own retryable reads and writes, which
+/// repeat an operation once, right away; this policy rides out longer
+/// outages such as a primary election.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Including the first; 1 means never retry.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled before each one after it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub const NEVER: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// The wait before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff.saturating_mul(2u32.saturating_pow(retry)).min(self.max_backoff)
+    }
+
+    /// Calls `attempt` until it succeeds, fails for good, or `max_attempts`
+    /// calls have been made.
+    pub async fn run<T, F, Fut>(&self, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_while(attempt, Error::is_transient).await
+    }
+
+    /// [`RetryPolicy::run`] for a write that mustn't be applied twice: only
+    /// retries errors the write [surely wasn't applied
+    /// with](crate::error::Error::is_retryable_write), not a connection that
+    /// broke while the server may have been carrying it out.
+    pub async fn run_write<T, F, Fut>(&self, attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_while(attempt, Error::is_retryable_write).await
+    }
+
+    async fn run_while<T, F, Fut>(&self, mut attempt: F, retryable: fn(&Error) -> bool) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if retryable(&e) && retry + 1 < self.max_attempts => {
+                    let backoff = self.backoff(retry);
+                    tracing::warn!(retry, backoff_ms = backoff.as_millis() as u64, "retrying after: {}", e);
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    const FAST: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    };
+
+    fn connection_error() -> Error {
+        mongodb::error::Error::from(std::io::Error::other("connection reset")).into()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<u64> = (0..6).map(|retry| policy.backoff(retry).as_millis() as u64).collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1600, 2000]);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_up_to_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = FAST.run(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(connection_error())
+        }).await;
+        assert!(matches!(result, Err(Error::Connection(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_returned_right_away() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = FAST.run(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(Error::NotFound)
+        }).await;
+        assert!(matches!(result, Err(Error::NotFound)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn writes_are_not_resent_after_a_broken_connection() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = FAST.run_write(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(connection_error())
+        }).await;
+        assert!(matches!(result, Err(Error::Connection(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn success_after_a_transient_error() {
+        let attempts = AtomicU32::new(0);
+        let result = FAST.run(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(connection_error()),
+                n => Ok(n),
+            }
+        }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+use futures::TryStreamExt;
+use mongodb::{Client, Collection};
+use mongodb::bson::{self, doc, Bson, DateTime, Document};
+use mongodb::options::ReplaceOptions;
+
+use crate::error::{Error, Result};
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// One [`Rollout`] per collection whose validator is being or was rolled out,
+/// keyed by the collection's unprefixed name.
+pub const VALIDATOR_ROLLOUTS: &str = "validator_rollouts";
+
+/// The server's "Document would fail validation" log message, written for
+/// every write a validator in `warn` mode lets through.
+const WOULD_FAIL_VALIDATION: i64 = 20294;
+
+/// How many ids of documents that would have failed a [`Rollout`] keeps.
+pub const SAMPLES: usize = 10;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RolloutStatus {
+    /// Applied with `validationAction: "warn"`: writes it rejects go through
+    /// and are logged.
+    Canary,
+    /// Applied with `validationAction: "error"`.
+    Promoted,
+    /// Replaced by the validator it was rolled out over.
+    Aborted,
+}
+
+/// A new validator rolled out in two steps: [`canary`] applies it in `warn`
+/// mode to all traffic, [`collect`] counts from the server log the writes it
+/// would have rejected, and [`promote`] makes it reject them once there are
+/// none, or [`abort`] puts the old one back.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Rollout {
+    #[serde(rename = "_id")]
+    pub collection: String,
+    pub validator: Document,
+    pub status: RolloutStatus,
+    /// The validator and `validationAction` the collection had before, for
+    /// [`abort`]; `None` where it had none.
+    pub previous: Option<Document>,
+    pub previous_action: Option<String>,
+    #[serde(with = "serde_helpers::datetime")]
+    pub started_at: DateTime,
+    /// The time of the last log line counted, so the next [`collect`]
+    /// doesn't count it again.
+    #[serde(with = "serde_helpers::datetime")]
+    pub checked_until: DateTime,
+    /// Writes the validator would have rejected since the canary started.
+    #[serde(with = "serde_helpers::int")]
+    pub warnings: i64,
+    /// `_id`s of the first [`SAMPLES`] documents behind `warnings`.
+    #[serde(default)]
+    pub samples: Vec<Bson>,
+}
+
+fn rollouts(ns: &Namespace) -> Collection<Rollout> {
+    ns.collection(VALIDATOR_ROLLOUTS)
+}
+
+async fn save(ns: &Namespace, rollout: &Rollout) -> Result<()> {
+    let options = ReplaceOptions::builder().upsert(true).build();
+    rollouts(ns).replace_one(doc! { "_id": &rollout.collection }, rollout, options).await?;
+    Ok(())
+}
+
+/// The rollout of `collection`'s validator, if there ever was one.
+pub async fn status(ns: &Namespace, collection: &str) -> Result<Option<Rollout>> {
+    Ok(rollouts(ns).find_one(doc! { "_id": collection }, None).await?)
+}
+
+async fn in_canary(ns: &Namespace, collection: &str) -> Result<Rollout> {
+    match status(ns, collection).await? {
+        Some(rollout) if rollout.status == RolloutStatus::Canary => Ok(rollout),
+        _ => Err(Error::Rollout(format!("no validator of {} is in canary", collection))),
+    }
+}
+
+async fn coll_mod(ns: &Namespace, collection: &str, validator: Document, action: &str) -> Result<()> {
+    let command = doc! {
+        "collMod": ns.name(collection),
+        "validator": validator,
+        "validationAction": action,
+    };
+    ns.db().run_command(command, None).await?;
+    Ok(())
+}
+
+/// Replaces `collection`'s validator with `validator` in `warn` mode,
+/// keeping the old one for [`abort`]. Fails while another one is in canary.
+pub async fn canary(ns: &Namespace, collection: &str, validator: Document) -> Result<Rollout> {
+    if in_canary(ns, collection).await.is_ok() {
+        return Err(Error::Rollout(format!("a validator of {} is in canary already", collection)));
+    }
+    let spec = ns.db().list_collections(doc! { "name": ns.name(collection) }, None).await?
+        .try_next().await?
+        .ok_or(Error::NotFound)?;
+    let previous_action = spec.options.validation_action
+        .and_then(|action| bson::to_bson(&action).ok())
+        .and_then(|action| action.as_str().map(str::to_string));
+    let now = DateTime::now();
+    let rollout = Rollout {
+        collection: collection.to_string(),
+        validator: validator.clone(),
+        status: RolloutStatus::Canary,
+        previous: spec.options.validator,
+        previous_action,
+        started_at: now,
+        checked_until: now,
+        warnings: 0,
+        samples: Vec::new(),
+    };
+    // Saved first, so an interrupted canary can still be aborted
+    save(ns, &rollout).await?;
+    coll_mod(ns, collection, validator, "warn").await?;
+    Ok(rollout)
+}
+
+/// Adds the writes the canary of `collection` would have rejected to its
+/// count, from the log lines the server still holds: `getLog` returns only
+/// the last 1024, so on a busy server this should run every few minutes
+/// while the canary lasts. Needs the `getLog` privilege on the cluster.
+pub async fn collect(client: &Client, ns: &Namespace, collection: &str) -> Result<Rollout> {
+    let mut rollout = in_canary(ns, collection).await?;
+    let log = client.database("admin").run_command(doc! { "getLog": "global" }, None).await?;
+    let namespace = format!("{}.{}", ns.db().name(), ns.name(collection));
+    let lines = log.get_array("log").map_err(|e| Error::Serialization(e.to_string()))?;
+    let checked_until = rollout.checked_until;
+    let warnings = lines.iter()
+        .filter_map(Bson::as_str)
+        .filter_map(|line| warning(line, &namespace))
+        .filter(|(at, _)| *at > checked_until);
+    for (at, id) in warnings {
+        rollout.warnings += 1;
+        rollout.checked_until = rollout.checked_until.max(at);
+        if rollout.samples.len() < SAMPLES {
+            rollout.samples.push(id);
+        }
+    }
+    save(ns, &rollout).await?;
+    Ok(rollout)
+}
+
+/// The time of `line` and the `_id` of the document it is about, if it is
+/// a validation warning for `namespace`. Lines in any other format, such as
+/// the plain text logs from before 4.4, are skipped.
+fn warning(line: &str, namespace: &str) -> Option<(DateTime, Bson)> {
+    let entry: serde_json::Value = serde_json::from_str(line).ok()?;
+    let attr = &entry["attr"];
+    if entry["id"].as_i64() != Some(WOULD_FAIL_VALIDATION) || attr["namespace"].as_str() != Some(namespace) {
+        return None;
+    }
+    let at = DateTime::parse_rfc3339_str(entry["t"]["$date"].as_str()?).ok()?;
+    let id = Bson::try_from(attr["document"]["_id"].clone()).unwrap_or(Bson::Null);
+    Some((at, id))
+}
+
+/// Makes the canary of `collection` reject writes, after a last
+/// [`collect`]. Fails while there are warnings, unless `force`.
+pub async fn promote(client: &Client, ns: &Namespace, collection: &str, force: bool) -> Result<Rollout> {
+    let mut rollout = collect(client, ns, collection).await?;
+    if rollout.warnings > 0 && !force {
+        return Err(Error::Rollout(format!(
+            "{} writes since {} would have been rejected; fix them, or promote with force",
+            rollout.warnings, rollout.started_at,
+        )));
+    }
+    coll_mod(ns, collection, rollout.validator.clone(), "error").await?;
+    rollout.status = RolloutStatus::Promoted;
+    save(ns, &rollout).await?;
+    Ok(rollout)
+}
+
+/// Puts back the validator the canary of `collection` replaced.
+pub async fn abort(ns: &Namespace, collection: &str) -> Result<Rollout> {
+    let mut rollout = in_canary(ns, collection).await?;
+    let previous = rollout.previous.clone().unwrap_or_default();
+    coll_mod(ns, collection, previous, rollout.previous_action.as_deref().unwrap_or("error")).await?;
+    rollout.status = RolloutStatus::Aborted;
+    save(ns, &rollout).await?;
+    Ok(rollout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_validation_warnings_for_the_namespace_count() {
+        let line = |id: i64, namespace: &str| format!(
+            r#"{{"t":{{"$date":"2024-05-01T10:00:00.123+00:00"}},"s":"W","c":"STORAGE","id":{},"ctx":"conn7",
+            "msg":"Document would fail validation","attr":{{"namespace":"{}",
+            "document":{{"_id":{{"$oid":"6631f0a0c3b5a1e2d4f6a8b0"}},"title":""}}}}}}"#,
+            id, namespace,
+        ).replace('\n', "");
+        let (at, id) = warning(&line(20294, "blog.posts"), "blog.posts").unwrap();
+        assert_eq!(at, DateTime::parse_rfc3339_str("2024-05-01T10:00:00.123Z").unwrap());
+        assert_eq!(id.as_object_id().unwrap().to_hex(), "6631f0a0c3b5a1e2d4f6a8b0");
+        assert!(warning(&line(20294, "blog.comments"), "blog.posts").is_none());
+        assert!(warning(&line(51803, "blog.posts"), "blog.posts").is_none());
+        assert!(warning("2019-01-01T00:00:00.000+0000 I NETWORK [conn1] end connection", "blog.posts").is_none());
+    }
+}
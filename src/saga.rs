@@ -0,0 +1,185 @@
+use mongodb::Collection;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::Result;
+use mongodb::options::UpdateOptions;
+
+use crate::Post;
+use crate::namespace::Namespace;
+use crate::transactions::TAG_COUNTS;
+
+pub const SAGAS: &str = "sagas";
+pub const OUTBOX: &str = "outbox";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaStatus {
+    Running,
+    Completed,
+    Compensated,
+    /// A compensating action failed too; needs a human.
+    Failed,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Pending,
+    Done,
+    Failed,
+    Compensated,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct StepRecord {
+    pub name: String,
+    pub status: StepStatus,
+    pub error: Option<String>,
+}
+
+/// Persisted progress of one saga run, updated after every transition so a
+/// crashed run can be inspected (or resumed) from the database alone.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SagaRecord {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub status: SagaStatus,
+    pub steps: Vec<StepRecord>,
+    pub post: Post,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    CreatePost,
+    IndexTags,
+    NotifyOutbox,
+}
+
+const STEPS: [Step; 3] = [Step::CreatePost, Step::IndexTags, Step::NotifyOutbox];
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::CreatePost => "create_post",
+            Step::IndexTags => "index_tags",
+            Step::NotifyOutbox => "notify_outbox",
+        }
+    }
+
+    async fn run(self, ns: &Namespace, post: &Post) -> Result<()> {
+        match self {
+            Step::CreatePost => {
+                ns.collection::<Post>("posts").insert_one(post, None).await?;
+            }
+            Step::IndexTags => {
+                let tag_counts = ns.collection::<Document>(TAG_COUNTS);
+                // Missing counts start at 0, which needs no undoing if a
+                // later tag fails
+                for tag in &post.tags {
+                    let options = UpdateOptions::builder().upsert(true).build();
+                    let create = doc! { "$setOnInsert": { "count": 0 } };
+                    tag_counts.update_one(doc! { "_id": tag }, create, options).await?;
+                }
+                // Then counted in one write rather than one per tag, so a
+                // failure doesn't leave some tags counted that compensation,
+                // which starts at the step before the failed one, would miss
+                let filter = doc! { "_id": { "$in": &post.tags } };
+                tag_counts.update_many(filter, doc! { "$inc": { "count": 1 } }, None).await?;
+            }
+            Step::NotifyOutbox => {
+                ns.collection::<Document>(OUTBOX).insert_one(
+                    doc! { "_id": post.id, "event": "post_created", "title": &post.title },
+                    None,
+                ).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn compensate(self, ns: &Namespace, post: &Post) -> Result<()> {
+        match self {
+            Step::CreatePost => {
+                ns.collection::<Post>("posts").delete_one(doc! { "_id": post.id }, None).await?;
+            }
+            Step::IndexTags => {
+                ns.collection::<Document>(TAG_COUNTS).update_many(
+                    doc! { "_id": { "$in": &post.tags } },
+                    doc! { "$inc": { "count": -1 } },
+                    None,
+                ).await?;
+            }
+            Step::NotifyOutbox => {
+                ns.collection::<Document>(OUTBOX).delete_one(doc! { "_id": post.id }, None).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a post, counts its tags and queues a notification as three
+/// separate writes, undoing the completed ones in reverse order if a later
+/// step fails.
+pub struct PublishPostSaga {
+    ns: Namespace,
+    sagas: Collection<SagaRecord>,
+}
+
+impl PublishPostSaga {
+    pub fn new(ns: &Namespace) -> Self {
+        PublishPostSaga { ns: ns.clone(), sagas: ns.collection(SAGAS) }
+    }
+
+    /// Runs the saga to completion or compensation and returns its final record.
+    /// Only errors from persisting the saga record itself are returned as `Err`.
+    pub async fn run(&self, post: Post) -> Result<SagaRecord> {
+        let mut record = SagaRecord {
+            id: ObjectId::new(),
+            status: SagaStatus::Running,
+            steps: STEPS.iter()
+                .map(|step| StepRecord { name: step.name().to_string(), status: StepStatus::Pending, error: None })
+                .collect(),
+            post,
+        };
+        self.sagas.insert_one(&record, None).await?;
+
+        let mut failed_at = None;
+        for (i, step) in STEPS.iter().enumerate() {
+            match step.run(&self.ns, &record.post).await {
+                Ok(()) => record.steps[i].status = StepStatus::Done,
+                Err(e) => {
+                    record.steps[i].status = StepStatus::Failed;
+                    record.steps[i].error = Some(e.to_string());
+                    failed_at = Some(i);
+                }
+            }
+            self.save(&record).await?;
+            if failed_at.is_some() {
+                break;
+            }
+        }
+
+        record.status = match failed_at {
+            None => SagaStatus::Completed,
+            Some(i) => self.compensate(&mut record, i).await?,
+        };
+        self.save(&record).await?;
+        Ok(record)
+    }
+
+    async fn compensate(&self, record: &mut SagaRecord, failed_at: usize) -> Result<SagaStatus> {
+        for i in (0..failed_at).rev() {
+            if let Err(e) = STEPS[i].compensate(&self.ns, &record.post).await {
+                record.steps[i].error = Some(e.to_string());
+                return Ok(SagaStatus::Failed);
+            }
+            record.steps[i].status = StepStatus::Compensated;
+            self.save(record).await?;
+        }
+        Ok(SagaStatus::Compensated)
+    }
+
+    async fn save(&self, record: &SagaRecord) -> Result<()> {
+        self.sagas.replace_one(doc! { "_id": record.id }, record, None).await?;
+        Ok(())
+    }
+}
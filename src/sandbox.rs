@@ -0,0 +1,53 @@
+use mongodb::{Client, Database};
+use mongodb::bson::oid::ObjectId;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// A uniquely named database that is dropped again by [`Sandbox::cleanup`],
+/// or else when the guard goes out of scope, including when the demo panics
+/// halfway through.
+pub struct Sandbox {
+    db: Database,
+    cleaned_up: bool,
+}
+
+impl Sandbox {
+    pub fn new(client: &Client) -> Self {
+        let db = client.database(&format!("sandbox_{}", ObjectId::new().to_hex()));
+        Sandbox { db, cleaned_up: false }
+    }
+
+    pub fn database(&self) -> Database {
+        self.db.clone()
+    }
+
+    /// Drops the database, on any runtime.
+    pub async fn cleanup(mut self) {
+        self.cleaned_up = true;
+        report(&self.db, self.db.drop(None).await);
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if self.cleaned_up {
+            return;
+        }
+        // `Drop` can't await, and may only block a worker thread of the
+        // multi-threaded runtime: any other thread would stall the tasks
+        // the driver needs to answer.
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                let dropped = tokio::task::block_in_place(|| handle.block_on(self.db.drop(None)));
+                report(&self.db, dropped);
+            }
+            _ => eprintln!("left sandbox database {} behind; use Sandbox::cleanup to drop it", self.db.name()),
+        }
+    }
+}
+
+fn report(db: &Database, dropped: mongodb::error::Result<()>) {
+    match dropped {
+        Ok(()) => println!("dropped sandbox database {}", db.name()),
+        Err(e) => eprintln!("unable to drop sandbox database {}: {}", db.name(), e),
+    }
+}
@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, doc, oid::ObjectId};
+
+use crate::deadline::Deadline;
+use crate::error::{is_duplicate_key, Error, Result};
+use crate::mongo_repository::MongoRepository;
+use crate::namespace::Namespace;
+use crate::repository::SearchFilters;
+use crate::scoped_repository::ScopedRepository;
+
+/// Named searches, unique per user by name (migration 6).
+pub const SAVED_SEARCHES: &str = "saved_searches";
+
+/// Longest name a saved search may have, in characters.
+const MAX_NAME_LEN: usize = 100;
+
+/// What a saved search matches: [`SearchFilters`] without the page, which
+/// is picked each time the search is run.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SearchFilter {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub text: Option<String>,
+}
+
+impl SearchFilter {
+    pub fn page(&self, page: u64, per_page: u64) -> SearchFilters {
+        SearchFilters {
+            tags: self.tags.clone(),
+            from: self.from,
+            to: self.to,
+            text: self.text.clone(),
+            page,
+            per_page,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedSearch {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user: String,
+    pub name: String,
+    pub filter: SearchFilter,
+    pub created_at: bson::DateTime,
+    pub updated_at: bson::DateTime,
+}
+
+/// Each user's saved searches, addressed by user and name.
+#[derive(Clone)]
+pub struct SavedSearches {
+    repo: MongoRepository<SavedSearch>,
+}
+
+impl SavedSearches {
+    pub fn new(ns: &Namespace) -> Self {
+        SavedSearches { repo: MongoRepository::new(ns, SAVED_SEARCHES) }
+    }
+
+    pub fn with_context(&self, context: impl Into<String>) -> Self {
+        SavedSearches { repo: self.repo.with_context(context) }
+    }
+
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        SavedSearches { repo: self.repo.with_deadline(deadline) }
+    }
+
+    /// Only `user`'s searches, so no method can read or change another's.
+    fn of(&self, user: &str) -> ScopedRepository<SavedSearch> {
+        self.repo.scoped(doc! { "user": user })
+    }
+
+    /// Saves `filter` as `user`'s search `name`; [`Error::DuplicateSearch`]
+    /// when they have one by that name already.
+    pub async fn create(&self, user: &str, name: &str, filter: SearchFilter) -> Result<SavedSearch> {
+        check_name(name)?;
+        let now = bson::DateTime::now();
+        let search = SavedSearch {
+            id: ObjectId::new(),
+            user: user.to_string(),
+            name: name.to_string(),
+            filter,
+            created_at: now,
+            updated_at: now,
+        };
+        match self.of(user).insert(&search).await {
+            Ok(_) => Ok(search),
+            Err(Error::Mongo(e)) if is_duplicate_key(&e) => Err(duplicate(user, name)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every search `user` saved, by name.
+    pub async fn list(&self, user: &str) -> Result<Vec<SavedSearch>> {
+        let mut searches = self.of(user).find(doc! {}).await?;
+        searches.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(searches)
+    }
+
+    /// `user`'s search `name`, or [`Error::NotFound`].
+    pub async fn get(&self, user: &str, name: &str) -> Result<SavedSearch> {
+        self.of(user).find(doc! { "name": name }).await?.into_iter().next().ok_or(Error::NotFound)
+    }
+
+    /// Replaces the filter of `user`'s search `name` and returns the search.
+    pub async fn update(&self, user: &str, name: &str, filter: SearchFilter) -> Result<SavedSearch> {
+        let set = doc! { "$set": { "filter": bson::to_bson(&filter)?, "updated_at": bson::DateTime::now() } };
+        if self.of(user).update(doc! { "name": name }, set).await?.matched_count == 0 {
+            return Err(Error::NotFound);
+        }
+        self.get(user, name).await
+    }
+
+    /// Deletes `user`'s search `name`, or fails with [`Error::NotFound`].
+    pub async fn delete(&self, user: &str, name: &str) -> Result<()> {
+        match self.of(user).delete(doc! { "name": name }).await? {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn duplicate(user: &str, name: &str) -> Error {
+    Error::DuplicateSearch { user: user.to_string(), name: name.to_string() }
+}
+
+#[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+fn check_name(name: &str) -> Result<()> {
+    let len = name.chars().count();
+    if len == 0 || len > MAX_NAME_LEN || name.trim() != name {
+        return Err(Error::Validation(format!(
+            "a search name is 1 to {} characters without surrounding spaces, not {:?}", MAX_NAME_LEN, name,
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::migrations;
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn names_are_checked() {
+        assert!(check_name("rust this week").is_ok());
+        assert!(matches!(check_name(""), Err(Error::Validation(_))));
+        assert!(matches!(check_name(" padded"), Err(Error::Validation(_))));
+        assert!(matches!(check_name(&"x".repeat(MAX_NAME_LEN + 1)), Err(Error::Validation(_))));
+    }
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn searches_are_saved_per_user_by_name() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        migrations::posts().run(&ns).await.unwrap();
+        let searches = SavedSearches::new(&ns);
+        let rust = SearchFilter { tags: vec!["rust".to_string()], ..SearchFilter::default() };
+        searches.create("ann", "rust", rust.clone()).await.unwrap();
+        searches.create("bob", "rust", SearchFilter::default()).await.unwrap();
+        let again = searches.create("ann", "rust", SearchFilter::default()).await;
+        assert!(matches!(again, Err(Error::DuplicateSearch { .. })));
+        assert_eq!(searches.get("ann", "rust").await.unwrap().filter.page(2, 10).tags, ["rust"]);
+
+        let text = SearchFilter { text: Some("mongo".to_string()), ..rust };
+        assert_eq!(searches.update("ann", "rust", text.clone()).await.unwrap().filter, text);
+        assert!(matches!(searches.update("ann", "missing", text).await, Err(Error::NotFound)));
+        searches.delete("ann", "rust").await.unwrap();
+        assert!(searches.list("ann").await.unwrap().is_empty());
+        assert_eq!(searches.list("bob").await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Result;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+
+use crate::{Post, PostStatus};
+use crate::namespace::Namespace;
+
+/// Publishes posts once their `publish_at` has passed.
+///
+/// All state lives in the posts themselves, so a restarted worker simply
+/// picks up whatever became due while it was down. Each claim is a single
+/// `find_one_and_update` conditioned on a status that may be published
+/// (see [`PostStatus::predecessors`]), which means two workers can never
+/// publish the same post twice.
+pub struct Scheduler {
+    posts: Collection<Post>,
+}
+
+impl Scheduler {
+    pub async fn new(ns: &Namespace) -> Result<Self> {
+        let posts = ns.collection::<Post>("posts");
+        let index_model = IndexModel::builder()
+            .keys(doc! { "status": 1, "publish_at": 1 })
+            .build();
+        posts.create_index(index_model, None).await?;
+        Ok(Scheduler { posts })
+    }
+
+    /// Claims and publishes the oldest due post, if any.
+    pub async fn publish_due(&self) -> Result<Option<Post>> {
+        let publishable: Vec<&str> = PostStatus::Published.predecessors().iter()
+            .map(|status| status.as_str())
+            .collect();
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "publish_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        self.posts.find_one_and_update(
+            doc! { "status": { "$in": publishable }, "publish_at": { "$lte": DateTime::now() } },
+            doc! { "$set": { "status": PostStatus::Published.as_str() }, "$inc": { "version": 1 } },
+            options,
+        ).await
+    }
+
+    /// Drains all due drafts every `interval`. Only returns on error.
+    pub async fn run(&self, interval: Duration) -> Result<()> {
+        loop {
+            while let Some(post) = self.publish_due().await? {
+                tracing::info!(post = %post.id, "published scheduled post {:?}", post.title);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
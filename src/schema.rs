@@ -0,0 +1,287 @@
+use futures::TryStreamExt;
+use mongodb::IndexModel;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{CreateCollectionOptions, IndexOptions};
+use mongodb::results::CollectionType;
+
+use crate::attributes;
+use crate::ejson::{self, Mode};
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::repository::{title_collation, TextIndexConfig, EMBEDDED_COMMENTS};
+
+/// Every collection's options (validator, capped size, TTL, time series, ...)
+/// and indexes, as written by `schema export` and read by `schema apply`.
+///
+/// Names are stored without the namespace prefix, so a file exported from a
+/// `demo_` namespace can be applied to an unprefixed one and vice versa.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SchemaFile {
+    pub collections: Vec<CollectionSchema>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct CollectionSchema {
+    pub name: String,
+    /// `CreateCollectionOptions` as the server reports them.
+    pub options: Document,
+    /// One `IndexModel` per index other than `_id_`.
+    pub indexes: Vec<Document>,
+}
+
+impl SchemaFile {
+    /// Relaxed Extended JSON, so dates, longs and the like survive a round trip.
+    pub fn to_json(&self) -> String {
+        ejson::to_string_pretty(self, Mode::Relaxed).expect("schema is always representable as BSON")
+    }
+
+    pub fn from_json(json: &str) -> ejson::Result<Self> {
+        ejson::from_str(json)
+    }
+}
+
+/// What [`apply`] did to one collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedCollection {
+    /// Prefixed, as created in the namespace.
+    pub name: String,
+    pub indexes: usize,
+}
+
+/// Reads the definitions of every collection in `ns`. Views are left out:
+/// they have no indexes, and nothing here creates them.
+pub async fn export(ns: &Namespace) -> Result<SchemaFile> {
+    let prefix = ns.name("");
+    let mut specs: Vec<_> = ns.db().list_collections(None, None).await?.try_collect().await?;
+    specs.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut collections = Vec::new();
+    for spec in specs {
+        let name = match spec.name.strip_prefix(&prefix) {
+            Some(name) if !spec.name.starts_with("system.") && spec.collection_type != CollectionType::View => {
+                name.to_string()
+            }
+            _ => continue,
+        };
+        let indexes: Vec<IndexModel> = ns.collection::<Document>(&name).list_indexes(None).await?
+            .try_collect().await?;
+        let indexes = indexes.iter()
+            .filter(|index| index.keys != doc! { "_id": 1 })
+            .map(bson::to_document)
+            .collect::<std::result::Result<_, _>>()?;
+        let options = bson::to_document(&spec.options)?;
+        collections.push(CollectionSchema { name, options, indexes });
+    }
+    Ok(SchemaFile { collections })
+}
+
+/// Creates whatever `schema` describes in `ns`. Collections that already
+/// exist get their validator replaced, since that is the only part of a
+/// collection's options that can change after creation; indexes that already
+/// exist with the same definition are left alone.
+pub async fn apply(ns: &Namespace, schema: &SchemaFile) -> Result<Vec<AppliedCollection>> {
+    let mut applied = Vec::new();
+    for collection in &schema.collections {
+        let options: CreateCollectionOptions = bson::from_document(collection.options.clone())
+            .map_err(mongodb::error::Error::from)?;
+        let validator = options.validator.clone();
+        let level = collection.options.get("validationLevel").cloned();
+        let action = collection.options.get("validationAction").cloned();
+        match ns.db().create_collection(ns.name(&collection.name), options).await {
+            // NamespaceExists
+            Err(e) if matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 48) => {
+                if let Some(validator) = validator {
+                    let mut coll_mod = doc! { "collMod": ns.name(&collection.name), "validator": validator };
+                    if let Some(level) = level {
+                        coll_mod.insert("validationLevel", level);
+                    }
+                    if let Some(action) = action {
+                        coll_mod.insert("validationAction", action);
+                    }
+                    ns.db().run_command(coll_mod, None).await?;
+                }
+            }
+            result => result?,
+        }
+        let indexes: Vec<IndexModel> = collection.indexes.iter()
+            .map(|index| bson::from_document(index.clone()))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(mongodb::error::Error::from)?;
+        if !indexes.is_empty() {
+            ns.collection::<Document>(&collection.name).create_indexes(indexes, None).await?;
+        }
+        applied.push(AppliedCollection { name: ns.name(&collection.name), indexes: collection.indexes.len() });
+    }
+    Ok(applied)
+}
+
+/// The validator of `collection` in `ns`, `None` when it has none.
+pub async fn validator(ns: &Namespace, collection: &str) -> Result<Option<Document>> {
+    let spec = ns.db().list_collections(doc! { "name": ns.name(collection) }, None).await?.try_next().await?;
+    Ok(spec.and_then(|spec| spec.options.validator))
+}
+
+/// What [`audit`] found in a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatorAudit {
+    /// Documents in the collection, estimated from its metadata.
+    pub documents: u64,
+    /// Documents the validator rejects.
+    pub failing: u64,
+    /// `_id`s of the first of them, in natural order.
+    pub samples: Vec<Bson>,
+}
+
+/// Counts the documents of `collection` that `validator` rejects, and
+/// samples up to `samples` of them, by matching on the validator under a
+/// `$nor`. The validator needn't be the collection's: auditing a stricter
+/// one shows what it would reject before it is applied, and auditing the
+/// current one shows what a `moderate` validation level lets stay, which
+/// `strict` would keep from being updated until fixed.
+pub async fn audit(ns: &Namespace, collection: &str, validator: Document, samples: usize)
+    -> Result<ValidatorAudit>
+{
+    let col = ns.collection::<Document>(collection);
+    let pipeline = vec![
+        doc! { "$match": { "$nor": [validator] } },
+        doc! { "$facet": {
+            "failing": [{ "$count": "n" }],
+            "samples": [{ "$limit": samples.max(1) as i64 }, { "$project": { "_id": 1 } }],
+        }},
+    ];
+    let facets = col.aggregate(pipeline, None).await?.try_next().await?.unwrap_or_default();
+    let failing = facets.get_array("failing").ok()
+        .and_then(|counts| counts.first())
+        .and_then(Bson::as_document)
+        .and_then(|count| count.get("n"))
+        .and_then(|n| n.as_i32().map(i64::from).or_else(|| n.as_i64()))
+        .unwrap_or(0);
+    let samples = facets.get_array("samples").ok().into_iter().flatten()
+        .filter_map(|sample| sample.as_document()?.get("_id").cloned())
+        .take(samples)
+        .collect();
+    Ok(ValidatorAudit {
+        documents: col.estimated_document_count(None).await?,
+        failing: failing as u64,
+        samples,
+    })
+}
+
+/// `posts` as the application expects it: strictly validated, with the tag,
+/// unique title, title prefix and text indexes.
+pub fn posts_schema() -> CollectionSchema {
+    let options = doc! {
+        "validator": {
+            "$jsonSchema": {
+                "title": "Tweet object validation",
+                "required": ["title", "message", "tags"],
+                "properties": {
+                    "title": {
+                        "bsonType": "string",
+                        "maxLength": 300
+                    },
+                    "message": {
+                        "bsonType": "string",
+                        "maxLength": 4000
+                    },
+                    "tags": {
+                        "bsonType": "array",
+                        "maxItems": 5,
+                        "items": {
+                            "bsonType": "string",
+                            "minLength": 3,
+                            "maxLength": 10
+                        }
+                    },
+                    "created_at": {
+                        "bsonType": "date"
+                    },
+                    "version": {
+                        "bsonType": ["int", "long"],
+                        "minimum": 0
+                    },
+                    "content": {
+                        "bsonType": "array",
+                        "items": {
+                            "bsonType": "object",
+                            "required": ["lang", "message"],
+                            "properties": {
+                                "lang": { "bsonType": "string" },
+                                "message": {
+                                    "bsonType": "string",
+                                    "maxLength": 4000
+                                }
+                            }
+                        }
+                    },
+                    "attachments": {
+                        "bsonType": "array",
+                        "items": { "bsonType": "objectId" }
+                    },
+                    "comment_ids": {
+                        "bsonType": "array",
+                        "maxItems": EMBEDDED_COMMENTS as i64,
+                        "items": { "bsonType": "objectId" }
+                    },
+                    "comments_overflow": {
+                        "bsonType": "bool"
+                    },
+                    "comment_count": {
+                        "bsonType": ["int", "long"],
+                        "minimum": 0
+                    },
+                    "metadata": {
+                        "bsonType": "array",
+                        "items": {
+                            "bsonType": "object",
+                            "required": ["k", "v"],
+                            "properties": {
+                                "k": { "bsonType": "string" }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+        "validationLevel": "strict",
+        "validationAction": "error",
+    };
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "tags": 1 })
+            .build(),
+        // Unique regardless of case
+        IndexModel::builder()
+            .keys(doc! { "title": 1 })
+            .options(IndexOptions::builder()
+                .unique(true)
+                .collation(title_collation())
+                .build())
+            .build(),
+        // Backs title suggestions
+        IndexModel::builder()
+            .keys(doc! { "title_prefixes": 1 })
+            .build(),
+        // `lang` on the post or on a translation picks the stemmer
+        TextIndexConfig::default().index_model(),
+        attributes::index(),
+        // Newest posts of some authors, for the follower feed
+        IndexModel::builder()
+            .keys(doc! { "author": 1, "_id": -1 })
+            .build(),
+    ];
+    CollectionSchema {
+        name: "posts".to_string(),
+        options,
+        indexes: indexes.iter()
+            .map(|index| bson::to_document(index).expect("index models always serialize"))
+            .collect(),
+    }
+}
+
+/// Creates `posts` with [`posts_schema`], or brings an existing one up to it
+/// without touching its documents.
+pub async fn setup_posts(ns: &Namespace) -> Result<()> {
+    apply(ns, &SchemaFile { collections: vec![posts_schema()] }).await?;
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::options::UpdateModifications;
+use mongodb::results::UpdateResult;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::deadline::Deadline;
+use crate::error::{Error, Result};
+use crate::mongo_repository::MongoRepository;
+
+/// A [`MongoRepository`] that only ever sees the documents matching `scope`,
+/// e.g. `{ tenant: "acme" }` or `{ user: "ann" }`: every filter, update and
+/// pipeline it sends has the scope added, so a caller can't forget it.
+///
+/// Documents inserted have to carry the scope's values and updates can't
+/// move a document out of it: an update document gets the scope `$set`
+/// again, and an update pipeline ends with a stage doing the same.
+pub struct ScopedRepository<T: Send + Sync> {
+    repo: MongoRepository<T>,
+    scope: Document,
+}
+
+impl<T: Send + Sync> Clone for ScopedRepository<T> {
+    fn clone(&self) -> Self {
+        ScopedRepository { repo: self.repo.clone(), scope: self.scope.clone() }
+    }
+}
+
+impl<T> MongoRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+{
+    /// This repository limited to the documents matching `scope`, a
+    /// document of plain field values.
+    ///
+    /// # Panics
+    ///
+    /// If `scope` is empty or has an operator, which would scope nothing.
+    pub fn scoped(&self, scope: Document) -> ScopedRepository<T> {
+        assert!(!scope.is_empty(), "a scope needs at least one field");
+        assert!(
+            scope.iter().all(|(field, value)| !field.starts_with('$') && !is_operator(value)),
+            "a scope is plain field values, not {}", scope,
+        );
+        ScopedRepository { repo: self.clone(), scope }
+    }
+}
+
+impl<T> ScopedRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+{
+    pub fn scope(&self) -> &Document {
+        &self.scope
+    }
+
+    pub fn with_context(&self, context: impl Into<String>) -> Self {
+        ScopedRepository { repo: self.repo.with_context(context), ..self.clone() }
+    }
+
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        ScopedRepository { repo: self.repo.with_deadline(deadline), ..self.clone() }
+    }
+
+    /// Inserts `document`, which has to have the scope's values; an
+    /// [`Error::Validation`] otherwise.
+    pub async fn insert(&self, document: &T) -> Result<Bson> {
+        self.check_in_scope(document)?;
+        self.repo.insert(document).await
+    }
+
+    /// The document with `id` if it is in scope, [`Error::NotFound`] otherwise.
+    pub async fn find_one_by_id(&self, id: impl Into<Bson>) -> Result<T> {
+        self.find(doc! { "_id": id.into() }).await?.into_iter().next().ok_or(Error::NotFound)
+    }
+
+    pub async fn find(&self, filter: Document) -> Result<Vec<T>> {
+        self.repo.find(scoped_filter(&self.scope, filter)).await
+    }
+
+    pub async fn update(&self, filter: Document, update: impl Into<UpdateModifications>) -> Result<UpdateResult> {
+        let update = scoped_update(&self.scope, update.into())?;
+        self.repo.update(scoped_filter(&self.scope, filter), update).await
+    }
+
+    pub async fn delete(&self, filter: Document) -> Result<u64> {
+        self.repo.delete(scoped_filter(&self.scope, filter)).await
+    }
+
+    /// Runs `pipeline` over the documents in scope. Stages that read other
+    /// collections, like `$lookup` or `$unionWith`, aren't scoped.
+    pub async fn aggregate_as<U>(&self, pipeline: Vec<Document>) -> Result<Vec<U>>
+    where
+        U: DeserializeOwned + Unpin + Send + Sync,
+    {
+        self.repo.aggregate_as(scoped_pipeline(&self.scope, pipeline)).await
+    }
+
+    #[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+    fn check_in_scope(&self, document: &T) -> Result<()> {
+        let document = bson::to_document(document)?;
+        for (field, value) in &self.scope {
+            if document.get(field) != Some(value) {
+                return Err(Error::Validation(format!("document is outside the scope {}", self.scope)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_operator(value: &Bson) -> bool {
+    value.as_document().is_some_and(|value| value.keys().any(|key| key.starts_with('$')))
+}
+
+/// `filter` narrowed to `scope`; combined with `$and` so that `filter` can
+/// constrain the scope's fields too without replacing the scope.
+fn scoped_filter(scope: &Document, filter: Document) -> Document {
+    if filter.is_empty() {
+        scope.clone()
+    } else {
+        doc! { "$and": [scope.clone(), filter] }
+    }
+}
+
+/// `pipeline` starting from the documents in scope. A leading `$match` or
+/// `$geoNear` gets the scope merged in, since those have to stay first.
+fn scoped_pipeline(scope: &Document, mut pipeline: Vec<Document>) -> Vec<Document> {
+    let first = pipeline.first_mut();
+    if let Some(first) = first {
+        if let Ok(filter) = first.get_document("$match") {
+            let filter = scoped_filter(scope, filter.clone());
+            first.insert("$match", filter);
+            return pipeline;
+        }
+        if let Ok(geo_near) = first.get_document_mut("$geoNear") {
+            let query = geo_near.get_document("query").cloned().unwrap_or_default();
+            geo_near.insert("query", scoped_filter(scope, query));
+            return pipeline;
+        }
+    }
+    pipeline.insert(0, doc! { "$match": scope.clone() });
+    pipeline
+}
+
+#[allow(clippy::result_large_err)] // same `Result` as the methods calling it
+fn scoped_update(scope: &Document, update: UpdateModifications) -> Result<UpdateModifications> {
+    match update {
+        UpdateModifications::Document(mut update) => {
+            let mut set = match update.get("$set") {
+                Some(Bson::Document(set)) => set.clone(),
+                Some(_) => return Err(Error::Validation("`$set` takes a document".to_string())),
+                None => Document::new(),
+            };
+            set.extend(scope.clone());
+            update.insert("$set", set);
+            Ok(UpdateModifications::Document(update))
+        }
+        UpdateModifications::Pipeline(mut stages) => {
+            stages.push(doc! { "$set": scope.clone() });
+            Ok(UpdateModifications::Pipeline(stages))
+        }
+        // Doesn't exist in this driver version, but the enum isn't exhaustive
+        _ => Err(Error::Validation("unsupported kind of update".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_and_pipelines_are_narrowed_to_the_scope() {
+        let scope = doc! { "user": "ann" };
+        assert_eq!(scoped_filter(&scope, doc! {}), scope);
+        assert_eq!(
+            scoped_filter(&scope, doc! { "user": "bob" }),
+            doc! { "$and": [{ "user": "ann" }, { "user": "bob" }] },
+        );
+        let text = doc! { "$text": { "$search": "x" } };
+        assert_eq!(
+            scoped_pipeline(&scope, vec![doc! { "$match": text.clone() }, doc! { "$limit": 1 }]),
+            [doc! { "$match": { "$and": [{ "user": "ann" }, text] } }, doc! { "$limit": 1 }],
+        );
+        assert_eq!(
+            scoped_pipeline(&scope, vec![doc! { "$count": "n" }]),
+            [doc! { "$match": { "user": "ann" } }, doc! { "$count": "n" }],
+        );
+    }
+
+    #[test]
+    fn updates_cant_leave_the_scope() {
+        let scope = doc! { "user": "ann" };
+        let update = scoped_update(&scope, doc! { "$set": { "user": "bob", "name": "x" } }.into()).unwrap();
+        assert!(matches!(update, UpdateModifications::Document(update)
+            if update == doc! { "$set": { "user": "ann", "name": "x" } }));
+        let update = scoped_update(&scope, vec![doc! { "$unset": "user" }].into()).unwrap();
+        assert!(matches!(update, UpdateModifications::Pipeline(stages)
+            if stages == [doc! { "$unset": "user" }, doc! { "$set": { "user": "ann" } }]));
+    }
+}
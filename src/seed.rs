@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{Acknowledgment, InsertManyOptions, WriteConcern};
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+use crate::Post;
+use crate::error::Result;
+use crate::ids::number;
+use crate::namespace::Namespace;
+
+/// Starts the title of every generated post, so they can be told apart from
+/// real ones and removed with [`clear`].
+pub const TITLE_PREFIX: &str = "[seed] ";
+
+const WORDS: [&str; 16] = [
+    "mongo", "index", "query", "shard", "replica", "cursor", "pipeline", "document",
+    "collection", "write", "read", "concern", "schema", "rust", "async", "driver",
+];
+
+/// What [`seed`] generates.
+#[derive(Debug, Clone, Copy)]
+pub struct SeedOptions {
+    pub posts: usize,
+    /// Distinct tags, `seed0` to `seed<n-1>`; low numbers are much more
+    /// common than high ones, like real tags.
+    pub tags: usize,
+    /// Characters per message, at most the validator's 4000.
+    pub message_len: usize,
+    pub batch_size: usize,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        SeedOptions { posts: 100_000, tags: 50, message_len: 200, batch_size: 1000 }
+    }
+}
+
+/// One query of [`SeedReport`]. The examined counts come from `explain` and
+/// are left out for the aggregation.
+#[derive(serde::Serialize, Debug)]
+pub struct QueryTiming {
+    pub name: &'static str,
+    pub time: Duration,
+    pub returned: i64,
+    pub keys_examined: Option<i64>,
+    pub docs_examined: Option<i64>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct SeedReport {
+    pub inserted: u64,
+    pub insert_time: Duration,
+    pub queries: Vec<QueryTiming>,
+}
+
+/// A post with one to three tags, skewed towards the low ones, a message of
+/// random words and a `created_at` in the last year.
+pub fn fake_post(rng: &mut impl Rng, n: usize, options: &SeedOptions) -> Post {
+    let tag_count = rng.gen_range(1..=3.min(options.tags.max(1)));
+    let mut tags: Vec<String> = Vec::with_capacity(tag_count);
+    while tags.len() < tag_count {
+        // Squaring a uniform value makes low tag numbers the popular ones
+        let tag = format!("seed{}", (rng.gen::<f64>().powi(2) * options.tags.max(1) as f64) as usize);
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    let message_len = options.message_len.min(4000);
+    let mut message = String::with_capacity(message_len);
+    while message.len() < message_len {
+        if !message.is_empty() {
+            message.push(' ');
+        }
+        message.push_str(WORDS.choose(rng).expect("words aren't empty"));
+    }
+    message.truncate(message_len);
+    let age = Duration::from_secs(rng.gen_range(0..365 * 24 * 3600));
+    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    Post {
+        created_at: DateTime::from_system_time(std::time::SystemTime::now() - age),
+        ..Post::new(&format!("{}{} {:08x}", TITLE_PREFIX, n, rng.gen::<u32>()), &message, &tags)
+    }
+}
+
+/// Inserts [`SeedOptions::posts`] fake posts into `posts` in unordered
+/// batches acknowledged by the primary alone, then times the same tag query
+/// through the `tags` index and as a collection scan, and a tag count
+/// aggregation over everything.
+///
+/// A post whose random title happens to be taken already is skipped; the
+/// report counts what was actually inserted.
+pub async fn seed(ns: &Namespace, options: &SeedOptions) -> Result<SeedReport> {
+    let col = ns.collection::<Post>("posts");
+    let insert_options = InsertManyOptions::builder()
+        .ordered(false)
+        .write_concern(WriteConcern::builder().w(Acknowledgment::Nodes(1)).journal(false).build())
+        .build();
+    let mut inserted = 0;
+    let started = Instant::now();
+    for batch_start in (0..options.posts).step_by(options.batch_size.max(1)) {
+        let batch_end = options.posts.min(batch_start + options.batch_size.max(1));
+        // `ThreadRng` isn't `Send`, so it can't be held across the insert
+        let batch: Vec<Post> = {
+            let mut rng = rand::thread_rng();
+            (batch_start..batch_end).map(|n| fake_post(&mut rng, n, options)).collect()
+        };
+        match col.insert_many(&batch, insert_options.clone()).await {
+            Ok(result) => inserted += result.inserted_ids.len() as u64,
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    let errors = failure.write_errors.as_deref().unwrap_or_default();
+                    if errors.iter().any(|error| error.code != 11000) {
+                        return Err(e.into());
+                    }
+                    inserted += (batch.len() - errors.len()) as u64;
+                }
+                _ => return Err(e.into()),
+            },
+        }
+    }
+    let insert_time = started.elapsed();
+
+    // Tag 0 is the most common, so both plans have plenty to read
+    let filter = doc! { "tags": "seed0" };
+    let queries = vec![
+        explain_find(ns, "tag via index", filter.clone(), None).await?,
+        explain_find(ns, "tag via collection scan", filter, Some(doc! { "$natural": 1 })).await?,
+        time_tag_counts(ns).await?,
+    ];
+    Ok(SeedReport { inserted, insert_time, queries })
+}
+
+async fn explain_find(
+    ns: &Namespace,
+    name: &'static str,
+    filter: Document,
+    hint: Option<Document>,
+) -> Result<QueryTiming> {
+    let mut find = doc! { "find": ns.name("posts"), "filter": filter };
+    if let Some(hint) = hint {
+        find.insert("hint", hint);
+    }
+    let explain = ns.db().run_command(doc! { "explain": find, "verbosity": "executionStats" }, None).await?;
+    let stats = explain.get_document("executionStats").ok();
+    let stat = |field: &str| stats.map_or(0, |stats| number(stats.get(field)));
+    Ok(QueryTiming {
+        name,
+        time: Duration::from_millis(stat("executionTimeMillis") as u64),
+        returned: stat("nReturned"),
+        keys_examined: Some(stat("totalKeysExamined")),
+        docs_examined: Some(stat("totalDocsExamined")),
+    })
+}
+
+async fn time_tag_counts(ns: &Namespace) -> Result<QueryTiming> {
+    let pipeline = vec![doc! { "$unwind": "$tags" }, doc! { "$sortByCount": "$tags" }];
+    let started = Instant::now();
+    let counts: Vec<Document> = ns.collection::<Document>("posts").aggregate(pipeline, None).await?
+        .try_collect().await?;
+    Ok(QueryTiming {
+        name: "tag counts aggregation",
+        time: started.elapsed(),
+        returned: counts.len() as i64,
+        keys_examined: None,
+        docs_examined: None,
+    })
+}
+
+/// Filter for the generated posts.
+pub fn seeded() -> Document {
+    doc! { "title": { "$regex": format!("^{}", regex_escape(TITLE_PREFIX)) } }
+}
+
+/// Deletes every generated post, returning how many there were.
+pub async fn clear(ns: &Namespace) -> Result<u64> {
+    Ok(ns.collection::<Document>("posts").delete_many(seeded(), None).await?.deleted_count)
+}
+
+fn regex_escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    #[test]
+    fn fake_posts_pass_the_validator_limits() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let options = SeedOptions { tags: 1000, message_len: 5000, ..SeedOptions::default() };
+        for n in 0..200 {
+            let post = fake_post(&mut rng, n, &options);
+            assert!(post.title.starts_with(TITLE_PREFIX));
+            assert!((1..=3).contains(&post.tags.len()));
+            assert!(post.tags.iter().all(|tag| (3..=10).contains(&tag.len())), "{:?}", post.tags);
+            assert_eq!(post.message.len(), 4000);
+        }
+    }
+
+    #[test]
+    fn low_tags_are_the_common_ones() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let options = SeedOptions { tags: 10, ..SeedOptions::default() };
+        let posts: Vec<Post> = (0..1000).map(|n| fake_post(&mut rng, n, &options)).collect();
+        let count = |tag: &str| posts.iter().filter(|post| post.tags.iter().any(|t| t == tag)).count();
+        assert!(count("seed0") > 2 * count("seed9"), "{} vs {}", count("seed0"), count("seed9"));
+    }
+
+    #[test]
+    fn the_title_prefix_is_matched_literally() {
+        assert_eq!(regex_escape(TITLE_PREFIX), "\\[seed\\] ");
+    }
+}
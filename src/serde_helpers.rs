@@ -0,0 +1,225 @@
+//! `#[serde(with = ...)]` helpers for the fields of stored documents, so
+//! every model reads the BSON quirks the same way: dates written as strings
+//! or numbers by other tools, integers stored with another width, or as
+//! doubles by a shell, and explicit nulls where a field is expected.
+//!
+//! How forgiving they are is [`PROFILE`], picked by the same features that
+//! decide what happens to unknown fields: [`Profile::STRICT`] with
+//! `strict-decoding`, [`Profile::LENIENT`] otherwise. Writing is the same
+//! under both: dates as BSON dates, integers at the width of the field.
+
+use chrono::Utc;
+use mongodb::bson::{Bson, DateTime};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// What the helpers accept when reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    /// Dates as RFC 3339 strings or milliseconds since the epoch too, not
+    /// only as BSON dates.
+    pub lenient_dates: bool,
+    /// Integers as doubles too, when they have no fraction. Integers of any
+    /// width are always read into any integer field they fit.
+    pub integral_doubles: bool,
+    /// A null where a value is expected as the field's default, as a
+    /// missing field with `#[serde(default)]` would be. Dates have none, so
+    /// a null one is always an error.
+    pub null_as_default: bool,
+}
+
+impl Profile {
+    pub const STRICT: Profile = Profile { lenient_dates: false, integral_doubles: false, null_as_default: false };
+    pub const LENIENT: Profile = Profile { lenient_dates: true, integral_doubles: true, null_as_default: true };
+}
+
+#[cfg(feature = "strict-decoding")]
+pub const PROFILE: Profile = Profile::STRICT;
+#[cfg(not(feature = "strict-decoding"))]
+pub const PROFILE: Profile = Profile::LENIENT;
+
+fn date_from(value: Bson, profile: Profile) -> Result<Option<DateTime>, String> {
+    match value {
+        Bson::DateTime(date) => Ok(Some(date)),
+        Bson::Null => Ok(None),
+        Bson::String(date) if profile.lenient_dates => {
+            DateTime::parse_rfc3339_str(&date).map(Some).map_err(|e| format!("invalid date {:?}: {}", date, e))
+        }
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) if profile.lenient_dates => {
+            let millis = integer_from(value, Profile { integral_doubles: true, ..profile })?;
+            Ok(millis.map(DateTime::from_millis))
+        }
+        other => Err(format!("expected a date, found {:?}", other.element_type())),
+    }
+}
+
+fn integer_from(value: Bson, profile: Profile) -> Result<Option<i64>, String> {
+    match value {
+        Bson::Int32(n) => Ok(Some(n.into())),
+        Bson::Int64(n) => Ok(Some(n)),
+        Bson::Double(n) if profile.integral_doubles && n.fract() == 0.0 && n.abs() < 2f64.powi(63) => {
+            Ok(Some(n as i64))
+        }
+        Bson::Null => Ok(None),
+        other => Err(format!("expected an integer, found {:?}", other.element_type())),
+    }
+}
+
+/// A null where a value is expected: its default, or an error.
+fn or_default<T: Default, E: serde::de::Error>(value: Option<T>, profile: Profile) -> Result<T, E> {
+    match value {
+        Some(value) => Ok(value),
+        None if profile.null_as_default => Ok(T::default()),
+        None => Err(E::custom("null where a value is expected")),
+    }
+}
+
+/// For `bson::DateTime` fields.
+pub mod datetime {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(date: &DateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        date.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+        let date = date_from(Bson::deserialize(deserializer)?, PROFILE).map_err(D::Error::custom)?;
+        date.ok_or_else(|| D::Error::custom("null where a date is expected"))
+    }
+}
+
+/// For `Option<bson::DateTime>` fields; they need `#[serde(default)]` too,
+/// to read a missing field as `None`.
+pub mod optional_datetime {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(date: &Option<DateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        date.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime>, D::Error> {
+        date_from(Bson::deserialize(deserializer)?, PROFILE).map_err(D::Error::custom)
+    }
+}
+
+/// For `chrono::DateTime<Utc>` fields, stored as BSON dates like
+/// `bson::DateTime` ones, so either type can be used for the same field.
+pub mod chrono_datetime {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(date: &chrono::DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        DateTime::from_chrono(*date).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<chrono::DateTime<Utc>, D::Error> {
+        Ok(datetime::deserialize(deserializer)?.to_chrono())
+    }
+}
+
+/// For integer fields of any width.
+pub mod int {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(n: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        n.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<i64> + Default,
+    {
+        match integer_from(Bson::deserialize(deserializer)?, PROFILE).map_err(D::Error::custom)? {
+            Some(n) => T::try_from(n).map_err(|_| D::Error::custom(format!("{} is out of range", n))),
+            None => or_default(None, PROFILE),
+        }
+    }
+}
+
+/// For `Option` integer fields; they need `#[serde(default)]` too.
+pub mod optional_int {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(n: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        n.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<i64>,
+    {
+        let n = integer_from(Bson::deserialize(deserializer)?, PROFILE).map_err(D::Error::custom)?;
+        n.map(|n| T::try_from(n).map_err(|_| D::Error::custom(format!("{} is out of range", n)))).transpose()
+    }
+}
+
+/// For fields of any other type with a default, to read a null as it.
+pub mod null_default {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de> + Default,
+    {
+        or_default(Option::<T>::deserialize(deserializer)?, PROFILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{self, doc};
+
+    use super::*;
+
+    #[test]
+    fn lenient_reads_what_strict_rejects() {
+        let date = DateTime::parse_rfc3339_str("2024-01-01T00:00:00Z").unwrap();
+        let read = |value: Bson, profile| date_from(value, profile);
+        for profile in [Profile::STRICT, Profile::LENIENT] {
+            assert_eq!(read(Bson::DateTime(date), profile), Ok(Some(date)));
+            assert_eq!(integer_from(Bson::Int32(7), profile), Ok(Some(7)));
+            assert_eq!(integer_from(Bson::Int64(1 << 40), profile), Ok(Some(1 << 40)));
+        }
+        assert_eq!(read(Bson::String("2024-01-01T00:00:00Z".into()), Profile::LENIENT), Ok(Some(date)));
+        assert_eq!(read(Bson::Int64(date.timestamp_millis()), Profile::LENIENT), Ok(Some(date)));
+        assert!(read(Bson::String("2024-01-01T00:00:00Z".into()), Profile::STRICT).is_err());
+        assert_eq!(integer_from(Bson::Double(3.0), Profile::LENIENT), Ok(Some(3)));
+        assert!(integer_from(Bson::Double(3.5), Profile::LENIENT).is_err());
+        assert!(integer_from(Bson::Double(3.0), Profile::STRICT).is_err());
+        assert!(or_default::<i64, bson::de::Error>(None, Profile::STRICT).is_err());
+        assert_eq!(or_default::<i64, bson::de::Error>(None, Profile::LENIENT).unwrap(), 0);
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Model {
+        #[serde(with = "int")]
+        narrow: u32,
+        #[serde(default, with = "optional_int")]
+        wide: Option<i64>,
+        #[serde(with = "chrono_datetime")]
+        at: chrono::DateTime<Utc>,
+        #[serde(default, with = "optional_datetime")]
+        until: Option<DateTime>,
+    }
+
+    #[test]
+    fn fields_round_trip_and_widths_are_coerced() {
+        let stored = doc! { "narrow": 5_i64, "wide": 6, "at": DateTime::from_millis(1_000) };
+        let model: Model = bson::from_document(stored).unwrap();
+        assert_eq!(model, Model {
+            narrow: 5,
+            wide: Some(6),
+            at: DateTime::from_millis(1_000).to_chrono(),
+            until: None,
+        });
+        let written = bson::to_document(&model).unwrap();
+        assert_eq!(written.get("at"), Some(&Bson::DateTime(DateTime::from_millis(1_000))));
+        assert!(bson::from_document::<Model>(doc! { "narrow": -1, "at": DateTime::now() }).is_err());
+    }
+}
@@ -0,0 +1,677 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{Extension, Json, Router};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use serde::{Deserialize, Deserializer};
+use chrono::{DateTime, Utc};
+use mongodb::Collection;
+use mongodb::bson::oid::ObjectId;
+use tracing::Instrument;
+
+use crate::Post;
+use crate::access::{ReadPolicy, Role};
+use crate::admin;
+use crate::attachments::{self, Attachments, Size};
+use crate::error::Error;
+use crate::bulkhead::{Bulkhead, BulkheadStats};
+use crate::change_bridge::{ChangeStreamBridge, LagPolicy, Received};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::deadline::Deadline;
+use crate::latency::{LatencyHistograms, OpLatency};
+use crate::metrics::{self, OpMetrics};
+use crate::namespace::Namespace;
+use crate::query_cache::QueryCache;
+use crate::query_counter::QueryCounter;
+use crate::storage_quota::StorageQuota;
+use crate::title_filter::TitleFilter;
+use crate::related::{RelatedTag, TagGraph};
+use crate::repository::{
+    nearest_reads, page_offset, search_pipeline, PostPatch, PostRepository, PostSummary, SearchFilters,
+    SearchResults, SimilarPost,
+};
+use crate::saved_searches::{SavedSearch, SavedSearches, SearchFilter};
+use crate::serde_helpers;
+use crate::watcher::{PostChange, Watcher};
+
+pub const AUDIT_LOG: &str = "audit_log";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Total time a request may spend in the database, across all its calls.
+const REQUEST_BUDGET: Duration = Duration::from_secs(2);
+/// How often the storage quota is checked.
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Unread changes kept per `/posts/changes` client before it misses some.
+const CHANGES_CAPACITY: usize = 256;
+
+struct AppState {
+    ns: Namespace,
+    repo: PostRepository,
+    searches: SavedSearches,
+    attachments: Attachments,
+    breaker: CircuitBreaker,
+    bulkhead: Bulkhead,
+    cache: QueryCache,
+    tag_graph: TagGraph,
+    audit: Collection<AuditEntry>,
+    latency: Arc<LatencyHistograms>,
+    metrics: Arc<OpMetrics>,
+    queries: Arc<QueryCounter>,
+    storage: Option<Arc<StorageQuota>>,
+    admin_token: Option<String>,
+    changes: ChangeStreamBridge,
+}
+
+type SharedState = Arc<AppState>;
+
+/// Id of the request being handled: taken from `x-request-id` when the
+/// caller sent one, generated otherwise.
+#[derive(Clone)]
+struct RequestId(String);
+
+/// One line of the audit log per handled request.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct AuditEntry {
+    request_id: String,
+    method: String,
+    path: String,
+    #[serde(with = "serde_helpers::int")]
+    status: u16,
+    #[serde(with = "serde_helpers::int")]
+    duration_ms: i64,
+    #[serde(with = "serde_helpers::datetime")]
+    at: mongodb::bson::DateTime,
+}
+
+/// `latency` and `metrics` should be the handlers registered with the client
+/// behind `ns`; they are served at `/metrics/latency` and, together in the
+/// Prometheus text format, at `/metrics`. `queries`, registered likewise,
+/// counts each request's queries; a request sending the same kind of query
+/// more often than it allows is logged. `storage`, when given, is checked
+/// every minute and exported at `/metrics`. Callers presenting `admin_token`
+/// read posts as [`Role::Admin`], everyone else as [`Role::Public`].
+pub async fn serve(
+    ns: &Namespace,
+    addr: &str,
+    latency: Arc<LatencyHistograms>,
+    metrics: Arc<OpMetrics>,
+    queries: Arc<QueryCounter>,
+    storage: Option<Arc<StorageQuota>>,
+    admin_token: Option<String>,
+) -> std::io::Result<()> {
+    if let Some(storage) = storage.clone() {
+        tokio::spawn(async move { storage.watch(STORAGE_CHECK_INTERVAL).await });
+    }
+    // `/posts/changes` subscribes to it; what changes while nobody listens is dropped
+    let (changes, _) = ChangeStreamBridge::new(CHANGES_CAPACITY, LagPolicy::DropOldest);
+    let watcher = Watcher::new(ns, "server");
+    let bridge = changes.clone();
+    tokio::spawn(async move {
+        if let Err(e) = watcher.run(&bridge).await {
+            tracing::warn!("no more post changes for /posts/changes: {}", e);
+        }
+    });
+    let state = Arc::new(AppState {
+        ns: ns.clone(),
+        // Reads are latency-sensitive: hedge them. Writes go to the primary regardless
+        repo: PostRepository::with_options(ns, nearest_reads(true))
+            .with_title_filter(Arc::new(TitleFilter::load(ns).await.map_err(std::io::Error::other)?)),
+        searches: SavedSearches::new(ns),
+        attachments: Attachments::new(ns),
+        // Five failures in a row stop database calls for ten seconds
+        breaker: CircuitBreaker::new(5, Duration::from_secs(10)),
+        // Stay below the driver's default pool of 10 connections
+        bulkhead: Bulkhead::new(8, Duration::from_millis(500)),
+        cache: QueryCache::new(ns, Duration::from_secs(60)).await.map_err(std::io::Error::other)?,
+        tag_graph: TagGraph::new(ns).await.map_err(std::io::Error::other)?,
+        audit: ns.collection(AUDIT_LOG),
+        latency,
+        metrics,
+        queries,
+        storage,
+        admin_token,
+        changes,
+    });
+    let app = Router::new()
+        .route("/posts", get(list_posts).post(create_post))
+        .route("/posts/search", get(search_posts))
+        .route("/posts/suggest", get(suggest_titles))
+        .route("/posts/title-taken", get(title_taken))
+        .route("/posts/changes", get(post_changes))
+        .route("/posts/:id", get(get_post).patch(patch_post).delete(delete_post))
+        .route("/posts/:id/similar", get(similar_posts))
+        .route("/attachments/:id", get(get_attachment))
+        .route("/tags/:tag/posts", get(tag_posts))
+        .route("/tags/:tag/related", get(related_tags))
+        .route("/users/:user/searches", get(list_searches).post(create_search))
+        .route("/users/:user/searches/:name", get(get_search).put(update_search).delete(delete_search))
+        .route("/users/:user/searches/:name/results", get(run_search))
+        .route("/admin/overview", get(admin_overview))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/metrics/bulkhead", get(bulkhead_stats))
+        .route("/metrics/latency", get(latency_stats))
+        .layer(middleware::from_fn_with_state(state.clone(), request_context))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("listening on {}", addr);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+}
+
+/// Runs the request inside a span carrying its id, hands the id, the
+/// request's [`Deadline`] and the caller's [`Role`] to the handlers (which
+/// tag, bound and restrict their database operations with them), echoes the
+/// id back in the response and records an audit entry under it, warning
+/// first if the handler sent the same kind of query over and over.
+async fn request_context(State(state): State<SharedState>, mut req: Request, next: Next) -> Response {
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| ObjectId::new().to_hex());
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    req.extensions_mut().insert(Deadline::after(REQUEST_BUDGET));
+    let role = caller_role(req.headers().get(AUTHORIZATION), state.admin_token.as_deref());
+    req.extensions_mut().insert(role);
+
+    let span = tracing::info_span!("http request", request_id = %request_id, method = %method, path = %path);
+    let started = Instant::now();
+    let mut response = next.run(req).instrument(span.clone()).await;
+    let entry = AuditEntry {
+        request_id: request_id.clone(),
+        method,
+        path,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_millis() as i64,
+        at: mongodb::bson::DateTime::now(),
+    };
+    async {
+        tracing::info!(status = entry.status, duration_ms = entry.duration_ms, "handled");
+        for repeated in state.queries.take(&request_id) {
+            let kind = &repeated.kind;
+            tracing::warn!(
+                command = %kind.command, collection = %kind.collection, op = %kind.op, count = repeated.count,
+                "likely N+1: the same query sent {} times in one request; batch it with $in or $lookup",
+                repeated.count,
+            );
+        }
+        if let Err(e) = state.audit.insert_one(&entry, None).await {
+            tracing::warn!("unable to write audit entry: {}", e);
+        }
+    }.instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// [`Role::Admin`] for `Authorization: Bearer <admin_token>`, [`Role::Public`]
+/// for anything else, including any token when there is no `admin_token`.
+fn caller_role(authorization: Option<&HeaderValue>, admin_token: Option<&str>) -> Role {
+    let token = authorization
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (token, admin_token) {
+        (Some(token), Some(admin_token)) if !admin_token.is_empty() && token == admin_token => Role::Admin,
+        _ => Role::Public,
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::DuplicateTitle(_)
+            | Error::DuplicateSearch { .. }
+            | Error::VersionConflict { .. }
+            | Error::IllegalTransition { .. } => StatusCode::CONFLICT,
+            Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Error::Connection(_) | Error::CircuitOpen { .. } | Error::Overloaded { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SearchParams {
+    /// Comma-separated, e.g. `tags=rust,mongodb`.
+    tags: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    q: Option<String>,
+    page: Option<u64>,
+    per_page: Option<u64>,
+}
+
+/// `GET /posts/search?tags=a,b&from=...&to=...&q=...&page=0&per_page=20`
+async fn search_posts(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Query(params): Query<SearchParams>,
+) -> Result<Response, Error> {
+    let filters = SearchFilters {
+        tags: params.tags
+            .map(|tags| tags.split(',').filter(|tag| !tag.is_empty()).map(str::to_string).collect())
+            .unwrap_or_default(),
+        from: params.from,
+        to: params.to,
+        text: params.q,
+        page: params.page.unwrap_or(0),
+        per_page: params.per_page.unwrap_or(20).min(100),
+    };
+    if page_offset(filters.page, filters.per_page).is_none() {
+        return Ok(page_out_of_range());
+    }
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    let search = state.bulkhead.call(state.breaker.call(repo.search(&filters)));
+    // Keyed by what the role may read, so one role's cached results aren't served to another
+    let key = ReadPolicy::posts().restrict_pipeline(role, search_pipeline(&filters));
+    let results: SearchResults = state.cache.get_or_compute("posts", &key, search).await?;
+    Ok(Json(results).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct SuggestParams {
+    q: String,
+    limit: Option<i64>,
+}
+
+/// `GET /posts/suggest?q=he&limit=10`
+async fn suggest_titles(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Query(params): Query<SuggestParams>,
+) -> Result<Json<Vec<String>>, Error> {
+    let limit = params.limit.unwrap_or(10).clamp(1, 50);
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    Ok(Json(state.bulkhead.call(state.breaker.call(repo.suggest(&params.q, limit))).await?))
+}
+
+#[derive(serde::Deserialize)]
+struct TitleParams {
+    title: String,
+}
+
+#[derive(serde::Serialize)]
+struct TitleTaken {
+    taken: bool,
+}
+
+/// `GET /posts/title-taken?title=Hello`, `{ "taken": bool }`; for forms to
+/// warn of a duplicate before `POST /posts` would fail with 409. Most free
+/// titles are answered from the title filter without a query.
+async fn title_taken(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Query(params): Query<TitleParams>,
+) -> Result<Json<TitleTaken>, Error> {
+    let repo = state.repo.with_context(request_id).with_deadline(deadline);
+    let taken = state.bulkhead.call(state.breaker.call(repo.title_taken(&params.title))).await?;
+    Ok(Json(TitleTaken { taken }))
+}
+
+/// `GET /posts/changes`, server-sent events as posts change: `upserted`
+/// with the post, `deleted` with its id, and `missed` with how many changes
+/// this client read too slowly to get, after which it should reload. Needs
+/// a replica set, for the change stream; elsewhere the stream stays empty.
+async fn post_changes(State(state): State<SharedState>) -> Response {
+    let receiver = state.changes.subscribe().expect("`DropOldest` bridges take any number of receivers");
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await? {
+            Received::Change(PostChange::Upserted(post)) => Event::default().event("upserted").json_data(&post),
+            Received::Change(PostChange::Deleted(id)) => Ok(Event::default().event("deleted").data(id.to_hex())),
+            Received::Missed(count) => Ok(Event::default().event("missed").data(count.to_string())),
+        };
+        Some((event, receiver))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+fn invalid_id() -> Response {
+    (StatusCode::BAD_REQUEST, "invalid post id").into_response()
+}
+
+fn page_out_of_range() -> Response {
+    (StatusCode::BAD_REQUEST, "page out of range").into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct ListParams {
+    limit: Option<i64>,
+}
+
+/// `GET /posts?limit=20`, newest first
+async fn list_posts(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Vec<PostSummary>>, Error> {
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    Ok(Json(state.bulkhead.call(state.breaker.call(repo.list_summaries(limit))).await?))
+}
+
+#[derive(serde::Deserialize)]
+struct NewPost {
+    title: String,
+    message: String,
+    tags: Vec<String>,
+    lang: Option<String>,
+}
+
+/// `POST /posts` with `{ title, message, tags, lang? }`; 409 on a duplicate
+/// title, 422 when the validator rejects the post
+async fn create_post(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Json(body): Json<NewPost>,
+) -> Result<Response, Error> {
+    let tags: Vec<&str> = body.tags.iter().map(String::as_str).collect();
+    let post = Post { lang: body.lang, ..Post::new(&body.title, &body.message, &tags) };
+    let repo = state.repo.with_context(request_id).with_deadline(deadline);
+    state.bulkhead.call(state.breaker.call(repo.insert(&post))).await?;
+    Ok((StatusCode::CREATED, Json(post)).into_response())
+}
+
+/// `GET /posts/:id`, 404 when there is no such post
+async fn get_post(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Path(id): Path<String>,
+) -> Result<Response, Error> {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return Ok(invalid_id()),
+    };
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    let post = state.bulkhead.call(state.breaker.call(repo.find_by_id(id))).await?;
+    Ok(Json(post).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct AttachmentParams {
+    #[serde(default)]
+    size: Size,
+}
+
+/// `GET /attachments/:id?size=thumb`, the file as uploaded without `size`;
+/// 404 when there is no such attachment, or no thumbnail of it
+async fn get_attachment(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+    Query(params): Query<AttachmentParams>,
+) -> Result<Response, Error> {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return Ok((StatusCode::BAD_REQUEST, "invalid attachment id").into_response()),
+    };
+    let download = state.attachments.download_sized(id, params.size);
+    let bytes = state.bulkhead.call(state.breaker.call(download)).await?;
+    Ok(([(CONTENT_TYPE, attachments::content_type(&bytes))], bytes).into_response())
+}
+
+/// Tells an absent field (`None`) apart from an explicit `null` (`Some(None)`).
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Only the fields present are changed; `null` removes `lang` or `publish_at`.
+#[derive(serde::Deserialize)]
+struct PatchBody {
+    title: Option<String>,
+    message: Option<String>,
+    tags: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    lang: Option<Option<String>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    publish_at: Option<Option<DateTime<Utc>>>,
+}
+
+/// `PATCH /posts/:id`, returning the updated post
+async fn patch_post(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Path(id): Path<String>,
+    Json(body): Json<PatchBody>,
+) -> Result<Response, Error> {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return Ok(invalid_id()),
+    };
+    let patch = PostPatch {
+        title: body.title,
+        message: body.message,
+        tags: body.tags,
+        lang: body.lang,
+        publish_at: body.publish_at.map(|at| at.map(mongodb::bson::DateTime::from_chrono)),
+    };
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    let post = state.bulkhead.call(state.breaker.call(repo.patch_post(id, &patch))).await?;
+    Ok(Json(post).into_response())
+}
+
+/// `DELETE /posts/:id`, 204 once deleted
+async fn delete_post(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path(id): Path<String>,
+) -> Result<Response, Error> {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return Ok(invalid_id()),
+    };
+    let repo = state.repo.with_context(request_id).with_deadline(deadline);
+    state.bulkhead.call(state.breaker.call(repo.delete_by_id(id))).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `GET /tags/rust/posts`, newest first
+async fn tag_posts(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Path(tag): Path<String>,
+) -> Result<Json<Vec<PostSummary>>, Error> {
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    Ok(Json(state.bulkhead.call(state.breaker.call(repo.find_summaries_by_tag(&tag))).await?))
+}
+
+#[derive(serde::Deserialize)]
+struct PageParams {
+    page: Option<u64>,
+    per_page: Option<u64>,
+}
+
+/// `GET /posts/:id/similar?page=0&per_page=10`
+async fn similar_posts(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Path(id): Path<String>,
+    Query(params): Query<PageParams>,
+) -> Result<Response, Error> {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return Ok(invalid_id()),
+    };
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    let page = params.page.unwrap_or(0);
+    let per_page = params.per_page.unwrap_or(10).min(50);
+    if page_offset(page, per_page).is_none() {
+        return Ok(page_out_of_range());
+    }
+    let similar: Vec<SimilarPost> = state.bulkhead
+        .call(state.breaker.call(repo.similar_posts(id, page, per_page)))
+        .await?;
+    Ok(Json(similar).into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct RelatedParams {
+    limit: Option<i64>,
+}
+
+/// `GET /tags/rust/related?limit=10`
+async fn related_tags(
+    State(state): State<SharedState>,
+    Path(tag): Path<String>,
+    Query(params): Query<RelatedParams>,
+) -> Result<Json<Vec<RelatedTag>>, Error> {
+    let limit = params.limit.unwrap_or(10).clamp(1, 50);
+    let related = state.tag_graph.related_tags(&tag, limit);
+    Ok(Json(state.bulkhead.call(state.breaker.call(related)).await?))
+}
+
+#[derive(serde::Deserialize)]
+struct NewSearch {
+    name: String,
+    #[serde(default)]
+    filter: SearchFilter,
+}
+
+/// `GET /users/:user/searches`, by name
+async fn list_searches(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path(user): Path<String>,
+) -> Result<Json<Vec<SavedSearch>>, Error> {
+    let searches = state.searches.with_context(request_id).with_deadline(deadline);
+    Ok(Json(state.bulkhead.call(state.breaker.call(searches.list(&user))).await?))
+}
+
+/// `POST /users/:user/searches` with `{ name, filter: { tags?, from?, to?, text? } }`;
+/// 409 when the user has a search by that name
+async fn create_search(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path(user): Path<String>,
+    Json(body): Json<NewSearch>,
+) -> Result<Response, Error> {
+    let searches = state.searches.with_context(request_id).with_deadline(deadline);
+    let search = state.bulkhead.call(state.breaker.call(searches.create(&user, &body.name, body.filter))).await?;
+    Ok((StatusCode::CREATED, Json(search)).into_response())
+}
+
+/// `GET /users/:user/searches/:name`, 404 when there is no such search
+async fn get_search(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path((user, name)): Path<(String, String)>,
+) -> Result<Json<SavedSearch>, Error> {
+    let searches = state.searches.with_context(request_id).with_deadline(deadline);
+    Ok(Json(state.bulkhead.call(state.breaker.call(searches.get(&user, &name))).await?))
+}
+
+/// `PUT /users/:user/searches/:name` with the new filter, returning the search
+async fn update_search(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path((user, name)): Path<(String, String)>,
+    Json(filter): Json<SearchFilter>,
+) -> Result<Json<SavedSearch>, Error> {
+    let searches = state.searches.with_context(request_id).with_deadline(deadline);
+    Ok(Json(state.bulkhead.call(state.breaker.call(searches.update(&user, &name, filter))).await?))
+}
+
+/// `DELETE /users/:user/searches/:name`, 204 once deleted
+async fn delete_search(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Path((user, name)): Path<(String, String)>,
+) -> Result<Response, Error> {
+    let searches = state.searches.with_context(request_id).with_deadline(deadline);
+    state.bulkhead.call(state.breaker.call(searches.delete(&user, &name))).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `GET /users/:user/searches/:name/results?page=0&per_page=20`, the saved
+/// filter run like `/posts/search` and cached with it
+async fn run_search(
+    State(state): State<SharedState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(deadline): Extension<Deadline>,
+    Extension(role): Extension<Role>,
+    Path((user, name)): Path<(String, String)>,
+    Query(params): Query<PageParams>,
+) -> Result<Response, Error> {
+    let (page, per_page) = (params.page.unwrap_or(0), params.per_page.unwrap_or(20).min(100));
+    if page_offset(page, per_page).is_none() {
+        return Ok(page_out_of_range());
+    }
+    let searches = state.searches.with_context(request_id.clone()).with_deadline(deadline);
+    let search = state.bulkhead.call(state.breaker.call(searches.get(&user, &name))).await?;
+    let filters = search.filter.page(page, per_page);
+    let repo = state.repo.with_context(request_id).with_deadline(deadline).with_role(role);
+    let results = state.bulkhead.call(state.breaker.call(repo.search(&filters)));
+    let key = ReadPolicy::posts().restrict_pipeline(role, search_pipeline(&filters));
+    let results: SearchResults = state.cache.get_or_compute("posts", &key, results).await?;
+    Ok(Json(results).into_response())
+}
+
+/// `GET /admin/overview`, for a dashboard; 403 unless the caller is
+/// [`Role::Admin`]
+async fn admin_overview(
+    State(state): State<SharedState>,
+    Extension(role): Extension<Role>,
+) -> Result<Response, Error> {
+    if role < Role::Admin {
+        return Ok((StatusCode::FORBIDDEN, "admin only").into_response());
+    }
+    let overview = state.bulkhead.call(state.breaker.call(admin::overview(&state.ns, &state.latency))).await?;
+    Ok(Json(overview).into_response())
+}
+
+/// `GET /metrics/bulkhead`
+async fn bulkhead_stats(State(state): State<SharedState>) -> Json<BulkheadStats> {
+    Json(state.bulkhead.stats())
+}
+
+async fn latency_stats(State(state): State<SharedState>) -> Json<Vec<OpLatency>> {
+    Json(state.latency.summary())
+}
+
+/// `GET /metrics`, for Prometheus to scrape.
+async fn prometheus_metrics(State(state): State<SharedState>) -> impl IntoResponse {
+    let storage = state.storage.as_ref().and_then(|storage| storage.last());
+    let body = metrics::prometheus(&state.metrics.counters(), &state.latency.summary(), storage.as_ref());
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
@@ -0,0 +1,171 @@
+use futures::TryStreamExt;
+use mongodb::{Client, Collection};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{DeleteOptions, FindOptions, UpdateOptions};
+use mongodb::results::{DeleteResult, UpdateResult};
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+use crate::explain::Plan;
+use crate::namespace::Namespace;
+
+/// The fields a collection is sharded on, e.g. `{ author_id: 1, _id: 1 }`.
+///
+/// mongos can only route a query to the shards owning its documents when the
+/// filter pins a prefix of the shard key to values; anything else is sent to
+/// every shard and the results merged ("scatter-gather"), which gets slower
+/// with every shard added.
+#[derive(Debug, Clone)]
+pub struct ShardKey {
+    fields: Vec<String>,
+}
+
+impl ShardKey {
+    pub fn new(fields: &[&str]) -> Self {
+        ShardKey { fields: fields.iter().map(|field| field.to_string()).collect() }
+    }
+
+    /// `{ <field>: 1, ... }`, to pass to `shardCollection`.
+    pub fn key_pattern(&self) -> Document {
+        self.fields.iter().map(|field| (field.clone(), Bson::Int32(1))).collect()
+    }
+
+    /// The leading key field when `filter` doesn't pin it, at the top level
+    /// or in a top-level `$and`. The leading field is what routing needs; the
+    /// others only narrow the query down to fewer chunks.
+    pub fn untargeted(&self, filter: &Document) -> Option<&str> {
+        let first = self.fields.first()?;
+        let pinned = |filter: &Document| filter.contains_key(first);
+        let in_and = filter.get_array("$and").is_ok_and(|clauses| {
+            clauses.iter().any(|clause| clause.as_document().is_some_and(pinned))
+        });
+        if pinned(filter) || in_and {
+            None
+        } else {
+            Some(first)
+        }
+    }
+
+    /// `filter` restricted to the documents under `key`, which gives values
+    /// for a prefix of the shard key's fields, at least the leading one.
+    #[allow(clippy::result_large_err)] // same `Result` as the query methods
+    pub fn targeted(&self, key: &Document, filter: Document) -> Result<Document> {
+        if let Some(field) = self.untargeted(key) {
+            return Err(Error::MissingShardKey(field.to_string()));
+        }
+        let key = key.clone();
+        Ok(if filter.is_empty() { key } else { doc! { "$and": [key, filter] } })
+    }
+}
+
+/// A collection queried only through its shard key: every method takes the
+/// key of the documents it is about and adds it to the filter, so a query
+/// can't forget it and fan out to every shard.
+///
+/// With [`ShardedCollection::with_lint`] each query is explained first and a
+/// warning logged when it would still go to more than one shard, e.g. because
+/// the key's values span chunks on different shards.
+#[derive(Clone)]
+pub struct ShardedCollection<T: Send + Sync> {
+    ns: Namespace,
+    name: String,
+    col: Collection<T>,
+    key: ShardKey,
+    lint: bool,
+}
+
+impl<T> ShardedCollection<T>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    pub fn new(ns: &Namespace, name: &str, key: ShardKey) -> Self {
+        ShardedCollection { ns: ns.clone(), name: name.to_string(), col: ns.collection(name), key, lint: false }
+    }
+
+    pub fn with_lint(self, lint: bool) -> Self {
+        ShardedCollection { lint, ..self }
+    }
+
+    pub fn key(&self) -> &ShardKey {
+        &self.key
+    }
+
+    /// Shards the collection on the key. Only works through mongos, once
+    /// sharding is enabled for the database.
+    pub async fn shard(&self, client: &Client) -> Result<()> {
+        let namespace = format!("{}.{}", self.ns.db().name(), self.ns.name(&self.name));
+        let command = doc! { "shardCollection": namespace, "key": self.key.key_pattern() };
+        client.database("admin").run_command(command, None).await?;
+        Ok(())
+    }
+
+    /// Documents under `key` matching `filter`.
+    pub async fn find(&self, key: &Document, filter: Document) -> Result<Vec<T>> {
+        let filter = self.key.targeted(key, filter)?;
+        self.lint("find", &filter).await?;
+        let options = FindOptions::builder().comment_bson(comment("find")).build();
+        Ok(self.col.find(filter, options).await?.try_collect().await?)
+    }
+
+    pub async fn count(&self, key: &Document, filter: Document) -> Result<u64> {
+        let filter = self.key.targeted(key, filter)?;
+        self.lint("count", &filter).await?;
+        Ok(self.col.count_documents(filter, None).await?)
+    }
+
+    /// Updates the first document under `key` matching `filter`. Before 4.4,
+    /// sharded clusters refuse `update_one` unless the filter has the full
+    /// shard key or `_id`.
+    pub async fn update_one(&self, key: &Document, filter: Document, update: Document) -> Result<UpdateResult> {
+        let filter = self.key.targeted(key, filter)?;
+        let options = UpdateOptions::builder().comment(comment("update_one")).build();
+        Ok(self.col.update_one(filter, update, options).await?)
+    }
+
+    pub async fn delete_many(&self, key: &Document, filter: Document) -> Result<DeleteResult> {
+        let filter = self.key.targeted(key, filter)?;
+        let options = DeleteOptions::builder().comment(comment("delete_many")).build();
+        Ok(self.col.delete_many(filter, options).await?)
+    }
+
+    /// How many shards `filter` would be sent to, from its query plan.
+    pub async fn shards_for(&self, filter: &Document) -> Result<usize> {
+        let explain = self.ns.db().run_command(doc! {
+            "explain": { "find": self.ns.name(&self.name), "filter": filter.clone() },
+            "verbosity": "queryPlanner",
+        }, None).await?;
+        Ok(Plan::of(&explain).shards)
+    }
+
+    async fn lint(&self, op: &str, filter: &Document) -> Result<()> {
+        if !self.lint {
+            return Ok(());
+        }
+        let shards = self.shards_for(filter).await?;
+        if shards > 1 {
+            tracing::warn!(collection = %self.name, op, shards, filter = %filter, "scatter-gather query");
+        }
+        Ok(())
+    }
+}
+
+fn comment(op: &str) -> Bson {
+    Bson::Document(doc! { "app": crate::repository::APP_NAME, "op": op })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targeting_needs_the_leading_key_field() {
+        let key = ShardKey::new(&["author_id", "_id"]);
+        let filter = key.targeted(&doc! { "author_id": "ann" }, doc! { "tags": "rust" }).unwrap();
+        assert_eq!(key.untargeted(&filter), None);
+        assert!(matches!(
+            key.targeted(&doc! { "_id": 1 }, doc! {}),
+            Err(Error::MissingShardKey(field)) if field == "author_id"
+        ));
+        assert_eq!(key.untargeted(&doc! { "tags": "rust" }), Some("author_id"));
+    }
+}
@@ -0,0 +1,416 @@
+use chrono::{DateTime as ChronoDateTime, Utc};
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::FindOptions;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// A `SELECT` of the subset of SQL newcomers reach for first, translated
+/// to a filter, sort, limit and projection:
+///
+/// ```sql
+/// SELECT title, created_at FROM posts
+/// WHERE tags = 'rust' AND created_at >= TIMESTAMP '2024-01-01T00:00:00Z'
+/// ORDER BY created_at DESC LIMIT 10
+/// ```
+///
+/// Conditions are `field op literal` joined by `AND`, with `=`, `!=` (or
+/// `<>`), `<`, `<=`, `>`, `>=`, `[NOT] IN (...)`, `LIKE` (`%` and `_`
+/// wildcards) and `IS [NOT] NULL`. Literals are `'strings'`, numbers,
+/// `TRUE`, `FALSE`, `NULL` and `TIMESTAMP '<RFC 3339>'`. Fields may be
+/// dotted paths. `=` on an array field matches documents with the value
+/// among its elements, as in any MongoDB filter. There is no `OR`, join or
+/// `GROUP BY`: those are pipelines, best written as such in `queries.toml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    /// Unprefixed, like every collection name.
+    pub collection: String,
+    /// Every field, `_id` included, when empty (`SELECT *`).
+    pub fields: Vec<String>,
+    pub filter: Document,
+    pub sort: Document,
+    pub limit: Option<i64>,
+}
+
+impl Select {
+    pub fn parse(sql: &str) -> Result<Select> {
+        Parser { tokens: tokenize(sql)?, at: 0 }.select()
+    }
+
+    /// Only the listed fields, and `_id` only when listed, as SQL would.
+    fn projection(&self) -> Option<Document> {
+        if self.fields.is_empty() {
+            return None;
+        }
+        let mut projection: Document = self.fields.iter().map(|field| (field.clone(), Bson::Int32(1))).collect();
+        if !projection.contains_key("_id") {
+            projection.insert("_id", 0);
+        }
+        Some(projection)
+    }
+
+    pub fn find_options(&self) -> FindOptions {
+        FindOptions::builder()
+            .projection(self.projection())
+            .sort((!self.sort.is_empty()).then(|| self.sort.clone()))
+            .limit(self.limit)
+            .build()
+    }
+
+    /// The same query as a pipeline, projecting last so that it can sort on
+    /// fields it doesn't return.
+    pub fn pipeline(&self) -> Vec<Document> {
+        let mut pipeline = vec![doc! { "$match": self.filter.clone() }];
+        if !self.sort.is_empty() {
+            pipeline.push(doc! { "$sort": self.sort.clone() });
+        }
+        if let Some(limit) = self.limit {
+            pipeline.push(doc! { "$limit": limit });
+        }
+        if let Some(projection) = self.projection() {
+            pipeline.push(doc! { "$project": projection });
+        }
+        pipeline
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A keyword or a field or collection name, as written.
+    Word(String),
+    Text(String),
+    Number(Bson),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn is(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+const SYMBOLS: [&str; 11] = ["<=", ">=", "<>", "!=", "=", "<", ">", ",", "(", ")", "*"];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = sql.trim_end_matches([';', ' ', '\n', '\t']);
+    loop {
+        rest = rest.trim_start();
+        let Some(first) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if first == '\'' {
+            // `''` is a quote within the string
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((at, '\'')) if rest[1 + at + 1..].starts_with('\'') => {
+                        text.push('\'');
+                        chars.next();
+                    }
+                    Some((at, '\'')) => break 1 + at + 1,
+                    Some((_, c)) => text.push(c),
+                    None => return Err("unterminated string".into()),
+                }
+            };
+            tokens.push(Token::Text(text));
+            rest = &rest[end..];
+        } else if first.is_ascii_digit() || first == '-' {
+            let end = rest[1..].find(|c: char| !c.is_ascii_digit() && c != '.').map_or(rest.len(), |end| end + 1);
+            let number = &rest[..end];
+            let number = match number.parse::<i64>() {
+                Ok(n) => Bson::Int64(n),
+                Err(_) => Bson::Double(number.parse().map_err(|_| format!("not a number: {}", number))?),
+            };
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if first.is_alphabetic() || first == '_' {
+            let end = rest.find(|c: char| !c.is_alphanumeric() && c != '_' && c != '.').unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("unexpected {:?}", first).into());
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    /// Consumes `keyword` if it comes next.
+    fn eat(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_some_and(|token| token.is(keyword));
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, keyword: &str) -> Result<()> {
+        if self.eat(keyword) {
+            Ok(())
+        } else {
+            Err(format!("expected {}, found {}", keyword, self.found()).into())
+        }
+    }
+
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            Some(Token::Text(text)) => format!("'{}'", text),
+            Some(Token::Number(number)) => number.to_string(),
+            Some(Token::Symbol(symbol)) => symbol.to_string(),
+            None => "the end".to_string(),
+        }
+    }
+
+    fn name(&mut self, what: &str) -> Result<String> {
+        match self.peek() {
+            Some(Token::Word(word)) if !is_keyword(word) => {
+                let word = word.clone();
+                self.at += 1;
+                Ok(word)
+            }
+            _ => Err(format!("expected {}, found {}", what, self.found()).into()),
+        }
+    }
+
+    fn select(&mut self) -> Result<Select> {
+        self.expect("SELECT")?;
+        let mut fields = Vec::new();
+        if !self.eat_symbol("*") {
+            loop {
+                fields.push(self.name("a field")?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.expect("FROM")?;
+        let collection = self.name("a collection")?;
+        let mut conditions = Vec::new();
+        if self.eat("WHERE") {
+            loop {
+                conditions.push(self.condition()?);
+                if self.eat("OR") {
+                    return Err("OR isn't supported, write the query as a pipeline instead".into());
+                }
+                if !self.eat("AND") {
+                    break;
+                }
+            }
+        }
+        let mut sort = Document::new();
+        if self.eat("ORDER") {
+            self.expect("BY")?;
+            loop {
+                let field = self.name("a field")?;
+                let descending = self.eat("DESC");
+                if !descending {
+                    self.eat("ASC");
+                }
+                sort.insert(field, if descending { -1 } else { 1 });
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let limit = if self.eat("LIMIT") {
+            match self.next() {
+                Some(Token::Number(Bson::Int64(limit))) if limit >= 0 => Some(limit),
+                _ => return Err("LIMIT takes a whole number".into()),
+            }
+        } else {
+            None
+        };
+        if self.peek().is_some() {
+            return Err(format!("unexpected {}", self.found()).into());
+        }
+        Ok(Select { collection, fields, filter: filter_of(conditions), sort, limit })
+    }
+
+    /// One `field op literal` as a field and its filter.
+    fn condition(&mut self) -> Result<(String, Bson)> {
+        let field = self.name("a field")?;
+        if self.eat("IS") {
+            let not = self.eat("NOT");
+            self.expect("NULL")?;
+            return Ok((field, if not { doc! { "$ne": Bson::Null }.into() } else { Bson::Null }));
+        }
+        let not = self.eat("NOT");
+        if self.eat("IN") {
+            if !self.eat_symbol("(") {
+                return Err(format!("expected (, found {}", self.found()).into());
+            }
+            let mut values = Vec::new();
+            loop {
+                values.push(self.literal()?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+            if !self.eat_symbol(")") {
+                return Err(format!("expected ), found {}", self.found()).into());
+            }
+            let operator = if not { "$nin" } else { "$in" };
+            return Ok((field, doc! { operator: values }.into()));
+        }
+        if self.eat("LIKE") {
+            let Token::Text(pattern) = self.next().ok_or("LIKE takes a string")? else {
+                return Err("LIKE takes a string".into());
+            };
+            let like = doc! { "$regex": like_regex(&pattern) };
+            return Ok((field, if not { doc! { "$not": like }.into() } else { like.into() }));
+        }
+        if not {
+            return Err(format!("expected IN or LIKE after NOT, found {}", self.found()).into());
+        }
+        let operator = match self.next() {
+            Some(Token::Symbol(symbol)) => symbol,
+            _ => return Err(format!("expected a comparison after {}", field).into()),
+        };
+        let value = self.literal()?;
+        let filter = match operator {
+            "=" => value,
+            "!=" | "<>" => doc! { "$ne": value }.into(),
+            "<" => doc! { "$lt": value }.into(),
+            "<=" => doc! { "$lte": value }.into(),
+            ">" => doc! { "$gt": value }.into(),
+            ">=" => doc! { "$gte": value }.into(),
+            symbol => return Err(format!("expected a comparison after {}, found {}", field, symbol).into()),
+        };
+        Ok((field, filter))
+    }
+
+    fn literal(&mut self) -> Result<Bson> {
+        let found = self.found();
+        match self.next() {
+            Some(Token::Text(text)) => Ok(Bson::String(text)),
+            Some(Token::Number(number)) => Ok(number),
+            Some(token) if token.is("TRUE") => Ok(Bson::Boolean(true)),
+            Some(token) if token.is("FALSE") => Ok(Bson::Boolean(false)),
+            Some(token) if token.is("NULL") => Ok(Bson::Null),
+            Some(token) if token.is("TIMESTAMP") => match self.next() {
+                Some(Token::Text(text)) => {
+                    let date = ChronoDateTime::parse_from_rfc3339(&text)
+                        .map_err(|e| format!("TIMESTAMP '{}': {}", text, e))?;
+                    Ok(Bson::DateTime(DateTime::from_chrono(date.with_timezone(&Utc))))
+                }
+                _ => Err("TIMESTAMP takes a string".into()),
+            },
+            _ => Err(format!("expected a value, found {}", found).into()),
+        }
+    }
+}
+
+const KEYWORDS: [&str; 17] = [
+    "SELECT", "FROM", "WHERE", "AND", "OR", "NOT", "IN", "LIKE", "IS", "NULL", "ORDER", "BY", "ASC", "DESC",
+    "LIMIT", "TRUE", "FALSE",
+];
+
+fn is_keyword(word: &str) -> bool {
+    KEYWORDS.iter().any(|keyword| keyword.eq_ignore_ascii_case(word))
+}
+
+/// `pattern` as an anchored regex, matching `%` as any run of characters
+/// and `_` as any one.
+fn like_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The conditions as one filter: a field each, or `$and` of them when a
+/// field comes up more than once.
+fn filter_of(conditions: Vec<(String, Bson)>) -> Document {
+    let distinct = conditions.iter().enumerate()
+        .all(|(at, (field, _))| conditions[..at].iter().all(|(other, _)| other != field));
+    if distinct {
+        conditions.into_iter().collect()
+    } else {
+        let clauses: Vec<Document> = conditions.into_iter().map(|(field, filter)| doc! { field: filter }).collect();
+        doc! { "$and": clauses }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_translate_to_filter_sort_limit_and_projection() {
+        let select = Select::parse(
+            "select title, created_at from posts \
+             where tags = 'rust' and version >= 2 and status in ('published', 'draft') and title like 'It''s_%' \
+             order by created_at desc, title limit 10;",
+        ).unwrap();
+        assert_eq!(select.collection, "posts");
+        assert_eq!(select.filter, doc! {
+            "tags": "rust",
+            "version": { "$gte": 2_i64 },
+            "status": { "$in": ["published", "draft"] },
+            "title": { "$regex": "^It's..*$" },
+        });
+        assert_eq!(select.pipeline(), [
+            doc! { "$match": select.filter.clone() },
+            doc! { "$sort": { "created_at": -1, "title": 1 } },
+            doc! { "$limit": 10_i64 },
+            doc! { "$project": { "title": 1, "created_at": 1, "_id": 0 } },
+        ]);
+    }
+
+    #[test]
+    fn repeated_fields_are_anded_and_everything_else_is_an_error() {
+        let select = Select::parse(
+            "SELECT * FROM posts WHERE created_at >= TIMESTAMP '2024-01-01T00:00:00Z' AND created_at < 5 \
+             AND lang IS NOT NULL",
+        ).unwrap();
+        let from = DateTime::parse_rfc3339_str("2024-01-01T00:00:00Z").unwrap();
+        assert_eq!(select.filter, doc! { "$and": [
+            { "created_at": { "$gte": from } },
+            { "created_at": { "$lt": 5_i64 } },
+            { "lang": { "$ne": Bson::Null } },
+        ]});
+        assert_eq!(select.pipeline().len(), 1);
+        for sql in [
+            "SELECT * FROM posts WHERE tags = 'a' OR tags = 'b'",
+            "SELECT * FROM posts WHERE title = 'unterminated",
+            "SELECT * FROM posts LIMIT -1",
+            "SELECT FROM posts",
+            "SELECT * FROM posts GROUP BY tags",
+        ] {
+            assert!(Select::parse(sql).is_err(), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use mongodb::bson::{doc, Document};
+
+use crate::error::Result;
+use crate::ids::number;
+use crate::namespace::Namespace;
+
+/// Share of the quota from which on [`QuotaLevel::Warning`] applies.
+pub const WARNING_AT: f64 = 0.80;
+/// Share of the quota from which on [`QuotaLevel::Critical`] applies.
+pub const CRITICAL_AT: f64 = 0.95;
+
+/// How close storage is to its quota, from least to most alarming.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaLevel {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl QuotaLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaLevel::Ok => "ok",
+            QuotaLevel::Warning => "warning",
+            QuotaLevel::Critical => "critical",
+        }
+    }
+
+    fn of(used: f64) -> QuotaLevel {
+        if used >= CRITICAL_AT {
+            QuotaLevel::Critical
+        } else if used >= WARNING_AT {
+            QuotaLevel::Warning
+        } else {
+            QuotaLevel::Ok
+        }
+    }
+}
+
+impl fmt::Display for QuotaLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the database takes on disk, from `dbStats`, against its quota.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct StorageUsage {
+    /// Uncompressed size of the documents.
+    pub data_bytes: i64,
+    /// Documents and indexes on disk, compressed, including free space: what
+    /// the quota counts, as it is what the disk fills up with.
+    pub used_bytes: i64,
+    pub quota_bytes: u64,
+    pub level: QuotaLevel,
+}
+
+impl StorageUsage {
+    fn of(stats: &Document, quota_bytes: u64) -> StorageUsage {
+        let used_bytes = number(stats.get("storageSize")) + number(stats.get("indexSize"));
+        StorageUsage {
+            data_bytes: number(stats.get("dataSize")),
+            used_bytes,
+            quota_bytes,
+            level: QuotaLevel::of(used_bytes as f64 / quota_bytes.max(1) as f64),
+        }
+    }
+
+    /// `used_bytes` as a percentage of the quota.
+    pub fn percent(&self) -> f64 {
+        self.used_bytes as f64 / self.quota_bytes.max(1) as f64 * 100.0
+    }
+}
+
+/// A soft quota on the storage of a namespace's database: nothing is
+/// refused once it is used up, but [`StorageQuota::check`] logs a warning
+/// when usage crosses [`WARNING_AT`] and an error when it crosses
+/// [`CRITICAL_AT`], and the last usage seen is served at `/metrics`.
+///
+/// `dbStats` counts the whole database, so collections of other prefixes
+/// in it count against the quota too.
+pub struct StorageQuota {
+    ns: Namespace,
+    quota_bytes: u64,
+    last: Mutex<Option<StorageUsage>>,
+}
+
+impl StorageQuota {
+    pub fn new(ns: &Namespace, quota_bytes: u64) -> Self {
+        StorageQuota { ns: ns.clone(), quota_bytes, last: Mutex::new(None) }
+    }
+
+    /// The usage found by the last [`StorageQuota::check`], if any.
+    pub fn last(&self) -> Option<StorageUsage> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// Reads `dbStats` and logs when the level changed since the last check:
+    /// up as a warning, or an error once critical, and back down as info.
+    pub async fn check(&self) -> Result<StorageUsage> {
+        let stats = self.ns.db().run_command(doc! { "dbStats": 1 }, None).await?;
+        let usage = StorageUsage::of(&stats, self.quota_bytes);
+        let previous = self.last.lock().unwrap().replace(usage.clone());
+        let previous = previous.map_or(QuotaLevel::Ok, |previous| previous.level);
+        let (used_bytes, quota_bytes, percent) = (usage.used_bytes, usage.quota_bytes, usage.percent());
+        match usage.level {
+            level if level == previous => {}
+            QuotaLevel::Critical => {
+                tracing::error!(used_bytes, quota_bytes, percent, "storage at {:.0}% of its quota", percent);
+            }
+            QuotaLevel::Warning if previous < QuotaLevel::Warning => {
+                tracing::warn!(used_bytes, quota_bytes, percent, "storage at {:.0}% of its quota", percent);
+            }
+            level => tracing::info!(used_bytes, quota_bytes, percent, "storage back to {}", level),
+        }
+        Ok(usage)
+    }
+
+    /// Checks every `interval`, for as long as the process runs. A failed
+    /// check is logged and tried again next time.
+    pub async fn watch(&self, interval: Duration) {
+        loop {
+            if let Err(e) = self.check().await {
+                tracing::warn!("unable to check storage quota: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_start_at_eighty_and_ninety_five_percent() {
+        let usage = |storage: i64| StorageUsage::of(&doc! { "storageSize": storage, "indexSize": 50_i64 }, 1000);
+        assert_eq!(usage(700).level, QuotaLevel::Ok);
+        assert_eq!(usage(750).level, QuotaLevel::Warning);
+        assert_eq!(usage(900).level, QuotaLevel::Critical);
+        assert_eq!(usage(900).percent(), 95.0);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use mongodb::bson::Document;
+use opentelemetry::KeyValue;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::TracerProvider;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::latency::op_of;
+use crate::log_sink::MongoLogLayer;
+use crate::metrics;
+use crate::namespace::Namespace;
+use crate::repository::APP_NAME;
+
+/// Target of the events [`CommandTracer`] emits.
+pub const COMMAND_TARGET: &str = "mongodb::command";
+
+/// Keeps the OTLP pipeline alive; call [`Telemetry::shutdown`] before exiting
+/// so batched spans get flushed.
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("unable to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global tracing subscriber: log lines filtered by `RUST_LOG`
+/// (default `info`), a copy of every event in `app_logs` when `log_sink` is
+/// given and, when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, OTLP span export to
+/// that collector. Must be called from within the Tokio runtime.
+pub fn init(log_sink: Option<&Namespace>) -> Telemetry {
+    let provider = if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        let pipeline = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+            .with_trace_config(opentelemetry_sdk::trace::Config::default()
+                .with_resource(Resource::new([KeyValue::new("service.name", APP_NAME)])))
+            .install_batch(opentelemetry_sdk::runtime::Tokio);
+        match pipeline {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                eprintln!("OTLP export disabled: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let otel = provider.as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(APP_NAME)));
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_sink.map(MongoLogLayer::new))
+        .with(otel)
+        .init();
+    Telemetry { provider }
+}
+
+/// Turns the driver's command monitoring events into tracing spans and events.
+///
+/// Every command gets a `mongodb command` span from its started event to its
+/// outcome, carrying the command name, the repository method (`op`, from the
+/// comment), the collection and, once it finished, `duration_ms` and the
+/// `documents` it returned or wrote. The driver calls these handlers from the
+/// task running the operation, so the span is a child of whatever span is
+/// current there (the repository method inside the HTTP request).
+///
+/// Commands taking at least `slow` are also logged as a warning with their
+/// command document, like the server's slow query log.
+pub struct CommandTracer {
+    slow: Duration,
+    /// Span and command document of every command that started but hasn't
+    /// finished, by request id.
+    in_flight: Mutex<HashMap<i32, (Span, Document)>>,
+}
+
+impl CommandTracer {
+    pub fn new(slow: Duration) -> Self {
+        CommandTracer { slow, in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    fn finish(&self, request_id: i32, duration: Duration, documents: Option<u64>, event: impl FnOnce()) {
+        let Some((span, command)) = self.in_flight.lock().unwrap().remove(&request_id) else {
+            return event();
+        };
+        let duration_ms = duration.as_secs_f64() * 1000.0;
+        span.record("duration_ms", duration_ms);
+        if let Some(documents) = documents {
+            span.record("documents", documents);
+        }
+        span.in_scope(|| {
+            event();
+            if duration >= self.slow {
+                tracing::warn!(target: COMMAND_TARGET, duration_ms, command = %command, "slow mongodb command");
+            }
+        });
+    }
+}
+
+impl CommandEventHandler for CommandTracer {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let span = tracing::info_span!(
+            target: COMMAND_TARGET,
+            "mongodb command",
+            command = %event.command_name,
+            op = op_of(&event.command).as_deref().unwrap_or(""),
+            collection = metrics::collection(&event.command_name, &event.command).unwrap_or(""),
+            db = %event.db,
+            duration_ms = tracing::field::Empty,
+            documents = tracing::field::Empty,
+        );
+        span.in_scope(|| tracing::debug!(
+            target: COMMAND_TARGET,
+            request_id = event.request_id,
+            comment = ?event.command.get("comment"),
+            "mongodb command started",
+        ));
+        self.in_flight.lock().unwrap().insert(event.request_id, (span, event.command));
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        let documents = metrics::documents(&event.reply);
+        self.finish(event.request_id, event.duration, Some(documents), || tracing::info!(
+            target: COMMAND_TARGET,
+            command = %event.command_name,
+            request_id = event.request_id,
+            duration_ms = event.duration.as_secs_f64() * 1000.0,
+            "mongodb command succeeded",
+        ));
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finish(event.request_id, event.duration, None, || tracing::warn!(
+            target: COMMAND_TARGET,
+            command = %event.command_name,
+            request_id = event.request_id,
+            duration_ms = event.duration.as_secs_f64() * 1000.0,
+            error = %event.failure,
+            "mongodb command failed",
+        ));
+    }
+}
+
+/// Hands every command event to each of several handlers, in order; the
+/// driver only takes one.
+pub struct CommandHandlers(pub Vec<Arc<dyn CommandEventHandler>>);
+
+impl CommandEventHandler for CommandHandlers {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        for handler in &self.0 {
+            handler.handle_command_started_event(event.clone());
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        for handler in &self.0 {
+            handler.handle_command_succeeded_event(event.clone());
+        }
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        for handler in &self.0 {
+            handler.handle_command_failed_event(event.clone());
+        }
+    }
+}
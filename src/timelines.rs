@@ -0,0 +1,183 @@
+use std::time::Instant;
+
+use futures::TryStreamExt;
+use futures::future::{BoxFuture, FutureExt};
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOptions, UpdateOptions};
+
+use crate::{Post, PostId, PostStatus};
+use crate::bench::LatencyReport;
+use crate::error::{is_duplicate_key, Error, Result};
+use crate::follows::Follows;
+use crate::namespace::Namespace;
+use crate::watcher::{ChangeHandler, PostChange};
+
+/// One [`Timeline`] per user who follows anyone with posts.
+pub const TIMELINES: &str = "timelines";
+
+/// Post ids a timeline keeps, newest first; older ones fall off.
+pub const TIMELINE_LENGTH: i32 = 500;
+
+/// The newest posts of everyone a user follows, by id.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Timeline {
+    #[serde(rename = "_id")]
+    pub user: String,
+    #[serde(default)]
+    pub posts: Vec<PostId>,
+}
+
+/// Fan-out on write: every published post is pushed into the timeline of
+/// each follower of its author as it comes in, so reading a timeline is one
+/// lookup plus a fetch by `_id`, however many people the reader follows;
+/// [`Follows::posts_from_followed`] is the fan-out on read it replaces.
+///
+/// The posts come from the change stream, as a [`ChangeHandler`] run by a
+/// [`Watcher`](crate::watcher::Watcher), so writing a post never waits for
+/// its fan-out. The price is on the write side: an author with a million
+/// followers costs a million updates per post, and timelines only hold
+/// what was posted while the watcher ran.
+#[derive(Clone)]
+pub struct Timelines {
+    timelines: Collection<Timeline>,
+    posts: Collection<Post>,
+    follows: Follows,
+}
+
+impl Timelines {
+    pub async fn new(ns: &Namespace, follows: Follows) -> Result<Self> {
+        let timelines = ns.collection::<Timeline>(TIMELINES);
+        // For taking deleted posts back out of every timeline
+        timelines.create_index(IndexModel::builder().keys(doc! { "posts": 1 }).build(), None).await?;
+        Ok(Timelines { timelines, posts: ns.collection("posts"), follows })
+    }
+
+    /// Adds `post_id` to `user`'s timeline, unless it is there already: the
+    /// change stream delivers a post again after a restart, and once more
+    /// for every later update of it.
+    async fn push(&self, user: &str, post_id: PostId) -> Result<()> {
+        let update = doc! {
+            "$push": { "posts": { "$each": [post_id], "$sort": -1, "$slice": TIMELINE_LENGTH } },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        match self.timelines.update_one(doc! { "_id": user, "posts": { "$ne": post_id } }, update, options).await {
+            // The upsert found the timeline holding the post already
+            Err(e) if is_duplicate_key(&e) => Ok(()),
+            result => result.map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    /// Takes `post_id` out of every timeline.
+    async fn pull(&self, post_id: PostId) -> Result<()> {
+        self.timelines.update_many(doc! { "posts": post_id }, doc! { "$pull": { "posts": post_id } }, None).await?;
+        Ok(())
+    }
+
+    /// Fans a published post out to its author's followers, or takes one
+    /// that is gone or unpublished back out of every timeline.
+    async fn apply(&self, change: &PostChange) -> Result<()> {
+        match change {
+            PostChange::Upserted(post) => match (&post.author, post.status) {
+                (Some(author), PostStatus::Published) => {
+                    for follower in self.follows.followers(author).await? {
+                        self.push(&follower, post.id).await?;
+                    }
+                    Ok(())
+                }
+                // Unpublished again, or never had an author to be followed by
+                _ => self.pull(post.id).await,
+            },
+            PostChange::Deleted(id) => self.pull(*id).await,
+        }
+    }
+
+    /// The newest `limit` posts of `user`'s timeline.
+    pub async fn timeline(&self, user: &str, limit: i64) -> Result<Vec<Post>> {
+        let timeline = self.timelines.find_one(doc! { "_id": user }, None).await?;
+        let ids: Vec<ObjectId> = timeline.map(|timeline| timeline.posts).unwrap_or_default()
+            .into_iter()
+            .take(limit.max(0) as usize)
+            .collect();
+        let options = FindOptions::builder().sort(doc! { "_id": -1 }).build();
+        let posts = self.posts.find(doc! { "_id": { "$in": ids } }, options).await?.try_collect().await?;
+        Ok(posts)
+    }
+
+    /// Reads `user`'s newest `limit` posts `samples` times each way.
+    pub async fn compare(&self, user: &str, limit: i64, samples: usize) -> Result<TimelineComparison> {
+        let (mut on_read, mut on_write) = (Vec::new(), Vec::new());
+        let (mut read, mut written) = (Vec::new(), Vec::new());
+        for _ in 0..samples {
+            let started = Instant::now();
+            read = self.follows.posts_from_followed(user, limit).await?;
+            on_read.push(started.elapsed());
+            let started = Instant::now();
+            written = self.timeline(user, limit).await?;
+            on_write.push(started.elapsed());
+        }
+        let ids = |posts: &[Post]| posts.iter().map(|post| post.id).collect::<Vec<_>>();
+        Ok(TimelineComparison {
+            on_read: LatencyReport::from_samples(on_read),
+            on_write: LatencyReport::from_samples(on_write),
+            agree: ids(&read) == ids(&written),
+        })
+    }
+}
+
+impl ChangeHandler for Timelines {
+    fn handle<'a>(&'a self, change: &'a PostChange) -> BoxFuture<'a, mongodb::error::Result<()>> {
+        async move {
+            self.apply(change).await.map_err(|e| match e {
+                Error::Mongo(e) | Error::Connection(e) => e,
+                e => mongodb::error::Error::custom(e),
+            })
+        }.boxed()
+    }
+}
+
+/// Timeline latency read by fan-out on read and on write, from
+/// [`Timelines::compare`]. `agree` is whether both returned the same posts,
+/// which they only do once the fan-out has seen every post they return.
+#[derive(Debug)]
+pub struct TimelineComparison {
+    pub on_read: LatencyReport,
+    pub on_write: LatencyReport,
+    pub agree: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn posts_fan_out_once_and_leave_when_deleted() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let follows = Follows::new(&ns).await.unwrap();
+        let timelines = Timelines::new(&ns, follows.clone()).await.unwrap();
+        follows.follow("dee", "ann").await.unwrap();
+        let post = Post { author: Some("ann".to_string()), ..Post::new("Fanned", "Out", &["timeline"]) };
+        ns.collection::<Post>("posts").insert_one(&post, None).await.unwrap();
+
+        // Delivered again, as after a restart
+        let change = PostChange::Upserted(Box::new(post.clone()));
+        timelines.apply(&change).await.unwrap();
+        timelines.apply(&change).await.unwrap();
+        let timeline = timelines.timeline("dee", 10).await.unwrap();
+        assert_eq!(timeline.iter().map(|post| post.id).collect::<Vec<_>>(), [post.id]);
+        assert!(timelines.timeline("ann", 10).await.unwrap().is_empty());
+
+        timelines.apply(&PostChange::Deleted(post.id)).await.unwrap();
+        let stored = timelines.timelines.find_one(doc! { "_id": "dee" }, None).await.unwrap().unwrap();
+        assert!(stored.posts.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+
+use crate::error::Result;
+use crate::namespace::Namespace;
+
+/// Bits per title the filter is sized for: with [`HASHES`] hashes, about one
+/// title in a hundred that was never added is taken for one that was.
+const BITS_PER_TITLE: usize = 10;
+
+/// Bits set per title.
+const HASHES: u64 = 7;
+
+/// Titles a filter has room for at least, so an empty collection doesn't
+/// start with one that fills up after a few inserts.
+const MIN_CAPACITY: usize = 1024;
+
+/// A bloom filter of the titles of the stored posts, so that checking that
+/// a title is free mostly needs no query: a title the filter doesn't hold
+/// was never added to it, while one it holds is only maybe taken, and has
+/// to be looked up.
+///
+/// The filter is a hint, never the answer. It only learns of titles written
+/// through the handles it is given to, so one written by another process
+/// since [`TitleFilter::load`] looks free until it is written again here, and
+/// the title of a deleted post still looks taken. The unique title index
+/// decides: an insert of a title the filter took for free still fails with
+/// [`Error::DuplicateTitle`](crate::error::Error::DuplicateTitle) when it
+/// isn't.
+///
+/// Titles are lowercased before hashing, to match the case-insensitive
+/// index; where its collation equates titles that lowercasing doesn't, the
+/// filter errs towards free, which the index catches the same way.
+pub struct TitleFilter {
+    bits: Vec<AtomicU64>,
+}
+
+impl TitleFilter {
+    /// An empty filter with room for `titles` titles; it still works past
+    /// that, only answering "maybe" more often.
+    pub fn with_capacity(titles: usize) -> Self {
+        let words = (titles.max(MIN_CAPACITY) * BITS_PER_TITLE).div_ceil(64);
+        TitleFilter { bits: (0..words).map(|_| AtomicU64::new(0)).collect() }
+    }
+
+    /// A filter of every title in `posts`, with room for as many again.
+    ///
+    /// The titles are read in one pass projected down to the title, rather
+    /// than off the unique index: an index with a collation holds collation
+    /// keys, not the titles, so it can't cover a query for them.
+    pub async fn load(ns: &Namespace) -> Result<Self> {
+        let posts = ns.collection::<Document>("posts");
+        let filter = TitleFilter::with_capacity(2 * posts.estimated_document_count(None).await? as usize);
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 0, "title": 1 })
+            .batch_size(10_000)
+            .build();
+        let mut titles = posts.find(doc! {}, options).await?;
+        while let Some(post) = titles.try_next().await? {
+            if let Ok(title) = post.get_str("title") {
+                filter.insert(title);
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Marks `title` as taken. Safe to call from many tasks at once.
+    pub fn insert(&self, title: &str) {
+        for bit in self.positions(title) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// `false` if `title` was never inserted; `true` if it may have been.
+    pub fn may_contain(&self, title: &str) -> bool {
+        self.positions(title).all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits of `title`, from two hashes combined as in Kirsch and
+    /// Mitzenmacher's "Less Hashing, Same Performance".
+    fn positions(&self, title: &str) -> impl Iterator<Item = usize> {
+        let key = title.to_lowercase();
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            (seed, &key).hash(&mut hasher);
+            hasher.finish()
+        };
+        let (first, second) = (hash(0), hash(1));
+        let len = (self.bits.len() * 64) as u64;
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_titles_are_always_found_and_few_others_are() {
+        let filter = TitleFilter::with_capacity(1000);
+        for n in 0..1000 {
+            filter.insert(&format!("Post {}", n));
+        }
+        assert!((0..1000).all(|n| filter.may_contain(&format!("POST {}", n))));
+        let others = (0..1000).filter(|n| filter.may_contain(&format!("Other {}", n))).count();
+        assert!(others < 30, "{} false positives", others);
+    }
+}
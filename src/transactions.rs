@@ -0,0 +1,220 @@
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use mongodb::{Client, ClientSession};
+use mongodb::bson::{doc, Document};
+use mongodb::error::{Result, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::options::UpdateOptions;
+
+use crate::{Post, PostId};
+use crate::error::{is_duplicate_key, Error};
+use crate::namespace::Namespace;
+use crate::repository::replace_tags_update;
+
+/// Number of posts per tag, `{ _id: <tag>, count }`.
+pub const TAG_COUNTS: &str = "tag_counts";
+
+/// Number of posts per author, `{ _id: <author>, count }`.
+pub const AUTHOR_COUNTS: &str = "author_counts";
+
+/// How long [`run_in_txn`] keeps retrying, as the driver's own
+/// `with_transaction` does in other languages: past it, the last error is
+/// returned.
+pub const TXN_RETRY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Runs `f` inside a transaction on a fresh session.
+///
+/// The transaction is committed when `f` returns `Ok` and aborted when it
+/// returns `Err`. Following the driver's error labels, the whole transaction
+/// is retried on `TransientTransactionError` and only the commit is retried on
+/// `UnknownTransactionCommitResult`, so `f` may be called more than once;
+/// neither once [`TXN_RETRY_TIMEOUT`] has passed since the first attempt.
+pub async fn run_in_txn<T, F>(client: &Client, f: F) -> Result<T>
+where
+    F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, Result<T>>,
+{
+    Ok(run_in_txn_with_stats(client, f).await?.0)
+}
+
+/// How much retrying [`run_in_txn_with_stats`] took.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TxnStats {
+    /// Times the transaction was started, including the first.
+    pub attempts: u32,
+    /// Commits repeated after `UnknownTransactionCommitResult`.
+    pub commit_retries: u32,
+}
+
+/// [`run_in_txn`], also reporting how often the transaction was retried.
+pub async fn run_in_txn_with_stats<T, F>(client: &Client, mut f: F) -> Result<(T, TxnStats)>
+where
+    F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, Result<T>>,
+{
+    let mut stats = TxnStats::default();
+    let mut session = client.start_session(None).await?;
+    let started = Instant::now();
+    let may_retry = |e: &mongodb::error::Error, label: &str| {
+        e.contains_label(label) && started.elapsed() < TXN_RETRY_TIMEOUT
+    };
+    'txn: loop {
+        stats.attempts += 1;
+        session.start_transaction(None).await?;
+        let value = match f(&mut session).await {
+            Ok(value) => value,
+            Err(e) => {
+                // The server may already have aborted; either way the
+                // original error is the one worth reporting.
+                let _ = session.abort_transaction().await;
+                if may_retry(&e, TRANSIENT_TRANSACTION_ERROR) {
+                    continue 'txn;
+                }
+                return Err(e);
+            }
+        };
+        loop {
+            match session.commit_transaction().await {
+                Ok(()) => return Ok((value, stats)),
+                Err(e) if may_retry(&e, UNKNOWN_TRANSACTION_COMMIT_RESULT) => stats.commit_retries += 1,
+                Err(e) if may_retry(&e, TRANSIENT_TRANSACTION_ERROR) => continue 'txn,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Inserts `post` and bumps the [`TAG_COUNTS`] of each of its tags in one
+/// transaction: if the insert fails, e.g. on a duplicate title, no count moves.
+pub async fn insert_counting_tags(client: &Client, ns: &Namespace, post: &Post) -> Result<()> {
+    let posts = ns.collection::<Post>("posts");
+    let tag_counts = ns.collection::<Document>(TAG_COUNTS);
+    run_in_txn(client, |session| {
+        let (posts, tag_counts, post) = (posts.clone(), tag_counts.clone(), post.clone());
+        async move {
+            posts.insert_one_with_session(&post, None, session).await?;
+            for tag in &post.tags {
+                let options = UpdateOptions::builder().upsert(true).build();
+                tag_counts.update_one_with_session(
+                    doc! { "_id": tag }, doc! { "$inc": { "count": 1 } }, options, session,
+                ).await?;
+            }
+            Ok(())
+        }.boxed()
+    }).await
+}
+
+/// Posts touched by [`merge_tags`] or [`split_tag`].
+#[derive(serde::Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct TagRewrite {
+    pub matched: u64,
+    pub modified: u64,
+}
+
+/// Replaces every one of `sources` with `target` on every post, a post with
+/// several of them ending up with `target` once, in one transaction with
+/// the [`TAG_COUNTS`] update: the sources' counts go, `target`'s is recounted.
+pub async fn merge_tags(client: &Client, ns: &Namespace, sources: &[&str], target: &str) -> Result<TagRewrite> {
+    rewrite_tags(client, ns, sources, &[target]).await
+}
+
+/// Replaces `source` with all of `targets` on every post carrying it, in
+/// one transaction with the [`TAG_COUNTS`] update.
+pub async fn split_tag(client: &Client, ns: &Namespace, source: &str, targets: &[&str]) -> Result<TagRewrite> {
+    rewrite_tags(client, ns, &[source], targets).await
+}
+
+async fn rewrite_tags(client: &Client, ns: &Namespace, sources: &[&str], targets: &[&str]) -> Result<TagRewrite> {
+    let posts = ns.collection::<Post>("posts");
+    let tag_counts = ns.collection::<Document>(TAG_COUNTS);
+    let filter = doc! { "tags": { "$in": sources } };
+    let update = replace_tags_update(sources, targets);
+    // Counted afresh: posts that had several of the tags would be counted twice otherwise
+    let retired: Vec<&str> = sources.iter().filter(|source| !targets.contains(source)).copied().collect();
+    let retired = doc! { "_id": { "$in": retired } };
+    let targets: Vec<String> = targets.iter().map(|target| target.to_string()).collect();
+    run_in_txn(client, |session| {
+        let (posts, tag_counts) = (posts.clone(), tag_counts.clone());
+        let (filter, update, retired, targets) = (filter.clone(), update.clone(), retired.clone(), targets.clone());
+        async move {
+            let result = posts.update_many_with_session(filter, update, None, session).await?;
+            tag_counts.delete_many_with_session(retired, None, session).await?;
+            for target in &targets {
+                let count = posts.count_documents_with_session(doc! { "tags": target }, None, session).await? as i32;
+                let options = UpdateOptions::builder().upsert(true).build();
+                tag_counts.update_one_with_session(
+                    doc! { "_id": target }, doc! { "$set": { "count": count } }, options, session,
+                ).await?;
+            }
+            Ok(TagRewrite { matched: result.matched_count, modified: result.modified_count })
+        }.boxed()
+    }).await
+}
+
+/// Fails the quota transaction so it aborts, told apart from other errors afterwards.
+struct QuotaReached;
+
+/// Inserts `post` as written by `author`, bumping their [`AUTHOR_COUNTS`] in
+/// the same transaction, unless they already have `max_posts`: then the
+/// insert fails with [`Error::QuotaExceeded`].
+///
+/// The check and the increment are one guarded upsert. It only matches the
+/// author's count while it is below the limit; at the limit it goes on to
+/// insert a second count with the same `_id`, which the `_id` index refuses.
+/// Two inserts racing for the last slot conflict, and the retried one finds
+/// the count at the limit. A post that fails to insert, e.g. on a duplicate
+/// title, uses up no quota.
+pub async fn insert_within_quota(
+    client: &Client,
+    ns: &Namespace,
+    author: &str,
+    post: &Post,
+    max_posts: u32,
+) -> crate::error::Result<PostId> {
+    let quota_exceeded = || Error::QuotaExceeded { author: author.to_string(), limit: max_posts };
+    if max_posts == 0 {
+        // The upsert would create the author's first count regardless
+        return Err(quota_exceeded());
+    }
+    let posts = ns.collection::<Post>("posts");
+    let author_counts = ns.collection::<Document>(AUTHOR_COUNTS);
+    let post = Post { author: Some(author.to_string()), ..post.clone() };
+    let guard = doc! { "_id": author, "count": { "$lt": max_posts as i64 } };
+    let result = run_in_txn(client, |session| {
+        let (posts, author_counts, post, guard) = (posts.clone(), author_counts.clone(), post.clone(), guard.clone());
+        async move {
+            let options = UpdateOptions::builder().upsert(true).build();
+            match author_counts.update_one_with_session(guard, doc! { "$inc": { "count": 1 } }, options, session).await {
+                Err(e) if is_duplicate_key(&e) => return Err(mongodb::error::Error::custom(QuotaReached)),
+                result => result?,
+            };
+            posts.insert_one_with_session(&post, None, session).await?;
+            Ok(post.id)
+        }.boxed()
+    }).await;
+    match result {
+        Ok(id) => Ok(id),
+        Err(e) if e.get_custom::<QuotaReached>().is_some() => Err(quota_exceeded()),
+        Err(e) if is_duplicate_key(&e) => Err(Error::DuplicateTitle(post.title)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Deletes the post with `id` and, if it has an author, gives them back the
+/// quota it used, in one transaction. [`Error::NotFound`] if there is no such post.
+pub async fn delete_within_quota(client: &Client, ns: &Namespace, id: PostId) -> crate::error::Result<()> {
+    let posts = ns.collection::<Post>("posts");
+    let author_counts = ns.collection::<Document>(AUTHOR_COUNTS);
+    let deleted = run_in_txn(client, |session| {
+        let (posts, author_counts) = (posts.clone(), author_counts.clone());
+        async move {
+            let deleted = posts.find_one_and_delete_with_session(doc! { "_id": id }, None, session).await?;
+            if let Some(author) = deleted.as_ref().and_then(|post| post.author.as_ref()) {
+                author_counts.update_one_with_session(
+                    doc! { "_id": author }, doc! { "$inc": { "count": -1 } }, None, session,
+                ).await?;
+            }
+            Ok(deleted.is_some())
+        }.boxed()
+    }).await?;
+    if deleted { Ok(()) } else { Err(Error::NotFound) }
+}
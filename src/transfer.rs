@@ -0,0 +1,534 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, RecordBatchOptions, StringArray,
+    TimestampMillisecondArray,
+};
+use arrow_schema::{Field, Schema};
+use chrono::SecondsFormat;
+use futures::{Stream, TryStreamExt};
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{FindOptions, ReplaceOptions, SessionOptions};
+use parquet::arrow::ArrowWriter;
+
+use crate::Post;
+use crate::batch_jobs::{BatchJob, BatchJobs, JobKind};
+use crate::capabilities::{Capabilities, Feature};
+use crate::codec::{
+    parquet_properties, BsonCodec, CsvCodec, JsonLinesCodec, MessagePackCodec, ParquetCodec, Record, RecordCodec,
+    Records,
+};
+use crate::decoding_cursor::DecodingCursor;
+use crate::ejson;
+use crate::ids::number;
+use crate::namespace::Namespace;
+use crate::pipeline_lint;
+use crate::repository::{ImportFailure, ImportMode, ImportReport, OnDuplicate, PostRepository};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// On-disk format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One post per line as relaxed Extended JSON, with every field.
+    JsonLines,
+    /// BSON documents back to back, with every field and its exact type.
+    Bson,
+    /// MessagePack maps back to back, with every field.
+    MessagePack,
+    /// One post per row with [`CSV_COLUMNS`](crate::codec::CSV_COLUMNS);
+    /// translations, attachments, authors, their emails and fields unknown
+    /// to this version are left out.
+    Csv,
+    /// The columns of [`Format::Csv`], typed, compressed with Snappy, for
+    /// DataFrame tooling. Export only: it can't be imported back.
+    Parquet,
+}
+
+impl Format {
+    /// `.bson`, `.msgpack`, `.csv` and `.parquet` files are in those
+    /// formats, anything else JSON Lines.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("bson") => Format::Bson,
+            Some("msgpack") => Format::MessagePack,
+            Some("csv") => Format::Csv,
+            Some("parquet") => Format::Parquet,
+            _ => Format::JsonLines,
+        }
+    }
+
+    /// How posts are written and read in this format.
+    pub fn codec(self) -> &'static dyn RecordCodec {
+        match self {
+            Format::JsonLines => &JsonLinesCodec,
+            Format::Bson => &BsonCodec,
+            Format::MessagePack => &MessagePackCodec,
+            Format::Csv => &CsvCodec,
+            Format::Parquet => &ParquetCodec,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "bson" => Ok(Format::Bson),
+            "msgpack" => Ok(Format::MessagePack),
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown format {:?}, try jsonl, bson, msgpack, csv or parquet", s)),
+        }
+    }
+}
+
+/// Streams every post to `path` in `_id` order through the codec of
+/// `format`, and returns how many there were.
+///
+/// Codecs of [`Records::Decoded`] posts, CSV and Parquet, get them from a
+/// [`DecodingCursor`]: up to `budget` posts that don't decode are logged and
+/// left out, and one more fails the export. The others write posts as
+/// stored, so every post is exported whatever its shape.
+pub async fn export(ns: &Namespace, path: &Path, format: Format, budget: usize) -> Result<u64> {
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let posts = ns.collection::<Document>("posts").find(None, options).await?;
+    write_posts(posts, path, format, budget).await
+}
+
+/// [`export`] as of a single point in time. A plain export reads batch by
+/// batch as it goes, so a post changed while it runs is exported as it was
+/// or as it became depending on whether its batch was read yet, and posts
+/// added meanwhile may or may not be in. Here every batch is read from the
+/// same snapshot, in a session with snapshot reads, so the file holds the
+/// collection exactly as it was when the export started.
+///
+/// Snapshot reads need MongoDB 5.0 and a replica set or sharded cluster;
+/// other servers are refused before anything is read, with the
+/// [`Unsupported`](crate::capabilities::Unsupported) reason. Servers only
+/// keep a snapshot for `minSnapshotHistoryWindowInSeconds`, five minutes by
+/// default, so an export running longer fails rather than mixing points in
+/// time.
+pub async fn export_snapshot(ns: &Namespace, path: &Path, format: Format, budget: usize) -> Result<u64> {
+    let posts = ns.collection::<Document>("posts");
+    let client = posts.client();
+    Capabilities::probe(client).await?.check(Feature::SnapshotReads)?;
+    let mut session = client.start_session(SessionOptions::builder().snapshot(true).build()).await?;
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let mut cursor = posts.find_with_session(None, options, &mut session).await?;
+    match write_posts(cursor.stream(&mut session), path, format, budget).await {
+        Err(e) if snapshot_too_old(&*e) => Err(format!(
+            "the export outlived the server's snapshot history (minSnapshotHistoryWindowInSeconds): {}", e,
+        ).into()),
+        result => result,
+    }
+}
+
+/// SnapshotTooOld: the snapshot a read asked for is no longer kept. The
+/// driver's error comes boxed as is, or through [`DecodingCursor`] as ours.
+fn snapshot_too_old(e: &(dyn std::error::Error + 'static)) -> bool {
+    let e = match e.downcast_ref::<crate::error::Error>() {
+        Some(crate::error::Error::Mongo(e)) => Some(e),
+        _ => e.downcast_ref::<mongodb::error::Error>(),
+    };
+    e.is_some_and(|e| matches!(e.kind.as_ref(), ErrorKind::Command(c) if c.code == 239))
+}
+
+/// Writes `posts`, read in `_id` order, to `path` as [`export`] describes.
+async fn write_posts<S>(mut posts: S, path: &Path, format: Format, budget: usize) -> Result<u64>
+where
+    S: Stream<Item = mongodb::error::Result<Document>> + Unpin,
+{
+    let codec = format.codec();
+    let mut out = codec.encoder(Box::new(BufWriter::new(File::create(path)?)))?;
+    let mut exported = 0;
+    match codec.records() {
+        Records::Stored => {
+            while let Some(post) = posts.try_next().await? {
+                out.encode(Record::Stored(&post))?;
+                exported += 1;
+            }
+        }
+        Records::Decoded => {
+            let mut posts = DecodingCursor::<Post, _>::new(posts, budget);
+            while let Some(post) = posts.next().await? {
+                out.encode(Record::Decoded(&post))?;
+                exported += 1;
+            }
+        }
+    }
+    out.finish()?;
+    Ok(exported)
+}
+
+/// The posts in `path`, in any format but Parquet, read lazily in file
+/// order. A line or document that isn't a valid post comes out as an error
+/// naming it.
+pub fn read_posts(path: &Path, format: Format) -> Result<Box<dyn Iterator<Item = Result<Post>>>> {
+    format.codec().decoder(Box::new(BufReader::new(File::open(path)?)))
+}
+
+/// How an import treats posts whose `_id` is already stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflicts {
+    /// Insert only; posts already there, by `_id` or title, are skipped.
+    Insert,
+    /// Replace stored posts with the imported ones, insert the others.
+    Upsert,
+}
+
+/// How many posts [`read_posts`] would read from `path`, without parsing
+/// them.
+pub fn count_posts(path: &Path, format: Format) -> Result<u64> {
+    format.codec().count(Box::new(BufReader::new(File::open(path)?)))
+}
+
+/// Imports the posts in `path` in batches of `batch_size`, reading the next
+/// batch only once the last is written. With [`Conflicts::Insert`] each
+/// batch is one unordered `insert_many` via [`PostRepository::import`], so a
+/// bad post doesn't stop the others; with [`Conflicts::Upsert`] it is a
+/// replace per post keyed on `_id`. Failures are indexed by their position
+/// among the file's posts.
+///
+/// Progress is saved as the [`BatchJob`] `job` after every batch. A run
+/// finding it unfinished skips the posts it counts as done, and one finding
+/// it finished imports nothing; [`BatchJobs::remove`] it to import again.
+/// The report covers this run only, the job's counts every run.
+pub async fn import(
+    ns: &Namespace,
+    repo: &PostRepository,
+    path: &Path,
+    format: Format,
+    batch_size: usize,
+    conflicts: Conflicts,
+    job: &str,
+) -> Result<ImportReport> {
+    let batch_size = batch_size.max(1);
+    let jobs = BatchJobs::new(ns);
+    let mut job = match jobs.get(job).await? {
+        Some(job) => job,
+        None => BatchJob::new(job, JobKind::Import, Some(count_posts(path, format)?)),
+    };
+    let mut report = ImportReport::default();
+    if job.finished() {
+        return Ok(report);
+    }
+    let mut posts = read_posts(path, format)?;
+    for skipped in posts.by_ref().take(job.done as usize) {
+        skipped?;
+    }
+    let mut offset = job.done as usize;
+    loop {
+        let batch = posts.by_ref().take(batch_size).collect::<Result<Vec<Post>>>()?;
+        if batch.is_empty() {
+            job.finished_at = Some(DateTime::now());
+            jobs.save(&mut job).await?;
+            break;
+        }
+        let batch_len = batch.len();
+        let batch_report = match conflicts {
+            Conflicts::Insert => repo.import(batch, ImportMode::Unordered, OnDuplicate::Skip).await?,
+            Conflicts::Upsert => upsert(ns, &batch).await?,
+        };
+        report.inserted += batch_report.inserted;
+        report.updated += batch_report.updated;
+        report.skipped_duplicates += batch_report.skipped_duplicates;
+        report.rejected_validation += batch_report.rejected_validation;
+        report.not_attempted += batch_report.not_attempted;
+        for (name, count) in [
+            ("inserted", batch_report.inserted),
+            ("updated", batch_report.updated),
+            ("skipped_duplicates", batch_report.skipped_duplicates),
+            ("failed", batch_report.failed.len()),
+        ] {
+            job.counts.insert(name, (job.count(name) + count as u64) as i64);
+        }
+        report.failed.extend(batch_report.failed.into_iter()
+            .map(|failure| ImportFailure { index: failure.index + offset, ..failure }));
+        offset += batch_len;
+        job.done = offset as u64;
+        jobs.save(&mut job).await?;
+    }
+    Ok(report)
+}
+
+/// The 2.x driver has no bulk write of mixed operations, so the replaces of
+/// a batch are sent concurrently instead.
+async fn upsert(ns: &Namespace, posts: &[Post]) -> Result<ImportReport> {
+    let col = ns.collection::<Post>("posts");
+    let replaces = posts.iter().map(|post| {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        col.replace_one(doc! { "_id": post.id }, post, options)
+    });
+    let mut report = ImportReport::default();
+    for (index, result) in futures::future::join_all(replaces).await.into_iter().enumerate() {
+        match result {
+            Ok(result) if result.upserted_id.is_some() => report.inserted += 1,
+            Ok(_) => report.updated += 1,
+            Err(e) => {
+                let (code, message) = match e.kind.as_ref() {
+                    mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(error)) => {
+                        (error.code, error.message.clone())
+                    }
+                    _ => return Err(e.into()),
+                };
+                if code == 121 {
+                    report.rejected_validation += 1;
+                }
+                report.failed.push(ImportFailure { index, title: posts[index].title.clone(), code, message });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A pipeline from `path`: a JSON array of stages in Extended JSON, so
+/// stages can hold dates and ids, e.g. `{ "$date": "2024-01-01T00:00:00Z" }`.
+/// Mistakes [`pipeline_lint::lint`] can find are reported before anything runs.
+pub fn read_pipeline(path: &Path) -> Result<Vec<Document>> {
+    let pipeline = parse_pipeline(&std::fs::read_to_string(path)?)?;
+    pipeline_lint::lint(&pipeline)?;
+    Ok(pipeline)
+}
+
+fn parse_pipeline(json: &str) -> Result<Vec<Document>> {
+    match ejson::from_str(json)? {
+        Bson::Array(stages) => stages.into_iter()
+            .enumerate()
+            .map(|(index, stage)| match stage {
+                Bson::Document(stage) => Ok(stage),
+                _ => Err(format!("stage {} is not an object", index).into()),
+            })
+            .collect(),
+        _ => Err("a pipeline is a JSON array of stages".into()),
+    }
+}
+
+/// Runs `pipeline` on `collection` and writes its results to `out`,
+/// returning how many there were.
+///
+/// Formats of documents as stored are written by their codec as the
+/// results come. CSV and Parquet, whose codecs have the columns of a post,
+/// are written here instead, and need the columns up front, so the results
+/// are collected first: the columns are every field any result has, in the
+/// order they first appear, as flattened by [`flatten`]; a result without
+/// one of them leaves its cell empty, or null in Parquet. A Parquet column is typed after its values: integers,
+/// numbers, booleans or dates when they all are, strings written like the
+/// CSV cells otherwise.
+pub async fn export_aggregation(
+    ns: &Namespace,
+    collection: &str,
+    pipeline: Vec<Document>,
+    format: Format,
+    mut out: impl Write,
+) -> Result<u64> {
+    let mut results = ns.collection::<Document>(collection).aggregate(pipeline, None).await?;
+    let mut exported = 0;
+    match format {
+        Format::Csv => {
+            let rows: Vec<Vec<(String, String)>> = results.map_ok(|result| flatten(&result)).try_collect().await?;
+            let columns = columns_of(&rows);
+            let mut out = csv::Writer::from_writer(out);
+            out.write_record(&columns)?;
+            for row in &rows {
+                out.write_record(columns.iter().map(|column| {
+                    row.iter().find(|(field, _)| field == column).map_or("", |(_, value)| value.as_str())
+                }))?;
+                exported += 1;
+            }
+            out.flush()?;
+        }
+        Format::Parquet => {
+            let results: Vec<Document> = results.try_collect().await?;
+            let rows: Vec<Vec<(String, &Bson)>> = results.iter().map(|result| leaves(result)).collect();
+            let batch = parquet_batch(&rows)?;
+            // The writer has to be `Send`, which a locked stdout isn't
+            let mut parquet = Vec::new();
+            let mut writer = ArrowWriter::try_new(&mut parquet, batch.schema(), Some(parquet_properties()))?;
+            writer.write(&batch)?;
+            writer.close()?;
+            out.write_all(&parquet)?;
+            out.flush()?;
+            exported = rows.len() as u64;
+        }
+        format => {
+            let mut out = format.codec().encoder(Box::new(out))?;
+            while let Some(result) = results.try_next().await? {
+                out.encode(Record::Stored(&result))?;
+                exported += 1;
+            }
+            out.finish()?;
+        }
+    }
+    Ok(exported)
+}
+
+/// Every field of `rows`, in the order they first appear.
+fn columns_of<T>(rows: &[Vec<(String, T)>]) -> Vec<&str> {
+    let mut columns: Vec<&str> = Vec::new();
+    for (column, _) in rows.iter().flatten() {
+        if !columns.contains(&column.as_str()) {
+            columns.push(column);
+        }
+    }
+    columns
+}
+
+/// `rows` as one batch, a column per field, typed after its values.
+#[allow(clippy::result_large_err)] // boxed like the rest of this module
+fn parquet_batch(rows: &[Vec<(String, &Bson)>]) -> Result<RecordBatch> {
+    let mut fields = Vec::new();
+    let mut arrays: Vec<ArrayRef> = Vec::new();
+    for column in columns_of(rows) {
+        let values: Vec<Option<&Bson>> = rows.iter()
+            .map(|row| row.iter().find(|(field, _)| field == column).map(|(_, value)| *value))
+            .map(|value| value.filter(|value| !matches!(value, Bson::Null)))
+            .collect();
+        let all = |is: fn(&Bson) -> bool| values.iter().flatten().all(|value| is(value));
+        let array: ArrayRef = if all(|value| matches!(value, Bson::Int32(_) | Bson::Int64(_))) {
+            Arc::new(values.iter().map(|value| value.map(|value| number(Some(value)))).collect::<Int64Array>())
+        } else if all(|value| matches!(value, Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_))) {
+            let float = |value: &Bson| value.as_f64().unwrap_or_else(|| number(Some(value)) as f64);
+            Arc::new(values.iter().map(|value| value.map(float)).collect::<Float64Array>())
+        } else if all(|value| matches!(value, Bson::Boolean(_))) {
+            Arc::new(values.iter().map(|value| value.and_then(Bson::as_bool)).collect::<BooleanArray>())
+        } else if all(|value| matches!(value, Bson::DateTime(_))) {
+            let millis: TimestampMillisecondArray = values.iter()
+                .map(|value| value.and_then(Bson::as_datetime).map(|date| date.timestamp_millis()))
+                .collect();
+            Arc::new(millis.with_timezone("UTC"))
+        } else {
+            Arc::new(values.iter().map(|value| value.map(cell)).collect::<StringArray>())
+        };
+        fields.push(Field::new(column, array.data_type().clone(), true));
+        arrays.push(array);
+    }
+    // Without columns, only the options can tell how many rows there are
+    let options = RecordBatchOptions::new().with_row_count(Some(rows.len()));
+    Ok(RecordBatch::try_new_with_options(Arc::new(Schema::new(fields)), arrays, &options)?)
+}
+
+/// `doc` as CSV cells, one per leaf field:
+///
+/// - embedded documents are flattened into dotted paths, `{ a: { b: 1 } }`
+///   giving the column `a.b`
+/// - arrays of plain values are joined with `;`, like the tags of an export;
+///   arrays holding documents or arrays are written as relaxed Extended JSON
+/// - ids are hex, dates RFC 3339, `null` an empty cell, and any other
+///   non-string value relaxed Extended JSON
+pub fn flatten(doc: &Document) -> Vec<(String, String)> {
+    leaves(doc).into_iter().map(|(path, value)| (path, cell(value))).collect()
+}
+
+/// The leaf fields of `doc` by dotted path, arrays included as a whole.
+fn leaves(doc: &Document) -> Vec<(String, &Bson)> {
+    let mut leaves = Vec::new();
+    leaves_into("", doc, &mut leaves);
+    leaves
+}
+
+fn leaves_into<'a>(prefix: &str, doc: &'a Document, leaves: &mut Vec<(String, &'a Bson)>) {
+    for (key, value) in doc {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            Bson::Document(doc) => leaves_into(&path, doc, leaves),
+            value => leaves.push((path, value)),
+        }
+    }
+}
+
+pub(crate) fn cell(value: &Bson) -> String {
+    match value {
+        Bson::String(value) => value.clone(),
+        Bson::ObjectId(id) => id.to_hex(),
+        Bson::DateTime(date) => date.to_chrono().to_rfc3339_opts(SecondsFormat::Millis, true),
+        Bson::Null => String::new(),
+        Bson::Int32(n) => n.to_string(),
+        Bson::Int64(n) => n.to_string(),
+        Bson::Double(n) => n.to_string(),
+        Bson::Boolean(b) => b.to_string(),
+        Bson::Array(items) if !items.iter().any(|item| matches!(item, Bson::Document(_) | Bson::Array(_))) => {
+            items.iter().map(cell).collect::<Vec<_>>().join(";")
+        }
+        value => value.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::DataType;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+    use crate::codec::timestamp;
+
+    #[test]
+    fn nested_results_flatten_into_dotted_columns() {
+        let id = ObjectId::new();
+        let result = doc! {
+            "_id": { "tag": "rust", "year": 2024 },
+            "count": 3_i64,
+            "post_ids": [id],
+            "top": [{ "title": "Hello" }],
+            "lang": Bson::Null,
+        };
+        assert_eq!(flatten(&result), [
+            ("_id.tag".to_string(), "rust".to_string()),
+            ("_id.year".to_string(), "2024".to_string()),
+            ("count".to_string(), "3".to_string()),
+            ("post_ids".to_string(), id.to_hex()),
+            ("top".to_string(), r#"[{"title":"Hello"}]"#.to_string()),
+            ("lang".to_string(), String::new()),
+        ]);
+    }
+
+    #[test]
+    fn pipelines_are_arrays_of_stages() {
+        let json = r#"[{ "$match": { "created_at": { "$gte": { "$date": "2024-01-01T00:00:00Z" } } } }]"#;
+        let pipeline = parse_pipeline(json).unwrap();
+        let since = pipeline[0].get_document("$match").unwrap().get_document("created_at").unwrap();
+        assert!(matches!(since.get("$gte"), Some(Bson::DateTime(_))));
+        assert!(parse_pipeline(r#"{ "$match": {} }"#).is_err());
+        assert!(parse_pipeline(r#"[1]"#).is_err());
+    }
+
+    #[test]
+    fn format_follows_the_extension() {
+        assert_eq!(Format::from_path(Path::new("posts.csv")), Format::Csv);
+        assert_eq!(Format::from_path(Path::new("posts.jsonl")), Format::JsonLines);
+        assert_eq!(Format::from_path(Path::new("posts.parquet")), Format::Parquet);
+        assert_eq!(Format::from_path(Path::new("posts.bson")), Format::Bson);
+        assert_eq!(Format::from_path(Path::new("posts.msgpack")), Format::MessagePack);
+    }
+
+    #[test]
+    fn parquet_columns_are_typed_after_their_values() {
+        let at = DateTime::from_millis(1_700_000_000_000);
+        let results = [
+            doc! { "_id": { "tag": "rust" }, "count": 3_i64, "share": 0.5, "at": at },
+            doc! { "_id": { "tag": "go" }, "count": 1, "share": 1, "at": Bson::Null, "top": true },
+        ];
+        let rows: Vec<Vec<(String, &Bson)>> = results.iter().map(leaves).collect();
+        let batch = parquet_batch(&rows).unwrap();
+        let schema = batch.schema();
+        let types: Vec<(&str, &DataType)> = schema.fields().iter()
+            .map(|field| (field.name().as_str(), field.data_type()))
+            .collect();
+        assert_eq!(types, [
+            ("_id.tag", &DataType::Utf8),
+            ("count", &DataType::Int64),
+            ("share", &DataType::Float64),
+            ("at", &timestamp()),
+            ("top", &DataType::Boolean),
+        ]);
+        assert_eq!((batch.num_rows(), batch.column(3).null_count(), batch.column(4).null_count()), (2, 1, 1));
+        assert_eq!(parquet_batch(&[]).unwrap().num_rows(), 0);
+    }
+}
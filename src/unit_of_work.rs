@@ -0,0 +1,165 @@
+use futures::FutureExt;
+use mongodb::Client;
+use mongodb::bson::{self, doc, Document};
+use mongodb::error::Result;
+use mongodb::options::UpdateOptions;
+use serde::Serialize;
+
+use crate::namespace::Namespace;
+use crate::repository::COMMENTS;
+use crate::transactions::{run_in_txn, TAG_COUNTS};
+use crate::{Comment, Post, PostId};
+
+/// A single pending write against a named collection.
+#[derive(Debug, Clone)]
+enum Mutation {
+    Insert(Document),
+    /// `many` for every matching document, else only the first.
+    Update { filter: Document, update: Document, upsert: bool, many: bool },
+    Delete { filter: Document, many: bool },
+}
+
+/// Collects writes across collections and applies them atomically on commit.
+///
+/// Nothing touches the database until [`UnitOfWork::commit`], which replays
+/// the mutations in registration order inside a single transaction. The
+/// 2.x driver has no cross-collection `bulk_write`, so a transaction is the
+/// only way to get all-or-nothing semantics here.
+///
+/// Posts, comments and tag counts have typed methods; anything else goes
+/// through the untyped ones, which name the collection.
+pub struct UnitOfWork {
+    client: Client,
+    ns: Namespace,
+    pending: Vec<(String, Mutation)>,
+}
+
+impl UnitOfWork {
+    pub fn new(client: &Client, ns: &Namespace) -> Self {
+        UnitOfWork { client: client.clone(), ns: ns.clone(), pending: Vec::new() }
+    }
+
+    pub fn insert<T: Serialize>(&mut self, collection: &str, value: &T) -> Result<()> {
+        let doc = bson::to_document(value)?;
+        self.pending.push((collection.to_string(), Mutation::Insert(doc)));
+        Ok(())
+    }
+
+    pub fn update_one(&mut self, collection: &str, filter: Document, update: Document, upsert: bool) {
+        self.pending.push((collection.to_string(), Mutation::Update { filter, update, upsert, many: false }));
+    }
+
+    pub fn update_many(&mut self, collection: &str, filter: Document, update: Document, upsert: bool) {
+        self.pending.push((collection.to_string(), Mutation::Update { filter, update, upsert, many: true }));
+    }
+
+    pub fn delete_one(&mut self, collection: &str, filter: Document) {
+        self.pending.push((collection.to_string(), Mutation::Delete { filter, many: false }));
+    }
+
+    pub fn delete_many(&mut self, collection: &str, filter: Document) {
+        self.pending.push((collection.to_string(), Mutation::Delete { filter, many: true }));
+    }
+
+    pub fn insert_post(&mut self, post: &Post) -> Result<()> {
+        self.insert("posts", post)
+    }
+
+    pub fn delete_post(&mut self, id: PostId) {
+        self.delete_one("posts", doc! { "_id": id });
+    }
+
+    pub fn insert_comment(&mut self, comment: &Comment) -> Result<()> {
+        self.insert(COMMENTS, comment)
+    }
+
+    /// Adds `by`, which may be negative, to `tag`'s entry in `tag_counts`,
+    /// creating it if need be.
+    pub fn count_tag(&mut self, tag: &str, by: i64) {
+        self.update_one(TAG_COUNTS, doc! { "_id": tag }, doc! { "$inc": { "count": by } }, true);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Flushes every pending mutation in one transaction.
+    pub async fn commit(self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        run_in_txn(&self.client, |session| {
+            let ns = self.ns.clone();
+            let pending = self.pending.clone();
+            async move {
+                for (name, mutation) in pending {
+                    let col = ns.collection::<Document>(&name);
+                    match mutation {
+                        Mutation::Insert(doc) => {
+                            col.insert_one_with_session(doc, None, session).await?;
+                        }
+                        Mutation::Update { filter, update, upsert, many: true } => {
+                            let options = UpdateOptions::builder().upsert(upsert).build();
+                            col.update_many_with_session(filter, update, options, session).await?;
+                        }
+                        Mutation::Update { filter, update, upsert, many: false } => {
+                            let options = UpdateOptions::builder().upsert(upsert).build();
+                            col.update_one_with_session(filter, update, options, session).await?;
+                        }
+                        Mutation::Delete { filter, many: true } => {
+                            col.delete_many_with_session(filter, None, session).await?;
+                        }
+                        Mutation::Delete { filter, many: false } => {
+                            col.delete_one_with_session(filter, None, session).await?;
+                        }
+                    }
+                }
+                Ok(())
+            }.boxed()
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Client;
+    use mongodb::bson::{doc, Document};
+
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::sandbox::Sandbox;
+
+    /// Needs a server at `MONGODB_URI` (localhost:27017 by default); run with `cargo test -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn a_failing_mutation_rolls_back_the_ones_before_it() {
+        let client = Client::with_uri_str(AppConfig::load(None).unwrap().uri).await.unwrap();
+        let sandbox = Sandbox::new(&client);
+        let ns = Namespace::new(sandbox.database(), "");
+        let post = Post::new("Kept", "Untouched by the failed commit", &["uow"]);
+        ns.collection::<Post>("posts").insert_one(&post, None).await.unwrap();
+        // Collections can't be created inside a transaction on older servers
+        ns.collection::<Document>(COMMENTS).insert_one(doc! {}, None).await.unwrap();
+        ns.collection::<Document>(TAG_COUNTS).insert_one(doc! { "_id": "uow", "count": 1 }, None).await.unwrap();
+
+        let mut uow = UnitOfWork::new(&client, &ns);
+        uow.delete_post(post.id);
+        uow.count_tag("uow", -1);
+        // The same `_id` again fails on the duplicate key
+        uow.insert_post(&Post::new("First", "", &[])).unwrap();
+        uow.insert("posts", &doc! { "_id": post.id }).unwrap();
+        assert_eq!(uow.len(), 4);
+        assert!(uow.commit().await.is_err());
+
+        let stored = ns.collection::<Post>("posts").find_one(doc! { "_id": post.id }, None).await.unwrap();
+        assert_eq!(stored.map(|stored| stored.title), Some("Kept".to_string()));
+        assert_eq!(ns.collection::<Document>("posts").count_documents(None, None).await.unwrap(), 1);
+        let count = ns.collection::<Document>(TAG_COUNTS).find_one(doc! { "_id": "uow" }, None).await.unwrap();
+        assert_eq!(count.unwrap().get_i32("count"), Ok(1));
+        sandbox.cleanup().await;
+    }
+}
@@ -0,0 +1,159 @@
+use chrono::{DateTime, DurationRound, Utc};
+use futures::TryStreamExt;
+use mongodb::{Collection, IndexModel};
+use mongodb::bson::{self, doc};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::UpdateOptions;
+
+use crate::PostId;
+use crate::error::Result;
+use crate::namespace::Namespace;
+use crate::serde_helpers;
+
+/// Views of posts, [`ViewBucket`]s of up to [`BUCKET_CAPACITY`] events each.
+pub const POST_VIEWS: &str = "post_views";
+
+/// Events per bucket. A post viewed more often than this in an hour gets
+/// more than one bucket for it, so no bucket grows without bound.
+pub const BUCKET_CAPACITY: i64 = 200;
+
+/// One view of a post.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewEvent {
+    #[serde(with = "serde_helpers::chrono_datetime")]
+    pub at: DateTime<Utc>,
+    /// Who viewed it, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub viewer: Option<String>,
+}
+
+/// The views of one post in one UTC hour, or some of them when there were
+/// more than [`BUCKET_CAPACITY`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ViewBucket {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub post_id: PostId,
+    /// The start of the hour.
+    #[serde(with = "serde_helpers::chrono_datetime")]
+    pub hour: DateTime<Utc>,
+    /// `events.len()`, kept alongside so counting doesn't unroll the events
+    /// and filling a bucket can be matched on.
+    #[serde(with = "serde_helpers::int")]
+    pub count: i64,
+    pub events: Vec<ViewEvent>,
+}
+
+/// A view as reported by [`ViewLog::events`]: one event out of its bucket.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct View {
+    pub post_id: PostId,
+    #[serde(with = "serde_helpers::chrono_datetime")]
+    pub at: DateTime<Utc>,
+    #[serde(default)]
+    pub viewer: Option<String>,
+}
+
+/// Views of a post over some time, from [`ViewLog::most_viewed`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewCount {
+    #[serde(rename = "_id")]
+    pub post_id: PostId,
+    #[serde(with = "serde_helpers::int")]
+    pub views: i64,
+}
+
+/// Post views in the bucket pattern: rather than a document per view, a
+/// document per post per hour holding that hour's views. Far fewer, larger
+/// documents means a far smaller `_id` and `post_id` index, and reading a
+/// post's views for a day reads some 24 documents rather than every view.
+///
+/// Recording a view is one upsert into the post's current bucket; counts
+/// come from the buckets' `count`s where whole hours are asked for, and
+/// reports needing single views unroll the buckets with `$unwind`.
+#[derive(Clone)]
+pub struct ViewLog {
+    buckets: Collection<ViewBucket>,
+}
+
+/// The start of the UTC hour `at` is in.
+fn hour_of(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(chrono::Duration::hours(1)).expect("an hour divides any instant")
+}
+
+impl ViewLog {
+    pub async fn new(ns: &Namespace) -> Result<Self> {
+        let buckets = ns.collection::<ViewBucket>(POST_VIEWS);
+        // Also what `record` finds the current bucket by, and what serves
+        // the hour range of every report on one post
+        let index = IndexModel::builder().keys(doc! { "post_id": 1, "hour": 1 }).build();
+        buckets.create_index(index, None).await?;
+        Ok(ViewLog { buckets })
+    }
+
+    /// Adds `event` to the bucket of its post and hour, starting a new one
+    /// when there is none or the last one is full.
+    pub async fn record(&self, post_id: PostId, event: &ViewEvent) -> Result<()> {
+        let filter = doc! {
+            "post_id": post_id,
+            "hour": bson::DateTime::from_chrono(hour_of(event.at)),
+            "count": { "$lt": BUCKET_CAPACITY },
+        };
+        let update = doc! {
+            "$push": { "events": bson::to_bson(event)? },
+            "$inc": { "count": 1 },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.buckets.update_one(filter, update, options).await?;
+        Ok(())
+    }
+
+    /// The views of `post_id` in `[from, to)`, oldest first, unrolled out
+    /// of their buckets. The buckets are picked by hour, then the events of
+    /// the first and last hours narrowed to the range.
+    pub async fn events(&self, post_id: PostId, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<View>> {
+        let first_hour = bson::DateTime::from_chrono(hour_of(from));
+        let (from, to) = (bson::DateTime::from_chrono(from), bson::DateTime::from_chrono(to));
+        let pipeline = vec![
+            doc! { "$match": { "post_id": post_id, "hour": { "$gte": first_hour, "$lt": to } } },
+            doc! { "$unwind": "$events" },
+            doc! { "$match": { "events.at": { "$gte": from, "$lt": to } } },
+            doc! { "$replaceWith": { "$mergeObjects": [{ "post_id": "$post_id" }, "$events"] } },
+            doc! { "$sort": { "at": 1 } },
+        ];
+        let views = self.buckets.aggregate(pipeline, None).await?.with_type::<View>().try_collect().await?;
+        Ok(views)
+    }
+
+    /// The `limit` posts viewed most in the hours from `from` to `to`,
+    /// most viewed first. Summed from the buckets' counts without unrolling
+    /// them, so both ends are rounded down to the hour.
+    pub async fn most_viewed(&self, from: DateTime<Utc>, to: DateTime<Utc>, limit: i64) -> Result<Vec<ViewCount>> {
+        let hours = doc! {
+            "$gte": bson::DateTime::from_chrono(hour_of(from)),
+            "$lt": bson::DateTime::from_chrono(hour_of(to)),
+        };
+        let pipeline = vec![
+            doc! { "$match": { "hour": hours } },
+            doc! { "$group": { "_id": "$post_id", "views": { "$sum": "$count" } } },
+            doc! { "$sort": { "views": -1, "_id": 1 } },
+            doc! { "$limit": limit },
+        ];
+        let counts = self.buckets.aggregate(pipeline, None).await?.with_type::<ViewCount>().try_collect().await?;
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn buckets_start_on_the_utc_hour() {
+        let at = Utc.with_ymd_and_hms(2024, 5, 1, 10, 59, 59).unwrap() + chrono::Duration::milliseconds(999);
+        assert_eq!(hour_of(at), Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap());
+        assert_eq!(hour_of(hour_of(at)), hour_of(at));
+    }
+}
@@ -0,0 +1,142 @@
+use futures::TryStreamExt;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use mongodb::Collection;
+use mongodb::bson::{doc, Document};
+use mongodb::change_stream::event::{OperationType, ResumeToken};
+use mongodb::error::Result;
+use mongodb::options::{ChangeStreamOptions, FullDocumentType, ReplaceOptions, UpdateOptions};
+
+use crate::{Post, PostId};
+use crate::namespace::Namespace;
+
+/// Where each [`Watcher`] keeps the token of the last change it handled,
+/// `{ _id: <watcher name>, token }`.
+pub const RESUME_TOKENS: &str = "resume_tokens";
+/// `{ _id: <tag>, post_ids }`, kept up to date by [`PostsByTag`].
+pub const POSTS_BY_TAG: &str = "posts_by_tag";
+
+/// A change to `posts`, as handed to a [`ChangeHandler`].
+#[derive(Debug, Clone)]
+pub enum PostChange {
+    /// Inserted, updated or replaced; `post` is the document as it is now.
+    Upserted(Box<Post>),
+    Deleted(PostId),
+}
+
+/// Reacts to changes in `posts`. Changes are delivered at least once: one
+/// that was handled just before a crash is delivered again after the
+/// restart, so handlers must be idempotent.
+pub trait ChangeHandler: Sync {
+    fn handle<'a>(&'a self, change: &'a PostChange) -> BoxFuture<'a, Result<()>>;
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredToken {
+    #[serde(rename = "_id")]
+    name: String,
+    token: ResumeToken,
+}
+
+/// Follows the `posts` change stream and hands every change to a handler,
+/// saving the resume token after each one so a restarted watcher of the same
+/// name picks up where the last one stopped.
+pub struct Watcher {
+    name: String,
+    posts: Collection<Post>,
+    tokens: Collection<StoredToken>,
+}
+
+impl Watcher {
+    pub fn new(ns: &Namespace, name: &str) -> Self {
+        Watcher {
+            name: name.to_string(),
+            posts: ns.collection("posts"),
+            tokens: ns.collection(RESUME_TOKENS),
+        }
+    }
+
+    /// Only returns on error, e.g. when the server doesn't support change
+    /// streams or the saved token has fallen off the oplog, or once `posts`
+    /// is dropped or renamed; the saved token is forgotten in that case, as
+    /// a stream can't resume past it.
+    pub async fn run(&self, handler: &impl ChangeHandler) -> Result<()> {
+        let resume_after = self.tokens.find_one(doc! { "_id": &self.name }, None).await?
+            .map(|stored| stored.token);
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(resume_after)
+            .build();
+        let mut stream = self.posts.watch(None, options).await?;
+        while let Some(event) = stream.try_next().await? {
+            let change = match event.operation_type {
+                OperationType::Insert | OperationType::Update | OperationType::Replace => {
+                    // An update looked up after the post was deleted has no document;
+                    // the delete that follows takes care of it
+                    event.full_document.map(|post| PostChange::Upserted(Box::new(post)))
+                }
+                OperationType::Delete => event.document_key.as_ref()
+                    .and_then(|key| key.get_object_id("_id").ok())
+                    .map(PostChange::Deleted),
+                OperationType::Invalidate => {
+                    self.tokens.delete_one(doc! { "_id": &self.name }, None).await?;
+                    return Ok(());
+                }
+                _ => None,
+            };
+            if let Some(change) = change {
+                handler.handle(&change).await?;
+            }
+            let stored = StoredToken { name: self.name.clone(), token: event.id };
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.tokens.replace_one(doc! { "_id": &self.name }, stored, options).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps `posts_by_tag` in step with `posts`: the live counterpart of
+/// [`PostRepository::group_by_tag`](crate::repository::PostRepository::group_by_tag).
+/// Only posts changed since the watcher first ran are in it.
+pub struct PostsByTag {
+    col: Collection<Document>,
+}
+
+impl PostsByTag {
+    pub fn new(ns: &Namespace) -> Self {
+        PostsByTag { col: ns.collection(POSTS_BY_TAG) }
+    }
+
+    /// Takes `id` out of every tag but `keep`, and drops tags left empty.
+    async fn remove(&self, id: PostId, keep: &[String]) -> Result<()> {
+        self.col.update_many(
+            doc! { "post_ids": id, "_id": { "$nin": keep } },
+            doc! { "$pull": { "post_ids": id } },
+            None,
+        ).await?;
+        self.col.delete_many(doc! { "post_ids": { "$size": 0 } }, None).await?;
+        Ok(())
+    }
+}
+
+impl ChangeHandler for PostsByTag {
+    fn handle<'a>(&'a self, change: &'a PostChange) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match change {
+                PostChange::Upserted(post) => {
+                    self.remove(post.id, &post.tags).await?;
+                    for tag in &post.tags {
+                        let options = UpdateOptions::builder().upsert(true).build();
+                        self.col.update_one(
+                            doc! { "_id": tag },
+                            doc! { "$addToSet": { "post_ids": post.id } },
+                            options,
+                        ).await?;
+                    }
+                }
+                PostChange::Deleted(id) => self.remove(*id, &[]).await?,
+            }
+            Ok(())
+        }.boxed()
+    }
+}
@@ -0,0 +1,440 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use mongodb::Collection;
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
+
+use crate::PostId;
+use crate::access::{ReadPolicy, Role};
+use crate::error::{Error, Result};
+use crate::namespace::Namespace;
+use crate::retry::RetryPolicy;
+use crate::serde_helpers;
+use crate::watcher::{ChangeHandler, PostChange};
+
+/// Who wants to hear about what, one [`Subscription`] per document.
+pub const WEBHOOKS: &str = "webhooks";
+/// One [`Delivery`] per event and subscription, indexed by status and due
+/// time (migration 7).
+pub const WEBHOOK_DELIVERIES: &str = "webhook_deliveries";
+
+/// How long a claimed delivery is left to its deliverer before another may
+/// take it over, should the first have crashed mid-request.
+const LEASE: Duration = Duration::from_secs(60);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// A post was inserted, updated or replaced.
+    #[serde(rename = "post.upserted")]
+    PostUpserted,
+    #[serde(rename = "post.deleted")]
+    PostDeleted,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::PostUpserted => "post.upserted",
+            WebhookEvent::PostDeleted => "post.deleted",
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        [WebhookEvent::PostUpserted, WebhookEvent::PostDeleted].into_iter()
+            .find(|event| event.as_str() == s)
+            .ok_or_else(|| format!("unknown event {:?}, try post.upserted or post.deleted", s))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Subscription {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// Where deliveries are POSTed, http or https.
+    pub url: String,
+    /// The events wanted; every event when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    #[serde(with = "serde_helpers::datetime")]
+    pub created_at: DateTime,
+}
+
+impl Subscription {
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Not sent yet, or sent and due again at `next_attempt_at`.
+    Pending,
+    /// Answered with a 2xx.
+    Delivered,
+    /// Gave up after the last attempt.
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DeliveryStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        [DeliveryStatus::Pending, DeliveryStatus::Delivered, DeliveryStatus::Failed].into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown status {:?}, try pending, delivered or failed", s))
+    }
+}
+
+/// One event on its way to one subscription, and how that is going.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    /// `<subscription>:<post>:<version>`, or `:deleted` in place of the
+    /// version, so the same change seen twice is delivered once. Sent as
+    /// the `X-Webhook-Delivery` header for receivers to deduplicate by.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub subscription_id: ObjectId,
+    pub url: String,
+    pub event: WebhookEvent,
+    /// The body, sent as relaxed extended JSON.
+    pub payload: Document,
+    pub status: DeliveryStatus,
+    #[serde(with = "serde_helpers::int")]
+    pub attempts: i32,
+    /// The HTTP status of the last attempt, if it got one.
+    #[serde(default, with = "serde_helpers::optional_int")]
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    #[serde(with = "serde_helpers::datetime")]
+    pub next_attempt_at: DateTime,
+    #[serde(with = "serde_helpers::datetime")]
+    pub created_at: DateTime,
+    #[serde(default, with = "serde_helpers::optional_datetime")]
+    pub delivered_at: Option<DateTime>,
+}
+
+/// What subscribers receive for `change`: the post as a public reader sees
+/// it, or just its id once it's deleted.
+pub fn payload(change: &PostChange) -> bson::ser::Result<(WebhookEvent, PostId, Document)> {
+    match change {
+        PostChange::Upserted(post) => {
+            let mut post_doc = bson::to_document(post.as_ref())?;
+            for field in ReadPolicy::posts().hidden(Role::Public) {
+                post_doc.remove(field);
+            }
+            let event = WebhookEvent::PostUpserted;
+            Ok((event, post.id, doc! { "event": event.as_str(), "post": post_doc }))
+        }
+        PostChange::Deleted(id) => {
+            let event = WebhookEvent::PostDeleted;
+            Ok((event, *id, doc! { "event": event.as_str(), "post_id": id }))
+        }
+    }
+}
+
+/// Adding, listing and removing subscriptions, and looking at deliveries.
+#[derive(Clone)]
+pub struct Webhooks {
+    subscriptions: Collection<Subscription>,
+    deliveries: Collection<Delivery>,
+}
+
+impl Webhooks {
+    pub fn new(ns: &Namespace) -> Self {
+        Webhooks { subscriptions: ns.collection(WEBHOOKS), deliveries: ns.collection(WEBHOOK_DELIVERIES) }
+    }
+
+    /// Subscribes `url` to `events`, all of them when empty.
+    pub async fn subscribe(&self, url: &str, events: Vec<WebhookEvent>) -> Result<Subscription> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| Error::Validation(format!("{:?}: {}", url, e)))?;
+        if !["http", "https"].contains(&parsed.scheme()) {
+            return Err(Error::Validation(format!("a webhook URL is http or https, not {:?}", url)));
+        }
+        let subscription = Subscription {
+            id: ObjectId::new(),
+            url: url.to_string(),
+            events,
+            created_at: DateTime::now(),
+        };
+        self.subscriptions.insert_one(&subscription, None).await?;
+        Ok(subscription)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Subscription>> {
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        Ok(self.subscriptions.find(None, options).await?.try_collect().await?)
+    }
+
+    /// Removes the subscription and what is still pending for it; what was
+    /// delivered or failed is kept. [`Error::NotFound`] if there is none.
+    pub async fn unsubscribe(&self, id: ObjectId) -> Result<()> {
+        if self.subscriptions.delete_one(doc! { "_id": id }, None).await?.deleted_count == 0 {
+            return Err(Error::NotFound);
+        }
+        self.deliveries.delete_many(doc! { "subscription_id": id, "status": "pending" }, None).await?;
+        Ok(())
+    }
+
+    /// The latest `limit` deliveries, newest first, only those in `status`
+    /// if given.
+    pub async fn deliveries(&self, status: Option<DeliveryStatus>, limit: i64) -> Result<Vec<Delivery>> {
+        let filter = status.map(|status| doc! { "status": status.as_str() });
+        let options = FindOptions::builder().sort(doc! { "created_at": -1 }).limit(limit).build();
+        Ok(self.deliveries.find(filter, options).await?.try_collect().await?)
+    }
+}
+
+/// Turns every change to `posts` into a pending [`Delivery`] for each
+/// subscription wanting it; run it under a
+/// [`Watcher`](crate::watcher::Watcher) and send them with a [`Deliverer`].
+///
+/// A subscription only hears about changes made after it was added, and
+/// changes are delivered at least once: receivers should deduplicate by the
+/// `X-Webhook-Delivery` header.
+pub struct Dispatcher {
+    subscriptions: Collection<Subscription>,
+    deliveries: Collection<Delivery>,
+}
+
+impl Dispatcher {
+    pub fn new(ns: &Namespace) -> Self {
+        Dispatcher { subscriptions: ns.collection(WEBHOOKS), deliveries: ns.collection(WEBHOOK_DELIVERIES) }
+    }
+
+    async fn dispatch(&self, change: &PostChange) -> mongodb::error::Result<()> {
+        let (event, post_id, payload) = payload(change).map_err(mongodb::error::Error::from)?;
+        let version = match change {
+            PostChange::Upserted(post) => post.version.to_string(),
+            PostChange::Deleted(_) => "deleted".to_string(),
+        };
+        let subscriptions: Vec<Subscription> = self.subscriptions.find(None, None).await?.try_collect().await?;
+        for subscription in subscriptions.iter().filter(|subscription| subscription.wants(event)) {
+            let now = DateTime::now();
+            let delivery = Delivery {
+                id: format!("{}:{}:{}", subscription.id, post_id, version),
+                subscription_id: subscription.id,
+                url: subscription.url.clone(),
+                event,
+                payload: payload.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                last_status: None,
+                last_error: None,
+                next_attempt_at: now,
+                created_at: now,
+                delivered_at: None,
+            };
+            let mut insert = bson::to_document(&delivery)?;
+            insert.remove("_id");
+            // A change handled again after a restart finds its delivery there already
+            let options = UpdateOptions::builder().upsert(true).build();
+            self.deliveries.update_one(doc! { "_id": &delivery.id }, doc! { "$setOnInsert": insert }, options)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl ChangeHandler for Dispatcher {
+    fn handle<'a>(&'a self, change: &'a PostChange) -> BoxFuture<'a, mongodb::error::Result<()>> {
+        self.dispatch(change).boxed()
+    }
+}
+
+/// Sends pending deliveries once they are due, retrying those that fail
+/// with `policy`'s backoff until `policy.max_attempts` have been made.
+///
+/// Any number of deliverers can run at once: each claims a delivery before
+/// sending it, and one it claimed but never finished is claimed again after
+/// a minute.
+pub struct Deliverer {
+    deliveries: Collection<Delivery>,
+    http: reqwest::Client,
+    policy: RetryPolicy,
+}
+
+impl Deliverer {
+    /// Eight attempts over about 20 minutes.
+    pub const DEFAULT_POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 8,
+        initial_backoff: Duration::from_secs(10),
+        max_backoff: Duration::from_secs(600),
+    };
+
+    pub fn new(ns: &Namespace, policy: RetryPolicy) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("HTTP client settings are valid");
+        Deliverer { deliveries: ns.collection(WEBHOOK_DELIVERIES), http, policy }
+    }
+
+    /// Delivers what is due every `poll`; only returns on a database error.
+    pub async fn run(&self, poll: Duration) -> Result<()> {
+        loop {
+            self.deliver_due().await?;
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    /// Sends every delivery due now, and returns how many were attempted.
+    pub async fn deliver_due(&self) -> Result<usize> {
+        let mut attempted = 0;
+        while let Some(delivery) = self.claim().await? {
+            let outcome = self.send(&delivery).await;
+            let update = match &outcome {
+                Ok(status) => doc! {
+                    "$set": {
+                        "status": DeliveryStatus::Delivered.as_str(),
+                        "last_status": *status,
+                        "last_error": Bson::Null,
+                        "delivered_at": DateTime::now(),
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                Err((status, error)) => {
+                    tracing::warn!(delivery = %delivery.id, "webhook delivery failed: {}", error);
+                    after_failure(&self.policy, delivery.attempts + 1, *status, error, DateTime::now())
+                }
+            };
+            self.deliveries.update_one(doc! { "_id": &delivery.id, "status": "pending" }, update, None).await?;
+            attempted += 1;
+        }
+        Ok(attempted)
+    }
+
+    /// The next due delivery, leased so no other deliverer takes it meanwhile.
+    async fn claim(&self) -> Result<Option<Delivery>> {
+        let now = DateTime::now();
+        let lease_end = DateTime::from_millis(now.timestamp_millis() + LEASE.as_millis() as i64);
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "next_attempt_at": 1 })
+            .return_document(ReturnDocument::Before)
+            .build();
+        Ok(self.deliveries.find_one_and_update(
+            doc! { "status": "pending", "next_attempt_at": { "$lte": now } },
+            doc! { "$set": { "next_attempt_at": lease_end } },
+            options,
+        ).await?)
+    }
+
+    /// POSTs the payload; the status on a 2xx, otherwise the status if
+    /// there was one and what went wrong.
+    async fn send(&self, delivery: &Delivery) -> std::result::Result<i32, (Option<i32>, String)> {
+        let body = Bson::Document(delivery.payload.clone()).into_relaxed_extjson();
+        let response = self.http.post(&delivery.url)
+            .header("X-Webhook-Event", delivery.event.as_str())
+            .header("X-Webhook-Delivery", &delivery.id)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| (None, e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.as_u16() as i32)
+        } else {
+            Err((Some(status.as_u16() as i32), format!("HTTP {}", status)))
+        }
+    }
+}
+
+/// The update recording that attempt number `attempts` failed: due again
+/// after the policy's backoff, or failed for good after the last attempt.
+fn after_failure(
+    policy: &RetryPolicy,
+    attempts: i32,
+    status: Option<i32>,
+    error: &str,
+    now: DateTime,
+) -> Document {
+    let mut set = doc! { "last_status": status, "last_error": error };
+    if attempts as u32 >= policy.max_attempts {
+        set.insert("status", DeliveryStatus::Failed.as_str());
+    } else {
+        let backoff = policy.backoff(attempts as u32 - 1);
+        set.insert("next_attempt_at", DateTime::from_millis(now.timestamp_millis() + backoff.as_millis() as i64));
+    }
+    doc! { "$set": set, "$inc": { "attempts": 1 } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Post;
+
+    #[test]
+    fn payloads_show_posts_as_the_public_sees_them() {
+        let mut post = Post::new("Hooked", "Sent out", &["rust"]);
+        post.author_email = Some("ann@example.com".to_string());
+        let (event, id, upserted) = payload(&PostChange::Upserted(Box::new(post.clone()))).unwrap();
+        assert_eq!((event, id), (WebhookEvent::PostUpserted, post.id));
+        assert_eq!(upserted.get_str("event"), Ok("post.upserted"));
+        let sent = upserted.get_document("post").unwrap();
+        assert_eq!(sent.get_str("title"), Ok("Hooked"));
+        assert!(!sent.contains_key("author_email"));
+
+        let (_, _, deleted) = payload(&PostChange::Deleted(post.id)).unwrap();
+        assert_eq!(deleted, doc! { "event": "post.deleted", "post_id": post.id });
+    }
+
+    #[test]
+    fn failed_attempts_back_off_then_give_up() {
+        let policy = Deliverer::DEFAULT_POLICY;
+        let now = DateTime::from_millis(1_000_000);
+        let update = after_failure(&policy, 2, Some(503), "HTTP 503", now);
+        let set = update.get_document("$set").unwrap();
+        assert_eq!(set.get_datetime("next_attempt_at"), Ok(&DateTime::from_millis(1_020_000)));
+        assert!(!set.contains_key("status"));
+
+        let update = after_failure(&policy, 8, None, "connection refused", now);
+        let set = update.get_document("$set").unwrap();
+        assert_eq!(set.get_str("status"), Ok("failed"));
+        assert_eq!(set.get("last_status"), Some(&Bson::Null));
+    }
+
+    #[test]
+    fn subscriptions_without_events_want_them_all() {
+        let mut subscription = Subscription {
+            id: ObjectId::new(),
+            url: "https://example.com/hook".to_string(),
+            events: vec![],
+            created_at: DateTime::now(),
+        };
+        assert!(subscription.wants(WebhookEvent::PostDeleted));
+        subscription.events = vec![WebhookEvent::PostUpserted];
+        assert!(!subscription.wants(WebhookEvent::PostDeleted));
+        assert_eq!("post.deleted".parse(), Ok(WebhookEvent::PostDeleted));
+    }
+}
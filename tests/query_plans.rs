@@ -0,0 +1,118 @@
+//! The plans of the repository's queries against seeded data, read back
+//! from the profiler after running them, so a schema or index change that
+//! leaves one of them without its index fails here rather than in
+//! production. No query is hinted: what is checked is what the planner
+//! picks by itself.
+//!
+//! Needs a MongoDB at `MONGODB_TEST_URI`, falling back to the application's
+//! `MONGODB_URI` (localhost:27017 by default), that isn't a mongos, which
+//! has no profiler; run with `cargo test -- --ignored`.
+
+use std::collections::BTreeMap;
+
+use futures::TryStreamExt;
+use mongodb::Client;
+use mongodb::bson::{doc, Bson, Document};
+use rust_mongodb_example::attributes::Attribute;
+use rust_mongodb_example::config::AppConfig;
+use rust_mongodb_example::migrations;
+use rust_mongodb_example::namespace::Namespace;
+use rust_mongodb_example::repository::{PostPatch, PostRepository};
+use rust_mongodb_example::sandbox::Sandbox;
+use rust_mongodb_example::seed::{self, SeedOptions};
+
+/// The operations, by the `op` of their comment, that an index serves.
+/// Every one has to show up in the profile, so an operation renamed or no
+/// longer run here doesn't pass by being left out.
+const INDEXED: &[&str] = &[
+    "find_by_id",
+    "find_by_ids",
+    "find_by_title",
+    "title_taken",
+    "find_by_tag",
+    "find_summaries_by_tag",
+    "list_summaries",
+    "find_posts_paginated",
+    "ids_by_author",
+    "find_by_attributes",
+    "suggest",
+    "search_posts",
+    "add_comment",
+    "find_comments",
+    "find_comment_ids",
+    "patch_post",
+    "update_title_by_tag",
+    "delete_comment",
+    "delete_by_id",
+];
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn indexed_queries_dont_scan_the_collection() {
+    let uri = match std::env::var("MONGODB_TEST_URI") {
+        Ok(uri) => uri,
+        Err(_) => AppConfig::load(None).unwrap().uri,
+    };
+    let client = Client::with_uri_str(uri).await.unwrap();
+    let sandbox = Sandbox::new(&client);
+    let ns = Namespace::new(sandbox.database(), "");
+    migrations::posts().run(&ns).await.unwrap();
+    let options = SeedOptions { posts: 2000, tags: 20, ..SeedOptions::default() };
+    seed::seed(&ns, &options).await.unwrap();
+    let repo = PostRepository::new(&ns).with_context("query-plans");
+    let post = repo.list_summaries(1).await.unwrap().remove(0);
+
+    ns.db().run_command(doc! { "profile": 2 }, None).await.unwrap();
+    repo.find_by_id(post.id).await.unwrap();
+    repo.find_by_ids(&[post.id]).await.unwrap();
+    repo.find_by_title(&post.title).await.unwrap();
+    repo.title_taken(&post.title.to_uppercase()).await.unwrap();
+    repo.find_by_tag("seed1").await.unwrap();
+    repo.find_summaries_by_tag("seed1").await.unwrap();
+    repo.list_summaries(20).await.unwrap();
+    repo.find_posts_paginated(doc! {}, Some(post.id), 20).await.unwrap();
+    repo.ids_by_author("ann", 20).await.unwrap();
+    repo.find_by_attributes(&[Attribute::new("color", "red")]).await.unwrap();
+    repo.suggest("[se", 10).await.unwrap();
+    repo.search_posts("mongo").await.unwrap();
+    let comment = repo.add_comment(post.id, "ann", "Planned").await.unwrap();
+    repo.find_comments(post.id).await.unwrap();
+    repo.find_comment_ids(post.id).await.unwrap();
+    let patch = PostPatch { message: Some("Patched".to_string()), ..PostPatch::default() };
+    repo.patch_post(post.id, &patch).await.unwrap();
+    repo.update_title_by_tag("untagged", "[seed] Retitled").await.unwrap();
+    repo.delete_comment(comment.id).await.unwrap();
+    repo.delete_by_id(post.id).await.unwrap();
+    ns.db().run_command(doc! { "profile": 0 }, None).await.unwrap();
+
+    let plans = profiled_plans(&ns).await;
+    for op in INDEXED {
+        let summaries = plans.get(*op).unwrap_or_else(|| panic!("{} wasn't profiled", op));
+        assert!(
+            !summaries.iter().any(|summary| summary.contains("COLLSCAN")),
+            "{} scans the collection: {:?}", op, summaries,
+        );
+    }
+}
+
+/// The plan summaries of every profiled operation, by the `op` of its
+/// comment. Reads and aggregations carry the comment in their command,
+/// writes next to it.
+async fn profiled_plans(ns: &Namespace) -> BTreeMap<String, Vec<String>> {
+    let profile = ns.db().collection::<Document>("system.profile");
+    let entries: Vec<Document> = profile.find(doc! { "planSummary": { "$exists": true } }, None).await.unwrap()
+        .try_collect().await.unwrap();
+    let mut plans = BTreeMap::<String, Vec<String>>::new();
+    for entry in entries {
+        let comment = entry.get_document("command").ok().and_then(|command| command.get("comment"))
+            .or_else(|| entry.get("comment"));
+        let op = match comment {
+            Some(Bson::Document(comment)) => comment.get_str("op").ok(),
+            _ => None,
+        };
+        if let (Some(op), Ok(summary)) = (op, entry.get_str("planSummary")) {
+            plans.entry(op.to_string()).or_default().push(summary.to_string());
+        }
+    }
+    plans
+}
@@ -0,0 +1,260 @@
+//! Repository operations against a real server, each test in its own sandbox
+//! database set up with the `posts` schema.
+//!
+//! Needs a MongoDB at `MONGODB_TEST_URI`, falling back to the application's
+//! `MONGODB_URI` (localhost:27017 by default), e.g. a throwaway
+//! `docker run --rm -p 27017:27017 mongo`; run with `cargo test -- --ignored`.
+
+use std::sync::Arc;
+
+use mongodb::Client;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::{ErrorKind, WriteFailure};
+use rust_mongodb_example::access::Role;
+use rust_mongodb_example::attributes::Attribute;
+use rust_mongodb_example::config::AppConfig;
+use rust_mongodb_example::error::Error;
+use rust_mongodb_example::namespace::Namespace;
+use rust_mongodb_example::repository::{
+    CommentCountDrift, PostRepository, SearchFilters, COMMENTS, EMBEDDED_COMMENTS,
+};
+use rust_mongodb_example::sandbox::Sandbox;
+use rust_mongodb_example::title_filter::TitleFilter;
+use rust_mongodb_example::{schema, Comment, Post};
+
+/// Keep the sandbox alive for as long as the test uses `ns`.
+async fn posts() -> (Sandbox, Namespace, PostRepository) {
+    let uri = match std::env::var("MONGODB_TEST_URI") {
+        Ok(uri) => uri,
+        Err(_) => AppConfig::load(None).unwrap().uri,
+    };
+    let client = Client::with_uri_str(uri).await.unwrap();
+    let sandbox = Sandbox::new(&client);
+    let ns = Namespace::new(sandbox.database(), "");
+    schema::setup_posts(&ns).await.unwrap();
+    let repo = PostRepository::new(&ns).with_context("test");
+    (sandbox, ns, repo)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn inserted_post_reads_back() {
+    let (_sandbox, _, repo) = posts().await;
+    let post = Post::new("Round trip", "Stored and read back", &["test"]);
+    let id = repo.insert(&post).await.unwrap();
+    let found = repo.find_by_id(id).await.unwrap();
+    assert_eq!((found.title.as_str(), found.message.as_str()), ("Round trip", "Stored and read back"));
+    assert_eq!(found.tags, ["test"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn titles_differing_in_case_are_duplicates() {
+    let (_sandbox, _, repo) = posts().await;
+    repo.insert(&Post::new("Unique", "First", &["test"])).await.unwrap();
+    let duplicate = repo.insert(&Post::new("UNIQUE", "Second", &["test"])).await;
+    assert!(matches!(duplicate, Err(Error::DuplicateTitle(title)) if title == "UNIQUE"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn title_filter_only_hints_and_the_index_decides() {
+    let (_sandbox, ns, repo) = posts().await;
+    repo.insert(&Post::new("Loaded", "Before the filter", &["test"])).await.unwrap();
+    let repo = repo.with_title_filter(Arc::new(TitleFilter::load(&ns).await.unwrap()));
+    assert!(repo.title_taken("LOADED").await.unwrap());
+    repo.insert(&Post::new("Written", "Through the filter", &["test"])).await.unwrap();
+    assert!(repo.title_taken("written").await.unwrap());
+    assert!(!repo.title_taken("Free").await.unwrap());
+
+    // Written elsewhere: the filter says free, the index still refuses it
+    PostRepository::new(&ns).insert(&Post::new("Elsewhere", "Not seen", &["test"])).await.unwrap();
+    assert!(!repo.title_taken("Elsewhere").await.unwrap());
+    let duplicate = repo.insert(&Post::new("ELSEWHERE", "Again", &["test"])).await;
+    assert!(matches!(duplicate, Err(Error::DuplicateTitle(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn validator_rejects_documents_breaking_the_schema() {
+    let (_sandbox, ns, _) = posts().await;
+    // `message` is required
+    let invalid = doc! { "title": "No message", "tags": ["test"] };
+    let err = ns.collection::<Document>("posts").insert_one(invalid, None).await.unwrap_err();
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => assert_eq!(write_error.code, 121),
+        other => panic!("expected DocumentValidationFailure, got {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn replacing_with_an_invalid_post_is_a_validation_error() {
+    let (_sandbox, _, repo) = posts().await;
+    let id = repo.insert(&Post::new("Valid", "For now", &["test"])).await.unwrap();
+    let post = repo.find_by_id(id).await.unwrap();
+    // Tags are at most 10 characters long
+    let invalid = Post { tags: vec!["far-too-long-a-tag".to_string()], ..post };
+    assert!(matches!(repo.replace_post(&invalid).await, Err(Error::Validation(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn tag_queries_use_the_tag_index() {
+    let (_sandbox, ns, repo) = posts().await;
+    repo.insert(&Post::new("Indexed", "Found through tags_1", &["test"])).await.unwrap();
+    let explain = ns.db().run_command(doc! {
+        "explain": { "find": ns.name("posts"), "filter": { "tags": "test" } },
+        "verbosity": "queryPlanner",
+    }, None).await.unwrap();
+    let plan = explain.get_document("queryPlanner").unwrap().get_document("winningPlan").unwrap();
+    // The index scan sits somewhere under the FETCH stage
+    let plan = plan.to_string();
+    assert!(plan.contains("IXSCAN") && plan.contains("tags_1"), "winning plan: {}", plan);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn group_by_tag_collects_post_ids() {
+    let (_sandbox, _, repo) = posts().await;
+    let first = repo.insert(&Post::new("First", "Tagged twice", &["one", "two"])).await.unwrap();
+    let second = repo.insert(&Post::new("Second", "Tagged once", &["two"])).await.unwrap();
+    let mut groups = repo.group_by_tag().await.unwrap();
+    groups.sort_by(|a, b| a.tag.cmp(&b.tag));
+    for group in &mut groups {
+        group.post_ids.sort();
+    }
+    let groups: Vec<_> = groups.into_iter().map(|group| (group.tag, group.post_ids)).collect();
+    let mut both = vec![first, second];
+    both.sort();
+    assert_eq!(groups, [("one".to_string(), vec![first]), ("two".to_string(), both)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn title_matches_outrank_message_matches() {
+    let (_sandbox, _, repo) = posts().await;
+    repo.insert(&Post::new("Assorted notes", "Some mongo advice", &["test"])).await.unwrap();
+    repo.insert(&Post::new("Mongo tips", "Assorted advice", &["test"])).await.unwrap();
+    repo.insert(&Post::new("Unrelated", "Nothing to see", &["test"])).await.unwrap();
+    let hits = repo.search_posts("mongo").await.unwrap();
+    let titles: Vec<&str> = hits.iter().map(|hit| hit.post.title.as_str()).collect();
+    assert_eq!(titles, ["Mongo tips", "Assorted notes"]);
+    assert!(hits[0].score > hits[1].score);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn renaming_a_tag_merges_it_into_an_existing_one() {
+    let (_sandbox, _, repo) = posts().await;
+    let both = repo.insert(&Post::new("Both", "Tagged old and new", &["old", "mid", "new"])).await.unwrap();
+    let old = repo.insert(&Post::new("Old", "Tagged old only", &["old", "mid"])).await.unwrap();
+    repo.insert(&Post::new("Other", "Untouched", &["mid"])).await.unwrap();
+    let renamed = repo.rename_tag("old", "new").await.unwrap();
+    assert_eq!((renamed.matched, renamed.modified, renamed.merged), (2, 2, 1));
+    assert_eq!(repo.find_by_id(both).await.unwrap().tags, ["new", "mid"]);
+    assert_eq!(repo.find_by_id(old).await.unwrap().tags, ["new", "mid"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn posts_join_their_comments_in_order() {
+    let (_sandbox, _, repo) = posts().await;
+    let commented = repo.insert(&Post::new("Commented", "Has comments", &["test"])).await.unwrap();
+    let quiet = repo.insert(&Post::new("Quiet", "Has none", &["test"])).await.unwrap();
+    let first = repo.add_comment(commented, "ann", "First!").await.unwrap();
+    let second = repo.add_comment(commented, "bob", "Second").await.unwrap();
+    let joined = repo.find_posts_with_comments(doc! { "tags": "test" }).await.unwrap();
+    let joined: Vec<_> = joined.into_iter().map(|post| (post.post.id, post.comments)).collect();
+    assert_eq!(joined, [(commented, vec![first, second]), (quiet, vec![])]);
+    let missing = repo.add_comment(mongodb::bson::oid::ObjectId::new(), "cy", "Lost").await;
+    assert!(matches!(missing, Err(Error::NotFound)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn comment_ids_past_the_embedded_ones_overflow_and_read_back_in_order() {
+    let (_sandbox, _, repo) = posts().await;
+    let viral = repo.insert(&Post::new("Viral", "Many comments", &["test"])).await.unwrap();
+    let mut added = Vec::new();
+    for n in 0..EMBEDDED_COMMENTS + 3 {
+        added.push(repo.add_comment(viral, "ann", &format!("Comment {}", n)).await.unwrap().id);
+    }
+    let post = repo.find_by_id(viral).await.unwrap();
+    assert_eq!((post.comment_ids.len(), post.comments_overflow), (EMBEDDED_COMMENTS, true));
+    assert_eq!(repo.find_comment_ids(viral).await.unwrap(), added);
+    let quiet = repo.insert(&Post::new("Quiet", "No comments", &["test"])).await.unwrap();
+    assert!(repo.find_comment_ids(quiet).await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn comment_counts_follow_comment_writes_and_drift_is_repaired() {
+    let (_sandbox, ns, repo) = posts().await;
+    let post = repo.insert(&Post::new("Counted", "Has comments", &["test"])).await.unwrap();
+    let first = repo.add_comment(post, "ann", "One").await.unwrap();
+    repo.add_comment(post, "bob", "Two").await.unwrap();
+    repo.delete_comment(first.id).await.unwrap();
+    assert_eq!(repo.find_by_id(post).await.unwrap().comment_count, 1);
+    assert!(repo.comment_count_drift().await.unwrap().is_empty());
+
+    // Written around the repository, so never counted
+    let stray = Comment { id: ObjectId::new(), ..first };
+    ns.collection::<Comment>(COMMENTS).insert_one(&stray, None).await.unwrap();
+    let drift = repo.comment_count_drift().await.unwrap();
+    assert_eq!(drift, [CommentCountDrift { post_id: post, stored: 1, actual: 2 }]);
+    assert_eq!(repo.repair_comment_counts(&drift).await.unwrap(), 1);
+    assert!(repo.comment_count_drift().await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn posts_are_found_by_attributes_of_the_same_pair() {
+    let (_sandbox, _, repo) = posts().await;
+    let book = Post {
+        metadata: vec![Attribute::new("color", "red"), Attribute::new("pages", 120)],
+        ..Post::new("Book", "Red, 120 pages", &["test"])
+    };
+    let poster = Post {
+        metadata: vec![Attribute::new("color", "blue"), Attribute::new("size", "red")],
+        ..Post::new("Poster", "Blue, size red", &["test"])
+    };
+    repo.insert_many(&[book.clone(), poster]).await.unwrap();
+    let found = repo.find_by_attributes(&[Attribute::new("color", "red")]).await.unwrap();
+    assert_eq!(found.iter().map(|post| post.id).collect::<Vec<_>>(), [book.id]);
+    let both = [Attribute::new("color", "red"), Attribute::new("pages", 120)];
+    assert_eq!(repo.find_by_attributes(&both).await.unwrap().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn digest_ranks_each_tags_posts_by_comments() {
+    let (_sandbox, _, repo) = posts().await;
+    let quiet = repo.insert(&Post::new("Quiet", "No comments", &["rust", "mongo"])).await.unwrap();
+    let busy = repo.insert(&Post::new("Busy", "Two comments", &["rust"])).await.unwrap();
+    repo.add_comment(busy, "ann", "One").await.unwrap();
+    repo.add_comment(busy, "bob", "Two").await.unwrap();
+    let to = chrono::Utc::now() + chrono::Duration::minutes(1);
+    let digest = repo.weekly_digest(to - chrono::Duration::days(7), to, 1).await.unwrap();
+    let tags: Vec<_> = digest.tags.iter()
+        .map(|tag| (tag.tag.as_str(), tag.new_posts, tag.top_posts.iter().map(|post| post.id.clone()).collect()))
+        .collect();
+    assert_eq!(tags, [("rust", 2, vec![busy.to_hex()]), ("mongo", 1, vec![quiet.to_hex()])]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn public_readers_dont_see_author_emails() {
+    let (_sandbox, _, repo) = posts().await;
+    let email = Some("ann@example.com".to_string());
+    let post = Post { author_email: email, ..Post::new("Private", "Mailed", &["test"]) };
+    let id = repo.insert(&post).await.unwrap();
+    let public = repo.with_role(Role::Public);
+    assert_eq!(public.find_by_id(id).await.unwrap().author_email, None);
+    assert_eq!(public.find_by_tag("test").await.unwrap()[0].author_email, None);
+    let filters = SearchFilters { tags: vec!["test".to_string()], per_page: 10, ..SearchFilters::default() };
+    assert_eq!(public.search(&filters).await.unwrap().items[0].author_email, None);
+    let admin = repo.with_role(Role::Admin);
+    assert_eq!(admin.find_by_id(id).await.unwrap().author_email.as_deref(), Some("ann@example.com"));
+}